@@ -0,0 +1,136 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Finding the outputs of a plan that depend on changed files of the build
+//! context, used by `modus affected`.
+//!
+//! An output depends on a file if any image it is built from copies the file
+//! from the context, and the file is not excluded by `.dockerignore`. Changes
+//! to the Modusfile itself are not considered, use `modus plan-diff` for these.
+
+use serde::Serialize;
+
+use crate::{
+    dockerignore::{path_components, path_matches, DockerIgnore},
+    imagegen::{BuildNode, BuildPlan, MergeOperation, NodeId},
+    planview::output_names,
+};
+
+/// The source paths of the local copies needed to build `node`.
+pub fn local_copy_sources(plan: &BuildPlan, node: NodeId) -> Vec<&str> {
+    let mut sources = Vec::new();
+    for n in plan.nodes_needed_by([node]) {
+        match &plan.nodes[n] {
+            BuildNode::CopyFromLocal { src_path, .. } => sources.push(src_path.as_str()),
+            BuildNode::Merge(merge) => {
+                for op in &merge.operations {
+                    if let MergeOperation::CopyFromLocal { src_path, .. } = op {
+                        sources.push(src_path.as_str());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    sources.sort_unstable();
+    sources.dedup();
+    sources
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AffectedOutput {
+    /// The ground literal of the output.
+    pub name: String,
+    pub node: NodeId,
+    /// The changed files copied into the image, normalized.
+    pub changed_files: Vec<String>,
+}
+
+/// The outputs which copy any of the changed files. Paths are relative to the
+/// context directory.
+pub fn affected_outputs(
+    plan: &BuildPlan,
+    changed_files: &[String],
+    ignore: &DockerIgnore,
+) -> Vec<AffectedOutput> {
+    let changed_files: Vec<String> = changed_files
+        .iter()
+        .map(|f| path_components(f).join("/"))
+        .filter(|f| !f.is_empty() && !ignore.is_ignored(f))
+        .collect();
+    output_names(plan)
+        .into_iter()
+        .zip(plan.outputs.iter())
+        .filter_map(|(name, output)| {
+            let sources = local_copy_sources(plan, output.node);
+            let files: Vec<String> = changed_files
+                .iter()
+                .filter(|f| sources.iter().any(|src| path_matches(src, f)))
+                .cloned()
+                .collect();
+            if files.is_empty() {
+                None
+            } else {
+                Some(AffectedOutput {
+                    name,
+                    node: output.node,
+                    changed_files: files,
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagegen::plan_for;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn outputs_depending_on_files() {
+        let plan = plan_for(
+            r#"
+            base :- from("alpine"), copy("shared/", "/shared").
+            service("api") :- base, copy("api", "/app").
+            service("web") :- base, (copy("web/package.json", "."), run("npm ci"))::merge.
+            service("docs") :- from("alpine"), run("echo docs").
+        "#,
+            "service(X)",
+        );
+        let ignore = DockerIgnore::parse("**/*.md\n");
+        let affected = |files: &[&str]| -> Vec<String> {
+            let files: Vec<String> = files.iter().map(|f| f.to_string()).collect();
+            let mut names: Vec<String> = affected_outputs(&plan, &files, &ignore)
+                .into_iter()
+                .map(|o| o.name)
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(affected(&["api/main.go"]), vec![r#"service("api")"#]);
+        assert_eq!(affected(&["./web/package.json"]), vec![r#"service("web")"#]);
+        assert!(affected(&["web/src/index.js"]).is_empty());
+        assert_eq!(
+            affected(&["shared/lib.rs"]),
+            vec![r#"service("api")"#, r#"service("web")"#]
+        );
+        // Ignored files are not sent to the builder.
+        assert!(affected(&["shared/README.md"]).is_empty());
+    }
+}
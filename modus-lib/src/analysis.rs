@@ -34,12 +34,18 @@ use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
 use codespan_reporting::files::Files;
 use codespan_reporting::term::{self, Config};
 use petgraph::algo::find_negative_cycle;
+use petgraph::visit::Dfs;
 
-use crate::builtin::{select_builtin, OPERATOR_KIND_MAP};
-use crate::logic::{self, Literal, Predicate, SpannedPosition};
-use crate::modusfile::{Expression, ModusClause, Operator};
+use crate::builtin::{self, select_builtin, SelectBuiltinResult, OPERATOR_KIND_MAP};
+use crate::logic::{self, ConstantType, Literal, Predicate, Signature, SpannedPosition};
+use crate::modusfile::{
+    Expression, FormatStringFragment, KindDeclaration, ModeDeclaration, ModusClause, Operator,
+};
 use crate::modusfile::{ModusTerm, Modusfile};
+use crate::suggest;
+use crate::testing::TEST_PREFIX;
 use crate::translate::translate_modusfile;
+use crate::wellformed::{self, ModeError};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Kind {
@@ -147,8 +153,14 @@ impl ModusSemantics for Modusfile {
         }
 
         fn generate_unknown_operator_diag(op: &Operator) -> Diagnostic<()> {
-            let diag =
-                Diagnostic::error().with_message(format!("Unknown operator: {}", op.predicate));
+            let diag = Diagnostic::error()
+                .with_message(format!("Unknown operator: {}", op.predicate))
+                .with_notes(
+                    suggest::suggest_operator(op)
+                        .map(|s| s.message)
+                        .into_iter()
+                        .collect(),
+                );
             if let Some(pos) = &op.position {
                 diag.with_labels(vec![Label::primary(
                     (),
@@ -159,6 +171,15 @@ impl ModusSemantics for Modusfile {
             }
         }
 
+        fn generate_declaration_labels(
+            decl: &KindDeclaration,
+        ) -> impl Iterator<Item = Label<()>> + '_ {
+            decl.position.iter().map(move |pos| {
+                Label::secondary((), Range::from(pos))
+                    .with_message(format!("declared to be of kind {:?} here", decl.kind))
+            })
+        }
+
         fn generate_failed_assumption(
             expr: &Expression,
             expected: &Kind,
@@ -451,6 +472,89 @@ impl ModusSemantics for Modusfile {
             }
         }
 
+        let mut messages = Vec::new();
+        let mut errs = Vec::new();
+
+        // Declared kinds are known upfront, so a clause that doesn't match the declaration
+        // is reported at the clause, rather than where the predicate is used.
+        let defined: HashSet<Signature> = self
+            .0
+            .iter()
+            .map(|c| Signature(c.head.predicate.clone(), c.head.args.len() as u32))
+            .collect();
+        let mut declared: HashMap<&Predicate, &KindDeclaration> = HashMap::new();
+        for decl in self.kind_declarations() {
+            let labels: Vec<Label<()>> = decl
+                .position
+                .iter()
+                .map(|pos| Label::primary((), Range::from(pos)))
+                .collect();
+            if !defined.contains(&decl.signature()) {
+                errs.push(
+                    Diagnostic::warning()
+                        .with_message(format!(
+                            "Kind declared for `{}`, which is not defined.",
+                            decl.signature()
+                        ))
+                        .with_labels(labels.clone()),
+                );
+            }
+            match declared.get(&decl.predicate) {
+                Some(previous) if previous.kind != decl.kind => errs.push(
+                    Diagnostic::error()
+                        .with_message(format!(
+                            "Conflicting kind declarations for `{}`.",
+                            decl.predicate
+                        ))
+                        .with_labels(
+                            labels
+                                .into_iter()
+                                .chain(
+                                    generate_declaration_labels(previous)
+                                        .map(|l| l.with_message("previously declared here")),
+                                )
+                                .collect(),
+                        ),
+                ),
+                Some(_) => {}
+                None => {
+                    declared.insert(&decl.predicate, decl);
+                    pred_kind.insert(decl.predicate.clone(), decl.kind);
+                    messages.push(
+                        Diagnostic::note()
+                            .with_message(format!(
+                                "{} is declared to be of kind {:?}",
+                                decl.predicate, decl.kind
+                            ))
+                            .with_labels(labels),
+                    );
+                }
+            }
+        }
+        for c in self.0.iter().filter(|c| c.body.is_none()) {
+            match declared.get(&c.head.predicate) {
+                Some(decl) if decl.kind != Kind::Logic => errs.push(
+                    Diagnostic::error()
+                        .with_message(format!(
+                            "{} is declared to be of kind {:?}, but has a fact.",
+                            c.head.predicate, decl.kind
+                        ))
+                        .with_labels(
+                            c.head
+                                .position
+                                .iter()
+                                .map(|pos| {
+                                    Label::primary((), Range::from(pos))
+                                        .with_message("facts are of kind Logic")
+                                })
+                                .chain(generate_declaration_labels(decl))
+                                .collect(),
+                        ),
+                ),
+                _ => {}
+            }
+        }
+
         loop {
             let mut new_pred = false;
             for c in self.0.iter().filter(|c| c.body.is_some()) {
@@ -475,8 +579,6 @@ impl ModusSemantics for Modusfile {
             }
         }
 
-        let mut messages = Vec::new();
-        let mut errs = Vec::new();
         // evaluate all the expression bodies a final time to pick up any conflicting definitions
         for c in self.0.iter().filter(|c| c.body.is_some()) {
             let assertion = pred_kind.get(&c.head.predicate).copied();
@@ -528,10 +630,19 @@ impl ModusSemantics for Modusfile {
                     messages.push(generate_msg_diagnostic(c, &kind));
                     pred_kind.insert(pred.clone(), kind);
                 }
-                Err(e) => errs.push(e.with_notes(vec![format!(
-                    "Therefore, couldn't evaluate clause with head {}.",
-                    c.head,
-                )])),
+                Err(e) => errs.push(
+                    e.with_labels(
+                        declared
+                            .get(&c.head.predicate)
+                            .into_iter()
+                            .flat_map(|decl| generate_declaration_labels(decl))
+                            .collect(),
+                    )
+                    .with_notes(vec![format!(
+                        "Therefore, couldn't evaluate clause with head {}.",
+                        c.head,
+                    )]),
+                ),
             }
         }
 
@@ -827,20 +938,32 @@ pub fn check_and_output_analysis<
 
     let can_translate = term_errors.is_empty();
 
-    let negation_errors = if can_translate {
+    let source = file.source(()).ok();
+    let source = source.as_ref().map(|s| s.as_ref());
+    let (negation_errors, mode_errors) = if can_translate {
         let ir_clauses = translate_modusfile(&mf);
-        check_negated_logic_kind(&ir_clauses, &kind_res.pred_kind)
+        let negation_errors = check_negated_logic_kind(&ir_clauses, &kind_res.pred_kind)
             .err()
-            .unwrap_or_default()
+            .unwrap_or_default();
+        let mode_errors = wellformed::check_modes(&ir_clauses, &mf.modes())
+            .iter()
+            .map(|e| generate_mode_error_diag(e, source))
+            .collect();
+        (negation_errors, mode_errors)
     } else {
-        Vec::new()
+        (Vec::new(), Vec::new())
     };
+    let declaration_errors = check_mode_declarations(&mf);
+    let type_errors = type_errors(&mf, source);
 
     let errs = kind_res
         .errs
         .iter()
         .chain(&negation_errors)
         .chain(&term_errors)
+        .chain(&declaration_errors)
+        .chain(&mode_errors)
+        .chain(&type_errors)
         .collect::<Vec<_>>();
     for err in &errs {
         term::emit(out, config, file, err).expect("Error when writing to stderr.");
@@ -864,6 +987,723 @@ pub fn check_and_output_analysis<
     errs.iter().all(|err| err.severity != Severity::Error)
 }
 
+/// The range of an argument of a literal in the source, found by splitting the
+/// text of the literal at the commas between its arguments.
+fn argument_range(source: &str, position: &SpannedPosition, index: usize) -> Option<Range<usize>> {
+    let text = source.get(Range::from(position))?;
+    let open = text.find('(')?;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = open + 1;
+    let mut current = 0;
+    for (i, c) in text[open + 1..].char_indices() {
+        let i = open + 1 + i;
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '(' | '[' => depth += 1,
+            ')' | ']' if depth > 0 => depth -= 1,
+            ',' | ')' if depth == 0 => {
+                if current == index {
+                    let arg = &text[start..i];
+                    let arg_start = start + arg.len() - arg.trim_start().len();
+                    let arg_end = start + arg.trim_end().len();
+                    return Some(position.offset + arg_start..position.offset + arg_end);
+                }
+                current += 1;
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn generate_mode_error_diag(error: &ModeError, source: Option<&str>) -> Diagnostic<()> {
+    let (lit, index, message, label) = match error {
+        ModeError::UngroundInput(lit, index) => (
+            lit,
+            *index,
+            format!(
+                "Argument {} of `{}` is declared as an input (+), but might not be ground.",
+                index + 1,
+                lit.predicate
+            ),
+            "might not be ground",
+        ),
+        ModeError::UnboundOutput(lit, index) => (
+            lit,
+            *index,
+            format!(
+                "Argument {} of `{}` is declared as an output (-), but this clause does not bind it.",
+                index + 1,
+                lit.predicate
+            ),
+            "not bound by the body",
+        ),
+    };
+    let mut labels = Vec::new();
+    if let Some(pos) = &lit.position {
+        match source.and_then(|source| argument_range(source, pos, index)) {
+            Some(range) => {
+                labels.push(Label::primary((), range).with_message(label));
+                labels.push(Label::secondary((), Range::from(pos)));
+            }
+            None => labels.push(Label::primary((), Range::from(pos)).with_message(label)),
+        }
+    }
+    Diagnostic::error()
+        .with_message(message)
+        .with_labels(labels)
+        .with_notes(if lit.position.is_none() {
+            vec![lit.to_string()]
+        } else {
+            Vec::new()
+        })
+}
+
+/// Checks the types of the constants passed to builtin predicates and operators,
+/// e.g. that an integer is not passed where a string is expected.
+fn type_errors(mf: &Modusfile, source: Option<&str>) -> Vec<Diagnostic<()>> {
+    /// `first_arg` is the index of the first argument in the builtin, since the
+    /// builtins of operators take an extra argument to match them.
+    fn check_args(
+        name: &str,
+        builtin: &str,
+        first_arg: usize,
+        args: &[ModusTerm],
+        position: Option<&SpannedPosition>,
+        source: Option<&str>,
+    ) -> Vec<Diagnostic<()>> {
+        fn constant_types(term: &ModusTerm) -> Vec<ConstantType> {
+            match term {
+                ModusTerm::List(_, ts) => ts.iter().flat_map(constant_types).collect(),
+                t => t.constant_type().into_iter().collect(),
+            }
+        }
+
+        let arity = first_arg + args.len();
+        let mut diags = Vec::new();
+        for (index, arg) in args.iter().enumerate() {
+            let ty = match constant_types(arg).into_iter().find(|ty| {
+                builtin::builtin_accepts(builtin, arity, first_arg + index, *ty) == Some(false)
+            }) {
+                Some(ty) => ty,
+                None => continue,
+            };
+            let verb = if let ModusTerm::List(..) = arg {
+                "contains"
+            } else {
+                "is"
+            };
+            let expected = [
+                ConstantType::String,
+                ConstantType::Integer,
+                ConstantType::Boolean,
+            ]
+            .iter()
+            .filter(|t| {
+                builtin::builtin_accepts(builtin, arity, first_arg + index, **t) == Some(true)
+            })
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>()
+            .join(" or ");
+            let label = format!("expected {}", expected);
+            let mut labels = Vec::new();
+            if let Some(pos) = position {
+                match source.and_then(|source| argument_range(source, pos, index)) {
+                    Some(range) => {
+                        labels.push(Label::primary((), range).with_message(label));
+                        labels.push(Label::secondary((), Range::from(pos)));
+                    }
+                    None => labels.push(Label::primary((), Range::from(pos)).with_message(label)),
+                }
+            }
+            diags.push(
+                Diagnostic::error()
+                    .with_message(format!(
+                        "Argument {} of `{}` {} {}, but {} is expected.",
+                        index + 1,
+                        name,
+                        verb,
+                        ty,
+                        expected
+                    ))
+                    .with_labels(labels),
+            );
+        }
+        diags
+    }
+
+    fn collect(expr: &Expression, source: Option<&str>, diags: &mut Vec<Diagnostic<()>>) {
+        match expr {
+            Expression::Literal(lit) => diags.extend(check_args(
+                &lit.predicate.0,
+                &lit.predicate.0,
+                0,
+                &lit.args,
+                lit.position.as_ref(),
+                source,
+            )),
+            Expression::OperatorApplication(_, e, op) => {
+                collect(e, source, diags);
+                diags.extend(check_args(
+                    &format!("::{}", op.predicate),
+                    &format!("_operator_{}_begin", op.predicate),
+                    1,
+                    &op.args,
+                    op.position.as_ref(),
+                    source,
+                ));
+            }
+            Expression::And(_, _, e1, e2) | Expression::Or(_, _, e1, e2) => {
+                collect(e1, source, diags);
+                collect(e2, source, diags);
+            }
+        }
+    }
+
+    let mut diags = Vec::new();
+    for body in mf.0.iter().filter_map(|c| c.body.as_ref()) {
+        collect(body, source, &mut diags);
+    }
+    diags
+}
+
+/// Checks that the mode declarations are of defined predicates, and don't conflict.
+fn check_mode_declarations(mf: &Modusfile) -> Vec<Diagnostic<()>> {
+    let defined: HashSet<Signature> =
+        mf.0.iter()
+            .map(|c| Signature(c.head.predicate.clone(), c.head.args.len() as u32))
+            .collect();
+    let mut declared: HashMap<Signature, &ModeDeclaration> = HashMap::new();
+    let mut diags = Vec::new();
+    for decl in mf.mode_declarations() {
+        let signature = decl.signature();
+        let labels: Vec<Label<()>> = decl
+            .position
+            .iter()
+            .map(|pos| Label::primary((), Range::from(pos)))
+            .collect();
+        if !defined.contains(&signature) {
+            diags.push(
+                Diagnostic::warning()
+                    .with_message(format!(
+                        "Mode declared for `{}`, which is not defined.",
+                        signature
+                    ))
+                    .with_labels(labels.clone()),
+            );
+        }
+        match declared.get(&signature) {
+            Some(previous) if previous.modes != decl.modes => diags.push(
+                Diagnostic::error()
+                    .with_message(format!(
+                        "Conflicting mode declarations for `{}`.",
+                        signature
+                    ))
+                    .with_labels(
+                        labels
+                            .into_iter()
+                            .chain(previous.position.iter().map(|pos| {
+                                Label::secondary((), Range::from(pos))
+                                    .with_message("previously declared here")
+                            }))
+                            .collect(),
+                    ),
+            ),
+            Some(_) => {}
+            None => {
+                declared.insert(signature, decl);
+            }
+        }
+    }
+    diags
+}
+
+/// The predicates `modus check --entry` treats as queried, to find the unused
+/// ones. Tests are always entry points.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entries {
+    /// Every image predicate, with `--all-entries`.
+    All,
+    Predicates(Vec<String>),
+}
+
+fn unused_predicates(
+    mf: &Modusfile,
+    kind_res: &KindResult,
+    entries: &Entries,
+) -> Vec<Diagnostic<()>> {
+    let mut diags = Vec::new();
+    let (g, indices) = mf.compute_dependency();
+    // Tests are run by `modus test` rather than queried, so they are always
+    // entry points.
+    let tests =
+        mf.0.iter()
+            .map(|c| c.head.predicate.0.as_str())
+            .filter(|p| p.starts_with(TEST_PREFIX));
+    let mut roots: Vec<&str> = match entries {
+        Entries::All => {
+            mf.0.iter()
+                .map(|c| c.head.predicate.0.as_str())
+                .filter(|p| {
+                    kind_res
+                        .pred_kind
+                        .get(&Predicate(p.to_string()))
+                        .is_some_and(Kind::is_image)
+                })
+                .collect()
+        }
+        Entries::Predicates(preds) => preds
+            .iter()
+            .filter(|p| {
+                let defined = indices.contains_key(p.as_str());
+                if !defined {
+                    diags.push(
+                        Diagnostic::warning()
+                            .with_message(format!("Entry point `{}` is not defined.", p)),
+                    );
+                }
+                defined
+            })
+            .map(String::as_str)
+            .collect(),
+    };
+    roots.extend(tests);
+
+    let mut used = HashSet::new();
+    for root in roots {
+        let mut dfs = Dfs::new(&g, indices[root]);
+        while let Some(n) = dfs.next(&g) {
+            used.insert(g[n]);
+        }
+    }
+
+    let mut unused: Vec<(&str, Vec<&SpannedPosition>)> = Vec::new();
+    for clause in &mf.0 {
+        let pred = clause.head.predicate.0.as_str();
+        if used.contains(pred) {
+            continue;
+        }
+        let positions = match unused.iter_mut().find(|(p, _)| *p == pred) {
+            Some((_, positions)) => positions,
+            None => {
+                unused.push((pred, Vec::new()));
+                &mut unused.last_mut().unwrap().1
+            }
+        };
+        positions.extend(&clause.head.position);
+    }
+    diags.extend(unused.into_iter().map(|(pred, positions)| {
+        Diagnostic::warning()
+            .with_message(format!("Predicate `{}` is never used.", pred))
+            .with_labels(
+                positions
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, pos)| {
+                        if i == 0 {
+                            Label::primary((), Range::from(pos))
+                        } else {
+                            Label::secondary((), Range::from(pos))
+                        }
+                    })
+                    .collect(),
+            )
+    }));
+    diags
+}
+
+fn may_unify(t1: &ModusTerm, t2: &ModusTerm) -> bool {
+    match (t1, t2) {
+        (ModusTerm::Constant(c1), ModusTerm::Constant(c2)) => {
+            logic::IRTerm::from(ModusTerm::Constant(c1.clone()))
+                == logic::IRTerm::from(ModusTerm::Constant(c2.clone()))
+        }
+        (ModusTerm::Integer(i1), ModusTerm::Integer(i2)) => i1 == i2,
+        (ModusTerm::Boolean(b1), ModusTerm::Boolean(b2)) => b1 == b2,
+        _ => match (t1.constant_type(), t2.constant_type()) {
+            (Some(ty1), Some(ty2)) => ty1 == ty2,
+            _ => true,
+        },
+    }
+}
+
+/// Why a literal can never hold, given the heads of the clauses that may
+/// succeed, if it is known.
+fn never_holds(
+    lit: &Literal<ModusTerm>,
+    live_heads: &HashMap<(&str, usize), Vec<&Literal<ModusTerm>>>,
+    signatures: &HashSet<(&str, usize)>,
+) -> Option<String> {
+    let name = lit.predicate.0.as_str();
+    let placeholder = Literal {
+        positive: lit.positive,
+        position: None,
+        predicate: lit.predicate.clone(),
+        args: vec![logic::IRTerm::UserVariable("_".to_owned()); lit.args.len()],
+    };
+    if select_builtin(&placeholder).0 != SelectBuiltinResult::NoMatch {
+        if !builtin::builtin_signatures().any(|signature| signature == (name, lit.args.len())) {
+            return Some(format!(
+                "`{}` is not defined with {} argument(s)",
+                name,
+                lit.args.len()
+            ));
+        }
+        if !lit.args.iter().all(|t| {
+            matches!(
+                t,
+                ModusTerm::Constant(_) | ModusTerm::Integer(_) | ModusTerm::Boolean(_)
+            )
+        }) {
+            return None;
+        }
+        // A ground literal holds if the literal the builtin returns is the same.
+        let ir_lit: Literal = lit.clone().into();
+        return match select_builtin(&ir_lit) {
+            (SelectBuiltinResult::Match, Some(builtin))
+                if builtin
+                    .apply(&ir_lit)
+                    .is_some_and(|res| res.args == ir_lit.args)
+                    != lit.positive =>
+            {
+                Some(format!(
+                    "`{}{}` is always false",
+                    if lit.positive { "" } else { "!" },
+                    lit
+                ))
+            }
+            _ => None,
+        };
+    }
+    if !lit.positive {
+        return None;
+    }
+    let signature = (name, lit.args.len());
+    if !signatures.iter().any(|(n, _)| *n == name) {
+        Some(format!("`{}` is not defined", name))
+    } else if !signatures.contains(&signature) {
+        Some(format!(
+            "`{}` is not defined with {} argument(s)",
+            name,
+            lit.args.len()
+        ))
+    } else {
+        match live_heads.get(&signature) {
+            None => Some(format!("`{}` can never succeed", name)),
+            Some(heads)
+                if !heads.iter().any(|head| {
+                    head.args
+                        .iter()
+                        .zip(&lit.args)
+                        .all(|(t1, t2)| may_unify(t1, t2))
+                }) =>
+            {
+                Some(format!("no clause of `{}` matches these arguments", name))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The literal which makes an expression never succeed, if any, and why.
+fn never_succeeds<'a>(
+    expr: &'a Expression,
+    live_heads: &HashMap<(&str, usize), Vec<&Literal<ModusTerm>>>,
+    signatures: &HashSet<(&str, usize)>,
+) -> Option<(&'a Literal<ModusTerm>, String)> {
+    match expr {
+        Expression::Literal(lit) => never_holds(lit, live_heads, signatures).map(|r| (lit, r)),
+        Expression::OperatorApplication(_, e, _) => never_succeeds(e, live_heads, signatures),
+        Expression::And(_, true, e1, e2) => never_succeeds(e1, live_heads, signatures)
+            .or_else(|| never_succeeds(e2, live_heads, signatures)),
+        Expression::Or(_, true, e1, e2) => {
+            let reason = never_succeeds(e1, live_heads, signatures)?;
+            never_succeeds(e2, live_heads, signatures).map(|_| reason)
+        }
+        // Negated conjunctions and disjunctions are not considered.
+        Expression::And(..) | Expression::Or(..) => None,
+    }
+}
+
+/// The literal binding a variable to a constant other than the one an earlier
+/// literal of the conjunction bound it to, e.g. `X = "2"` in `X = "1", X = "2"`.
+fn contradictory_binding(expr: &Expression) -> Option<(&Literal<ModusTerm>, String)> {
+    fn conjuncts<'a>(expr: &'a Expression, lits: &mut Vec<&'a Literal<ModusTerm>>) {
+        match expr {
+            Expression::Literal(lit) => lits.push(lit),
+            Expression::OperatorApplication(_, e, _) => conjuncts(e, lits),
+            Expression::And(_, true, e1, e2) => {
+                conjuncts(e1, lits);
+                conjuncts(e2, lits);
+            }
+            // Bindings in disjunctions and negations do not always hold.
+            Expression::And(..) | Expression::Or(..) => {}
+        }
+    }
+
+    let mut lits = Vec::new();
+    conjuncts(expr, &mut lits);
+    let mut bindings: HashMap<&str, &ModusTerm> = HashMap::new();
+    for lit in lits {
+        if !lit.positive || lit.predicate.0 != "string_eq" || lit.args.len() != 2 {
+            continue;
+        }
+        let (var, value) = match (&lit.args[0], &lit.args[1]) {
+            (ModusTerm::UserVariable(v), c) | (c, ModusTerm::UserVariable(v))
+                if c.constant_type().is_some() =>
+            {
+                (v.as_str(), c)
+            }
+            _ => continue,
+        };
+        match bindings.get(var) {
+            Some(bound) if !may_unify(bound, value) => {
+                return Some((lit, format!("`{}` is already bound to {}", var, bound)))
+            }
+            Some(_) => {}
+            None => {
+                bindings.insert(var, value);
+            }
+        }
+    }
+    None
+}
+
+/// Clauses whose body can never succeed, because they use undefined
+/// predicates, builtins that are always false, constants that no clause
+/// matches, or bind a variable to different constants. This is repeated until no more clauses are found, since a
+/// predicate whose clauses can never succeed can't either.
+fn unreachable_clauses(mf: &Modusfile) -> Vec<Diagnostic<()>> {
+    let signatures: HashSet<(&str, usize)> =
+        mf.0.iter()
+            .map(|c| (c.head.predicate.0.as_str(), c.head.args.len()))
+            .collect();
+    let mut dead: Vec<Option<(&Literal<ModusTerm>, String)>> = vec![None; mf.0.len()];
+    loop {
+        let mut live_heads: HashMap<(&str, usize), Vec<&Literal<ModusTerm>>> = HashMap::new();
+        for (clause, _) in mf.0.iter().zip(&dead).filter(|(_, d)| d.is_none()) {
+            live_heads
+                .entry((clause.head.predicate.0.as_str(), clause.head.args.len()))
+                .or_default()
+                .push(&clause.head);
+        }
+        let mut changed = false;
+        for (clause, d) in mf.0.iter().zip(dead.iter_mut()) {
+            if let (None, Some(body)) = (&d, &clause.body) {
+                *d = never_succeeds(body, &live_heads, &signatures)
+                    .or_else(|| contradictory_binding(body));
+                changed |= d.is_some();
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    let user_signatures: Vec<(&str, usize)> = signatures.iter().copied().collect();
+    mf.0.iter()
+        .zip(dead)
+        .filter_map(|(clause, d)| {
+            let (lit, reason) = d?;
+            let suggestion = suggest::suggest_predicate(lit, &user_signatures).map(|s| s.message);
+            let mut labels = Vec::new();
+            labels.extend(
+                lit.position
+                    .as_ref()
+                    .map(|pos| Label::primary((), Range::from(pos)).with_message(reason.clone())),
+            );
+            labels.extend(
+                clause
+                    .head
+                    .position
+                    .as_ref()
+                    .map(|pos| Label::secondary((), Range::from(pos))),
+            );
+            Some(
+                Diagnostic::warning()
+                    .with_message(format!(
+                        "This clause of `{}` can never succeed.",
+                        clause.head.predicate
+                    ))
+                    .with_labels(labels)
+                    .with_notes(
+                        lit.position
+                            .is_none()
+                            .then_some(reason)
+                            .into_iter()
+                            .chain(suggestion)
+                            .collect(),
+                    ),
+            )
+        })
+        .collect()
+}
+
+/// Renames the variables of a clause in order of appearance, and removes
+/// positions, so that clauses which only differ by these are equal.
+struct CanonicalClause {
+    variables: HashMap<String, String>,
+}
+
+impl CanonicalClause {
+    fn position() -> SpannedPosition {
+        SpannedPosition {
+            offset: 0,
+            length: 0,
+        }
+    }
+
+    fn variable(&mut self, v: &str) -> String {
+        let n = self.variables.len();
+        self.variables
+            .entry(v.to_owned())
+            .or_insert_with(|| format!("V{}", n))
+            .clone()
+    }
+
+    fn term(&mut self, t: &ModusTerm) -> ModusTerm {
+        match t {
+            ModusTerm::UserVariable(v) => ModusTerm::UserVariable(self.variable(v)),
+            ModusTerm::FormatString { fragments, .. } => ModusTerm::FormatString {
+                position: Self::position(),
+                fragments: fragments
+                    .iter()
+                    .map(|f| match f {
+                        FormatStringFragment::StringContent(_, s) => {
+                            FormatStringFragment::StringContent(Self::position(), s.clone())
+                        }
+                        FormatStringFragment::InterpolatedVariable(_, v) => {
+                            FormatStringFragment::InterpolatedVariable(
+                                Self::position(),
+                                self.variable(v),
+                            )
+                        }
+                        FormatStringFragment::InterpolatedAnonymousVariable(_) => {
+                            FormatStringFragment::InterpolatedAnonymousVariable(Self::position())
+                        }
+                    })
+                    .collect(),
+            },
+            ModusTerm::List(_, ts) => {
+                ModusTerm::List(Self::position(), ts.iter().map(|t| self.term(t)).collect())
+            }
+            ModusTerm::Constant(_)
+            | ModusTerm::Integer(_)
+            | ModusTerm::Boolean(_)
+            | ModusTerm::AnonymousVariable => t.clone(),
+        }
+    }
+
+    fn literal(&mut self, lit: &Literal<ModusTerm>) -> Literal<ModusTerm> {
+        Literal {
+            positive: lit.positive,
+            position: None,
+            predicate: lit.predicate.clone(),
+            args: lit.args.iter().map(|t| self.term(t)).collect(),
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) -> Expression {
+        match expr {
+            Expression::Literal(lit) => Expression::Literal(self.literal(lit)),
+            Expression::OperatorApplication(_, e, op) => Expression::OperatorApplication(
+                None,
+                Box::new(self.expression(e)),
+                Operator {
+                    position: None,
+                    predicate: op.predicate.clone(),
+                    args: op.args.iter().map(|t| self.term(t)).collect(),
+                },
+            ),
+            Expression::And(_, positive, e1, e2) => Expression::And(
+                None,
+                *positive,
+                Box::new(self.expression(e1)),
+                Box::new(self.expression(e2)),
+            ),
+            Expression::Or(_, positive, e1, e2) => Expression::Or(
+                None,
+                *positive,
+                Box::new(self.expression(e1)),
+                Box::new(self.expression(e2)),
+            ),
+        }
+    }
+
+    fn of(clause: &ModusClause) -> ModusClause {
+        let mut c = CanonicalClause {
+            variables: HashMap::new(),
+        };
+        ModusClause {
+            head: c.literal(&clause.head),
+            body: clause.body.as_ref().map(|e| c.expression(e)),
+        }
+    }
+}
+
+/// Clauses which are the same as an earlier clause, up to the names of their
+/// variables. These only duplicate the proofs of the earlier clause.
+fn duplicate_clauses(mf: &Modusfile) -> Vec<Diagnostic<()>> {
+    let mut seen: Vec<(ModusClause, &ModusClause)> = Vec::new();
+    let mut diags = Vec::new();
+    for clause in &mf.0 {
+        let canonical = CanonicalClause::of(clause);
+        match seen.iter().find(|(c, _)| *c == canonical) {
+            Some((_, original)) => {
+                let mut labels = Vec::new();
+                labels.extend(
+                    clause
+                        .head
+                        .position
+                        .as_ref()
+                        .map(|pos| Label::primary((), Range::from(pos))),
+                );
+                labels.extend(original.head.position.as_ref().map(|pos| {
+                    Label::secondary((), Range::from(pos)).with_message("first defined here")
+                }));
+                diags.push(
+                    Diagnostic::warning()
+                        .with_message(format!(
+                            "This clause of `{}` duplicates an earlier clause.",
+                            clause.head.predicate
+                        ))
+                        .with_labels(labels),
+                );
+            }
+            None => seen.push((canonical, clause)),
+        }
+    }
+    diags
+}
+
+/// Warnings about dead code, for `modus check`: clauses which can never
+/// succeed, duplicate clauses, and, given the entry points, predicates which
+/// are never used.
+pub fn dead_code(
+    mf: &Modusfile,
+    kind_res: &KindResult,
+    entries: Option<&Entries>,
+) -> Vec<Diagnostic<()>> {
+    let mut diags = unreachable_clauses(mf);
+    diags.extend(duplicate_clauses(mf));
+    if let Some(entries) = entries {
+        diags.extend(unused_predicates(mf, kind_res, entries));
+    }
+    diags
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1126,6 +1966,16 @@ mod tests {
         assert!(kind_res.errs[0].message.contains("Unknown operator"));
     }
 
+    #[test]
+    fn suggests_similar_operator() {
+        let mf: Modusfile = "head :- from(\"alpine\")::set_evn(\"A\", \"1\")."
+            .parse()
+            .unwrap();
+
+        let kind_res = mf.kinds();
+        assert_eq!(kind_res.errs[0].notes[0], "did you mean `::set_env`?");
+    }
+
     #[test]
     fn kind_errors_with_incorrect_operator_inp() {
         let clauses = vec![
@@ -1138,4 +1988,204 @@ mod tests {
         assert_eq!(kind_res.errs[0].severity, Severity::Error);
         assert!(kind_res.errs[0].message.contains("Expected kind: Image"));
     }
+
+    #[test]
+    fn kind_declarations_report_errors_at_definitions() {
+        let source = "image app.\n\
+                      layer install.\n\
+                      image base(version).\n\
+                      install :- from(\"alpine\").\n\
+                      app :- from(\"alpine\"), install.\n\
+                      base(\"1\").";
+        let mf: Modusfile = source.parse().unwrap();
+        let kind_res = mf.kinds();
+
+        let errs: Vec<(&str, &str)> = kind_res
+            .errs
+            .iter()
+            .map(|d| (d.message.as_str(), &source[d.labels[0].range.clone()]))
+            .collect();
+        assert_eq!(
+            errs,
+            [
+                (
+                    "base is declared to be of kind Image, but has a fact.",
+                    "base(\"1\")"
+                ),
+                ("Expected kind: Layer", "from(\"alpine\")"),
+            ]
+        );
+        assert_eq!(kind_res.pred_kind[&Predicate("app".into())], Kind::Image);
+        assert!(kind_res
+            .messages
+            .iter()
+            .any(|m| m.message == "install is declared to be of kind Layer"));
+    }
+
+    #[test]
+    fn highlights_arguments_violating_modes() {
+        let source = ":- mode version_of(-, -).\n\
+                      :- mode version_of(+, -).\n\
+                      :- mode missing(+).\n\
+                      version_of(\"alpine\", \"3.15\").\n\
+                      app :- version_of( [\"a, b\", N] , V), from(V).";
+        let mf: Modusfile = source.parse().unwrap();
+        let errors = wellformed::check_modes(&translate_modusfile(&mf), &mf.modes());
+        assert_eq!(errors.len(), 1);
+        let diag = generate_mode_error_diag(&errors[0], Some(source));
+        assert_eq!(
+            diag.message,
+            "Argument 1 of `version_of` is declared as an input (+), but might not be ground."
+        );
+        assert_eq!(&source[diag.labels[0].range.clone()], "[\"a, b\", N]");
+
+        let messages: Vec<String> = check_mode_declarations(&mf)
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Conflicting mode declarations for `version_of/2`.",
+                "Mode declared for `missing/1`, which is not defined."
+            ]
+        );
+    }
+
+    #[test]
+    fn type_errors_for_builtin_arguments() {
+        let source = "app(V) :- from(\"alpine\")::set_env(\"V\", V), run(1), number_gt(V, 2).\n\
+                      tag(T) :- app(V), string_concat(\"v\", V, T), number_gt(T, false).\n\
+                      b :- from(f\"alpine:${X}\")::set_env(\"PORT\", 8080).\n\
+                      c :- from(\"alpine\")::set_cmd([1, \"a\"]).";
+        let mf: Modusfile = source.parse().unwrap();
+        let diags = type_errors(&mf, Some(source));
+        let messages: Vec<&str> = diags.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "Argument 1 of `run` is an integer, but a string is expected.",
+                "Argument 2 of `number_gt` is a boolean, but a string or an integer is expected.",
+                "Argument 2 of `::set_env` is an integer, but a string is expected.",
+                "Argument 1 of `::set_cmd` contains an integer, but a string is expected.",
+            ]
+        );
+        assert_eq!(&source[diags[0].labels[0].range.clone()], "1");
+        assert_eq!(&source[diags[1].labels[0].range.clone()], "false");
+        assert_eq!(&source[diags[2].labels[0].range.clone()], "8080");
+        assert_eq!(&source[diags[3].labels[0].range.clone()], "[1, \"a\"]");
+
+        let source = "num(N) :- N = 3.\nflag(B) :- B = true.";
+        let mf: Modusfile = source.parse().unwrap();
+        assert!(type_errors(&mf, Some(source)).is_empty());
+    }
+
+    fn dead_code_messages(clauses: &[&str], entries: Option<&Entries>) -> Vec<String> {
+        let mf: Modusfile = clauses.join("\n").parse().unwrap();
+        dead_code(&mf, &mf.kinds(), entries)
+            .into_iter()
+            .map(|d| {
+                assert_eq!(d.severity, Severity::Warning);
+                match d.labels.first() {
+                    Some(label) if !label.message.is_empty() => {
+                        format!("{} {}", d.message, label.message)
+                    }
+                    _ => d.message,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn warns_unreachable_clauses() {
+        let clauses = [
+            "a :- from(\"alpine\"), undefined_pred.",
+            "b(X) :- version(X), string_eq(\"1\", \"2\").",
+            "c :- from(\"alpine\"), version(\"3\").",
+            "d :- from(\"alpine\"), b(\"1\").",
+            "e :- from(\"alpine\"), version(\"1\"), !string_eq(\"a\", \"a\").",
+            "f :- (from(\"alpine\"); undefined_pred), version(\"1\", \"2\").",
+            "ok :- (from(\"alpine\"); undefined_pred), version(\"1\"), !undefined_pred.",
+            "g(X) :- X = \"1\", from(\"alpine\"), \"2\" = X.",
+            "h(X) :- X = \"1\", (X = \"2\"; from(\"alpine\")), X = \"1\".",
+            "version(\"1\").",
+        ];
+        assert_eq!(
+            dead_code_messages(&clauses, None),
+            [
+                "This clause of `a` can never succeed. `undefined_pred` is not defined",
+                "This clause of `b` can never succeed. `string_eq(\"1\", \"2\")` is always false",
+                "This clause of `c` can never succeed. no clause of `version` matches these arguments",
+                "This clause of `d` can never succeed. `b` can never succeed",
+                "This clause of `e` can never succeed. `!string_eq(\"a\", \"a\")` is always false",
+                "This clause of `f` can never succeed. `version` is not defined with 2 argument(s)",
+                "This clause of `g` can never succeed. `X` is already bound to \"1\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_duplicate_clauses() {
+        let clauses = [
+            "a(X) :- from(f\"alpine:${X}\"), run(\"make\").",
+            "a(Y) :- from(f\"alpine:${Y}\"), run(\"make\").",
+            "a(Y) :- from(f\"alpine:${Y}\"), run(\"make test\").",
+            "b(X, Y) :- a(X), a(Y).",
+            "b(X, Y) :- a(Y), a(X).",
+        ];
+        assert_eq!(
+            dead_code_messages(&clauses, None),
+            ["This clause of `a` duplicates an earlier clause."]
+        );
+    }
+
+    #[test]
+    fn suggests_similar_predicates_for_unreachable_clauses() {
+        let clauses = [
+            "a :- from(\"alpine\"), copy(\"a\", \"b\", \"c\").",
+            "b :- from(\"alpine\"), verison(\"1\").",
+            "version(\"1\").",
+        ];
+        let mf: Modusfile = clauses.join("\n").parse().unwrap();
+        let notes: Vec<Vec<String>> = dead_code(&mf, &mf.kinds(), None)
+            .into_iter()
+            .map(|d| d.notes)
+            .collect();
+        assert_eq!(
+            notes,
+            [
+                vec!["`copy/3` does not exist, did you mean `copy/2`?".to_owned()],
+                vec!["did you mean `version`?".to_owned()],
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_unused_predicates() {
+        let clauses = [
+            "app :- from(\"alpine\"), install.",
+            "install :- run(\"make\").",
+            "helper :- run(\"echo unused\").",
+            "flag(\"a\").",
+            "test_app :- flag(\"a\").",
+        ];
+        assert!(dead_code_messages(&clauses, None).is_empty());
+        assert_eq!(
+            dead_code_messages(&clauses, Some(&Entries::All)),
+            ["Predicate `helper` is never used."]
+        );
+        assert_eq!(
+            dead_code_messages(
+                &clauses,
+                Some(&Entries::Predicates(vec![
+                    "app".to_owned(),
+                    "nope".to_owned()
+                ]))
+            ),
+            [
+                "Entry point `nope` is not defined.",
+                "Predicate `helper` is never used.",
+            ]
+        );
+    }
 }
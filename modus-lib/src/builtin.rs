@@ -18,7 +18,7 @@ use std::collections::HashMap;
 
 use crate::{
     analysis::Kind,
-    logic::{Clause, IRTerm, Literal, Predicate},
+    logic::{Clause, ConstantType, IRTerm, Literal, Predicate},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -47,6 +47,27 @@ pub trait BuiltinPredicate {
     /// Return if the argument is allowed to be ungrounded. This means that a "false" here will force a constant.
     fn arg_groundness(&self) -> &'static [bool];
 
+    /// Return if the argument accepts constants of the given type. Most builtins
+    /// only accept strings.
+    fn accepts(&self, _index: usize, ty: ConstantType) -> bool {
+        ty == ConstantType::String
+    }
+
+    /// Checks the types of the constant arguments of the literal, including the
+    /// elements of list arguments.
+    fn accepts_args(&self, lit: &Literal) -> bool {
+        fn accepts_term<B: BuiltinPredicate + ?Sized>(b: &B, index: usize, term: &IRTerm) -> bool {
+            match term {
+                IRTerm::List(ts) => ts.iter().all(|t| accepts_term(b, index, t)),
+                t => t.constant_type().is_none_or(|ty| b.accepts(index, ty)),
+            }
+        }
+        lit.args
+            .iter()
+            .enumerate()
+            .all(|(i, arg)| accepts_term(self, i, arg))
+    }
+
     fn select(&self, lit: &Literal) -> SelectBuiltinResult {
         let Literal {
             ref predicate,
@@ -87,26 +108,28 @@ pub trait BuiltinPredicate {
 
 mod string_concat {
     use super::BuiltinPredicate;
-    use crate::logic::{IRTerm, Literal, Predicate, SpannedPosition};
+    use crate::logic::{ConstantType, IRTerm, Literal, Predicate, SpannedPosition};
 
     fn string_concat_result(
-        a: &str,
-        b: &str,
-        c: &str,
+        a: IRTerm,
+        b: IRTerm,
+        c: IRTerm,
         pos: &Option<SpannedPosition>,
     ) -> Option<Literal> {
         Some(Literal {
             positive: true,
             position: pos.clone(),
             predicate: Predicate("string_concat".to_owned()),
-            args: vec![
-                IRTerm::Constant(a.to_owned()),
-                IRTerm::Constant(b.to_owned()),
-                IRTerm::Constant(c.to_owned()),
-            ],
+            args: vec![a, b, c],
         })
     }
 
+    /// An argument that is concatenated and forced to be a constant may be an integer or
+    /// a boolean, which is formatted. This is how they are interpolated in f-strings.
+    fn accepts_formatted(arg_groundness: &[bool], index: usize, ty: ConstantType) -> bool {
+        (index < 2 && !arg_groundness[index]) || ty == ConstantType::String
+    }
+
     pub struct StringConcat1;
     impl BuiltinPredicate for StringConcat1 {
         fn name(&self) -> &'static str {
@@ -121,11 +144,15 @@ mod string_concat {
             &[false, false, true]
         }
 
+        fn accepts(&self, index: usize, ty: ConstantType) -> bool {
+            accepts_formatted(self.arg_groundness(), index, ty)
+        }
+
         fn apply(&self, lit: &Literal) -> Option<Literal> {
-            let a = lit.args[0].as_constant()?;
-            let b = lit.args[1].as_constant()?;
-            let c = a.to_owned() + b;
-            string_concat_result(a, b, &c, &lit.position)
+            let a = lit.args[0].as_formatted()?;
+            let b = lit.args[1].as_formatted()?;
+            let c = IRTerm::Constant(a + &b);
+            string_concat_result(lit.args[0].clone(), lit.args[1].clone(), c, &lit.position)
         }
     }
 
@@ -143,11 +170,20 @@ mod string_concat {
             &[true, false, false]
         }
 
+        fn accepts(&self, index: usize, ty: ConstantType) -> bool {
+            accepts_formatted(self.arg_groundness(), index, ty)
+        }
+
         fn apply(&self, lit: &Literal) -> Option<Literal> {
-            let b = lit.args[1].as_constant()?;
+            let b = lit.args[1].as_formatted()?;
             let c = lit.args[2].as_constant()?;
             if let Some(a) = c.strip_suffix(&b) {
-                string_concat_result(a, b, c, &lit.position)
+                string_concat_result(
+                    IRTerm::Constant(a.to_owned()),
+                    lit.args[1].clone(),
+                    lit.args[2].clone(),
+                    &lit.position,
+                )
             } else {
                 None
             }
@@ -168,11 +204,52 @@ mod string_concat {
             &[false, true, false]
         }
 
+        fn accepts(&self, index: usize, ty: ConstantType) -> bool {
+            accepts_formatted(self.arg_groundness(), index, ty)
+        }
+
         fn apply(&self, lit: &Literal) -> Option<Literal> {
-            let a = lit.args[0].as_constant()?;
+            let a = lit.args[0].as_formatted()?;
             let c = lit.args[2].as_constant()?;
             if let Some(b) = c.strip_prefix(&a) {
-                string_concat_result(a, b, c, &lit.position)
+                string_concat_result(
+                    lit.args[0].clone(),
+                    IRTerm::Constant(b.to_owned()),
+                    lit.args[2].clone(),
+                    &lit.position,
+                )
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Checks that the first string contains the second one, e.g. to look for
+/// `curl | sh` in commands.
+mod string_contains {
+    use super::BuiltinPredicate;
+    use crate::logic::Literal;
+
+    pub struct StringContains;
+    impl BuiltinPredicate for StringContains {
+        fn name(&self) -> &'static str {
+            "string_contains"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false]
+        }
+
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let a = lit.args[0].as_constant()?;
+            let b = lit.args[1].as_constant()?;
+            if a.contains(b) {
+                Some(lit.clone())
             } else {
                 None
             }
@@ -181,10 +258,26 @@ mod string_concat {
 }
 
 mod equality {
-    use crate::logic::{IRTerm, Literal, Predicate};
+    use crate::logic::{ConstantType, Literal, Predicate};
 
     use super::BuiltinPredicate;
 
+    /// Equality holds between constants of the same type, so the argument at
+    /// `ground` is returned for both sides.
+    fn equal_to(lit: &Literal, ground: usize) -> Option<Literal> {
+        let c = &lit.args[ground];
+        let ty = c.constant_type()?;
+        if matches!(lit.args[1 - ground].constant_type(), Some(other) if other != ty) {
+            return None;
+        }
+        Some(Literal {
+            positive: true,
+            position: lit.position.clone(),
+            predicate: Predicate("string_eq".to_owned()),
+            args: vec![c.clone(), c.clone()],
+        })
+    }
+
     pub struct StringEq1;
     impl BuiltinPredicate for StringEq1 {
         fn name(&self) -> &'static str {
@@ -199,17 +292,12 @@ mod equality {
             &[false, true]
         }
 
+        fn accepts(&self, _index: usize, _ty: ConstantType) -> bool {
+            true
+        }
+
         fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
-            let a = lit.args[0].as_constant()?;
-            Some(Literal {
-                positive: true,
-                position: lit.position.clone(),
-                predicate: Predicate("string_eq".to_owned()),
-                args: vec![
-                    IRTerm::Constant(a.to_owned()),
-                    IRTerm::Constant(a.to_owned()),
-                ],
-            })
+            equal_to(lit, 0)
         }
     }
 
@@ -227,23 +315,28 @@ mod equality {
             &[true, false]
         }
 
+        fn accepts(&self, _index: usize, _ty: ConstantType) -> bool {
+            true
+        }
+
         fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
-            let b = lit.args[1].as_constant()?;
-            Some(Literal {
-                positive: true,
-                position: lit.position.clone(),
-                predicate: Predicate("string_eq".to_owned()),
-                args: vec![
-                    IRTerm::Constant(b.to_owned()),
-                    IRTerm::Constant(b.to_owned()),
-                ],
-            })
+            equal_to(lit, 1)
         }
     }
 }
 
 mod number {
     use super::BuiltinPredicate;
+    use crate::logic::{ConstantType, IRTerm};
+
+    /// Strings are parsed, so that numbers from e.g. `string_concat` can be compared.
+    fn as_number(t: &IRTerm) -> Option<f64> {
+        match t {
+            IRTerm::Integer(i) => Some(*i as f64),
+            IRTerm::Constant(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
 
     macro_rules! define_number_comparison {
         ($name:ident, $cond:expr) => {
@@ -262,10 +355,14 @@ mod number {
                     &[false, false]
                 }
 
+                fn accepts(&self, _index: usize, ty: ConstantType) -> bool {
+                    ty != ConstantType::Boolean
+                }
+
                 /// Parses and checks that arg1 > arg2.
                 fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
-                    let a: f64 = lit.args[0].as_constant().and_then(|s| s.parse().ok())?;
-                    let b: f64 = lit.args[1].as_constant().and_then(|s| s.parse().ok())?;
+                    let a = as_number(&lit.args[0])?;
+                    let b = as_number(&lit.args[1])?;
                     if $cond(a, b) {
                         Some(lit.clone())
                     } else {
@@ -362,8 +459,13 @@ macro_rules! intrinsic_predicate {
                 &[$($arg_groundness),*]
             }
 
+            /// The build instructions are generated from string arguments.
             fn apply(&self, lit: &Literal) -> Option<Literal> {
-                Some(lit.clone())
+                if self.accepts_args(lit) {
+                    Some(lit.clone())
+                } else {
+                    None
+                }
             }
         }
     };
@@ -499,77 +601,111 @@ intrinsic_predicate!(
     false,
     false
 );
+intrinsic_predicate!(
+    _operator_export_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_export_end,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(_operator_check_begin, crate::analysis::Kind::Layer, false);
+intrinsic_predicate!(_operator_check_end, crate::analysis::Kind::Layer, false);
 intrinsic_predicate!(copy, crate::analysis::Kind::Layer, false, false);
 intrinsic_predicate!(_operator_merge_begin, crate::analysis::Kind::Layer, false);
 intrinsic_predicate!(_operator_merge_end, crate::analysis::Kind::Layer, false);
 
-/// Convenience macro that returns Some(b) for the first b that can be selected.
-macro_rules! select_builtins {
-    ( $lit:expr, $( $x:expr ),+, ) => {{
-        let mut has_ground_mismatch = false;
-        $(
-            match $x.select($lit) {
-                SelectBuiltinResult::Match => return (SelectBuiltinResult::Match, Some(&$x)),
-                SelectBuiltinResult::GroundnessMismatch => {
-                    has_ground_mismatch = true;
-                },
-                _ => {}
-            }
-        );+
-        if has_ground_mismatch {
-            return (SelectBuiltinResult::GroundnessMismatch, None);
-        } else {
-            return (SelectBuiltinResult::NoMatch, None);
-        }
-    }};
-}
-
+/// All the builtin predicates, in the order they are tried by `select_builtin`.
+const BUILTINS: &[&dyn BuiltinPredicate] = &[
+    &string_concat::StringConcat1,
+    &string_concat::StringConcat2,
+    &string_concat::StringConcat3,
+    &string_contains::StringContains,
+    &run,
+    &from,
+    &_operator_copy_begin,
+    &_operator_copy_end,
+    &_operator_in_workdir_begin,
+    &_operator_in_workdir_end,
+    &_operator_set_workdir_begin,
+    &_operator_set_workdir_end,
+    &_operator_set_entrypoint_begin,
+    &_operator_set_entrypoint_end,
+    &_operator_set_cmd_begin,
+    &_operator_set_cmd_end,
+    &_operator_set_label_begin,
+    &_operator_set_label_end,
+    &_operator_set_env_begin,
+    &_operator_set_env_end,
+    &_operator_in_env_begin,
+    &_operator_in_env_end,
+    &_operator_append_path_begin,
+    &_operator_append_path_end,
+    &_operator_set_user_begin,
+    &_operator_set_user_end,
+    &_operator_check_begin,
+    &_operator_check_end,
+    &_operator_export_begin,
+    &_operator_export_end,
+    &copy,
+    &equality::StringEq1,
+    &equality::StringEq2,
+    &_operator_merge_begin,
+    &_operator_merge_end,
+    &number::number_eq,
+    &number::number_gt,
+    &number::number_lt,
+    &number::number_geq,
+    &number::number_leq,
+    &semver::semver_exact,
+    &semver::semver_gt,
+    &semver::semver_lt,
+    &semver::semver_geq,
+    &semver::semver_leq,
+];
+
+/// Returns the first builtin that can be selected for the literal.
 pub fn select_builtin<'a>(
     lit: &Literal,
 ) -> (SelectBuiltinResult, Option<&'a dyn BuiltinPredicate>) {
-    select_builtins!(
-        lit,
-        string_concat::StringConcat1,
-        string_concat::StringConcat2,
-        string_concat::StringConcat3,
-        run,
-        from,
-        _operator_copy_begin,
-        _operator_copy_end,
-        _operator_in_workdir_begin,
-        _operator_in_workdir_end,
-        _operator_set_workdir_begin,
-        _operator_set_workdir_end,
-        _operator_set_entrypoint_begin,
-        _operator_set_entrypoint_end,
-        _operator_set_cmd_begin,
-        _operator_set_cmd_end,
-        _operator_set_label_begin,
-        _operator_set_label_end,
-        _operator_set_env_begin,
-        _operator_set_env_end,
-        _operator_in_env_begin,
-        _operator_in_env_end,
-        _operator_append_path_begin,
-        _operator_append_path_end,
-        _operator_set_user_begin,
-        _operator_set_user_end,
-        copy,
-        equality::StringEq1,
-        equality::StringEq2,
-        _operator_merge_begin,
-        _operator_merge_end,
-        number::number_eq,
-        number::number_gt,
-        number::number_lt,
-        number::number_geq,
-        number::number_leq,
-        semver::semver_exact,
-        semver::semver_gt,
-        semver::semver_lt,
-        semver::semver_geq,
-        semver::semver_leq,
-    )
+    let mut has_ground_mismatch = false;
+    for builtin in BUILTINS {
+        match builtin.select(lit) {
+            SelectBuiltinResult::Match => return (SelectBuiltinResult::Match, Some(*builtin)),
+            SelectBuiltinResult::GroundnessMismatch => has_ground_mismatch = true,
+            SelectBuiltinResult::NoMatch => {}
+        }
+    }
+    if has_ground_mismatch {
+        (SelectBuiltinResult::GroundnessMismatch, None)
+    } else {
+        (SelectBuiltinResult::NoMatch, None)
+    }
+}
+
+/// The name and arity of every builtin predicate, including the intrinsics
+/// that operators are translated to.
+pub fn builtin_signatures() -> impl Iterator<Item = (&'static str, usize)> {
+    BUILTINS
+        .iter()
+        .map(|builtin| (builtin.name(), builtin.arg_groundness().len()))
+}
+
+/// Whether the argument of a builtin accepts constants of the given type, or
+/// `None` if there is no builtin with this name and arity.
+pub fn builtin_accepts(name: &str, arity: usize, index: usize, ty: ConstantType) -> Option<bool> {
+    let mut builtins = BUILTINS
+        .iter()
+        .filter(|builtin| builtin.name() == name && builtin.arg_groundness().len() == arity)
+        .peekable();
+    builtins.peek()?;
+    Some(builtins.any(|builtin| builtin.accepts(index, ty)))
 }
 
 lazy_static! {
@@ -587,6 +723,8 @@ lazy_static! {
         m.insert("in_workdir", (Kind::Layer, Kind::Layer));
         m.insert("in_env", (Kind::Layer, Kind::Layer));
         m.insert("merge", (Kind::Layer, Kind::Layer));
+        m.insert("check", (Kind::Layer, Kind::Layer));
+        m.insert("export", (Kind::Image, Kind::Image));
         m
     };
 }
@@ -691,6 +829,68 @@ mod test {
         assert_eq!(proof.len(), 1);
     }
 
+    #[test]
+    pub fn test_typed_constants() {
+        use crate::logic::{ConstantType, Literal};
+
+        let apply = |lit: &str| {
+            let lit: Literal = lit.parse().unwrap();
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            b.1.unwrap().apply(&lit)
+        };
+
+        assert_eq!(
+            apply("string_concat(\"v\", 3, X)").map(|lit| lit.args),
+            Some(vec![
+                IRTerm::Constant("v".to_owned()),
+                IRTerm::Integer(3),
+                IRTerm::Constant("v3".to_owned()),
+            ])
+        );
+        assert_eq!(
+            apply("string_concat(X, true, \"is true\")").map(|lit| lit.args),
+            Some(vec![
+                IRTerm::Constant("is ".to_owned()),
+                IRTerm::Boolean(true),
+                IRTerm::Constant("is true".to_owned()),
+            ])
+        );
+        assert!(apply("number_gt(10, 9)").is_some());
+        assert!(apply("number_gt(\"10\", 9)").is_some());
+        assert!(apply("number_lt(10, 9)").is_none());
+        assert!(apply("number_eq(true, true)").is_none());
+        assert!(apply("string_eq(1, \"1\")").is_none());
+        assert_eq!(
+            apply("string_eq(N, 3)").map(|lit| lit.args),
+            Some(vec![IRTerm::Integer(3), IRTerm::Integer(3)])
+        );
+        assert_eq!(
+            apply("string_eq(true, B)").map(|lit| lit.args),
+            Some(vec![IRTerm::Boolean(true), IRTerm::Boolean(true)])
+        );
+        assert!(apply("run(1)").is_none());
+        assert!(apply("_operator_set_cmd_begin(\"0\", [\"a\", \"b\"])").is_some());
+        assert!(apply("_operator_set_cmd_begin(\"0\", [1, \"a\"])").is_none());
+
+        assert_eq!(
+            super::builtin_accepts("run", 1, 0, ConstantType::Integer),
+            Some(false)
+        );
+        assert_eq!(
+            super::builtin_accepts("string_concat", 3, 1, ConstantType::Boolean),
+            Some(true)
+        );
+        assert_eq!(
+            super::builtin_accepts("number_geq", 2, 0, ConstantType::Integer),
+            Some(true)
+        );
+        assert_eq!(
+            super::builtin_accepts("run", 2, 0, ConstantType::String),
+            None
+        );
+    }
+
     #[test]
     pub fn test_number_and_semver_compare() {
         use crate::logic::{Literal, Predicate};
@@ -770,6 +970,11 @@ mod test {
                 ],
                 vec![("42.1", "42.0"), ("1e-10", "0"), ("NaN", "NaN")],
             ),
+            (
+                "string_contains",
+                vec![("curl -L x | sh", "| sh"), ("abc", "abc"), ("abc", "")],
+                vec![("abc", "abcd"), ("", "a"), ("curl -L x", "| sh")],
+            ),
             (
                 "semver_exact",
                 vec![
@@ -0,0 +1,225 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Matching of paths in the build context against `.dockerignore` patterns,
+//! following the rules of docker: `*`, `?` and `[...]` match within a path
+//! component, `**` matches any number of components, a pattern matching a
+//! directory also matches everything in it, and lines starting with `!`
+//! re-include paths excluded by earlier lines.
+//!
+//! This is used to reason about the context on our side, and to filter the
+//! context when we send it to buildkitd ourselves. With the other backends,
+//! buildkit does the actual filtering when sending the context.
+
+/// Splits a relative path into components, resolving `.` and `..` like
+/// `filepath.Clean` does. A leading `/` is ignored.
+pub fn path_components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for c in path.split('/') {
+        match c {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(c),
+        }
+    }
+    components
+}
+
+/// Matches a single path component against a glob.
+fn component_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => {
+            component_matches(&pattern[1..], name)
+                || (!name.is_empty() && component_matches(pattern, &name[1..]))
+        }
+        Some('?') => !name.is_empty() && component_matches(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let end = match pattern.iter().skip(2).position(|&c| c == ']') {
+                Some(i) => i + 2,
+                // An unterminated class is taken literally.
+                None => {
+                    return name.first() == Some(&'[')
+                        && component_matches(&pattern[1..], &name[1..])
+                }
+            };
+            let (negated, class) = match pattern[1] {
+                '^' | '!' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            let c = match name.first() {
+                Some(&c) => c,
+                None => return false,
+            };
+            let mut in_class = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    in_class |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    in_class |= class[i] == c;
+                    i += 1;
+                }
+            }
+            in_class != negated && component_matches(&pattern[end + 1..], &name[1..])
+        }
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && component_matches(&pattern[2..], &name[1..])
+        }
+        Some(&p) => name.first() == Some(&p) && component_matches(&pattern[1..], &name[1..]),
+    }
+}
+
+fn components_match(pattern: &[Vec<char>], path: &[Vec<char>]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(p) if p.iter().collect::<String>() == "**" => {
+            components_match(&pattern[1..], path)
+                || (!path.is_empty() && components_match(pattern, &path[1..]))
+        }
+        Some(p) => {
+            !path.is_empty()
+                && component_matches(p, &path[0])
+                && components_match(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+fn to_chars(components: Vec<&str>) -> Vec<Vec<char>> {
+    components
+        .into_iter()
+        .map(|c| c.chars().collect())
+        .collect()
+}
+
+/// Whether `pattern` matches `path` or one of the directories containing it.
+/// Both are relative to the context, and the empty pattern (e.g. `.`) matches
+/// everything.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let pattern = to_chars(path_components(pattern));
+    let path = to_chars(path_components(path));
+    (0..=path.len()).any(|len| components_match(&pattern, &path[..len]))
+}
+
+/// Whether `pattern` may match paths inside the directory `dir`, so that the
+/// directory has to be looked into to find them.
+pub fn may_match_inside(pattern: &str, dir: &str) -> bool {
+    fn prefix_matches(pattern: &[Vec<char>], dir: &[Vec<char>]) -> bool {
+        match (pattern.first(), dir.first()) {
+            (Some(p), _) if p.iter().collect::<String>() == "**" => true,
+            (Some(p), Some(d)) => {
+                component_matches(p, d) && prefix_matches(&pattern[1..], &dir[1..])
+            }
+            // Either the pattern matches the directory itself or one containing
+            // it, or it goes on below the directory.
+            _ => true,
+        }
+    }
+
+    prefix_matches(
+        &to_chars(path_components(pattern)),
+        &to_chars(path_components(dir)),
+    )
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DockerIgnore {
+    /// The patterns, and whether they re-include paths (start with `!`).
+    patterns: Vec<(String, bool)>,
+}
+
+impl DockerIgnore {
+    pub fn parse(content: &str) -> DockerIgnore {
+        let patterns = content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| match l.strip_prefix('!') {
+                Some(p) => (p.trim().to_owned(), true),
+                None => (l.to_owned(), false),
+            })
+            .collect();
+        DockerIgnore { patterns }
+    }
+
+    /// Whether some patterns re-include paths. Otherwise, nothing in an ignored
+    /// directory is sent, so it does not need to be looked into.
+    pub fn has_exceptions(&self) -> bool {
+        self.patterns.iter().any(|(_, reinclude)| *reinclude)
+    }
+
+    /// Whether a path relative to the context is left out of the context.
+    pub fn is_ignored(&self, path: &str) -> bool {
+        let mut ignored = false;
+        for (pattern, reinclude) in &self.patterns {
+            // The empty pattern would match everything, but docker ignores it.
+            if !path_components(pattern).is_empty() && path_matches(pattern, path) {
+                ignored = !reinclude;
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(path_matches("src", "src/main.rs"));
+        assert!(path_matches("./src/", "src/main.rs"));
+        assert!(path_matches(".", "src/main.rs"));
+        assert!(path_matches("*.md", "README.md"));
+        assert!(!path_matches("*.md", "docs/README.md"));
+        assert!(path_matches("**/*.md", "docs/README.md"));
+        assert!(path_matches("**/*.md", "README.md"));
+        assert!(path_matches("docs/**", "docs/a/b.txt"));
+        assert!(path_matches("file?.[a-c]", "file1.b"));
+        assert!(!path_matches("file?.[^a-c]", "file1.b"));
+        assert!(!path_matches("src/main.rs", "src"));
+        assert!(!path_matches("sr", "src/main.rs"));
+    }
+
+    #[test]
+    fn matches_inside() {
+        assert!(may_match_inside("src/*.rs", "src"));
+        assert!(may_match_inside("**/*.md", "docs/img"));
+        assert!(may_match_inside("src", "src/a"));
+        assert!(!may_match_inside("src/*.rs", "docs"));
+        assert!(!may_match_inside("Dockerfile", "src"));
+    }
+
+    #[test]
+    fn ignore_file() {
+        let ignore = DockerIgnore::parse(
+            "# comment\n\
+             target\n\
+             *.md\n\
+             !README.md\n\
+             /docs/**/*.png\n",
+        );
+        assert!(ignore.is_ignored("target/debug/modus"));
+        assert!(ignore.is_ignored("CHANGELOG.md"));
+        assert!(!ignore.is_ignored("README.md"));
+        assert!(ignore.is_ignored("docs/img/a.png"));
+        assert!(!ignore.is_ignored("docs/a.txt"));
+        assert!(!ignore.is_ignored("src/main.rs"));
+    }
+}
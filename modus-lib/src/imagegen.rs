@@ -21,32 +21,107 @@ use std::path::{Path, PathBuf};
 use crate::analysis::{Kind, ModusSemantics};
 use crate::logic::{Clause, IRTerm, Literal, Predicate};
 use crate::modusfile::{self, Modusfile};
+use crate::planview::output_names;
 use crate::sld::{self, ClauseId, Proof, ResolutionError};
 use crate::translate::translate_modusfile;
 use crate::unification::Substitute;
 
-use codespan_reporting::diagnostic::Diagnostic;
+use codespan_reporting::diagnostic::{Diagnostic, Label};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
 
-const MODUS_LABEL: &str = "com.modus-continens.literal";
+/// Label on every image built from a literal, holding the literal.
+pub const MODUS_LABEL: &str = "com.modus-continens.literal";
+
+/// Version of the JSON format of build plans, which is how the CLI passes them
+/// to the frontend. It must be incremented on any incompatible change to
+/// `BuildPlan` or the types it contains, together with `build-plan.schema.json`
+/// and the label set on the frontend image in the Modusfile of this repository.
+pub const BUILD_PLAN_VERSION: u32 = 2;
+
+/// Label on frontend images holding the build plan version they accept.
+pub const PLAN_VERSION_LABEL: &str = "com.modus-continens.plan-version";
+
+#[derive(Error, Debug)]
+pub enum PlanFormatError {
+    #[error("the build plan has no version, it was likely created by an older version of modus")]
+    MissingVersion,
+    #[error("unsupported build plan version {0}, expected version {}; the modus CLI and the buildkit frontend need to be compatible versions", BUILD_PLAN_VERSION)]
+    UnsupportedVersion(u64),
+    #[error("invalid build plan: {0}")]
+    Invalid(#[source] serde_json::Error),
+}
 
 /// A build plan, designed to be easy to translate to buildkit and Dockerfile.
+///
+/// Its JSON format is described by `build-plan.schema.json`, and should be
+/// read with `BuildPlan::from_json`, which checks the version.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BuildPlan {
+    pub version: u32,
     pub nodes: Vec<BuildNode>,
     pub dependencies: Vec<Vec<NodeId>>,
     pub outputs: Vec<Output>,
+    /// Side nodes created by `::check`, which must build successfully but are
+    /// not part of any output image. They are solved alongside the outputs.
+    #[serde(default)]
+    pub checks: Vec<NodeId>,
+    /// Files to copy out of built images onto the host, created by `::export`.
+    #[serde(default)]
+    pub exports: Vec<Export>,
+    /// The literal that each node was generated from, for display. This is
+    /// not part of the plan format, so it is empty in deserialized plans.
+    #[serde(skip)]
+    pub node_sources: Vec<Option<Literal>>,
 }
 
 impl BuildPlan {
     pub fn new() -> BuildPlan {
         BuildPlan {
+            version: BUILD_PLAN_VERSION,
             nodes: Vec::new(),
             dependencies: Vec::new(),
             outputs: Vec::new(),
+            checks: Vec::new(),
+            exports: Vec::new(),
+            node_sources: Vec::new(),
         }
     }
 
+    /// Serializes the plan together with the content hash of every node, see
+    /// `content_hashes`. The hashes are computed when writing the plan, so they
+    /// follow any change to the nodes, and are not read back by `from_json`.
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct WithHashes<'a> {
+            #[serde(flatten)]
+            plan: &'a BuildPlan,
+            hashes: Vec<String>,
+        }
+
+        serde_json::to_string(&WithHashes {
+            plan: self,
+            hashes: self.content_hashes(),
+        })
+        .expect("Unable to serialize build plan")
+    }
+
+    /// Parses a build plan, failing with a clear error if it is from an
+    /// incompatible version, rather than with whatever field fails to parse.
+    pub fn from_json(json: &[u8]) -> Result<BuildPlan, PlanFormatError> {
+        let value: serde_json::Value =
+            serde_json::from_slice(json).map_err(PlanFormatError::Invalid)?;
+        match value.get("version").and_then(|v| v.as_u64()) {
+            None => return Err(PlanFormatError::MissingVersion),
+            Some(v) if v != BUILD_PLAN_VERSION as u64 => {
+                return Err(PlanFormatError::UnsupportedVersion(v))
+            }
+            Some(_) => {}
+        }
+        serde_json::from_value(value).map_err(PlanFormatError::Invalid)
+    }
+
     pub fn new_node(&mut self, node: BuildNode, deps: Vec<NodeId>) -> NodeId {
         let id = self.nodes.len();
         self.nodes.push(node);
@@ -56,9 +131,188 @@ impl BuildPlan {
                 .collect(),
         );
         debug_assert_eq!(self.nodes.len(), self.dependencies.len());
+        self.node_sources.resize(self.nodes.len(), None);
+        id
+    }
+
+    /// Like `new_node`, but also records the literal the node comes from.
+    pub fn new_node_from(
+        &mut self,
+        node: BuildNode,
+        deps: Vec<NodeId>,
+        source: &Literal,
+    ) -> NodeId {
+        let id = self.new_node(node, deps);
+        self.node_sources[id] = Some(source.clone());
         id
     }
 
+    /// The literal a node was generated from, if known.
+    pub fn node_source(&self, node: NodeId) -> Option<&Literal> {
+        self.node_sources.get(node).and_then(|s| s.as_ref())
+    }
+
+    /// All the nodes needed to build `roots`, in increasing id order.
+    pub fn nodes_needed_by(&self, roots: impl IntoIterator<Item = NodeId>) -> Vec<NodeId> {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<NodeId> = roots.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            if seen.insert(node) {
+                stack.extend(self.dependencies[node].iter().copied());
+            }
+        }
+        let mut nodes: Vec<NodeId> = seen.into_iter().collect();
+        nodes.sort_unstable();
+        nodes
+    }
+
+    /// A hash of every node identifying what it builds: the node with its
+    /// parameters, with the nodes it is built on replaced by their hashes. Nodes
+    /// doing the same thing on top of the same images have the same hash, in
+    /// any plan, independently of node ids. The hashes are hex-encoded SHA-256,
+    /// so they are stable across runs and versions of modus.
+    pub fn content_hashes(&self) -> Vec<String> {
+        fn hash(plan: &BuildPlan, node: NodeId, hashes: &mut Vec<Option<String>>) -> String {
+            if let Some(h) = &hashes[node] {
+                return h.clone();
+            }
+            let mut value =
+                serde_json::to_value(&plan.nodes[node]).expect("Unable to serialize node");
+            replace_ids(&mut value, plan, hashes);
+            let mut hasher = Sha256::new();
+            hasher.input(value.to_string());
+            let h = format!("{:x}", hasher.result());
+            hashes[node] = Some(h.clone());
+            h
+        }
+
+        /// Replaces references to other nodes by their hashes.
+        fn replace_ids(
+            value: &mut serde_json::Value,
+            plan: &BuildPlan,
+            hashes: &mut Vec<Option<String>>,
+        ) {
+            match value {
+                serde_json::Value::Object(map) => {
+                    for (field, v) in map.iter_mut() {
+                        match (field.as_str(), v.as_u64()) {
+                            ("parent", Some(id)) | ("src_image", Some(id)) => {
+                                *v = hash(plan, id as NodeId, hashes).into();
+                            }
+                            _ => replace_ids(v, plan, hashes),
+                        }
+                    }
+                }
+                serde_json::Value::Array(vs) => {
+                    for v in vs {
+                        replace_ids(v, plan, hashes);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut hashes = vec![None; self.nodes.len()];
+        (0..self.nodes.len())
+            .map(|n| hash(self, n, &mut hashes))
+            .collect()
+    }
+
+    /// Renumbers the nodes in an order which only depends on what they build,
+    /// merging nodes with the same content hash, so that planning the same
+    /// query gives the same plan however the proofs were found. Outputs are
+    /// sorted by literal, every node comes after the nodes it is built on, and
+    /// nodes not needed by any output, check or export are dropped.
+    pub fn canonicalize(&mut self) {
+        fn visit<'a>(
+            plan: &BuildPlan,
+            node: NodeId,
+            hashes: &'a [String],
+            new_ids: &mut HashMap<&'a str, NodeId>,
+            order: &mut Vec<NodeId>,
+        ) {
+            if new_ids.contains_key(hashes[node].as_str()) {
+                return;
+            }
+            let mut deps = plan.dependencies[node].clone();
+            deps.sort_by(|&a, &b| hashes[a].cmp(&hashes[b]));
+            for dep in deps {
+                visit(plan, dep, hashes, new_ids, order);
+            }
+            new_ids.insert(hashes[node].as_str(), order.len());
+            order.push(node);
+        }
+
+        let hashes = self.content_hashes();
+        let names = output_names(self);
+        let mut outputs: Vec<(String, Output)> =
+            names.into_iter().zip(self.outputs.drain(..)).collect();
+        outputs.sort_by(|(a_name, a), (b_name, b)| {
+            a_name
+                .cmp(b_name)
+                .then_with(|| hashes[a.node].cmp(&hashes[b.node]))
+        });
+        self.checks.sort_by(|&a, &b| hashes[a].cmp(&hashes[b]));
+        self.checks.dedup_by(|a, b| hashes[*a] == hashes[*b]);
+        let export_key = |e: &Export| {
+            (
+                hashes[e.node].clone(),
+                e.src_path.clone(),
+                e.dst_path.clone(),
+            )
+        };
+        self.exports.sort_by_key(export_key);
+        self.exports.dedup_by_key(|e| export_key(e));
+
+        let mut new_ids = HashMap::new();
+        let mut order = Vec::new();
+        let roots = outputs
+            .iter()
+            .map(|(_, o)| o.node)
+            .chain(self.checks.iter().copied())
+            .chain(self.exports.iter().map(|e| e.node));
+        for root in roots.collect::<Vec<_>>() {
+            visit(self, root, &hashes, &mut new_ids, &mut order);
+        }
+        let new_id = |node: NodeId| new_ids[hashes[node].as_str()];
+
+        self.nodes = order
+            .iter()
+            .map(|&old| {
+                let mut node = self.nodes[old].clone();
+                node.map_ids(new_id);
+                node
+            })
+            .collect();
+        self.dependencies = order
+            .iter()
+            .map(|&old| {
+                let mut deps: Vec<NodeId> =
+                    self.dependencies[old].iter().map(|&d| new_id(d)).collect();
+                deps.sort_unstable();
+                deps.dedup();
+                deps
+            })
+            .collect();
+        self.node_sources = order
+            .iter()
+            .map(|&old| self.node_sources.get(old).cloned().flatten())
+            .collect();
+        self.outputs = outputs
+            .into_iter()
+            .map(|(_, o)| Output {
+                node: new_id(o.node),
+                ..o
+            })
+            .collect();
+        for check in self.checks.iter_mut() {
+            *check = new_id(*check);
+        }
+        for export in self.exports.iter_mut() {
+            export.node = new_id(export.node);
+        }
+    }
+
     /// Return an ordering of nodes in which dependencies of a node comes before
     /// the node itself.
     pub fn topological_order(&self) -> Vec<NodeId> {
@@ -82,6 +336,12 @@ impl BuildPlan {
         for output in self.outputs.iter() {
             dfs(&self, output.node, &mut topological_order, &mut seen);
         }
+        for &check in self.checks.iter() {
+            dfs(self, check, &mut topological_order, &mut seen);
+        }
+        for export in self.exports.iter() {
+            dfs(self, export.node, &mut topological_order, &mut seen);
+        }
         topological_order
     }
 }
@@ -210,6 +470,38 @@ pub enum BuildNode {
     },
 }
 
+impl BuildNode {
+    /// Replaces the ids of the nodes this node is built on.
+    fn map_ids(&mut self, f: impl Fn(NodeId) -> NodeId) {
+        match self {
+            BuildNode::From { .. } | BuildNode::FromScratch { .. } => {}
+            BuildNode::CopyFromImage {
+                parent, src_image, ..
+            } => {
+                *parent = f(*parent);
+                *src_image = f(*src_image);
+            }
+            BuildNode::Merge(merge) => {
+                merge.parent = f(merge.parent);
+                for op in merge.operations.iter_mut() {
+                    if let MergeOperation::CopyFromImage { src_image, .. } = op {
+                        *src_image = f(*src_image);
+                    }
+                }
+            }
+            BuildNode::Run { parent, .. }
+            | BuildNode::CopyFromLocal { parent, .. }
+            | BuildNode::SetWorkdir { parent, .. }
+            | BuildNode::SetEntrypoint { parent, .. }
+            | BuildNode::SetCmd { parent, .. }
+            | BuildNode::SetLabel { parent, .. }
+            | BuildNode::SetEnv { parent, .. }
+            | BuildNode::AppendEnvValue { parent, .. }
+            | BuildNode::SetUser { parent, .. } => *parent = f(*parent),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MergeNode {
     pub parent: NodeId,
@@ -241,14 +533,29 @@ pub struct Output {
     pub source_literal: Option<Literal>,
 }
 
+/// A file or directory to be exported from an image to the host.
+///
+/// src_path is relative to the working directory of the image, like the source
+/// of an image-to-image copy. dst_path is always relative, and is resolved
+/// against the export directory on the host.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Export {
+    pub node: NodeId,
+    pub src_path: String,
+    pub dst_path: String,
+}
+
 /// Given a list of pairs of ground (solved) queries and their proof tree, output
-/// a build graph which builds all the queried images.
+/// a build graph which builds all the queried images. The graph is canonical,
+/// see `BuildPlan::canonicalize`. Operators used where they can't be built are
+/// reported as errors.
 pub fn build_dag_from_proofs(
     query_and_proofs: &[(Literal, Proof)],
     rules: &Vec<Clause<IRTerm>>,
-) -> BuildPlan {
+) -> Result<BuildPlan, Vec<Diagnostic<()>>> {
     let mut res = BuildPlan::new();
     let mut image_literals: HashMap<Literal, NodeId> = HashMap::new();
+    let mut errors = Vec::new();
 
     /// Takes in a part of the build tree, assuming that it is building an image
     /// (for example, the tree of an image literal, or a slice of a bigger tree,
@@ -264,7 +571,8 @@ pub fn build_dag_from_proofs(
         rules: &Vec<Clause<IRTerm>>,
         res: &mut BuildPlan,
         image_literals: &mut HashMap<Literal, NodeId>,
-        tag_with_literal: Option<String>,
+        tag_with_literal: Option<&Literal>,
+        errors: &mut Vec<Diagnostic<()>>,
     ) -> Option<NodeId> {
         let mut curr_state = State {
             current_node: None,
@@ -297,6 +605,7 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
+            errors: &mut Vec<Diagnostic<()>>,
         ) {
             match proof.clause {
                 ClauseId::Query => {}
@@ -323,7 +632,8 @@ pub fn build_dag_from_proofs(
                                 rules,
                                 res,
                                 image_literals,
-                                Some(substituted_lit.to_string()),
+                                Some(&substituted_lit),
+                                errors,
                             ) {
                                 curr_state.set_node(node_id);
                                 image_literals.insert(substituted_lit, node_id);
@@ -347,6 +657,7 @@ pub fn build_dag_from_proofs(
                 res,
                 image_literals,
                 curr_state,
+                errors,
             );
         }
 
@@ -373,15 +684,19 @@ pub fn build_dag_from_proofs(
                         let image_ref = intrinsic.args[0].as_constant().unwrap().to_owned();
                         let new_node;
                         if &image_ref == "scratch" {
-                            new_node =
-                                res.new_node(BuildNode::FromScratch { scratch_ref: None }, vec![]);
+                            new_node = res.new_node_from(
+                                BuildNode::FromScratch { scratch_ref: None },
+                                vec![],
+                                intrinsic,
+                            );
                         } else {
-                            new_node = res.new_node(
+                            new_node = res.new_node_from(
                                 BuildNode::From {
                                     display_name: image_ref.clone(),
                                     image_ref,
                                 },
                                 vec![],
+                                intrinsic,
                             );
                         }
                         curr_state.set_node(new_node);
@@ -401,7 +716,7 @@ pub fn build_dag_from_proofs(
                             panic!("No base layer yet.");
                         }
                         let parent = curr_state.current_node.unwrap();
-                        curr_state.set_node(res.new_node(
+                        curr_state.set_node(res.new_node_from(
                             BuildNode::Run {
                                 parent: parent,
                                 command: command,
@@ -409,6 +724,7 @@ pub fn build_dag_from_proofs(
                                 additional_envs: curr_state.additional_envs.clone(),
                             },
                             vec![parent],
+                            intrinsic,
                         ));
                     }
                 }
@@ -428,13 +744,14 @@ pub fn build_dag_from_proofs(
                             panic!("No base layer yet.");
                         }
                         let parent = curr_state.current_node.unwrap();
-                        curr_state.set_node(res.new_node(
+                        curr_state.set_node(res.new_node_from(
                             BuildNode::CopyFromLocal {
                                 parent,
                                 src_path,
                                 dst_path,
                             },
                             vec![parent],
+                            intrinsic,
                         ));
                     }
                 }
@@ -446,18 +763,25 @@ pub fn build_dag_from_proofs(
 
         fn process_operator(
             subtree_in_op: &[&Proof],
-            op_name: &str,
             lit: &Literal, // the "begin" literal of the operator.
             rules: &Vec<Clause<IRTerm>>,
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
+            errors: &mut Vec<Diagnostic<()>>,
         ) {
+            let op_name = lit
+                .predicate
+                .0
+                .strip_prefix("_operator_")
+                .and_then(|s| s.strip_suffix("_begin"))
+                .expect("Expected the begin literal of an operator");
             match op_name {
                 // Image-to-image copy. (local copy is not an operator)
                 "copy" => {
-                    let src_image = process_image(subtree_in_op, rules, res, image_literals, None)
-                        .expect("Stuff inside this copy does not build an image.");
+                    let src_image =
+                        process_image(subtree_in_op, rules, res, image_literals, None, errors)
+                            .expect("Stuff inside this copy does not build an image.");
                     let src_path = lit.args[1].as_constant().unwrap().to_owned();
                     let dst_path = join_path(&curr_state.cwd, lit.args[2].as_constant().unwrap());
                     if let Some(ref mut curr_merge) = curr_state.current_merge {
@@ -468,7 +792,7 @@ pub fn build_dag_from_proofs(
                         });
                     } else {
                         let parent = curr_state.current_node.expect("No base layer yet.");
-                        let node = res.new_node(
+                        let node = res.new_node_from(
                             BuildNode::CopyFromImage {
                                 parent,
                                 src_image,
@@ -476,6 +800,7 @@ pub fn build_dag_from_proofs(
                                 dst_path,
                             },
                             vec![parent, src_image],
+                            lit,
                         );
                         curr_state.set_node(node);
                     }
@@ -484,7 +809,14 @@ pub fn build_dag_from_proofs(
                     let new_p = lit.args[1].as_constant().unwrap();
                     let new_cwd = join_path(&curr_state.cwd, new_p);
                     curr_state.with_new_cwd(new_cwd, |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
+                        process_children(
+                            subtree_in_op,
+                            rules,
+                            res,
+                            image_literals,
+                            new_state,
+                            errors,
+                        );
                     });
                     // TODO: emit a warning if the tree inside attempts
                     // to build a fresh image - this is probably an incorrect usage.
@@ -494,8 +826,9 @@ pub fn build_dag_from_proofs(
                     if curr_state.current_merge.is_some() {
                         panic!("You can not generate a new image inside a merge.");
                     }
-                    let img = process_image(subtree_in_op, rules, res, image_literals, None)
-                        .expect(&format!("{} should be applied to an image.", op_name));
+                    let img =
+                        process_image(subtree_in_op, rules, res, image_literals, None, errors)
+                            .expect(&format!("{} should be applied to an image.", op_name));
                     if curr_state.has_base() {
                         panic!(
                             "{} generates a new image, so it should be the first instruction.",
@@ -506,12 +839,13 @@ pub fn build_dag_from_proofs(
                     match op_name {
                         "set_workdir" => {
                             let new_p = lit.args[1].as_constant().unwrap();
-                            curr_state.set_node(res.new_node(
+                            curr_state.set_node(res.new_node_from(
                                 BuildNode::SetWorkdir {
                                     parent: img,
                                     new_workdir: join_path(&curr_state.cwd, new_p),
                                 },
                                 vec![img],
+                                lit,
                             ));
                         }
                         "set_entrypoint" => {
@@ -524,12 +858,13 @@ pub fn build_dag_from_proofs(
                                     .collect(),
                                 _ => unreachable!(),
                             };
-                            curr_state.set_node(res.new_node(
+                            curr_state.set_node(res.new_node_from(
                                 BuildNode::SetEntrypoint {
                                     parent: img,
                                     new_entrypoint: entrypoint,
                                 },
                                 vec![img],
+                                lit,
                             ));
                         }
                         "set_cmd" => {
@@ -542,61 +877,125 @@ pub fn build_dag_from_proofs(
                                 IRTerm::Constant(c) => vec![c.to_owned()],
                                 _ => unreachable!(),
                             };
-                            curr_state.set_node(res.new_node(
+                            curr_state.set_node(res.new_node_from(
                                 BuildNode::SetCmd {
                                     parent: img,
                                     new_cmd: cmd,
                                 },
                                 vec![img],
+                                lit,
                             ));
                         }
                         "set_env" => {
                             let env_k = lit.args[1].as_constant().unwrap().to_owned();
                             let env_v = lit.args[2].as_constant().unwrap().to_owned();
-                            curr_state.set_node(res.new_node(
+                            curr_state.set_node(res.new_node_from(
                                 BuildNode::SetEnv {
                                     parent: img,
                                     key: env_k,
                                     value: env_v,
                                 },
                                 vec![img],
+                                lit,
                             ));
                         }
                         "append_path" => {
                             let append = format!(":{}", lit.args[1].as_constant().unwrap());
-                            curr_state.set_node(res.new_node(
+                            curr_state.set_node(res.new_node_from(
                                 BuildNode::AppendEnvValue {
                                     parent: img,
                                     key: "PATH".to_owned(),
                                     value: append,
                                 },
                                 vec![img],
+                                lit,
                             ));
                         }
                         "set_label" => {
                             let label_k = lit.args[1].as_constant().unwrap().to_owned();
                             let label_v = lit.args[2].as_constant().unwrap().to_owned();
-                            curr_state.set_node(res.new_node(
+                            curr_state.set_node(res.new_node_from(
                                 BuildNode::SetLabel {
                                     parent: img,
                                     label: label_k,
                                     value: label_v,
                                 },
                                 vec![img],
+                                lit,
                             ));
                         }
                         "set_user" => {
                             let user = lit.args[1].as_constant().unwrap().to_owned();
-                            curr_state.set_node(
-                                res.new_node(BuildNode::SetUser { parent: img, user }, vec![img]),
-                            );
+                            curr_state.set_node(res.new_node_from(
+                                BuildNode::SetUser { parent: img, user },
+                                vec![img],
+                                lit,
+                            ));
                         }
                         _ => unreachable!(),
                     }
                 }
+                "export" => {
+                    if curr_state.current_merge.is_some() {
+                        errors.push(literal_error(
+                            lit,
+                            "`::export` can not be used inside `::merge`.",
+                        ));
+                        return;
+                    }
+                    if curr_state.has_base() {
+                        errors.push(literal_error(
+                            lit,
+                            "`::export` must be applied to an image, not to a layer on top of one.",
+                        ));
+                        return;
+                    }
+                    let img = match process_image(
+                        subtree_in_op,
+                        rules,
+                        res,
+                        image_literals,
+                        None,
+                        errors,
+                    ) {
+                        Some(img) => img,
+                        None => {
+                            errors.push(literal_error(
+                                lit,
+                                "`::export` must be applied to an image.",
+                            ));
+                            return;
+                        }
+                    };
+                    let src_path = lit.args[1].as_constant().unwrap().to_owned();
+                    let dst_path = lit.args[2].as_constant().unwrap().to_owned();
+                    if dst_path.starts_with('/') {
+                        errors.push(literal_error(
+                            lit,
+                            "The destination of `::export` can not be an absolute path, since it is relative to the export directory.",
+                        ));
+                        return;
+                    }
+                    let export = Export {
+                        node: img,
+                        src_path,
+                        dst_path,
+                    };
+                    if !res.exports.contains(&export) {
+                        res.exports.push(export);
+                    }
+                    curr_state.set_node(img);
+                }
                 "merge" => {
                     if curr_state.current_merge.is_some() {
-                        process_children(subtree_in_op, rules, res, image_literals, curr_state);
+                        process_children(
+                            subtree_in_op,
+                            rules,
+                            res,
+                            image_literals,
+                            curr_state,
+                            errors,
+                        );
                         return;
                     }
                     if !curr_state.has_base() {
@@ -608,7 +1007,14 @@ pub fn build_dag_from_proofs(
                         operations: vec![],
                     };
                     let merge_node = curr_state.with_new_merge(merge_node, |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
+                        process_children(
+                            subtree_in_op,
+                            rules,
+                            res,
+                            image_literals,
+                            new_state,
+                            errors,
+                        );
                     });
                     let mut deps: Vec<NodeId> = merge_node
                         .operations
@@ -622,13 +1028,61 @@ pub fn build_dag_from_proofs(
                         })
                         .collect();
                     deps.push(parent);
-                    curr_state.set_node(res.new_node(BuildNode::Merge(merge_node), deps));
+                    curr_state.set_node(res.new_node_from(BuildNode::Merge(merge_node), deps, lit));
+                }
+                "check" => {
+                    // The layers inside a check are built on top of the current
+                    // image as a separate branch, which has to succeed, but is
+                    // not included in the image we are building.
+                    if curr_state.current_merge.is_some() {
+                        errors.push(literal_error(
+                            lit,
+                            "`::check` can not be used inside `::merge`.",
+                        ));
+                        return;
+                    }
+                    let parent = match curr_state.current_node {
+                        Some(parent) => parent,
+                        None => {
+                            errors.push(literal_error(
+                                lit,
+                                "`::check` must be applied to a layer on top of an image.",
+                            ));
+                            return;
+                        }
+                    };
+                    let mut check_state = State {
+                        current_node: Some(parent),
+                        cwd: curr_state.cwd.clone(),
+                        current_merge: None,
+                        additional_envs: curr_state.additional_envs.clone(),
+                    };
+                    process_children(
+                        subtree_in_op,
+                        rules,
+                        res,
+                        image_literals,
+                        &mut check_state,
+                        errors,
+                    );
+                    if let Some(check_node) = check_state.current_node {
+                        if check_node != parent && !res.checks.contains(&check_node) {
+                            res.checks.push(check_node);
+                        }
+                    }
                 }
                 "in_env" => {
                     let env_k = lit.args[1].as_constant().unwrap().to_owned();
                     let env_v = lit.args[2].as_constant().unwrap().to_owned();
                     curr_state.with_additional_envs([(env_k, env_v)], |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
+                        process_children(
+                            subtree_in_op,
+                            rules,
+                            res,
+                            image_literals,
+                            new_state,
+                            errors,
+                        );
                     });
                 }
                 _ => {
@@ -643,6 +1097,7 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
+            errors: &mut Vec<Diagnostic<()>>,
         ) {
             let mut i = 0usize;
             while i < children.len() {
@@ -673,35 +1128,35 @@ pub fn build_dag_from_proofs(
                         let subtree_in_op = &children[i + 1..j];
                         process_operator(
                             subtree_in_op,
-                            op_name,
                             lit,
                             rules,
                             res,
                             image_literals,
                             curr_state,
+                            errors,
                         );
                         i = j + 1;
                         continue;
                     }
                 }
-                process_tree(child, rules, res, image_literals, curr_state);
+                process_tree(child, rules, res, image_literals, curr_state, errors);
                 i += 1;
             }
         }
 
-        process_children(subtree, rules, res, image_literals, &mut curr_state);
+        process_children(subtree, rules, res, image_literals, &mut curr_state, errors);
 
         debug_assert!(curr_state.current_merge.is_none());
 
-        if curr_state.current_node.is_some() && tag_with_literal.is_some() {
-            let node = curr_state.current_node.unwrap();
-            let tagged_node = res.new_node(
+        if let (Some(node), Some(lit)) = (curr_state.current_node, tag_with_literal) {
+            let tagged_node = res.new_node_from(
                 BuildNode::SetLabel {
                     parent: node,
                     label: MODUS_LABEL.to_owned(),
-                    value: tag_with_literal.unwrap().to_owned(),
+                    value: lit.to_string(),
                 },
                 vec![node],
+                lit,
             );
             curr_state.set_node(tagged_node);
         }
@@ -726,19 +1181,36 @@ pub fn build_dag_from_proofs(
             rules,
             &mut res,
             &mut image_literals,
-            Some(query.to_string()),
+            Some(query),
+            &mut errors,
         ) {
             image_literals.insert(query.clone(), node_id);
             res.outputs.push(Output {
                 node: node_id,
                 source_literal: Some(query.clone()),
             });
-        } else {
+        } else if errors.is_empty() {
             panic!("{} does not resolve to any docker instructions.", query);
         }
     }
 
-    res
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    res.canonicalize();
+    Ok(res)
+}
+
+/// An error in the Modusfile found while planning, pointing at the literal.
+fn literal_error(lit: &Literal, message: &str) -> Diagnostic<()> {
+    let diag = Diagnostic::error().with_message(message);
+    match &lit.position {
+        Some(pos) => diag.with_labels(vec![Label::primary(
+            (),
+            pos.offset..(pos.offset + pos.length),
+        )]),
+        None => diag.with_notes(vec![lit.to_string()]),
+    }
 }
 
 fn join_path(base: &str, path: &str) -> String {
@@ -844,12 +1316,167 @@ pub fn plan_from_modusfile(
 
     // don't store full tree as this takes a lot of memory, and is probably not needed
     // when building/transpiling
-    let success_tree = Result::from(sld::sld(&ir_clauses, &query_goal, max_depth, false))?;
+    let success_tree = Result::from(sld::sld_with_modes(
+        &ir_clauses,
+        &mf.modes(),
+        &query_goal,
+        max_depth,
+        false,
+    ))?;
     let proofs = sld::proofs(&success_tree, &ir_clauses, &query_goal);
 
     let query_and_proofs = proofs
         .into_iter()
         .map(|(_, p)| (image_literal.substitute(&p.valuation), p))
         .collect::<Vec<_>>();
-    Ok(build_dag_from_proofs(&query_and_proofs[..], &ir_clauses))
+    build_dag_from_proofs(&query_and_proofs[..], &ir_clauses)
+}
+
+/// Plans a query of a Modusfile which is expected to be valid, for the tests
+/// of modules working on build plans.
+#[cfg(test)]
+pub(crate) fn plan_for(source: &str, query: &str) -> BuildPlan {
+    let mf: Modusfile = source.parse().unwrap();
+    let query: modusfile::Expression = query.parse().unwrap();
+    plan_from_modusfile(mf, query.without_position()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    fn plan_version_is_checked() {
+        let plan = BuildPlan::new();
+        assert!(BuildPlan::from_json(plan.to_json().as_bytes()).is_ok());
+        assert!(matches!(
+            BuildPlan::from_json(br#"{"nodes": [], "dependencies": [], "outputs": []}"#),
+            Err(PlanFormatError::MissingVersion)
+        ));
+        assert!(matches!(
+            BuildPlan::from_json(br#"{"version": 999, "nodes": "changed"}"#),
+            Err(PlanFormatError::UnsupportedVersion(999))
+        ));
+        assert!(matches!(
+            BuildPlan::from_json(format!(r#"{{"version": {}}}"#, BUILD_PLAN_VERSION).as_bytes()),
+            Err(PlanFormatError::Invalid(_))
+        ));
+    }
+
+    /// Checks that the published schema is in sync with the plans we create.
+    #[test]
+    #[serial]
+    fn plan_matches_schema() {
+        let schema: serde_json::Value =
+            serde_json::from_str(include_str!("../build-plan.schema.json")).unwrap();
+        assert_eq!(schema["properties"]["version"]["const"], BUILD_PLAN_VERSION);
+        let variants: Vec<&str> = schema["$defs"]["node"]["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["required"][0].as_str().unwrap())
+            .collect();
+
+        let mf: Modusfile = r#"
+            base :- from("alpine")::set_workdir("/app"), (copy("a", "b"), run("ls"))::merge.
+            other :- from("alpine"), run("make").
+            app :- base::set_env("A", "1")::set_user("root"),
+                   other::copy("/x", "/y"),
+                   copy("c", "d").
+        "#
+        .parse()
+        .unwrap();
+        let plan = plan_from_modusfile(mf, "app".parse().unwrap()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&plan.to_json()).unwrap();
+        for node in json["nodes"].as_array().unwrap() {
+            let (name, _) = node.as_object().unwrap().iter().next().unwrap();
+            assert!(variants.contains(&name.as_str()), "{} not in schema", name);
+        }
+        for field in schema["required"].as_array().unwrap() {
+            assert!(
+                json.get(field.as_str().unwrap()).is_some(),
+                "{} missing",
+                field
+            );
+        }
+        assert_eq!(json["hashes"], serde_json::json!(plan.content_hashes()));
+    }
+
+    #[test]
+    #[serial]
+    fn plans_are_canonical() {
+        let mf: Modusfile = r#"
+            tools :- from("alpine"), run("apk add make").
+            lib(X) :- from("alpine"), run("apk add make"), run(f"make lib${X}").
+            app(X) :- name(X), lib(X), tools::copy("/usr/bin/make", "/make").
+            name("1").
+            name("2").
+            name("3").
+        "#
+        .parse()
+        .unwrap();
+        let query: modusfile::Expression = "app(X)".parse().unwrap();
+        let plan = || plan_from_modusfile(mf.clone(), query.clone().without_position()).unwrap();
+
+        let first = plan();
+        for _ in 0..5 {
+            assert_eq!(plan().to_json(), first.to_json());
+        }
+        let names: Vec<String> = first
+            .outputs
+            .iter()
+            .map(|o| o.source_literal.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["app(\"1\")", "app(\"2\")", "app(\"3\")"]);
+        // The base image and the first step are shared by every image.
+        let froms = first
+            .nodes
+            .iter()
+            .filter(|n| matches!(n, BuildNode::From { .. }))
+            .count();
+        assert_eq!(froms, 1);
+        let hashes = first.content_hashes();
+        let unique: HashSet<&String> = hashes.iter().collect();
+        assert_eq!(unique.len(), hashes.len());
+        for (id, deps) in first.dependencies.iter().enumerate() {
+            assert!(deps.iter().all(|&d| d < id));
+        }
+    }
+
+    /// Plans which can't be built are reported as errors pointing at the
+    /// misused operator, rather than crashing the planner.
+    #[test]
+    #[serial]
+    fn misused_operators_are_errors() {
+        let errors = |source: &str| -> Vec<(String, String)> {
+            let mf: Modusfile = source.parse().unwrap();
+            plan_from_modusfile(mf, "app".parse().unwrap())
+                .unwrap_err()
+                .into_iter()
+                .map(|d| {
+                    let label = d
+                        .labels
+                        .first()
+                        .map(|l| source[l.range.clone()].to_owned())
+                        .unwrap_or_default();
+                    (d.message, label)
+                })
+                .collect()
+        };
+        assert_eq!(
+            errors(r#"app :- from("alpine"), (run("ls"), run("make test")::check)::merge."#),
+            [(
+                "`::check` can not be used inside `::merge`.".to_owned(),
+                "check".to_owned()
+            )]
+        );
+        assert_eq!(
+            errors(r#"app :- from("alpine")::export("/a", "/out")."#),
+            [(
+                "The destination of `::export` can not be an absolute path, since it is relative to the export directory.".to_owned(),
+                r#"export("/a", "/out")"#.to_owned()
+            )]
+        );
+    }
 }
@@ -14,15 +14,24 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod affected;
 pub mod analysis;
 // pub mod buildkit;
 pub mod builtin;
 pub mod dockerfile;
+pub mod dockerignore;
 pub mod imagegen;
+pub mod lint;
 pub mod logic;
 pub mod modusfile;
+pub mod plandiff;
+pub mod planview;
+pub mod policy;
 // pub mod reporting;
+pub mod sharding;
 pub mod sld;
+pub mod suggest;
+pub mod testing;
 pub mod translate;
 pub mod transpiler;
 pub mod unification;
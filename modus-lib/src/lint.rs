@@ -0,0 +1,395 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Lints for common mistakes in the images built by a Modusfile, used by
+//! `modus lint`.
+//!
+//! Lints run over the build plan of a query, so that format strings are
+//! resolved and the order of the steps of each image is known. Warnings are
+//! reported at the literal the offending node was generated from, and can be
+//! allowed with a comment `# modus:allow(code)` on the line of the literal or
+//! on the line above it.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    ops::Range,
+};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+
+use crate::{
+    dockerignore::path_components,
+    imagegen::{BuildNode, BuildPlan, MergeOperation, NodeId},
+    planview::{describe_node, node_parent},
+};
+
+pub struct Lint {
+    pub code: &'static str,
+    pub description: &'static str,
+}
+
+pub const LINTS: [Lint; 5] = [
+    Lint {
+        code: "untagged-image",
+        description: "an image is used without a tag or digest, or with the latest tag",
+    },
+    Lint {
+        code: "apt-get-yes",
+        description: "apt-get install is run without -y, so it waits for a confirmation",
+    },
+    Lint {
+        code: "apt-get-cleanup",
+        description: "apt-get lists are not removed in the step that downloads them",
+    },
+    Lint {
+        code: "cd-in-run",
+        description: "a command changes directory instead of using ::in_workdir",
+    },
+    Lint {
+        code: "early-context-copy",
+        description:
+            "the whole context is copied before commands, which rerun whenever any file changes",
+    },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LintWarning {
+    pub node: NodeId,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// The shell commands of a command line, split at `&&`, `||`, `;` and `|`.
+fn shell_commands(command: &str) -> Vec<Vec<&str>> {
+    command
+        .split(['&', '|', ';', '\n'])
+        .map(|c| c.split_whitespace().collect::<Vec<_>>())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+fn lint_image(image: &str) -> Option<String> {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    if image.contains('@') {
+        None
+    } else if !name.contains(':') {
+        Some(format!(
+            "{} has no tag, so builds may use a different image",
+            image
+        ))
+    } else if name.ends_with(":latest") {
+        Some(format!(
+            "{} uses the latest tag, so builds may use a different image",
+            image
+        ))
+    } else {
+        None
+    }
+}
+
+fn lint_run(command: &str, warn: &mut impl FnMut(&'static str, String)) {
+    let commands = shell_commands(command);
+    let apt_get = |c: &[&str], sub: &str| {
+        c.iter().any(|w| *w == "apt-get" || *w == "apt") && c.contains(&sub)
+    };
+    let installs = commands.iter().filter(|c| apt_get(c, "install"));
+    let is_yes = |w: &&str| {
+        matches!(*w, "-y" | "--yes" | "--assume-yes")
+            || (w.starts_with('-') && !w.starts_with("--") && w.contains('y'))
+    };
+    if installs.clone().any(|c| !c.iter().any(is_yes)) {
+        warn(
+            "apt-get-yes",
+            "apt-get install is run without -y, use `apt-get install -y`".to_owned(),
+        );
+    }
+    if (installs.count() > 0 || commands.iter().any(|c| apt_get(c, "update")))
+        && !command.contains("/var/lib/apt/lists")
+    {
+        warn(
+            "apt-get-cleanup",
+            "apt-get lists are left in the image, remove /var/lib/apt/lists/* in the same command"
+                .to_owned(),
+        );
+    }
+    if commands.iter().any(|c| c[0] == "cd") {
+        warn(
+            "cd-in-run",
+            "the command changes directory, use ::in_workdir instead".to_owned(),
+        );
+    }
+}
+
+const EARLY_CONTEXT_COPY: &str =
+    "the whole context is copied before running commands, so they rerun whenever any file changes";
+
+fn is_context_copy(op: &MergeOperation) -> bool {
+    matches!(op, MergeOperation::CopyFromLocal { src_path, .. } if path_components(src_path).is_empty())
+}
+
+fn copies_context(node: &BuildNode) -> bool {
+    match node {
+        BuildNode::CopyFromLocal { src_path, .. } => path_components(src_path).is_empty(),
+        BuildNode::Merge(merge) => merge.operations.iter().any(is_context_copy),
+        _ => false,
+    }
+}
+
+fn runs_commands(node: &BuildNode) -> bool {
+    match node {
+        BuildNode::Run { .. } => true,
+        BuildNode::Merge(merge) => merge
+            .operations
+            .iter()
+            .any(|op| matches!(op, MergeOperation::Run { .. })),
+        _ => false,
+    }
+}
+
+/// The lint warnings of the nodes of a plan, sorted by node.
+pub fn lint_plan(plan: &BuildPlan) -> Vec<LintWarning> {
+    let mut warnings = BTreeSet::new();
+    for (id, node) in plan.nodes.iter().enumerate() {
+        let mut warn = |code, message| {
+            warnings.insert(LintWarning {
+                node: id,
+                code,
+                message,
+            });
+        };
+        match node {
+            BuildNode::From { display_name, .. } => {
+                if let Some(message) = lint_image(display_name) {
+                    warn("untagged-image", message);
+                }
+            }
+            BuildNode::Run { command, .. } => lint_run(command, &mut warn),
+            BuildNode::Merge(merge) => {
+                for op in &merge.operations {
+                    if let MergeOperation::Run { command, .. } = op {
+                        lint_run(command, &mut warn);
+                    }
+                }
+                // Within a merge, the operations are still run in order.
+                if merge
+                    .operations
+                    .iter()
+                    .skip_while(|op| !is_context_copy(op))
+                    .any(|op| matches!(op, MergeOperation::Run { .. }))
+                {
+                    warn("early-context-copy", EARLY_CONTEXT_COPY.to_owned());
+                }
+            }
+            _ => {}
+        }
+        if runs_commands(node) {
+            let mut ancestor = node_parent(node);
+            while let Some(a) = ancestor {
+                if copies_context(&plan.nodes[a]) {
+                    warnings.insert(LintWarning {
+                        node: a,
+                        code: "early-context-copy",
+                        message: EARLY_CONTEXT_COPY.to_owned(),
+                    });
+                }
+                ancestor = node_parent(&plan.nodes[a]);
+            }
+        }
+    }
+    warnings.into_iter().collect()
+}
+
+/// A `# modus:allow(code, ...)` comment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllowComment {
+    /// The line of the comment, starting from 0.
+    pub line: usize,
+    pub span: Range<usize>,
+    pub codes: Vec<String>,
+}
+
+/// Finds the `modus:allow` comments of a Modusfile. Comments are not kept by
+/// the parser, so this looks for `#` outside of string literals.
+pub fn allow_comments(source: &str) -> Vec<AllowComment> {
+    let mut comments = Vec::new();
+    let mut offset = 0;
+    for (i, line) in source.split_inclusive('\n').enumerate() {
+        let mut in_string = false;
+        let mut escaped = false;
+        let start = line.char_indices().find_map(|(j, c)| {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_string => escaped = true,
+                '"' => in_string = !in_string,
+                '#' if !in_string => return Some(j),
+                _ => {}
+            }
+            None
+        });
+        let codes = start.and_then(|j| {
+            line[j + 1..]
+                .trim()
+                .strip_prefix("modus:allow(")
+                .and_then(|c| c.split(')').next())
+        });
+        if let (Some(j), Some(codes)) = (start, codes) {
+            comments.push(AllowComment {
+                line: i,
+                span: offset + j..offset + line.trim_end().len(),
+                codes: codes.split(',').map(|c| c.trim().to_owned()).collect(),
+            });
+        }
+        offset += line.len();
+    }
+    comments
+}
+
+/// Lints a plan built from `source`, leaving out the allowed warnings. Unknown
+/// codes in `modus:allow` comments are reported too.
+pub fn lint(plan: &BuildPlan, source: &str) -> Vec<Diagnostic<()>> {
+    let comments = allow_comments(source);
+    let is_allowed = |line: usize, code: &str| {
+        comments
+            .iter()
+            .any(|c| (c.line == line || c.line + 1 == line) && c.codes.iter().any(|c| c == code))
+    };
+    // Nodes generated from the same literal, e.g. for several outputs, share
+    // its warnings, so each warning is reported once per literal.
+    let mut seen = HashSet::new();
+    let mut diags: Vec<Diagnostic<()>> = lint_plan(plan)
+        .into_iter()
+        .filter_map(|w| {
+            let diag = Diagnostic::warning()
+                .with_code(w.code)
+                .with_message(w.message);
+            let description = format!("#{}: {}", w.node, describe_node(&plan.nodes[w.node]));
+            match plan
+                .node_source(w.node)
+                .and_then(|lit| lit.position.as_ref())
+            {
+                Some(pos) => {
+                    let line = source[..pos.offset.min(source.len())].matches('\n').count();
+                    if is_allowed(line, w.code) || !seen.insert((w.code, pos.offset, pos.length)) {
+                        None
+                    } else {
+                        Some(
+                            diag.with_labels(vec![
+                                Label::primary((), pos).with_message(description)
+                            ]),
+                        )
+                    }
+                }
+                None => Some(diag.with_notes(vec![format!("in {}", description)])),
+            }
+        })
+        .collect();
+    for comment in comments {
+        for code in comment.codes {
+            if !LINTS.iter().any(|l| l.code == code) {
+                diags.push(
+                    Diagnostic::warning()
+                        .with_message(format!("unknown lint code {}", code))
+                        .with_labels(vec![Label::primary((), comment.span.clone())]),
+                );
+            }
+        }
+    }
+    diags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagegen::plan_for;
+    use serial_test::serial;
+
+    fn codes(source: &str, query: &str) -> Vec<String> {
+        lint(&plan_for(source, query), source)
+            .into_iter()
+            .map(|d| d.code.unwrap_or(d.message))
+            .collect()
+    }
+
+    #[test]
+    fn lints_commands() {
+        let mut warnings = Vec::new();
+        let mut warn = |code, _| warnings.push(code);
+        lint_run("apt-get update && apt-get install curl", &mut warn);
+        lint_run("cd /src && make", &mut warn);
+        lint_run(
+            "apt-get update && apt-get install -qy curl && rm -rf /var/lib/apt/lists/*",
+            &mut warn,
+        );
+        lint_run("make -C /src", &mut warn);
+        assert_eq!(warnings, ["apt-get-yes", "apt-get-cleanup", "cd-in-run"]);
+
+        assert!(lint_image("ubuntu").is_some());
+        assert!(lint_image("ubuntu:latest").is_some());
+        assert!(lint_image("localhost:5000/ubuntu").is_some());
+        assert!(lint_image("localhost:5000/ubuntu:22.04").is_none());
+        assert!(lint_image("ubuntu@sha256:abcd").is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn lints_plans() {
+        let source = r#"
+            a :- from("alpine"), copy(".", "."), run("make").
+            b :- from("alpine:3.15"), copy("src", "src"), run("make"), copy(".", ".").
+        "#;
+        assert_eq!(codes(source, "a"), ["untagged-image", "early-context-copy"]);
+        assert!(codes(source, "b").is_empty());
+
+        let source = r#"
+            # modus:allow(untagged-image)
+            a :- from("alpine"),
+                (copy(".", "."), run("make"))::merge. # modus:allow(cd-in-run)
+        "#;
+        assert_eq!(codes(source, "a"), ["early-context-copy"]);
+
+        let source = r#"
+            a :- from("alpine:3.15"), run("cd /src && make"). # modus:allow(cd-in-run, cd)
+        "#;
+        assert_eq!(codes(source, "a"), ["unknown lint code cd"]);
+    }
+
+    #[test]
+    #[serial]
+    fn reports_each_literal_once() {
+        let source = r#"
+            version("3.15").
+            version("3.16").
+            app(V) :- version(V), from(f"alpine:${V}"), run("cd /src && make").
+        "#;
+        let plan = plan_for(source, "app(V)");
+        assert_eq!(plan.outputs.len(), 2);
+        assert_eq!(lint_plan(&plan).len(), 2);
+        assert_eq!(codes(source, "app(V)"), ["cd-in-run"]);
+    }
+
+    #[test]
+    fn parses_allow_comments() {
+        let source =
+            "# modus:allow(a, b)\nx :- run(\"# modus:allow(c)\"). # modus:allow(d)\n# other\n";
+        let comments = allow_comments(source);
+        assert_eq!(comments.len(), 2);
+        assert_eq!(comments[0].line, 0);
+        assert_eq!(comments[0].codes, ["a", "b"]);
+        assert_eq!(comments[1].line, 1);
+        assert_eq!(comments[1].codes, ["d"]);
+        assert_eq!(&source[comments[1].span.clone()], "# modus:allow(d)");
+    }
+}
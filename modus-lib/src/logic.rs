@@ -39,6 +39,8 @@ impl fmt::Display for IRTerm {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             IRTerm::Constant(s) => write!(f, "\"{}\"", s),
+            IRTerm::Integer(i) => write!(f, "{}", i),
+            IRTerm::Boolean(b) => write!(f, "{}", b),
             IRTerm::UserVariable(s) => write!(f, "{}", s),
             IRTerm::List(ts) => write!(
                 f,
@@ -61,7 +63,7 @@ pub static AVAILABLE_VARIABLE_INDEX: AtomicU32 = AtomicU32::new(0);
 impl Rename<IRTerm> for IRTerm {
     fn rename(&self) -> IRTerm {
         match self {
-            IRTerm::Constant(_) => (*self).clone(),
+            IRTerm::Constant(_) | IRTerm::Integer(_) | IRTerm::Boolean(_) => (*self).clone(),
             IRTerm::List(ts) => IRTerm::List(ts.iter().map(|t| t.rename()).collect()),
             _ => {
                 let index = AVAILABLE_VARIABLE_INDEX.fetch_add(1, Ordering::SeqCst);
@@ -124,9 +126,30 @@ impl From<String> for Predicate {
     }
 }
 
+/// The type of a constant term.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ConstantType {
+    String,
+    Integer,
+    Boolean,
+}
+
+impl fmt::Display for ConstantType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConstantType::String => write!(f, "a string"),
+            ConstantType::Integer => write!(f, "an integer"),
+            ConstantType::Boolean => write!(f, "a boolean"),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord)]
 pub enum IRTerm {
+    /// A string constant.
     Constant(String),
+    Integer(i64),
+    Boolean(bool),
     UserVariable(String),
     List(Vec<IRTerm>),
 
@@ -141,16 +164,23 @@ pub enum IRTerm {
 }
 
 impl IRTerm {
-    /// Returns `true` if the IRTerm is [`Constant`].
-    ///
-    /// [`Constant`]: IRTerm::Constant
+    /// Returns `true` if the IRTerm is a string, an integer or a boolean.
     pub fn is_constant(&self) -> bool {
-        matches!(self, Self::Constant(..))
+        self.constant_type().is_some()
+    }
+
+    pub fn constant_type(&self) -> Option<ConstantType> {
+        match self {
+            Self::Constant(_) => Some(ConstantType::String),
+            Self::Integer(_) => Some(ConstantType::Integer),
+            Self::Boolean(_) => Some(ConstantType::Boolean),
+            _ => None,
+        }
     }
 
     pub fn is_constant_or_compound_constant(&self) -> bool {
         match self {
-            Self::Constant(_) => true,
+            Self::Constant(_) | Self::Integer(_) | Self::Boolean(_) => true,
             Self::List(ts) => ts.iter().all(|t| t.is_constant_or_compound_constant()),
             _ => false,
         }
@@ -168,6 +198,7 @@ impl IRTerm {
         }
     }
 
+    /// The string of a string constant.
     pub fn as_constant(&self) -> Option<&str> {
         match self {
             IRTerm::Constant(c) => Some(&c[..]),
@@ -175,6 +206,16 @@ impl IRTerm {
         }
     }
 
+    /// The text a constant is interpolated as in a format string.
+    pub fn as_formatted(&self) -> Option<String> {
+        match self {
+            IRTerm::Constant(c) => Some(c.clone()),
+            IRTerm::Integer(i) => Some(i.to_string()),
+            IRTerm::Boolean(b) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+
     /// Gets the original IRTerm from a renamed one, or returns itself.
     pub fn get_original(&self) -> &IRTerm {
         match self {
@@ -280,7 +321,8 @@ impl IRTerm {
             | (IRTerm::UserVariable(_), _) => {
                 set.insert(self.clone());
             }
-            (IRTerm::Constant(_), _) | (IRTerm::AnonymousVariable(_), false) => (),
+            (IRTerm::Constant(_) | IRTerm::Integer(_) | IRTerm::Boolean(_), _)
+            | (IRTerm::AnonymousVariable(_), false) => (),
         }
         set
     }
@@ -356,7 +398,7 @@ impl Clause {
 
 impl Ground for IRTerm {
     fn is_ground(&self) -> bool {
-        matches!(self, IRTerm::Constant(_))
+        self.is_constant()
     }
 }
 
@@ -450,8 +492,8 @@ pub mod parser {
     use nom::{
         branch::alt,
         bytes::complete::{is_a, take_until},
-        character::complete::{alpha1, alphanumeric1, multispace0},
-        combinator::{cut, map, opt, recognize},
+        character::complete::{alpha1, alphanumeric1, char, digit1, multispace0},
+        combinator::{cut, map, map_res, opt, recognize},
         multi::{many0, many0_count, separated_list0, separated_list1},
         sequence::{delimited, pair, preceded, terminated, tuple},
         Offset, Slice,
@@ -495,6 +537,12 @@ pub mod parser {
         delimited(tag("\""), take_until("\""), tag("\""))(i)
     }
 
+    fn integer(i: Span) -> IResult<Span, i64> {
+        map_res(recognize(pair(opt(char('-')), digit1)), |s: Span| {
+            s.fragment().parse()
+        })(i)
+    }
+
     fn variable(i: Span) -> IResult<Span, Span> {
         literal_identifier(i)
     }
@@ -511,8 +559,13 @@ pub mod parser {
         alt((
             map(list_term, IRTerm::List),
             map(constant, |s| IRTerm::Constant(s.fragment().to_string())),
+            map(integer, IRTerm::Integer),
             map(is_a("_"), |_| sld::Auxiliary::aux(true)),
-            map(variable, |s| IRTerm::UserVariable(s.fragment().to_string())),
+            map(variable, |s| match *s.fragment() {
+                "true" => IRTerm::Boolean(true),
+                "false" => IRTerm::Boolean(false),
+                v => IRTerm::UserVariable(v.to_string()),
+            }),
         ))(i)
     }
 
@@ -18,18 +18,21 @@ use codespan_reporting::diagnostic::Diagnostic;
 use codespan_reporting::diagnostic::Label;
 use nom::character::complete::line_ending;
 use nom::character::complete::not_line_ending;
+use nom::Slice;
 use nom_supreme::error::BaseErrorKind;
 use nom_supreme::error::ErrorTree;
 use nom_supreme::error::StackContext;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::ops::Range;
 use std::str;
 
+use crate::analysis::Kind;
 use crate::logic;
 use crate::logic::parser::Span;
 use crate::logic::Predicate;
+use crate::logic::Signature;
 use crate::logic::SpannedPosition;
 use crate::sld;
 
@@ -220,7 +223,10 @@ impl fmt::Display for FormatStringFragment {
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum ModusTerm {
+    /// A string constant, with escape characters left unhandled.
     Constant(String),
+    Integer(i64),
+    Boolean(bool),
     /// A format string with '\$' left unhandled. This should be dealt with when
     /// converting to the IR.
     FormatString {
@@ -254,7 +260,23 @@ impl ModusTerm {
                 .collect(),
             ModusTerm::UserVariable(s) => vec![s],
             ModusTerm::List(_, ts) => ts.iter().flat_map(ModusTerm::variable_strings).collect(),
-            ModusTerm::Constant(_) | ModusTerm::AnonymousVariable => Vec::new(),
+            ModusTerm::Constant(_)
+            | ModusTerm::Integer(_)
+            | ModusTerm::Boolean(_)
+            | ModusTerm::AnonymousVariable => Vec::new(),
+        }
+    }
+
+    /// The type of the constants this term can be, if known. Format strings are
+    /// always strings.
+    pub fn constant_type(&self) -> Option<logic::ConstantType> {
+        match self {
+            ModusTerm::Constant(_) | ModusTerm::FormatString { .. } => {
+                Some(logic::ConstantType::String)
+            }
+            ModusTerm::Integer(_) => Some(logic::ConstantType::Integer),
+            ModusTerm::Boolean(_) => Some(logic::ConstantType::Boolean),
+            _ => None,
         }
     }
 
@@ -270,6 +292,8 @@ impl fmt::Display for ModusTerm {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ModusTerm::Constant(s) => write!(f, "\"{}\"", s),
+            ModusTerm::Integer(i) => write!(f, "{}", i),
+            ModusTerm::Boolean(b) => write!(f, "{}", b),
             ModusTerm::UserVariable(s) => write!(f, "{}", s),
             ModusTerm::FormatString {
                 position: _,
@@ -313,6 +337,8 @@ impl From<ModusTerm> for logic::IRTerm {
     fn from(modus_term: ModusTerm) -> Self {
         match modus_term {
             ModusTerm::Constant(c) => logic::IRTerm::Constant(process_raw_string(&c)),
+            ModusTerm::Integer(i) => logic::IRTerm::Integer(i),
+            ModusTerm::Boolean(b) => logic::IRTerm::Boolean(b),
             ModusTerm::FormatString { .. } => {
                 unreachable!("BUG: analysis should've handled this case.")
             }
@@ -401,10 +427,147 @@ impl fmt::Display for Operator {
     }
 }
 
+/// The mode of an argument in a mode declaration.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Mode {
+    /// `+`, the argument must be ground when the predicate is called.
+    Input,
+    /// `-`, the argument may be a variable, which the predicate binds.
+    Output,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Input => write!(f, "+"),
+            Mode::Output => write!(f, "-"),
+        }
+    }
+}
+
+/// Declares the modes of the arguments of a predicate, e.g. `:- mode version_of(+, -).`
 #[derive(Clone, PartialEq, Debug)]
-pub struct Modusfile(pub Vec<ModusClause>);
+pub struct ModeDeclaration {
+    pub position: Option<SpannedPosition>,
+    pub predicate: Predicate,
+    pub modes: Vec<Mode>,
+}
+
+impl ModeDeclaration {
+    pub fn signature(&self) -> Signature {
+        Signature(self.predicate.clone(), self.modes.len() as u32)
+    }
+
+    /// Whether each argument is allowed to be ungrounded, as used in resolution.
+    pub fn groundness(&self) -> Vec<bool> {
+        self.modes.iter().map(|m| *m == Mode::Output).collect()
+    }
+}
+
+impl fmt::Display for ModeDeclaration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            ":- mode {}({}).",
+            self.predicate,
+            self.modes
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+/// Declares the kind of a predicate, e.g. `image app(version).`
+///
+/// The parameters are only names for the arguments, which document them.
+#[derive(Clone, PartialEq, Debug)]
+pub struct KindDeclaration {
+    pub position: Option<SpannedPosition>,
+    pub kind: Kind,
+    pub predicate: Predicate,
+    pub params: Vec<String>,
+}
+
+impl KindDeclaration {
+    pub fn signature(&self) -> Signature {
+        Signature(self.predicate.clone(), self.params.len() as u32)
+    }
+}
+
+impl fmt::Display for KindDeclaration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Image => "image",
+            Kind::Layer => "layer",
+            Kind::Logic => "logic",
+        };
+        if self.params.is_empty() {
+            write!(f, "{} {}.", kind, self.predicate)
+        } else {
+            write!(
+                f,
+                "{} {}({}).",
+                kind,
+                self.predicate,
+                self.params.join(", ")
+            )
+        }
+    }
+}
+
+/// A directive in a Modusfile which is not a clause.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Declaration {
+    Mode(ModeDeclaration),
+    Kind(KindDeclaration),
+}
+
+/// An item at the top level of a Modusfile.
+enum Item {
+    Clause(ModusClause),
+    Declaration(Declaration),
+}
+
+/// The clauses of a Modusfile, and its declarations.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Modusfile(pub Vec<ModusClause>, pub Vec<Declaration>);
 
 impl Modusfile {
+    fn from_items(items: Vec<Item>) -> Modusfile {
+        let mut mf = Modusfile(Vec::new(), Vec::new());
+        for item in items {
+            match item {
+                Item::Clause(c) => mf.0.push(c),
+                Item::Declaration(d) => mf.1.push(d),
+            }
+        }
+        mf
+    }
+
+    pub fn mode_declarations(&self) -> impl Iterator<Item = &ModeDeclaration> {
+        self.1.iter().filter_map(|d| match d {
+            Declaration::Mode(m) => Some(m),
+            _ => None,
+        })
+    }
+
+    pub fn kind_declarations(&self) -> impl Iterator<Item = &KindDeclaration> {
+        self.1.iter().filter_map(|d| match d {
+            Declaration::Kind(k) => Some(k),
+            _ => None,
+        })
+    }
+
+    /// The declared groundness of the predicates with a mode declaration.
+    /// If a predicate is declared more than once, the last declaration is used.
+    pub fn modes(&self) -> HashMap<Signature, Vec<bool>> {
+        self.mode_declarations()
+            .map(|m| (m.signature(), m.groundness()))
+            .collect()
+    }
+
     /// Adds a rule with a head literal that serves as the goal `_query :- [body]`.
     /// Note: does not check whether there is an existing goal, or other checks.
     pub fn add_goal(&mut self, goal: Expression) -> &mut Self {
@@ -535,15 +698,112 @@ fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
     inner(e, Vec::new())
 }
 
+/// The furthest offset the parser reached before failing.
+fn error_offset(e: &ErrorTree<Span>) -> usize {
+    match e {
+        ErrorTree::Base { location, .. } => location.location_offset(),
+        ErrorTree::Stack { base, .. } => error_offset(base),
+        ErrorTree::Alt(alts) => alts.iter().map(error_offset).max().unwrap_or(0),
+    }
+}
+
+/// The offset where parsing resumes after a syntax error at `error` in the clause
+/// starting at `start`.
+///
+/// This is just after the first `.` at or after the error, or at the first line
+/// starting with an identifier or `:-`, i.e. a new clause or declaration,
+/// whichever comes first.
+/// Strings and comments are skipped.
+fn next_clause_boundary(source: &str, start: usize, error: usize) -> usize {
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut escaped = false;
+    let mut previous = None;
+    for (offset, c) in source[start..].char_indices() {
+        let offset = start + offset;
+        if in_comment {
+            in_comment = c != '\n';
+        } else if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if offset > start
+            && offset >= error
+            && previous == Some('\n')
+            && (c.is_ascii_alphabetic() || c == '_' || c == ':')
+        {
+            return offset;
+        } else if c == '"' {
+            in_string = true;
+        } else if c == '#' {
+            in_comment = true;
+        } else if c == '.' && offset >= error {
+            return offset + 1;
+        }
+        previous = Some(c);
+    }
+    source.len()
+}
+
+fn unexpected_end_of_input(source: &str) -> Diagnostic<()> {
+    Diagnostic::error()
+        .with_message("unexpected end of input")
+        .with_labels(vec![Label::primary((), source.len()..source.len())])
+}
+
+impl Modusfile {
+    /// Parses a Modusfile, recovering from syntax errors at clause boundaries
+    /// so that all of them are reported at once.
+    ///
+    /// Returns the clauses that could be parsed along with the diagnostics, so
+    /// the result can still be analysed when there are syntax errors.
+    pub fn parse_recovering(source: &str) -> (Modusfile, Vec<Diagnostic<()>>) {
+        let mut items = Vec::new();
+        let mut diags = Vec::new();
+        let mut i = Span::new(source);
+        loop {
+            if let Ok((rest, _)) = parser::token_sep0(i) {
+                i = rest;
+            }
+            if i.fragment().is_empty() {
+                break;
+            }
+            match parser::item(i) {
+                Ok((rest, item)) => {
+                    items.push(item);
+                    i = rest;
+                }
+                Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                    let start = i.location_offset();
+                    let next = next_clause_boundary(source, start, error_offset(&e));
+                    let mut clause_diags = better_convert_error(e);
+                    clause_diags.dedup();
+                    diags.extend(clause_diags);
+                    i = i.slice(next - start..);
+                }
+                Err(nom::Err::Incomplete(_)) => {
+                    diags.push(unexpected_end_of_input(source));
+                    break;
+                }
+            }
+        }
+        (Modusfile::from_items(items), diags)
+    }
+}
+
 impl str::FromStr for Modusfile {
     type Err = Vec<Diagnostic<()>>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let span = Span::new(s);
-        match parser::modusfile(span) {
-            Result::Ok((_, o)) => Ok(o),
-            Result::Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(better_convert_error(e)),
-            _ => unimplemented!(),
+        let (mf, diags) = Modusfile::parse_recovering(s);
+        if diags.is_empty() {
+            Ok(mf)
+        } else {
+            Err(diags)
         }
     }
 }
@@ -556,7 +816,7 @@ impl str::FromStr for Expression {
         match parser::body(span) {
             Ok((_, o)) => Ok(o),
             Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(better_convert_error(e)),
-            _ => unimplemented!(),
+            Err(nom::Err::Incomplete(_)) => Err(vec![unexpected_end_of_input(s)]),
         }
     }
 }
@@ -640,8 +900,8 @@ pub mod parser {
     use super::*;
 
     use nom::bytes::complete::{escaped, is_a};
-    use nom::character::complete::{multispace0, none_of, one_of};
-    use nom::combinator::{cut, opt, recognize};
+    use nom::character::complete::{digit1, multispace0, multispace1, none_of, one_of};
+    use nom::combinator::{cut, map_res, opt, peek, recognize};
     use nom::error::context;
     use nom::multi::{many0_count, many1, separated_list0, separated_list1};
     use nom::sequence::{pair, tuple};
@@ -989,6 +1249,20 @@ pub mod parser {
         )(i)
     }
 
+    pub fn modus_integer(i: Span) -> IResult<Span, i64> {
+        context(
+            stringify!(modus_integer),
+            preceded(
+                peek(pair(opt(tag("-")), digit1)),
+                // fails on integers which are out of range
+                cut(map_res(
+                    recognize(pair(opt(tag("-")), digit1)),
+                    |s: Span| s.fragment().parse(),
+                )),
+            ),
+        )(i)
+    }
+
     fn format_string_fragment(i: Span) -> IResult<Span, FormatStringFragment> {
         alt((
             map(string_interpolation, |v_span| {
@@ -1054,6 +1328,7 @@ pub mod parser {
             stringify!(modus_term),
             alt((
                 map(modus_const, ModusTerm::Constant),
+                map(modus_integer, ModusTerm::Integer),
                 map(recognized_span(modus_list_term), |(span, terms)| {
                     ModusTerm::List(span, terms)
                 }),
@@ -1064,8 +1339,10 @@ pub mod parser {
                     }
                 }),
                 map(is_a("_"), |_| ModusTerm::AnonymousVariable),
-                map(modus_var, |s| {
-                    ModusTerm::UserVariable(s.fragment().to_string())
+                map(modus_var, |s| match *s.fragment() {
+                    "true" => ModusTerm::Boolean(true),
+                    "false" => ModusTerm::Boolean(false),
+                    v => ModusTerm::UserVariable(v.to_string()),
                 }),
             )),
         )(i)
@@ -1075,13 +1352,102 @@ pub mod parser {
         alt((rule, fact))(i)
     }
 
+    fn mode(i: Span) -> IResult<Span, Mode> {
+        alt((
+            map(tag("+"), |_| Mode::Input),
+            map(tag("-"), |_| Mode::Output),
+        ))(i)
+    }
+
+    pub fn mode_declaration(i: Span) -> IResult<Span, ModeDeclaration> {
+        context(
+            stringify!(mode_declaration),
+            map(
+                recognized_span(preceded(
+                    pair(terminated(tag(":-"), token_sep0), tag("mode")),
+                    cut(pair(
+                        preceded(multispace1, literal_identifier),
+                        delimited(
+                            delimited(token_sep0, tag("("), token_sep0),
+                            separated_list1(delimited(token_sep0, tag(","), token_sep0), mode),
+                            delimited(token_sep0, tag(")"), token_sep0),
+                        ),
+                    )),
+                )),
+                |(position, (name, modes))| ModeDeclaration {
+                    position: Some(position),
+                    predicate: Predicate(name.fragment().to_string()),
+                    modes,
+                },
+            ),
+        )(i)
+    }
+
+    /// Parses a kind declaration. This is not a failure until the name of the
+    /// predicate, since a clause may start with `image`, `layer` or `logic`.
+    pub fn kind_declaration(i: Span) -> IResult<Span, KindDeclaration> {
+        context(
+            stringify!(kind_declaration),
+            map(
+                recognized_span(pair(
+                    pair(
+                        alt((
+                            map(tag("image"), |_| Kind::Image),
+                            map(tag("layer"), |_| Kind::Layer),
+                            map(tag("logic"), |_| Kind::Logic),
+                        )),
+                        preceded(multispace1, literal_identifier),
+                    ),
+                    cut(opt(delimited(
+                        delimited(token_sep0, tag("("), token_sep0),
+                        separated_list1(
+                            delimited(token_sep0, tag(","), token_sep0),
+                            literal_identifier,
+                        ),
+                        preceded(token_sep0, tag(")")),
+                    ))),
+                )),
+                |(position, ((kind, name), params))| KindDeclaration {
+                    position: Some(position),
+                    kind,
+                    predicate: Predicate(name.fragment().to_string()),
+                    params: params
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|p| p.fragment().to_string())
+                        .collect(),
+                },
+            ),
+        )(i)
+    }
+
+    pub fn declaration(i: Span) -> IResult<Span, Declaration> {
+        terminated(
+            alt((
+                map(mode_declaration, Declaration::Mode),
+                map(kind_declaration, Declaration::Kind),
+            )),
+            cut(preceded(
+                token_sep0,
+                terminated(nom::character::complete::char('.'), token_sep0),
+            )),
+        )(i)
+    }
+
+    pub(super) fn item(i: Span) -> IResult<Span, Item> {
+        alt((
+            map(declaration, Item::Declaration),
+            map(modus_clause, Item::Clause),
+        ))(i)
+    }
+
     pub fn modusfile(i: Span) -> IResult<Span, Modusfile> {
         map(
             terminated(
-                many0(preceded(token_sep0, modus_clause)),
+                many0(preceded(token_sep0, item)),
                 terminated(token_sep0, eof),
             ),
-            Modusfile,
+            Modusfile::from_items,
         )(i)
     }
 }
@@ -1351,6 +1717,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn typed_constants() {
+        let lit: Literal = "foo(1, -20, true, false, X, \"1\")".parse().unwrap();
+        assert_eq!(
+            lit.args,
+            vec![
+                ModusTerm::Integer(1),
+                ModusTerm::Integer(-20),
+                ModusTerm::Boolean(true),
+                ModusTerm::Boolean(false),
+                ModusTerm::UserVariable("X".to_owned()),
+                ModusTerm::Constant("1".to_owned()),
+            ]
+        );
+        assert_eq!(lit.to_string(), "foo(1, -20, true, false, X, \"1\")");
+        assert!("foo(99999999999999999999)".parse::<Literal>().is_err());
+    }
+
     #[test]
     fn anonymous_variables() {
         let expected = Literal {
@@ -1674,6 +2058,76 @@ mod tests {
         assert!(diags[0].labels[2].message.contains("rule"));
     }
 
+    #[test]
+    fn mode_declarations() {
+        let mf: Modusfile = ":- mode version_of(+, -).\n\
+                             version_of(\"alpine\", \"3.15\").\n\
+                             :-mode  base( - ) ."
+            .parse()
+            .unwrap();
+        assert_eq!(mf.0.len(), 1);
+        let decls: Vec<String> = mf.mode_declarations().map(|m| m.to_string()).collect();
+        assert_eq!(decls, [":- mode version_of(+, -).", ":- mode base(-)."]);
+        assert_eq!(
+            mf.mode_declarations().next().unwrap().position,
+            Some(SpannedPosition {
+                offset: 0,
+                length: 24
+            })
+        );
+        assert_eq!(
+            mf.modes()[&logic::Signature(Predicate("version_of".into()), 2)],
+            [false, true]
+        );
+
+        let (mf, diags) = Modusfile::parse_recovering(":- mode a(+, x).\nb.\n:- mode c.\nd.");
+        assert_eq!(diags.len(), 2);
+        assert_eq!(mf.0.len(), 2);
+    }
+
+    #[test]
+    fn kind_declarations() {
+        let mf: Modusfile = "image app(version).\n\
+                             layer install_deps.\n\
+                             logic supported( version , os ).\n\
+                             image :- from(\"alpine\").\n\
+                             logic_kind(\"a\")."
+            .parse()
+            .unwrap();
+        let decls: Vec<String> = mf.kind_declarations().map(|k| k.to_string()).collect();
+        assert_eq!(
+            decls,
+            [
+                "image app(version).",
+                "layer install_deps.",
+                "logic supported(version, os)."
+            ]
+        );
+        let heads: Vec<String> = mf.0.iter().map(|c| c.head.to_string()).collect();
+        assert_eq!(heads, ["image", "logic_kind(\"a\")"]);
+    }
+
+    #[test]
+    fn recovers_from_errors_at_clause_boundaries() {
+        let source = "a :- from(\"alpine\")\n\
+                      b :- run(\"make\").\n\
+                      c :- b, .\n\
+                      # a comment. with dots.\n\
+                      d :- run(\"echo a. b\"),\n    \
+                      ).\n\
+                      e.";
+        let (mf, diags) = Modusfile::parse_recovering(source);
+
+        let heads: Vec<String> = mf.0.iter().map(|c| c.head.to_string()).collect();
+        assert_eq!(heads, ["b", "e"]);
+        let lines: Vec<usize> = diags
+            .iter()
+            .map(|d| source[..d.labels[0].range.start].matches('\n').count() + 1)
+            .collect();
+        assert_eq!(lines, [2, 3, 5]);
+        assert_eq!(source.parse::<Modusfile>().unwrap_err(), diags);
+    }
+
     #[test]
     fn format_string() {
         let case = "f\"foo ${ X }\"";
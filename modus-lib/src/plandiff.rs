@@ -0,0 +1,392 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Structural comparison of two build plans, used by `modus plan-diff`.
+//!
+//! Node ids depend on the order in which the plan was generated, so nodes are
+//! matched by content instead: two nodes are the same if they do the same
+//! thing on top of the same images. A step whose base image changed is thus
+//! reported as changed too, which is what happens to its layer in a build.
+//!
+//! Outputs are matched by the literal they were built from, which is read
+//! from the label that modus adds to every output image.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::{self, Write},
+};
+
+use codespan_reporting::files::SimpleFile;
+use serde::Serialize;
+
+use crate::{
+    imagegen::{BuildPlan, NodeId},
+    planview::{describe_node, node_source, output_names, NodeSource},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStatus {
+    Added,
+    Removed,
+    Changed,
+    Unchanged,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutputDiff {
+    pub name: String,
+    pub status: OutputStatus,
+    pub old_node: Option<NodeId>,
+    pub new_node: Option<NodeId>,
+    /// Steps of the old image which are not in the new one, as old node ids.
+    pub removed_steps: Vec<NodeId>,
+    /// Steps of the new image which are not in the old one, as new node ids.
+    pub added_steps: Vec<NodeId>,
+}
+
+/// The differences between two plans. Removed steps are node ids of the old
+/// plan, added steps are node ids of the new plan.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlanDiff {
+    pub outputs: Vec<OutputDiff>,
+    pub removed_steps: Vec<NodeId>,
+    pub added_steps: Vec<NodeId>,
+    pub unchanged_steps: usize,
+}
+
+impl PlanDiff {
+    pub fn new(old: &BuildPlan, new: &BuildPlan) -> PlanDiff {
+        let old_keys = old.content_hashes();
+        let new_keys = new.content_hashes();
+        let difference = |a: &[NodeId], a_keys: &[String], b: &[NodeId], b_keys: &[String]| {
+            let b: HashSet<&str> = b.iter().map(|&n| b_keys[n].as_str()).collect();
+            a.iter()
+                .copied()
+                .filter(|&n| !b.contains(a_keys[n].as_str()))
+                .collect::<Vec<_>>()
+        };
+
+        let old_names = output_names(old);
+        let new_names = output_names(new);
+        let old_outputs: HashMap<&str, NodeId> = old_names
+            .iter()
+            .map(|n| n.as_str())
+            .zip(old.outputs.iter().map(|o| o.node))
+            .collect();
+        let mut outputs = Vec::new();
+        for (name, output) in new_names.iter().zip(new.outputs.iter()) {
+            let new_steps = new.nodes_needed_by([output.node]);
+            let diff = match old_outputs.get(name.as_str()) {
+                Some(&old_node) => {
+                    let old_steps = old.nodes_needed_by([old_node]);
+                    OutputDiff {
+                        name: name.clone(),
+                        status: if old_keys[old_node] == new_keys[output.node] {
+                            OutputStatus::Unchanged
+                        } else {
+                            OutputStatus::Changed
+                        },
+                        old_node: Some(old_node),
+                        new_node: Some(output.node),
+                        removed_steps: difference(&old_steps, &old_keys, &new_steps, &new_keys),
+                        added_steps: difference(&new_steps, &new_keys, &old_steps, &old_keys),
+                    }
+                }
+                None => OutputDiff {
+                    name: name.clone(),
+                    status: OutputStatus::Added,
+                    old_node: None,
+                    new_node: Some(output.node),
+                    removed_steps: vec![],
+                    added_steps: new_steps,
+                },
+            };
+            outputs.push(diff);
+        }
+        for (name, output) in old_names.iter().zip(old.outputs.iter()) {
+            if !new_names.contains(name) {
+                outputs.push(OutputDiff {
+                    name: name.clone(),
+                    status: OutputStatus::Removed,
+                    old_node: Some(output.node),
+                    new_node: None,
+                    removed_steps: old.nodes_needed_by([output.node]),
+                    added_steps: vec![],
+                });
+            }
+        }
+
+        let old_steps = old.nodes_needed_by(old.topological_order());
+        let new_steps = new.nodes_needed_by(new.topological_order());
+        let removed_steps = difference(&old_steps, &old_keys, &new_steps, &new_keys);
+        PlanDiff {
+            outputs,
+            added_steps: difference(&new_steps, &new_keys, &old_steps, &old_keys),
+            unchanged_steps: old_steps.len() - removed_steps.len(),
+            removed_steps,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.removed_steps.is_empty()
+            && self.added_steps.is_empty()
+            && self
+                .outputs
+                .iter()
+                .all(|o| o.status == OutputStatus::Unchanged)
+    }
+}
+
+/// A plan together with the file it was read from, for source locations.
+pub struct PlanWithSource<'a, N: Display + Clone, S: AsRef<str>> {
+    pub plan: &'a BuildPlan,
+    pub file: &'a SimpleFile<N, S>,
+}
+
+impl<'a, N: Display + Clone, S: AsRef<str>> PlanWithSource<'a, N, S> {
+    fn step_line(&self, sign: char, node: NodeId) -> String {
+        let mut line = format!(
+            "{} #{} {}",
+            sign,
+            node,
+            describe_node(&self.plan.nodes[node])
+        );
+        if let Some(source) = node_source(self.plan, node, self.file) {
+            line.push_str("  <- ");
+            line.push_str(&source.short());
+        }
+        line
+    }
+
+    fn step(&self, node: NodeId) -> JsonStep {
+        JsonStep {
+            id: node,
+            description: describe_node(&self.plan.nodes[node]),
+            source: node_source(self.plan, node, self.file),
+        }
+    }
+}
+
+/// The steps which do not depend on other changed steps, i.e. where the
+/// changes start.
+fn first_changes(plan: &BuildPlan, changed: &[NodeId]) -> Vec<NodeId> {
+    let set: HashSet<NodeId> = changed.iter().copied().collect();
+    changed
+        .iter()
+        .copied()
+        .filter(|&n| plan.dependencies[n].iter().all(|d| !set.contains(d)))
+        .collect()
+}
+
+/// Writes a summary of the diff. For changed outputs, only the steps where
+/// the changes start are listed, since everything after them changes too.
+pub fn write_diff_text<N: Display + Clone, S: AsRef<str>>(
+    diff: &PlanDiff,
+    old: &PlanWithSource<N, S>,
+    new: &PlanWithSource<N, S>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut unchanged = 0;
+    for output in &diff.outputs {
+        match output.status {
+            OutputStatus::Unchanged => {
+                unchanged += 1;
+                continue;
+            }
+            OutputStatus::Added => writeln!(out, "added output {}", output.name)?,
+            OutputStatus::Removed => writeln!(out, "removed output {}", output.name)?,
+            OutputStatus::Changed => writeln!(
+                out,
+                "changed output {}: {} step(s) removed, {} added",
+                output.name,
+                output.removed_steps.len(),
+                output.added_steps.len()
+            )?,
+        }
+        if output.status == OutputStatus::Changed {
+            let removed = first_changes(old.plan, &output.removed_steps);
+            let added = first_changes(new.plan, &output.added_steps);
+            for &node in &removed {
+                writeln!(out, "    {}", old.step_line('-', node))?;
+            }
+            for &node in &added {
+                writeln!(out, "    {}", new.step_line('+', node))?;
+            }
+            let later = output.added_steps.len() - added.len();
+            if later > 0 {
+                writeln!(
+                    out,
+                    "    ... and {} later step(s) built on top of these",
+                    later
+                )?;
+            }
+        }
+    }
+    if unchanged > 0 {
+        writeln!(out, "{} output(s) unchanged", unchanged)?;
+    }
+    writeln!(
+        out,
+        "{} step(s) removed, {} added, {} unchanged",
+        diff.removed_steps.len(),
+        diff.added_steps.len(),
+        diff.unchanged_steps
+    )
+}
+
+#[derive(Serialize)]
+struct JsonStep {
+    id: NodeId,
+    description: String,
+    source: Option<NodeSource>,
+}
+
+#[derive(Serialize)]
+struct JsonOutputDiff<'a> {
+    name: &'a str,
+    status: OutputStatus,
+    old_node: Option<NodeId>,
+    new_node: Option<NodeId>,
+    removed_steps: Vec<JsonStep>,
+    added_steps: Vec<JsonStep>,
+}
+
+#[derive(Serialize)]
+struct JsonDiff<'a> {
+    outputs: Vec<JsonOutputDiff<'a>>,
+    removed_steps: Vec<JsonStep>,
+    added_steps: Vec<JsonStep>,
+    unchanged_steps: usize,
+}
+
+pub fn write_diff_json<N: Display + Clone, S: AsRef<str>>(
+    diff: &PlanDiff,
+    old: &PlanWithSource<N, S>,
+    new: &PlanWithSource<N, S>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let json = JsonDiff {
+        outputs: diff
+            .outputs
+            .iter()
+            .map(|o| JsonOutputDiff {
+                name: &o.name,
+                status: o.status,
+                old_node: o.old_node,
+                new_node: o.new_node,
+                removed_steps: o.removed_steps.iter().map(|&n| old.step(n)).collect(),
+                added_steps: o.added_steps.iter().map(|&n| new.step(n)).collect(),
+            })
+            .collect(),
+        removed_steps: diff.removed_steps.iter().map(|&n| old.step(n)).collect(),
+        added_steps: diff.added_steps.iter().map(|&n| new.step(n)).collect(),
+        unchanged_steps: diff.unchanged_steps,
+    };
+    serde_json::to_writer_pretty(&mut *out, &json)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagegen::plan_for as plan;
+    use serial_test::serial;
+
+    const OLD: &str = r#"
+        base :- from("alpine"), run("apk add make").
+        name("a").
+        name("c").
+        app(n) :- name(n), base, run(f"make ${n}").
+    "#;
+
+    #[test]
+    #[serial]
+    fn identical_plans() {
+        let a = plan(OLD, "app(X)");
+        // Ids may differ, but the content is the same.
+        let reordered = OLD
+            .replace(r#"name("a")."#, "")
+            .replace(r#"name("c")."#, r#"name("c"). name("a")."#);
+        let b = plan(&reordered, "app(X)");
+        let diff = PlanDiff::new(&a, &b);
+        assert!(diff.is_empty(), "{:?}", diff);
+        assert_eq!(diff.unchanged_steps, a.topological_order().len());
+    }
+
+    #[test]
+    #[serial]
+    fn changed_shared_rule() {
+        let new = OLD
+            .replace("apk add make", "apk add make gcc")
+            .replace(r#"name("c")"#, r#"name("b")"#);
+        let a = plan(OLD, "app(X)");
+        let b = plan(&new, "app(X)");
+        let diff = PlanDiff::new(&a, &b);
+
+        let mut statuses: Vec<(&str, OutputStatus)> = diff
+            .outputs
+            .iter()
+            .map(|o| (o.name.as_str(), o.status))
+            .collect();
+        statuses.sort_by_key(|(name, _)| *name);
+        assert_eq!(
+            statuses,
+            vec![
+                (r#"app("a")"#, OutputStatus::Changed),
+                (r#"app("b")"#, OutputStatus::Added),
+                (r#"app("c")"#, OutputStatus::Removed),
+            ]
+        );
+        // `from` is shared, everything after the changed step is rebuilt.
+        let changed = diff
+            .outputs
+            .iter()
+            .find(|o| o.status == OutputStatus::Changed)
+            .unwrap();
+        let describe = |p: &BuildPlan, ns: &[NodeId]| -> Vec<String> {
+            ns.iter().map(|&n| describe_node(&p.nodes[n])).collect()
+        };
+        assert!(!describe(&b, &changed.added_steps).contains(&"FROM alpine".to_owned()));
+        assert!(describe(&b, &changed.added_steps).contains(&"RUN apk add make gcc".to_owned()));
+        assert!(describe(&a, &changed.removed_steps).contains(&"RUN make a".to_owned()));
+
+        let mut out = Vec::new();
+        let file = SimpleFile::new("Modusfile", new.as_str());
+        let old_file = SimpleFile::new("Modusfile", OLD);
+        write_diff_text(
+            &diff,
+            &PlanWithSource {
+                plan: &a,
+                file: &old_file,
+            },
+            &PlanWithSource {
+                plan: &b,
+                file: &file,
+            },
+            &mut out,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(r#"changed output app("a")"#), "{}", out);
+        assert!(
+            out.contains(r#"RUN apk add make gcc  <- Modusfile:2:33 run("apk add make gcc")"#),
+            "{}",
+            out
+        );
+    }
+}
@@ -0,0 +1,594 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Human and machine readable views of a build plan, used by `modus plan`.
+//!
+//! Each node is annotated with the part of the Modusfile it was generated
+//! from, when known. Nodes generated for an image literal (the label that modus
+//! puts on every image built from a literal) point to the literal itself.
+
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use codespan_reporting::files::{Files, SimpleFile};
+use ptree::{item::StringItem, write_tree, TreeBuilder};
+use serde::Serialize;
+
+use crate::imagegen::{BuildNode, BuildPlan, MergeOperation, NodeId, PlanFormatError, MODUS_LABEL};
+
+pub const PLAN_FORMATS: [&str; 3] = ["tree", "json", "dot"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    Tree,
+    Json,
+    Dot,
+}
+
+impl FromStr for PlanFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tree" => Ok(PlanFormat::Tree),
+            "json" => Ok(PlanFormat::Json),
+            "dot" => Ok(PlanFormat::Dot),
+            _ => Err(format!("unknown plan format: {}", s)),
+        }
+    }
+}
+
+/// Where a node comes from. The span is absent for nodes whose literal was
+/// not written in the Modusfile, such as the query.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeSource {
+    /// The source text of the span, or the literal if there is no span.
+    pub text: String,
+    pub file: Option<String>,
+    /// 1-based line number of the start of the span.
+    pub line: Option<usize>,
+    /// 1-based column number of the start of the span.
+    pub column: Option<usize>,
+    pub offset: Option<usize>,
+    pub length: Option<usize>,
+}
+
+impl NodeSource {
+    /// A short single-line description, e.g. `Modusfile:3:5 run("make")`.
+    pub fn short(&self) -> String {
+        let mut text = self.text.lines().next().unwrap_or_default().to_owned();
+        if text.len() < self.text.trim_end().len() {
+            text.push_str(" ...");
+        }
+        match (&self.file, self.line, self.column) {
+            (Some(file), Some(line), Some(column)) => {
+                format!("{}:{}:{} {}", file, line, column, text)
+            }
+            _ => text,
+        }
+    }
+}
+
+pub fn node_source<N: Display + Clone, S: AsRef<str>>(
+    plan: &BuildPlan,
+    node: NodeId,
+    file: &SimpleFile<N, S>,
+) -> Option<NodeSource> {
+    let lit = plan.node_source(node)?;
+    let span = lit.position.as_ref().and_then(|pos| {
+        let text = file
+            .source()
+            .as_ref()
+            .get(pos.offset..pos.offset + pos.length)?
+            .trim_end();
+        let location = file.location((), pos.offset).ok()?;
+        Some((text, location, pos))
+    });
+    Some(match span {
+        Some((text, location, pos)) => NodeSource {
+            text: text.to_owned(),
+            file: Some(file.name().to_string()),
+            line: Some(location.line_number),
+            column: Some(location.column_number),
+            offset: Some(pos.offset),
+            length: Some(text.len()),
+        },
+        None => NodeSource {
+            text: lit.to_string(),
+            file: None,
+            line: None,
+            column: None,
+            offset: None,
+            length: None,
+        },
+    })
+}
+
+/// The name of each output: the literal it was built from.
+pub fn output_names(plan: &BuildPlan) -> Vec<String> {
+    plan.outputs
+        .iter()
+        .enumerate()
+        .map(
+            |(i, output)| match (&plan.nodes[output.node], &output.source_literal) {
+                (_, Some(lit)) => lit.to_string(),
+                (BuildNode::SetLabel { label, value, .. }, None) if label == MODUS_LABEL => {
+                    value.clone()
+                }
+                _ => format!("output {}", i),
+            },
+        )
+        .collect()
+}
+
+/// The image a node is applied on, if any.
+pub fn node_parent(node: &BuildNode) -> Option<NodeId> {
+    match node {
+        BuildNode::From { .. } | BuildNode::FromScratch { .. } => None,
+        BuildNode::Run { parent, .. }
+        | BuildNode::CopyFromImage { parent, .. }
+        | BuildNode::CopyFromLocal { parent, .. }
+        | BuildNode::SetWorkdir { parent, .. }
+        | BuildNode::SetEntrypoint { parent, .. }
+        | BuildNode::SetCmd { parent, .. }
+        | BuildNode::SetLabel { parent, .. }
+        | BuildNode::SetEnv { parent, .. }
+        | BuildNode::AppendEnvValue { parent, .. }
+        | BuildNode::SetUser { parent, .. } => Some(*parent),
+        BuildNode::Merge(merge) => Some(merge.parent),
+    }
+}
+
+/// The images a node copies files from.
+pub fn node_copy_sources(node: &BuildNode) -> Vec<NodeId> {
+    match node {
+        BuildNode::CopyFromImage { src_image, .. } => vec![*src_image],
+        BuildNode::Merge(merge) => merge
+            .operations
+            .iter()
+            .filter_map(|op| match op {
+                MergeOperation::CopyFromImage { src_image, .. } => Some(*src_image),
+                MergeOperation::Run { .. } | MergeOperation::CopyFromLocal { .. } => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn describe_run(command: &str, cwd: &str) -> String {
+    if cwd.is_empty() {
+        format!("RUN {}", command)
+    } else {
+        format!("RUN {} (in {})", command, cwd)
+    }
+}
+
+fn describe_merge_operation(op: &MergeOperation) -> String {
+    match op {
+        MergeOperation::Run { command, cwd, .. } => describe_run(command, cwd),
+        MergeOperation::CopyFromImage {
+            src_image,
+            src_path,
+            dst_path,
+        } => format!("COPY --from=#{} {} {}", src_image, src_path, dst_path),
+        MergeOperation::CopyFromLocal { src_path, dst_path } => {
+            format!("COPY {} {}", src_path, dst_path)
+        }
+    }
+}
+
+/// A one-line, Dockerfile-like description of a node.
+pub fn describe_node(node: &BuildNode) -> String {
+    match node {
+        BuildNode::From { display_name, .. } => format!("FROM {}", display_name),
+        BuildNode::FromScratch { .. } => "FROM scratch".to_owned(),
+        BuildNode::Run { command, cwd, .. } => describe_run(command, cwd),
+        BuildNode::CopyFromImage {
+            src_image,
+            src_path,
+            dst_path,
+            ..
+        } => format!("COPY --from=#{} {} {}", src_image, src_path, dst_path),
+        BuildNode::CopyFromLocal {
+            src_path, dst_path, ..
+        } => format!("COPY {} {}", src_path, dst_path),
+        BuildNode::SetWorkdir { new_workdir, .. } => format!("WORKDIR {}", new_workdir),
+        BuildNode::SetEntrypoint { new_entrypoint, .. } => {
+            format!("ENTRYPOINT {:?}", new_entrypoint)
+        }
+        BuildNode::SetCmd { new_cmd, .. } => format!("CMD {:?}", new_cmd),
+        BuildNode::SetLabel { label, value, .. } => format!("LABEL {}={:?}", label, value),
+        BuildNode::Merge(merge) => format!(
+            "MERGE {}",
+            merge
+                .operations
+                .iter()
+                .map(describe_merge_operation)
+                .collect::<Vec<_>>()
+                .join("; ")
+        ),
+        BuildNode::SetEnv { key, value, .. } => format!("ENV {}={:?}", key, value),
+        BuildNode::AppendEnvValue { key, value, .. } => {
+            format!("ENV {}=\"${{{}}}{}\"", key, key, value)
+        }
+        BuildNode::SetUser { user, .. } => format!("USER {}", user),
+    }
+}
+
+fn node_line<N: Display + Clone, S: AsRef<str>>(
+    plan: &BuildPlan,
+    node: NodeId,
+    file: &SimpleFile<N, S>,
+) -> String {
+    let mut line = format!("#{} {}", node, describe_node(&plan.nodes[node]));
+    if let Some(source) = node_source(plan, node, file) {
+        line.push_str("  <- ");
+        line.push_str(&source.short());
+    }
+    line
+}
+
+fn build_tree<N: Display + Clone, S: AsRef<str>>(
+    plan: &BuildPlan,
+    file: &SimpleFile<N, S>,
+) -> StringItem {
+    /// Adds the instructions building `top`, from its base image onwards.
+    /// Instructions already shown elsewhere in the tree are collapsed.
+    fn add_image<N: Display + Clone, S: AsRef<str>>(
+        plan: &BuildPlan,
+        file: &SimpleFile<N, S>,
+        top: NodeId,
+        shown: &mut HashSet<NodeId>,
+        builder: &mut TreeBuilder,
+    ) {
+        let mut chain = vec![top];
+        while let Some(parent) = node_parent(&plan.nodes[*chain.last().unwrap()]) {
+            chain.push(parent);
+        }
+        chain.reverse();
+        // If a node was shown, so was everything below it.
+        let first_new = match chain.iter().rposition(|n| shown.contains(n)) {
+            Some(i) => {
+                builder.add_empty_child(format!(
+                    "#{} {} (see above)",
+                    chain[i],
+                    describe_node(&plan.nodes[chain[i]])
+                ));
+                i + 1
+            }
+            None => 0,
+        };
+        for &node in &chain[first_new..] {
+            shown.insert(node);
+            let copy_sources = node_copy_sources(&plan.nodes[node]);
+            if copy_sources.is_empty() {
+                builder.add_empty_child(node_line(plan, node, file));
+            } else {
+                builder.begin_child(node_line(plan, node, file));
+                for src in copy_sources {
+                    add_image(plan, file, src, shown, builder);
+                }
+                builder.end_child();
+            }
+        }
+    }
+
+    let mut builder = TreeBuilder::new(format!("{} output(s)", plan.outputs.len()));
+    let mut shown = HashSet::new();
+    for (i, output) in plan.outputs.iter().enumerate() {
+        builder.begin_child(match &output.source_literal {
+            Some(lit) => format!("output {}: {}", i, lit),
+            None => format!("output {}", i),
+        });
+        add_image(plan, file, output.node, &mut shown, &mut builder);
+        builder.end_child();
+    }
+    for &check in &plan.checks {
+        builder.begin_child("check".to_owned());
+        add_image(plan, file, check, &mut shown, &mut builder);
+        builder.end_child();
+    }
+    for export in &plan.exports {
+        builder.begin_child(format!("export {} to {}", export.src_path, export.dst_path));
+        add_image(plan, file, export.node, &mut shown, &mut builder);
+        builder.end_child();
+    }
+    builder.build()
+}
+
+/// Prints each output image as the list of instructions building it, with
+/// the images it copies from nested below the copy.
+pub fn write_plan_tree<N: Display + Clone, S: AsRef<str>>(
+    plan: &BuildPlan,
+    file: &SimpleFile<N, S>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    write_tree(&build_tree(plan, file), out)
+}
+
+#[derive(Serialize)]
+struct JsonNode<'a> {
+    id: NodeId,
+    /// See `BuildPlan::content_hashes`.
+    hash: String,
+    description: String,
+    dependencies: &'a [NodeId],
+    source: Option<NodeSource>,
+    node: &'a BuildNode,
+}
+
+#[derive(Serialize)]
+struct JsonOutput {
+    node: NodeId,
+    literal: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JsonPlan<'a> {
+    version: u32,
+    nodes: Vec<JsonNode<'a>>,
+    outputs: Vec<JsonOutput>,
+    checks: &'a [NodeId],
+    exports: &'a [crate::imagegen::Export],
+}
+
+/// Writes the plan as JSON. The `node` field of each node has the same format
+/// as in the build plan, see `build-plan.schema.json`.
+pub fn write_plan_json<N: Display + Clone, S: AsRef<str>>(
+    plan: &BuildPlan,
+    file: &SimpleFile<N, S>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut hashes = plan.content_hashes().into_iter();
+    let json = JsonPlan {
+        version: plan.version,
+        nodes: plan
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(id, node)| JsonNode {
+                id,
+                hash: hashes.next().unwrap(),
+                description: describe_node(node),
+                dependencies: &plan.dependencies[id],
+                source: node_source(plan, id, file),
+                node,
+            })
+            .collect(),
+        outputs: plan
+            .outputs
+            .iter()
+            .map(|o| JsonOutput {
+                node: o.node,
+                literal: o.source_literal.as_ref().map(|l| l.to_string()),
+            })
+            .collect(),
+        checks: &plan.checks,
+        exports: &plan.exports,
+    };
+    serde_json::to_writer_pretty(&mut *out, &json)?;
+    writeln!(out)
+}
+
+/// Reads either a build plan, or the output of `write_plan_json`.
+pub fn read_plan_json(json: &[u8]) -> Result<BuildPlan, PlanFormatError> {
+    let mut value: serde_json::Value =
+        serde_json::from_slice(json).map_err(PlanFormatError::Invalid)?;
+    let is_view = value["nodes"]
+        .as_array()
+        .and_then(|nodes| nodes.first())
+        .is_some_and(|node| node.get("node").is_some());
+    if is_view {
+        let nodes = value["nodes"].as_array().unwrap();
+        let dependencies: Vec<serde_json::Value> =
+            nodes.iter().map(|n| n["dependencies"].clone()).collect();
+        let nodes: Vec<serde_json::Value> = nodes.iter().map(|n| n["node"].clone()).collect();
+        value["nodes"] = nodes.into();
+        value["dependencies"] = dependencies.into();
+    }
+    BuildPlan::from_json(value.to_string().as_bytes())
+}
+
+type Nd = usize;
+/// An edge from a dependency to the node using it, and whether it is a copy.
+type Ed = (usize, usize, bool);
+
+struct PlanGraph {
+    labels: Vec<String>,
+    outputs: HashSet<NodeId>,
+    edges: Vec<Ed>,
+}
+
+impl<'a> dot::Labeller<'a, Nd, Ed> for PlanGraph {
+    fn graph_id(&'a self) -> dot::Id<'a> {
+        dot::Id::new("plan").unwrap()
+    }
+
+    fn node_id(&'a self, n: &Nd) -> dot::Id<'a> {
+        dot::Id::new(format!("N{}", n)).unwrap()
+    }
+
+    fn node_label(&'a self, n: &Nd) -> dot::LabelText<'a> {
+        dot::LabelText::LabelStr(self.labels[*n].as_str().into())
+    }
+
+    fn node_shape(&'a self, n: &Nd) -> Option<dot::LabelText<'a>> {
+        Some(dot::LabelText::LabelStr(
+            if self.outputs.contains(n) {
+                "doubleoctagon"
+            } else {
+                "box"
+            }
+            .into(),
+        ))
+    }
+
+    fn edge_label(&'a self, e: &Ed) -> dot::LabelText<'a> {
+        dot::LabelText::LabelStr(if e.2 { "copy" } else { "" }.into())
+    }
+
+    fn edge_style(&'a self, e: &Ed) -> dot::Style {
+        if e.2 {
+            dot::Style::Dashed
+        } else {
+            dot::Style::None
+        }
+    }
+}
+
+impl<'a> dot::GraphWalk<'a, Nd, Ed> for PlanGraph {
+    fn nodes(&'a self) -> dot::Nodes<'a, Nd> {
+        (0..self.labels.len()).collect()
+    }
+
+    fn edges(&'a self) -> dot::Edges<'a, Ed> {
+        self.edges.as_slice().into()
+    }
+
+    fn source(&'a self, edge: &Ed) -> Nd {
+        edge.0
+    }
+
+    fn target(&'a self, edge: &Ed) -> Nd {
+        edge.1
+    }
+}
+
+/// Writes the plan as a Graphviz DOT graph, with edges pointing from an image
+/// to the nodes built on top of it. Output images are drawn as octagons.
+pub fn write_plan_dot<N: Display + Clone, S: AsRef<str>>(
+    plan: &BuildPlan,
+    file: &SimpleFile<N, S>,
+    mut out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut edges = Vec::new();
+    for (id, node) in plan.nodes.iter().enumerate() {
+        if let Some(parent) = node_parent(node) {
+            edges.push((parent, id, false));
+        }
+        for src in node_copy_sources(node) {
+            edges.push((src, id, true));
+        }
+    }
+    let graph = PlanGraph {
+        labels: (0..plan.nodes.len())
+            .map(|id| {
+                let mut label = format!("#{} {}", id, describe_node(&plan.nodes[id]));
+                if let Some(source) = node_source(plan, id, file) {
+                    label.push('\n');
+                    label.push_str(&source.short());
+                }
+                label
+            })
+            .collect(),
+        outputs: plan.outputs.iter().map(|o| o.node).collect(),
+        edges,
+    };
+    dot::render(&graph, &mut out)
+}
+
+pub fn write_plan<N: Display + Clone, S: AsRef<str>>(
+    format: PlanFormat,
+    plan: &BuildPlan,
+    file: &SimpleFile<N, S>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    match format {
+        PlanFormat::Tree => write_plan_tree(plan, file, out),
+        PlanFormat::Json => write_plan_json(plan, file, out),
+        PlanFormat::Dot => write_plan_dot(plan, file, out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagegen::plan_for;
+    use serial_test::serial;
+
+    const SOURCE: &str = r#"base :- from("alpine")::set_workdir("/app").
+app :- base, run("make"), other::copy("/x", "/y").
+other :- base, run("build").
+"#;
+
+    fn plan() -> (BuildPlan, SimpleFile<&'static str, &'static str>) {
+        (
+            plan_for(SOURCE, "app"),
+            SimpleFile::new("Modusfile", SOURCE),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn nodes_have_sources() {
+        let (plan, file) = plan();
+        let run = plan
+            .nodes
+            .iter()
+            .position(|n| matches!(n, BuildNode::Run { command, .. } if command == "make"))
+            .unwrap();
+        let source = node_source(&plan, run, &file).unwrap();
+        assert_eq!(source.text, r#"run("make")"#);
+        assert_eq!((source.line, source.column), (Some(2), Some(14)));
+        assert_eq!(source.short(), r#"Modusfile:2:14 run("make")"#);
+    }
+
+    #[test]
+    #[serial]
+    fn tree_shows_copied_images() {
+        let (plan, file) = plan();
+        let mut out = Vec::new();
+        write_plan_tree(&plan, &file, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("output 0: app"), "{}", out);
+        assert!(out.contains(r#"RUN make  <- Modusfile:2:14 run("make")"#));
+        // The image of `other` is nested under the copy, and the part it
+        // shares with `app` is collapsed.
+        let copy = out.find("COPY --from=").unwrap();
+        assert!(out[copy..].contains("RUN build"));
+        assert!(
+            out[copy..].contains(r#"LABEL com.modus-continens.literal="base" (see above)"#),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn json_and_dot() {
+        let (plan, file) = plan();
+        let mut out = Vec::new();
+        write_plan_json(&plan, &file, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["nodes"].as_array().unwrap().len(), plan.nodes.len());
+        assert_eq!(json["nodes"][0]["source"]["line"], 1);
+        assert_eq!(json["nodes"][0]["hash"], plan.content_hashes()[0]);
+        let read = read_plan_json(&out).unwrap();
+        assert_eq!(read.to_json(), plan.to_json());
+        let read = read_plan_json(plan.to_json().as_bytes()).unwrap();
+        assert_eq!(read.to_json(), plan.to_json());
+
+        let mut out = Vec::new();
+        write_plan_dot(&plan, &file, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("digraph plan {"));
+        assert!(out.contains("style=\"dashed\""));
+    }
+}
@@ -0,0 +1,433 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checking build plans against policies, used by `modus policy check`.
+//!
+//! A policy is a Modusfile defining `violation(Subject, Message)`, which is
+//! evaluated over facts describing the plan, see `plan_facts`. The subject is
+//! either a node id or an output literal, as used in the facts, so that the
+//! violation can be reported at the literal of the Modusfile the offending
+//! node was generated from.
+//!
+//! For example, the following policy forbids piping downloads into a shell:
+//!
+//! ```text
+//! violation(N, "do not pipe downloads into a shell") :-
+//!     node_run(N, Cmd), string_contains(Cmd, "curl"), string_contains(Cmd, "| sh").
+//! ```
+
+use std::collections::{BTreeSet, HashSet};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
+
+use crate::{
+    imagegen::{BuildNode, BuildPlan, MergeOperation, NodeId},
+    logic::{Clause, IRTerm, Literal, Predicate},
+    modusfile::Modusfile,
+    planview::{describe_node, node_parent, output_names},
+    sld,
+    translate::translate_modusfile,
+    unification::Substitute,
+};
+
+/// The predicate a policy defines to report violations.
+pub const VIOLATION_PREDICATE: &str = "violation";
+
+/// The predicates of the facts describing a plan, with their arity:
+///
+/// - `node_from(Node, Ref)`: the node starts from the image `Ref`, as written
+///   in the Modusfile, or `scratch`.
+/// - `node_run(Node, Command)`: the node runs the command, possibly among
+///   other operations of a merge.
+/// - `node_copy(Node, Src, Dst)`: the node copies a file from the context.
+/// - `node_copy_image(Node, Image, Src, Dst)`: the node copies a file from the
+///   image built by the node `Image`.
+/// - `node_workdir(Node, Dir)`, `node_env(Node, Key, Value)`,
+///   `node_user(Node, User)` and `node_label(Node, Label, Value)`: the node
+///   sets the working directory, an environment variable, the user or a label.
+/// - `node_parent(Node, Parent)`: the node is applied on the image `Parent`.
+/// - `output(Output, Node)`: the output literal is built by the node.
+/// - `output_node(Output, Node)`: building the output requires the node.
+/// - `output_from(Output, Ref)`: the base image of the output.
+/// - `output_user(Output, User)`: the user the output runs as. The user set
+///   by the base image is not known, so this is `root` unless the plan sets it.
+pub const FACT_PREDICATES: [(&str, usize); 13] = [
+    ("node_from", 2),
+    ("node_run", 2),
+    ("node_copy", 3),
+    ("node_copy_image", 4),
+    ("node_workdir", 2),
+    ("node_env", 3),
+    ("node_user", 2),
+    ("node_label", 3),
+    ("node_parent", 2),
+    ("output", 2),
+    ("output_node", 2),
+    ("output_from", 2),
+    ("output_user", 2),
+];
+
+fn fact(predicate: &str, args: &[&str]) -> Clause {
+    Clause {
+        head: Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate(predicate.to_owned()),
+            args: args
+                .iter()
+                .map(|a| IRTerm::Constant((*a).to_owned()))
+                .collect(),
+        },
+        body: vec![],
+    }
+}
+
+/// The root of the chain of parents of a node.
+fn base_image(plan: &BuildPlan, mut node: NodeId) -> &str {
+    while let Some(parent) = node_parent(&plan.nodes[node]) {
+        node = parent;
+    }
+    match &plan.nodes[node] {
+        BuildNode::From { display_name, .. } => display_name,
+        _ => "scratch",
+    }
+}
+
+/// The last user set on the chain of parents of a node.
+fn user(plan: &BuildPlan, mut node: NodeId) -> &str {
+    loop {
+        if let BuildNode::SetUser { user, .. } = &plan.nodes[node] {
+            return user;
+        }
+        match node_parent(&plan.nodes[node]) {
+            Some(parent) => node = parent,
+            None => return "root",
+        }
+    }
+}
+
+/// The facts describing a plan. Nodes are identified by their id, and outputs
+/// by their literal, both as strings.
+pub fn plan_facts(plan: &BuildPlan) -> Vec<Clause> {
+    let mut facts = Vec::new();
+    for (id, node) in plan.nodes.iter().enumerate() {
+        let id = id.to_string();
+        let mut add = |predicate, args: &[&str]| {
+            facts.push(fact(predicate, &[&[id.as_str()], args].concat()))
+        };
+        match node {
+            BuildNode::From { display_name, .. } => add("node_from", &[display_name]),
+            BuildNode::FromScratch { .. } => add("node_from", &["scratch"]),
+            BuildNode::Run { command, .. } => add("node_run", &[command]),
+            BuildNode::CopyFromImage {
+                src_image,
+                src_path,
+                dst_path,
+                ..
+            } => add(
+                "node_copy_image",
+                &[&src_image.to_string(), src_path, dst_path],
+            ),
+            BuildNode::CopyFromLocal {
+                src_path, dst_path, ..
+            } => add("node_copy", &[src_path, dst_path]),
+            BuildNode::SetWorkdir { new_workdir, .. } => add("node_workdir", &[new_workdir]),
+            BuildNode::SetLabel { label, value, .. } => add("node_label", &[label, value]),
+            BuildNode::SetEnv { key, value, .. } => add("node_env", &[key, value]),
+            BuildNode::SetUser { user, .. } => add("node_user", &[user]),
+            BuildNode::Merge(merge) => {
+                for op in &merge.operations {
+                    match op {
+                        MergeOperation::Run { command, .. } => add("node_run", &[command]),
+                        MergeOperation::CopyFromImage {
+                            src_image,
+                            src_path,
+                            dst_path,
+                        } => add(
+                            "node_copy_image",
+                            &[&src_image.to_string(), src_path, dst_path],
+                        ),
+                        MergeOperation::CopyFromLocal { src_path, dst_path } => {
+                            add("node_copy", &[src_path, dst_path])
+                        }
+                    }
+                }
+            }
+            BuildNode::SetEntrypoint { .. }
+            | BuildNode::SetCmd { .. }
+            | BuildNode::AppendEnvValue { .. } => {}
+        }
+        if let Some(parent) = node_parent(node) {
+            add("node_parent", &[&parent.to_string()]);
+        }
+    }
+    for (name, output) in output_names(plan).iter().zip(&plan.outputs) {
+        facts.push(fact("output", &[name, &output.node.to_string()]));
+        for node in plan.nodes_needed_by([output.node]) {
+            facts.push(fact("output_node", &[name, &node.to_string()]));
+        }
+        facts.push(fact("output_from", &[name, base_image(plan, output.node)]));
+        facts.push(fact("output_user", &[name, user(plan, output.node)]));
+    }
+    facts
+}
+
+fn signature(lit: &Literal) -> (String, usize) {
+    (lit.predicate.0.clone(), lit.args.len())
+}
+
+/// Removes the literals of fact predicates which have no facts in this plan,
+/// since resolution reports unknown predicates as errors. A clause needing
+/// such a fact can never apply, and the negation of one always holds. This is
+/// repeated for the predicates of the removed clauses, which include the
+/// auxiliary predicates negations are translated to.
+fn prune_missing_facts(mut rules: Vec<Clause>, facts: &[Clause]) -> Vec<Clause> {
+    let mut removed: HashSet<(String, usize)> = FACT_PREDICATES
+        .iter()
+        .map(|(name, arity)| ((*name).to_owned(), *arity))
+        .collect();
+    loop {
+        let defined: HashSet<(String, usize)> = facts
+            .iter()
+            .chain(&rules)
+            .map(|c| signature(&c.head))
+            .collect();
+        let missing = |lit: &Literal| {
+            let signature = signature(lit);
+            removed.contains(&signature) && !defined.contains(&signature)
+        };
+        let (kept, dropped): (Vec<Clause>, Vec<Clause>) = rules
+            .into_iter()
+            .partition(|rule| !rule.body.iter().any(|lit| lit.positive && missing(lit)));
+        rules = kept
+            .into_iter()
+            .map(|rule| Clause {
+                body: rule.body.into_iter().filter(|lit| !missing(lit)).collect(),
+                ..rule
+            })
+            .collect();
+        if dropped.is_empty() {
+            return rules;
+        }
+        removed.extend(dropped.iter().map(|rule| signature(&rule.head)));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Violation {
+    /// The node the subject refers to, if it is a node id or an output.
+    pub node: Option<NodeId>,
+    pub subject: String,
+    pub message: String,
+}
+
+impl Violation {
+    /// An error labelled at the literal the node was generated from, in the
+    /// Modusfile of the plan. Nodes without one, such as the label of an output
+    /// built from the query, are labelled at the closest image they are
+    /// applied on that has one.
+    pub fn get_diagnostic(&self, plan: &BuildPlan) -> Diagnostic<()> {
+        let mut diag = Diagnostic::error().with_message(&self.message);
+        let mut node = match self.node {
+            Some(node) => node,
+            None => return diag.with_notes(vec![format!("in {}", self.subject)]),
+        };
+        if self.subject != node.to_string() {
+            diag = diag.with_notes(vec![format!("in {}", self.subject)]);
+        }
+        loop {
+            let description = format!("#{}: {}", node, describe_node(&plan.nodes[node]));
+            if let Some(pos) = plan.node_source(node).and_then(|lit| lit.position.as_ref()) {
+                return diag.with_labels(vec![Label::primary((), pos).with_message(description)]);
+            }
+            match node_parent(&plan.nodes[node]) {
+                Some(parent) => node = parent,
+                None => return diag,
+            }
+        }
+    }
+}
+
+/// Evaluates the `violation` rules of the policy over the facts of the plan.
+/// Errors are reported against the policy.
+pub fn check_policy(
+    plan: &BuildPlan,
+    policy: &Modusfile,
+) -> Result<Vec<Violation>, Vec<Diagnostic<()>>> {
+    let max_depth = 175;
+
+    let facts = plan_facts(plan);
+    let rules = translate_modusfile(policy);
+    if !rules
+        .iter()
+        .any(|c| c.head.predicate.0 == VIOLATION_PREDICATE && c.head.args.len() == 2)
+    {
+        return Err(vec![Diagnostic::error().with_message(format!(
+            "The policy does not define {}(Subject, Message).",
+            VIOLATION_PREDICATE
+        ))]);
+    }
+    let mut clauses = prune_missing_facts(rules, &facts);
+    if !clauses
+        .iter()
+        .any(|c| c.head.predicate.0 == VIOLATION_PREDICATE)
+    {
+        return Ok(vec![]);
+    }
+    clauses.extend(facts);
+
+    let goal = vec![Literal {
+        positive: true,
+        position: None,
+        predicate: Predicate(VIOLATION_PREDICATE.to_owned()),
+        args: vec![
+            IRTerm::UserVariable("Subject".to_owned()),
+            IRTerm::UserVariable("Message".to_owned()),
+        ],
+    }];
+    let tree = match Result::from(sld::sld_with_modes(
+        &clauses,
+        &policy.modes(),
+        &goal,
+        max_depth,
+        false,
+    )) {
+        Ok(tree) => tree,
+        // No violation could be proven.
+        Err(diags) if diags.iter().all(|d| d.severity < Severity::Error) => return Ok(vec![]),
+        Err(diags) => {
+            return Err(diags
+                .into_iter()
+                .filter(|d| d.severity >= Severity::Error)
+                .collect())
+        }
+    };
+
+    let outputs = output_names(plan);
+    let violations: BTreeSet<Violation> = sld::proofs(&tree, &clauses, &goal)
+        .into_values()
+        .map(|proof| {
+            let lit = goal[0].substitute(&proof.valuation);
+            let arg = |i: usize| match &lit.args[i] {
+                IRTerm::Constant(c) => c.clone(),
+                t => t.to_string(),
+            };
+            let subject = arg(0);
+            let node = match subject.parse::<NodeId>() {
+                Ok(node) if node < plan.nodes.len() => Some(node),
+                _ => outputs
+                    .iter()
+                    .position(|o| *o == subject)
+                    .map(|i| plan.outputs[i].node),
+            };
+            Violation {
+                node,
+                subject,
+                message: arg(1),
+            }
+        })
+        .collect();
+    Ok(violations.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagegen::plan_for as plan;
+    use serial_test::serial;
+
+    fn check(plan: &BuildPlan, policy: &str) -> Vec<(String, String)> {
+        check_policy(plan, &policy.parse().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|v| (describe_node(&plan.nodes[v.node.unwrap()]), v.message))
+            .collect()
+    }
+
+    #[test]
+    #[serial]
+    fn reports_violations() {
+        let modusfile = r#"
+            app("alpine") :- from("alpine:latest"),
+                run("curl -fsSL https://example.com/install | sh"),
+                run("make").
+            app("internal") :-
+                (from("registry.example.com/alpine"), run("make"))::set_user("app").
+            name("alpine").
+            name("internal").
+        "#;
+        let plan = plan(modusfile, r#"name(X), app(X)"#);
+
+        let violations = check(
+            &plan,
+            r#"
+            violation(N, "do not pipe downloads into a shell") :-
+                node_run(N, Cmd), string_contains(Cmd, "| sh").
+            violation(O, M) :-
+                output_from(O, Ref), !string_concat("registry.example.com/", _, Ref),
+                string_eq(M, f"${O} uses ${Ref}").
+            violation(O, "runs as root") :- output_user(O, "root").
+        "#,
+        );
+        assert_eq!(violations.len(), 3);
+        assert!(violations.contains(&(
+            "RUN curl -fsSL https://example.com/install | sh".to_owned(),
+            "do not pipe downloads into a shell".to_owned()
+        )));
+        assert!(violations
+            .iter()
+            .any(|(_, m)| m == r#"app("alpine") uses alpine:latest"#));
+        assert_eq!(
+            violations
+                .iter()
+                .filter(|(_, m)| m == "runs as root")
+                .count(),
+            1
+        );
+
+        // The plan has no environment variables.
+        let violations = check(
+            &plan,
+            r#"
+            violation(N, "env") :- node_env(N, _, _).
+            violation(O, "no env") :- output(O, _), !node_env(_, "PATH", _).
+        "#,
+        );
+        assert_eq!(violations.len(), 2);
+        assert!(violations.iter().all(|(_, m)| m == "no env"));
+
+        assert!(check(&plan, r#"violation("x", "never") :- string_eq("a", "b")."#).is_empty());
+        assert!(check_policy(&plan, &r#"allowed("x")."#.parse().unwrap()).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn points_at_source_literals() {
+        let plan = plan(r#"a :- from("alpine"), run("curl x | sh")."#, "a");
+        let violations = check_policy(
+            &plan,
+            &r#"violation(N, "bad") :- node_run(N, Cmd), string_contains(Cmd, "| sh")."#
+                .parse()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(violations.len(), 1);
+        let diag = violations[0].get_diagnostic(&plan);
+        assert_eq!(diag.message, "bad");
+        // The span of `run("curl x | sh")`.
+        assert_eq!(diag.labels[0].range, 21..39);
+    }
+}
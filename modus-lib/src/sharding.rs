@@ -0,0 +1,332 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Splitting the outputs of a plan into shards, to build them on several
+//! machines, e.g. with `modus build --shard 2/5`.
+//!
+//! Every machine plans the query on its own, and node ids or the order of the
+//! outputs may differ between runs, so the partition only depends on the
+//! output literals and the shape of the graph. Outputs are assigned greedily,
+//! largest first, to the shard where they add the least work, counting only
+//! the steps which that shard does not build already, as long as the shard
+//! does not get more than its even share of the total work. Outputs sharing
+//! intermediate images thus end up on the same machine, unless this makes the
+//! shards unbalanced.
+
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use serde::Serialize;
+
+use crate::{
+    imagegen::{BuildNode, BuildPlan, NodeId},
+    planview::output_names,
+};
+
+/// One of `count` shards, `index` starts from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid shard {:?}, expected e.g. 2/5", s);
+        let (index, count) = s.split_once('/').ok_or_else(err)?;
+        let index: usize = index.trim().parse().map_err(|_| err())?;
+        let count: usize = count.trim().parse().map_err(|_| err())?;
+        if index == 0 || index > count {
+            return Err(format!(
+                "invalid shard {:?}, the index must be between 1 and {}",
+                s, count
+            ));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// What one shard builds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShardContent {
+    /// Indices into `BuildPlan::outputs`, sorted by output literal.
+    pub outputs: Vec<usize>,
+    pub checks: Vec<NodeId>,
+    /// Indices into `BuildPlan::exports`.
+    pub exports: Vec<usize>,
+    /// The estimated work of this shard, see `node_cost`.
+    pub cost: usize,
+}
+
+impl ShardContent {
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty() && self.checks.is_empty() && self.exports.is_empty()
+    }
+}
+
+/// A rough estimate of the work of building a node. Only steps creating a
+/// layer count, since changing the image config is cheap.
+fn node_cost(node: &BuildNode) -> usize {
+    match node {
+        BuildNode::From { .. }
+        | BuildNode::FromScratch { .. }
+        | BuildNode::Run { .. }
+        | BuildNode::CopyFromImage { .. }
+        | BuildNode::CopyFromLocal { .. }
+        | BuildNode::Merge(_) => 1,
+        BuildNode::SetWorkdir { .. }
+        | BuildNode::SetEntrypoint { .. }
+        | BuildNode::SetCmd { .. }
+        | BuildNode::SetLabel { .. }
+        | BuildNode::SetEnv { .. }
+        | BuildNode::AppendEnvValue { .. }
+        | BuildNode::SetUser { .. } => 0,
+    }
+}
+
+/// Splits the outputs of the plan into `count` shards. Checks and exports go
+/// to the shard sharing the most nodes with them. Some shards may be empty if
+/// there are fewer outputs than shards, but then they have no checks or
+/// exports either.
+pub fn partition(plan: &BuildPlan, count: usize) -> Vec<ShardContent> {
+    assert!(count > 0, "Expected at least one shard");
+    let names = output_names(plan);
+    let closures: Vec<Vec<NodeId>> = plan
+        .outputs
+        .iter()
+        .map(|o| plan.nodes_needed_by([o.node]))
+        .collect();
+    let cost = |nodes: &mut dyn Iterator<Item = &NodeId>| -> usize {
+        nodes.map(|&n| node_cost(&plan.nodes[n])).sum()
+    };
+
+    let mut order: Vec<usize> = (0..plan.outputs.len()).collect();
+    order.sort_by(|&a, &b| {
+        cost(&mut closures[b].iter())
+            .cmp(&cost(&mut closures[a].iter()))
+            .then_with(|| names[a].cmp(&names[b]))
+    });
+
+    // The load of every shard if the work was split evenly. Outputs go to the
+    // shard where they add the least work, as long as it stays under this.
+    let target = {
+        let all_nodes: HashSet<NodeId> = closures.iter().flatten().copied().collect();
+        cost(&mut all_nodes.iter()).div_ceil(count)
+    };
+    let mut shards = vec![ShardContent::default(); count];
+    let mut shard_nodes = vec![HashSet::<NodeId>::new(); count];
+    for i in order {
+        let added: Vec<usize> = (0..count)
+            .map(|s| cost(&mut closures[i].iter().filter(|n| !shard_nodes[s].contains(n))))
+            .collect();
+        let fitting = (0..count)
+            .filter(|&s| shards[s].cost + added[s] <= target)
+            .min_by_key(|&s| (added[s], shards[s].cost, s));
+        let best = fitting.unwrap_or_else(|| {
+            (0..count)
+                .min_by_key(|&s| (shards[s].cost + added[s], s))
+                .unwrap()
+        });
+        shards[best].cost += added[best];
+        shards[best].outputs.push(i);
+        shard_nodes[best].extend(closures[i].iter().copied());
+    }
+    for shard in shards.iter_mut() {
+        shard.outputs.sort_by(|&a, &b| names[a].cmp(&names[b]));
+    }
+
+    // Shards without outputs are not built at all.
+    let has_outputs: Vec<bool> = shards.iter().map(|s| !s.outputs.is_empty()).collect();
+    let most_overlapping = |node: NodeId| -> usize {
+        let needed = plan.nodes_needed_by([node]);
+        (0..count)
+            .max_by_key(|&s| {
+                let overlap = needed.iter().filter(|n| shard_nodes[s].contains(n)).count();
+                (overlap, has_outputs[s], std::cmp::Reverse(s))
+            })
+            .unwrap()
+    };
+    for &check in &plan.checks {
+        shards[most_overlapping(check)].checks.push(check);
+    }
+    for (i, export) in plan.exports.iter().enumerate() {
+        shards[most_overlapping(export.node)].exports.push(i);
+    }
+    shards
+}
+
+/// Restricts the plan to what the given shard builds, dropping the nodes only
+/// needed by other shards.
+pub fn shard_plan(plan: &BuildPlan, shard: Shard) -> BuildPlan {
+    let content = partition(plan, shard.count).swap_remove(shard.index - 1);
+    let mut res = plan.clone();
+    res.outputs = content
+        .outputs
+        .iter()
+        .map(|&i| plan.outputs[i].clone())
+        .collect();
+    res.checks = content.checks;
+    res.exports = content
+        .exports
+        .iter()
+        .map(|&i| plan.exports[i].clone())
+        .collect();
+    res.canonicalize();
+    res
+}
+
+#[derive(Serialize)]
+struct MatrixEntry {
+    shard: String,
+    index: usize,
+    count: usize,
+    outputs: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Matrix {
+    include: Vec<MatrixEntry>,
+}
+
+/// A CI matrix with one entry per shard, in the `{"include": [...]}` form
+/// accepted by GitHub Actions' `fromJSON`. The number of shards is reduced to
+/// the number of outputs if there are fewer, so that no machine is idle.
+/// Each entry has a `shard` to pass to `modus build --shard`.
+pub fn matrix_json(plan: &BuildPlan, max_shards: usize) -> String {
+    let count = max_shards.min(plan.outputs.len()).max(1);
+    let names = output_names(plan);
+    let include = partition(plan, count)
+        .into_iter()
+        .enumerate()
+        .map(|(i, content)| MatrixEntry {
+            shard: Shard {
+                index: i + 1,
+                count,
+            }
+            .to_string(),
+            index: i + 1,
+            count,
+            outputs: content.outputs.iter().map(|&o| names[o].clone()).collect(),
+        })
+        .collect();
+    serde_json::to_string(&Matrix { include }).expect("Unable to serialize matrix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imagegen::plan_for;
+    use serial_test::serial;
+
+    const SOURCE: &str = r#"
+        base("a") :- from("alpine"), run("a1"), run("a2"), run("a3").
+        base("b") :- from("debian"), run("b1"), run("b2"), run("b3").
+        app(group, n) :- base(group), run(f"build ${n}").
+        pair("a", "1").
+        pair("a", "2").
+        pair("b", "1").
+        pair("b", "2").
+        all(group, n) :- pair(group, n), app(group, n).
+    "#;
+
+    fn plan() -> BuildPlan {
+        plan_for(SOURCE, "all(G, N)")
+    }
+
+    fn shard_names(plan: &BuildPlan, count: usize) -> Vec<Vec<String>> {
+        let names = output_names(plan);
+        partition(plan, count)
+            .into_iter()
+            .map(|s| s.outputs.into_iter().map(|o| names[o].clone()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parse_shard() {
+        assert_eq!("2/5".parse(), Ok(Shard { index: 2, count: 5 }));
+        assert!("0/5".parse::<Shard>().is_err());
+        assert!("6/5".parse::<Shard>().is_err());
+        assert!("2".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn shared_images_stay_together() {
+        let plan = plan();
+        let shards = shard_names(&plan, 2);
+        let mut groups: Vec<String> = shards
+            .iter()
+            .map(|s| {
+                s.iter()
+                    .map(|n| n[5..6].to_owned()) // the group letter in all("a", "1")
+                    .collect::<Vec<_>>()
+                    .concat()
+            })
+            .collect();
+        groups.sort();
+        assert_eq!(groups, vec!["aa", "bb"]);
+    }
+
+    #[test]
+    #[serial]
+    fn deterministic() {
+        let mut plan = plan();
+        let expected = shard_names(&plan, 3);
+        plan.outputs.reverse();
+        assert_eq!(shard_names(&plan, 3), expected);
+        assert_eq!(expected.iter().map(|s| s.len()).sum::<usize>(), 4);
+        assert!(expected.iter().all(|s| !s.is_empty()));
+
+        let sharded = shard_plan(&plan, Shard { index: 1, count: 3 });
+        assert_eq!(output_names(&sharded), expected[0]);
+    }
+
+    #[test]
+    #[serial]
+    fn shard_plan_drops_other_shards() {
+        let plan = plan();
+        let base_images = |plan: &BuildPlan| -> Vec<String> {
+            plan.nodes
+                .iter()
+                .filter_map(|n| match n {
+                    BuildNode::From { image_ref, .. } => Some(image_ref.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut images: Vec<Vec<String>> = (1..=2)
+            .map(|index| base_images(&shard_plan(&plan, Shard { index, count: 2 })))
+            .collect();
+        images.sort();
+        assert_eq!(images, vec![vec!["alpine"], vec!["debian"]]);
+    }
+
+    #[test]
+    #[serial]
+    fn matrix() {
+        let json: serde_json::Value = serde_json::from_str(&matrix_json(&plan(), 10)).unwrap();
+        let include = json["include"].as_array().unwrap();
+        assert_eq!(include.len(), 4);
+        assert_eq!(include[3]["shard"], "4/4");
+    }
+}
@@ -25,6 +25,7 @@ use crate::{
     analysis, builtin,
     logic::Predicate,
     modusfile::{self, Modusfile},
+    suggest,
     translate::translate_modusfile,
     unification::{compose_extend, compose_no_extend, Rename, Substitution},
 };
@@ -238,13 +239,10 @@ impl Tree {
             if !concat.args[0].is_constant_or_compound_constant()
                 && Some(&concat.args[0]) == prev_term
             {
-                prev_string += if concat.args[1].is_constant_or_compound_constant() {
-                    let as_string = concat.args[1].to_string();
-                    format!("{}", &as_string[1..as_string.len() - 1])
-                } else {
-                    format!("${{{}}}", concat.args[1])
-                }
-                .as_str();
+                prev_string += concat.args[1]
+                    .as_formatted()
+                    .unwrap_or_else(|| format!("${{{}}}", concat.args[1]))
+                    .as_str();
             } else {
                 if let Some(t) = prev_term {
                     let new_out = format!("f\"{prev_string}\" = {}", t);
@@ -252,18 +250,12 @@ impl Tree {
                 }
                 prev_string = format!(
                     "{}{}",
-                    if concat.args[0].is_constant_or_compound_constant() {
-                        let as_string = concat.args[0].to_string();
-                        format!("{}", &as_string[1..as_string.len() - 1])
-                    } else {
-                        format!("${{{}}}", concat.args[0])
-                    },
-                    if concat.args[1].is_constant_or_compound_constant() {
-                        let as_string = concat.args[1].to_string();
-                        format!("{}", &as_string[1..as_string.len() - 1])
-                    } else {
-                        format!("${{{}}}", concat.args[1])
-                    }
+                    concat.args[0]
+                        .as_formatted()
+                        .unwrap_or_else(|| format!("${{{}}}", concat.args[0])),
+                    concat.args[1]
+                        .as_formatted()
+                        .unwrap_or_else(|| format!("${{{}}}", concat.args[1])),
                 )
             }
             prev_term = Some(&concat.args[2]);
@@ -613,8 +605,9 @@ impl Substitute<IRTerm> for GoalWithHistory {
 
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
 pub enum ResolutionError {
-    /// Contains the literal with the unknown predicate name.
-    UnknownPredicate(Literal),
+    /// Contains the literal with the unknown predicate name, and a suggestion
+    /// of a similar known predicate, if any.
+    UnknownPredicate(Literal, Option<String>),
     /// Contains the relevant goals.
     InsufficientGroundness(Vec<Literal>),
     /// Contains the goals when the max depth was exceeded.
@@ -632,7 +625,7 @@ pub enum ResolutionError {
 impl fmt::Display for ResolutionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ResolutionError::UnknownPredicate(literal) => {
+            ResolutionError::UnknownPredicate(literal, _) => {
                 if literal.predicate.is_operator() {
                     write!(f, "unknown operator - {}", literal.predicate)
                 } else {
@@ -666,9 +659,20 @@ impl fmt::Display for ResolutionError {
 }
 
 impl ResolutionError {
+    /// Whether this only records that some goal has no proof, as opposed to a
+    /// problem with the program, such as an unknown predicate.
+    pub fn is_no_proof(&self) -> bool {
+        matches!(
+            self,
+            ResolutionError::BuiltinFailure(..)
+                | ResolutionError::InsufficientRules(_)
+                | ResolutionError::NegationProof(_)
+        )
+    }
+
     fn to_short_string(&self) -> String {
         match self {
-            ResolutionError::UnknownPredicate(literal) => {
+            ResolutionError::UnknownPredicate(literal, _) => {
                 if literal.predicate.is_operator() {
                     format!("unknown operator: {}", literal.predicate)
                 } else {
@@ -698,7 +702,7 @@ impl ResolutionError {
 
     fn severity(&self) -> Severity {
         match self {
-            ResolutionError::UnknownPredicate(_) => Severity::Error,
+            ResolutionError::UnknownPredicate(..) => Severity::Error,
             ResolutionError::InsufficientGroundness(_) => Severity::Error,
             ResolutionError::MaximumDepthExceeded(_, _) => Severity::Warning,
             ResolutionError::BuiltinFailure(_, _) => Severity::Warning,
@@ -714,7 +718,7 @@ impl ResolutionError {
     /// by it's `fmt::Display` output.
     fn get_data_list(&self) -> Option<Vec<String>> {
         match self {
-            ResolutionError::UnknownPredicate(_) => None,
+            ResolutionError::UnknownPredicate(..) => None,
             ResolutionError::InsufficientGroundness(ls) => {
                 Some(ls.iter().map(|x| x.to_string()).collect())
             }
@@ -753,9 +757,12 @@ impl ResolutionError {
 
         let message = self.to_string();
         let (labels, notes) = match &self {
-            ResolutionError::UnknownPredicate(literal) => (
+            ResolutionError::UnknownPredicate(literal, suggestion) => (
                 get_position_labels(&[literal.clone()]),
-                get_notes(&[literal.clone()]),
+                get_notes(&[literal.clone()])
+                    .into_iter()
+                    .chain(suggestion.clone())
+                    .collect(),
             ),
             ResolutionError::InsufficientGroundness(literals) => {
                 (get_position_labels(&literals), get_notes(&literals))
@@ -791,8 +798,8 @@ impl ResolutionError {
     /// renamed variable index.
     fn normalize(self) -> ResolutionError {
         match self {
-            ResolutionError::UnknownPredicate(l) => {
-                ResolutionError::UnknownPredicate(l.normalized_terms())
+            ResolutionError::UnknownPredicate(l, suggestion) => {
+                ResolutionError::UnknownPredicate(l.normalized_terms(), suggestion)
             }
             ResolutionError::InsufficientGroundness(ls) => ResolutionError::InsufficientGroundness(
                 ls.into_iter().map(|x| x.normalized_terms()).collect(),
@@ -849,6 +856,18 @@ pub fn sld(
     goal: &Goal,
     maxdepth: TreeLevel,
     store_full_tree: bool,
+) -> SLDResult {
+    sld_with_modes(rules, &HashMap::new(), goal, maxdepth, store_full_tree)
+}
+
+/// Like `sld`, but uses the declared groundness of the predicates with a mode
+/// declaration (see `Modusfile::modes`) instead of the inferred one.
+pub fn sld_with_modes(
+    rules: &[Clause<IRTerm>],
+    modes: &HashMap<Signature, Vec<bool>>,
+    goal: &Goal,
+    maxdepth: TreeLevel,
+    store_full_tree: bool,
 ) -> SLDResult {
     /// Select leftmost literal with compatible groundness.
     fn select(
@@ -894,14 +913,29 @@ pub fn sld(
                 } else {
                     continue;
                 }
-            } else if select_builtin_res.0 == SelectBuiltinResult::GroundnessMismatch
+            } else if (select_builtin_res.0 == SelectBuiltinResult::GroundnessMismatch
+                && builtin::builtin_signatures().any(|(name, arity)| {
+                    name == literal.predicate.0 && arity == literal.args.len()
+                }))
                 || (select_builtin_res.0 == SelectBuiltinResult::Match
                     && !positive_or_grounded_negation)
             {
                 continue;
             }
 
-            return Err(ResolutionError::UnknownPredicate(literal.clone()));
+            let suggestion = if literal.predicate.is_operator() {
+                None
+            } else {
+                let user_signatures: Vec<(&str, usize)> = grounded
+                    .keys()
+                    .map(|Signature(predicate, arity)| (predicate.0.as_str(), *arity as usize))
+                    .collect();
+                suggest::suggest_predicate(literal, &user_signatures).map(|s| s.message)
+            };
+            return Err(ResolutionError::UnknownPredicate(
+                literal.clone(),
+                suggestion,
+            ));
         }
 
         Err(ResolutionError::InsufficientGroundness(
@@ -1210,7 +1244,7 @@ pub fn sld(
         }
     }
 
-    let grounded_result = wellformed::check_grounded_variables(rules);
+    let grounded_result = wellformed::check_grounded_variables(rules, modes);
     let goal_with_history = goal
         .iter()
         .enumerate()
@@ -1442,7 +1476,7 @@ pub fn tree_from_modusfile(
     (
         goal.clone(),
         clauses.clone(),
-        sld(&clauses, &goal, max_depth, full_tree),
+        sld_with_modes(&clauses, &mf.modes(), &goal, max_depth, full_tree),
     )
 }
 
@@ -1845,11 +1879,98 @@ mod tests {
         assert_eq!(sld_res.errors.len(), 1);
         let is_match = matches!(
             sld_res.errors.iter().next(),
-            Some(ResolutionError::UnknownPredicate(_))
+            Some(ResolutionError::UnknownPredicate(..))
+        );
+        assert!(is_match);
+    }
+
+    #[test]
+    #[serial]
+    fn suggests_similar_predicates() {
+        let clauses: Vec<logic::Clause> = vec![
+            "a :- frm(\"alpine\").".parse().unwrap(),
+            "b :- c(\"x\").".parse().unwrap(),
+            "c(X, Y) :- run(\"echo\").".parse().unwrap(),
+        ];
+        for (query, expected) in [
+            ("a", "did you mean `from`?"),
+            ("b", "`c/1` does not exist, did you mean `c/2`?"),
+        ] {
+            let goal: Goal<logic::IRTerm> = vec![query.parse().unwrap()];
+            let sld_res = sld(&clauses, &goal, 10, true);
+
+            assert_eq!(sld_res.errors.len(), 1);
+            let is_match = matches!(
+                sld_res.errors.iter().next(),
+                Some(ResolutionError::UnknownPredicate(_, Some(suggestion))) if suggestion == expected
+            );
+            assert!(is_match);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn uses_declared_modes() {
+        let mf: Modusfile = ":- mode a(+).\na(X) :- string_eq(X, \"x\")."
+            .parse()
+            .unwrap();
+        let clauses = translate_modusfile(&mf);
+        let goal: Goal<logic::IRTerm> = vec!["a(X)".parse().unwrap()];
+
+        assert!(sld(&clauses, &goal, 10, false).errors.is_empty());
+        let sld_res = sld_with_modes(&clauses, &mf.modes(), &goal, 10, false);
+        let is_match = matches!(
+            sld_res.errors.iter().next(),
+            Some(ResolutionError::InsufficientGroundness(_))
         );
         assert!(is_match);
     }
 
+    #[test]
+    #[serial]
+    fn interpolates_typed_constants() {
+        let mf: Modusfile = "version(3, true).\n\
+                             tag(T) :- version(V, L), string_eq(f\"v${V}-${L}\", T)."
+            .parse()
+            .unwrap();
+        let clauses = translate_modusfile(&mf);
+
+        let goal: Goal<logic::IRTerm> = vec!["tag(T)".parse().unwrap()];
+        let tags = solutions(&sld(&clauses, &goal, 20, true).tree);
+        assert_eq!(tags.len(), 1);
+        assert!(contains_ignoring_position(
+            &tags,
+            &vec!["tag(\"v3-true\")".parse::<logic::Literal>().unwrap()]
+        ));
+
+        let goal: Goal<logic::IRTerm> = vec!["version(\"3\", L)".parse().unwrap()];
+        assert!(solutions(&sld(&clauses, &goal, 20, true).tree).is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn equates_typed_constants() {
+        let mf: Modusfile = "num(N) :- N = 3.\n\
+                             flag(B) :- B = true.\n\
+                             mixed(X) :- X = \"1\", X = 1."
+            .parse()
+            .unwrap();
+        let clauses = translate_modusfile(&mf);
+
+        for (goal, solution) in [("num(N)", "num(3)"), ("flag(B)", "flag(true)")] {
+            let goal: Goal<logic::IRTerm> = vec![goal.parse().unwrap()];
+            let found = solutions(&sld(&clauses, &goal, 20, true).tree);
+            assert_eq!(found.len(), 1);
+            assert!(contains_ignoring_position(
+                &found,
+                &vec![solution.parse::<logic::Literal>().unwrap()]
+            ));
+        }
+
+        let goal: Goal<logic::IRTerm> = vec!["mixed(X)".parse().unwrap()];
+        assert!(solutions(&sld(&clauses, &goal, 20, true).tree).is_empty());
+    }
+
     #[test]
     #[serial]
     fn lists() {
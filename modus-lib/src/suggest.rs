@@ -0,0 +1,344 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! "Did you mean" suggestions for unknown predicates and operators.
+//!
+//! A suggestion is rendered as a note of the diagnostic about the unknown
+//! name. When there is exactly one likely replacement, it also carries a
+//! [`Fix`] which can be applied without user input, e.g. by `modus check --fix`.
+
+use std::ops::Range;
+
+use crate::{
+    builtin::{self, OPERATOR_KIND_MAP},
+    logic::Literal,
+    modusfile::{Expression, ModusTerm, Modusfile, Operator},
+};
+
+/// A replacement of some text in the source.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Fix {
+    pub range: Range<usize>,
+    /// The text expected in the range, so that a fix computed for another
+    /// version of the source is not applied.
+    pub original: String,
+    pub replacement: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Suggestion {
+    pub message: String,
+    pub fix: Option<Fix>,
+}
+
+/// The edit distance between two strings, in chars, where swapping two
+/// adjacent chars counts as a single edit (optimal string alignment distance).
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = d[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = substitution.min(d[i - 1][j] + 1).min(d[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// The candidates closest to `name`, if they are close enough to be a typo of it.
+fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut best_distance = (name.chars().count() / 3).max(1);
+    let mut best = Vec::new();
+    for candidate in candidates {
+        if candidate == name {
+            continue;
+        }
+        let distance = edit_distance(name, candidate);
+        if distance < best_distance {
+            best_distance = distance;
+            best = vec![candidate];
+        } else if distance == best_distance && !best.contains(&candidate) {
+            best.push(candidate);
+        }
+    }
+    best.sort_unstable();
+    best
+}
+
+/// Formats a list of alternatives, e.g. "`a`, `b` or `c`".
+fn alternatives(items: &[String]) -> String {
+    match items {
+        [] => String::new(),
+        [item] => item.clone(),
+        [init @ .., last] => format!("{} or {}", init.join(", "), last),
+    }
+}
+
+/// Suggests a predicate for a literal whose predicate is not defined with its
+/// arity, among the builtins and the given user-defined predicates.
+///
+/// Returns `None` if the predicate is known, or no predicate is similar enough.
+pub fn suggest_predicate<T>(
+    lit: &Literal<T>,
+    user_signatures: &[(&str, usize)],
+) -> Option<Suggestion> {
+    let name = lit.predicate.0.as_str();
+    let arity = lit.args.len();
+    // Internal predicates, such as the ones operators and negation are translated to, are
+    // not something a user would write.
+    let mut signatures: Vec<(&str, usize)> = user_signatures.to_vec();
+    for signature in builtin::builtin_signatures() {
+        signatures.push(signature);
+    }
+    signatures.retain(|(n, _)| !n.starts_with('_'));
+    signatures.sort_unstable();
+    signatures.dedup();
+    if signatures.contains(&(name, arity)) {
+        return None;
+    }
+
+    let other_arities: Vec<String> = signatures
+        .iter()
+        .filter(|(n, _)| *n == name)
+        .map(|(n, a)| format!("`{}/{}`", n, a))
+        .collect();
+    if !other_arities.is_empty() {
+        return Some(Suggestion {
+            message: format!(
+                "`{}/{}` does not exist, did you mean {}?",
+                name,
+                arity,
+                alternatives(&other_arities)
+            ),
+            fix: None,
+        });
+    }
+
+    let same_arity = closest(
+        name,
+        signatures
+            .iter()
+            .filter(|(_, a)| *a == arity)
+            .map(|(n, _)| *n),
+    );
+    if !same_arity.is_empty() {
+        // The span of a negated literal starts at the negation.
+        let fix = match (same_arity.as_slice(), &lit.position) {
+            ([replacement], Some(pos)) if lit.positive => Some(Fix {
+                range: pos.offset..pos.offset + name.len(),
+                original: name.to_owned(),
+                replacement: replacement.to_string(),
+            }),
+            _ => None,
+        };
+        let names: Vec<String> = same_arity.iter().map(|n| format!("`{}`", n)).collect();
+        return Some(Suggestion {
+            message: format!("did you mean {}?", alternatives(&names)),
+            fix,
+        });
+    }
+
+    let names = closest(name, signatures.iter().map(|(n, _)| *n));
+    if names.is_empty() {
+        return None;
+    }
+    let with_arities: Vec<String> = signatures
+        .iter()
+        .filter(|(n, _)| names.contains(n))
+        .map(|(n, a)| format!("`{}/{}`", n, a))
+        .collect();
+    Some(Suggestion {
+        message: format!("did you mean {}?", alternatives(&with_arities)),
+        fix: None,
+    })
+}
+
+/// Suggests an operator for an operator that does not exist.
+pub fn suggest_operator(op: &Operator) -> Option<Suggestion> {
+    let name = op.predicate.0.as_str();
+    if OPERATOR_KIND_MAP.contains_key(name) {
+        return None;
+    }
+    let names = closest(name, OPERATOR_KIND_MAP.keys().copied());
+    let fix = match (names.as_slice(), &op.position) {
+        ([replacement], Some(pos)) => Some(Fix {
+            range: pos.offset..pos.offset + name.len(),
+            original: name.to_owned(),
+            replacement: replacement.to_string(),
+        }),
+        _ => None,
+    };
+    let names: Vec<String> = names.iter().map(|n| format!("`::{}`", n)).collect();
+    if names.is_empty() {
+        None
+    } else {
+        Some(Suggestion {
+            message: format!("did you mean {}?", alternatives(&names)),
+            fix,
+        })
+    }
+}
+
+/// The fixes for all the unknown predicates and operators in the bodies of a Modusfile.
+pub fn modusfile_fixes(mf: &Modusfile) -> Vec<Fix> {
+    fn collect(expr: &Expression, user_signatures: &[(&str, usize)], fixes: &mut Vec<Fix>) {
+        match expr {
+            Expression::Literal(lit) => fixes
+                .extend(suggest_predicate::<ModusTerm>(lit, user_signatures).and_then(|s| s.fix)),
+            Expression::OperatorApplication(_, e, op) => {
+                collect(e, user_signatures, fixes);
+                fixes.extend(suggest_operator(op).and_then(|s| s.fix));
+            }
+            Expression::And(_, _, e1, e2) | Expression::Or(_, _, e1, e2) => {
+                collect(e1, user_signatures, fixes);
+                collect(e2, user_signatures, fixes);
+            }
+        }
+    }
+
+    let user_signatures: Vec<(&str, usize)> =
+        mf.0.iter()
+            .map(|c| (c.head.predicate.0.as_str(), c.head.args.len()))
+            .collect();
+    let mut fixes = Vec::new();
+    for body in mf.0.iter().filter_map(|c| c.body.as_ref()) {
+        collect(body, &user_signatures, &mut fixes);
+    }
+    fixes
+}
+
+/// Applies the fixes to the source, skipping the ones which overlap an
+/// earlier fix or don't match the source.
+///
+/// Returns the new source and the number of fixes applied.
+pub fn apply_fixes(source: &str, fixes: &[Fix]) -> (String, usize) {
+    let mut fixes: Vec<&Fix> = fixes.iter().collect();
+    fixes.sort_by_key(|fix| (fix.range.start, fix.range.end));
+    let mut result = String::with_capacity(source.len());
+    let mut copied_up_to = 0;
+    let mut applied = 0;
+    for fix in fixes {
+        if fix.range.start < copied_up_to
+            || source.get(fix.range.clone()) != Some(fix.original.as_str())
+        {
+            continue;
+        }
+        result.push_str(&source[copied_up_to..fix.range.start]);
+        result.push_str(&fix.replacement);
+        copied_up_to = fix.range.end;
+        applied += 1;
+    }
+    result.push_str(&source[copied_up_to..]);
+    (result, applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("copy", "copy"), 0);
+        assert_eq!(edit_distance("cpy", "copy"), 1);
+        assert_eq!(edit_distance("set_evn", "set_env"), 1);
+        assert_eq!(edit_distance("rnu", "run"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "run"), 3);
+    }
+
+    #[test]
+    fn suggests_predicates() {
+        let user = [("app", 1), ("base_image", 1)];
+
+        let lit: Literal = "rnu(\"make\")".parse().unwrap();
+        let suggestion = suggest_predicate(&lit, &user).unwrap();
+        assert_eq!(suggestion.message, "did you mean `run`?");
+        assert_eq!(
+            suggestion.fix,
+            Some(Fix {
+                range: 0..3,
+                original: "rnu".to_owned(),
+                replacement: "run".to_owned(),
+            })
+        );
+
+        let lit: Literal = "base_imag(X)".parse().unwrap();
+        assert_eq!(
+            suggest_predicate(&lit, &user).unwrap().message,
+            "did you mean `base_image`?"
+        );
+
+        let lit: Literal = "copy(\"a\", \"b\", \"c\")".parse().unwrap();
+        assert_eq!(
+            suggest_predicate(&lit, &user).unwrap(),
+            Suggestion {
+                message: "`copy/3` does not exist, did you mean `copy/2`?".to_owned(),
+                fix: None,
+            }
+        );
+
+        let lit: Literal = "ap(X, Y)".parse().unwrap();
+        assert_eq!(
+            suggest_predicate(&lit, &user).unwrap(),
+            Suggestion {
+                message: "did you mean `app/1`?".to_owned(),
+                fix: None,
+            }
+        );
+
+        let lit: Literal = "!rnu(\"make\")".parse().unwrap();
+        assert_eq!(suggest_predicate(&lit, &user).unwrap().fix, None);
+
+        let lit: Literal = "app(X)".parse().unwrap();
+        assert_eq!(suggest_predicate(&lit, &user), None);
+        let lit: Literal = "something_else(X)".parse().unwrap();
+        assert_eq!(suggest_predicate(&lit, &user), None);
+    }
+
+    #[test]
+    fn applies_fixes() {
+        let source = "a :- from(\"alpine\")::set_evn(\"A\", \"1\").\n\
+                      b :- a, rnu(\"make\").\n\
+                      c :- !b.";
+        let mf: Modusfile = source.parse().unwrap();
+        let fixes = modusfile_fixes(&mf);
+        assert_eq!(fixes.len(), 2);
+        let (fixed, applied) = apply_fixes(source, &fixes);
+        assert_eq!(applied, 2);
+        assert_eq!(
+            fixed,
+            "a :- from(\"alpine\")::set_env(\"A\", \"1\").\n\
+             b :- a, run(\"make\").\n\
+             c :- !b."
+        );
+
+        // Fixes that don't match the source are skipped.
+        let (unchanged, applied) = apply_fixes(&fixed, &fixes);
+        assert_eq!(applied, 0);
+        assert_eq!(unchanged, fixed);
+    }
+}
@@ -0,0 +1,326 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Unit tests written in Modus.
+//!
+//! A test is a nullary predicate whose name starts with `test_`, e.g.
+//! `test_supported :- supported("3.14").`. It passes if the solver finds a proof.
+//! Predicates starting with `test_fail_` are expected-failure tests, which pass
+//! only if no proof can be found. They still fail if the solver reports an error,
+//! such as an unknown predicate.
+//!
+//! Tests are only run through the solver, no images are built.
+
+use std::{fmt::Write, time::Duration, time::Instant};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+
+use crate::{
+    logic::{Predicate, SpannedPosition},
+    modusfile::{Expression, Modusfile},
+    sld,
+};
+
+pub const TEST_PREFIX: &str = "test_";
+pub const EXPECTED_FAILURE_PREFIX: &str = "test_fail_";
+
+/// Maximum depth of the SLD tree used when running a test, this matches what
+/// is used to build images.
+const MAX_DEPTH: usize = 175;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TestCase {
+    pub name: String,
+    pub expect_failure: bool,
+    /// Position of the head of the first clause declaring this test.
+    pub position: Option<SpannedPosition>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum TestOutcome {
+    Passed,
+    /// The test failed, with the diagnostics explaining why.
+    Failed(Vec<Diagnostic<()>>),
+}
+
+#[derive(Clone, Debug)]
+pub struct TestResult {
+    pub case: TestCase,
+    pub outcome: TestOutcome,
+    pub duration: Duration,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == TestOutcome::Passed
+    }
+}
+
+/// Collects the test declarations of a Modusfile, in order of first appearance.
+pub fn find_tests(mf: &Modusfile) -> Vec<TestCase> {
+    let mut tests: Vec<TestCase> = Vec::new();
+    for clause in &mf.0 {
+        let name = &clause.head.predicate.0;
+        if !name.starts_with(TEST_PREFIX)
+            || !clause.head.args.is_empty()
+            || tests.iter().any(|t| &t.name == name)
+        {
+            continue;
+        }
+        tests.push(TestCase {
+            name: name.clone(),
+            expect_failure: name.starts_with(EXPECTED_FAILURE_PREFIX),
+            position: clause.head.position.clone(),
+        });
+    }
+    tests
+}
+
+/// Runs a single test through the solver.
+pub fn run_test(mf: &Modusfile, case: &TestCase) -> TestResult {
+    let start = Instant::now();
+    let query = Expression::Literal(crate::logic::Literal {
+        positive: true,
+        position: None,
+        predicate: Predicate(case.name.clone()),
+        args: Vec::new(),
+    });
+    let (_, _, sld_result) = sld::tree_from_modusfile(mf.clone(), query, MAX_DEPTH, false);
+    // An expected failure must fail because there is no proof, not because the
+    // test could not be run, e.g. due to a misspelled predicate.
+    let run_errors: Vec<Diagnostic<()>> = sld_result
+        .errors
+        .iter()
+        .filter(|e| !e.is_no_proof())
+        .map(|e| e.clone().get_diagnostic())
+        .collect();
+    let res: Result<sld::Tree, Vec<Diagnostic<()>>> = sld_result.into();
+
+    let outcome = match (res, case.expect_failure) {
+        (Ok(_), false) => TestOutcome::Passed,
+        (Err(_), true) if run_errors.is_empty() => TestOutcome::Passed,
+        (Err(_), true) => {
+            let mut diags = run_errors;
+            diags.insert(
+                0,
+                Diagnostic::error()
+                    .with_message(format!(
+                        "{} is expected to fail, but it could not be run",
+                        case.name
+                    ))
+                    .with_labels(head_labels(case)),
+            );
+            TestOutcome::Failed(diags)
+        }
+        (Err(mut diags), false) => {
+            diags.insert(
+                0,
+                Diagnostic::error()
+                    .with_message(format!("no proof found for {}", case.name))
+                    .with_labels(head_labels(case)),
+            );
+            TestOutcome::Failed(diags)
+        }
+        (Ok(_), true) => TestOutcome::Failed(vec![Diagnostic::error()
+            .with_message(format!(
+                "a proof was found for {}, but it is expected to fail",
+                case.name
+            ))
+            .with_labels(head_labels(case))]),
+    };
+
+    TestResult {
+        case: case.clone(),
+        outcome,
+        duration: start.elapsed(),
+    }
+}
+
+fn head_labels(case: &TestCase) -> Vec<Label<()>> {
+    case.position
+        .iter()
+        .map(|pos| Label::primary((), pos.offset..(pos.offset + pos.length)))
+        .collect()
+}
+
+/// Runs all the tests whose name contains `filter`, or all the tests if no filter is given.
+pub fn run_tests(mf: &Modusfile, filter: Option<&str>) -> Vec<TestResult> {
+    find_tests(mf)
+        .iter()
+        .filter(|case| filter.is_none_or(|f| case.name.contains(f)))
+        .map(|case| run_test(mf, case))
+        .collect()
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders the test results as a JUnit XML report, which is understood by most CI systems.
+pub fn to_junit_xml(suite_name: &str, results: &[TestResult]) -> String {
+    let failures = results.iter().filter(|r| !r.passed()).count();
+    let total_time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+
+    let mut xml = String::new();
+    writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        xml,
+        r#"<testsuites tests="{}" failures="{}" time="{:.3}">"#,
+        results.len(),
+        failures,
+        total_time
+    )
+    .unwrap();
+    writeln!(
+        xml,
+        r#"  <testsuite name="{}" tests="{}" failures="{}" errors="0" time="{:.3}">"#,
+        escape_xml(suite_name),
+        results.len(),
+        failures,
+        total_time
+    )
+    .unwrap();
+    for r in results {
+        let name = escape_xml(&r.case.name);
+        let time = r.duration.as_secs_f64();
+        match &r.outcome {
+            TestOutcome::Passed => writeln!(
+                xml,
+                r#"    <testcase name="{}" classname="{}" time="{:.3}"/>"#,
+                name,
+                escape_xml(suite_name),
+                time
+            )
+            .unwrap(),
+            TestOutcome::Failed(diags) => {
+                writeln!(
+                    xml,
+                    r#"    <testcase name="{}" classname="{}" time="{:.3}">"#,
+                    name,
+                    escape_xml(suite_name),
+                    time
+                )
+                .unwrap();
+                let message = diags.first().map(|d| d.message.as_str()).unwrap_or("");
+                let details = diags
+                    .iter()
+                    .flat_map(|d| std::iter::once(&d.message).chain(d.notes.iter()))
+                    .map(|s| escape_xml(s))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                writeln!(
+                    xml,
+                    r#"      <failure message="{}">{}</failure>"#,
+                    escape_xml(message),
+                    details
+                )
+                .unwrap();
+                writeln!(xml, "    </testcase>").unwrap();
+            }
+        }
+    }
+    writeln!(xml, "  </testsuite>").unwrap();
+    writeln!(xml, "</testsuites>").unwrap();
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    const MODUSFILE: &str = r#"
+supported(version) :- version = "3.14" ; version = "3.15".
+helper_test(x) :- supported(x).
+
+test_supported :- supported("3.14").
+test_unsupported :- supported("2.7").
+test_fail_old :- supported("2.7").
+test_fail_new :- supported("3.15").
+"#;
+
+    #[test]
+    fn finds_tests() {
+        let mf: Modusfile = MODUSFILE.parse().unwrap();
+        let tests = find_tests(&mf);
+        let names: Vec<&str> = tests.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "test_supported",
+                "test_unsupported",
+                "test_fail_old",
+                "test_fail_new"
+            ]
+        );
+        assert!(!tests[0].expect_failure);
+        assert!(tests[2].expect_failure);
+    }
+
+    #[test]
+    #[serial]
+    fn runs_tests() {
+        let mf: Modusfile = MODUSFILE.parse().unwrap();
+        let results = run_tests(&mf, None);
+        let passed: Vec<bool> = results.iter().map(|r| r.passed()).collect();
+        assert_eq!(passed, vec![true, false, true, false]);
+
+        let filtered = run_tests(&mf, Some("fail"));
+        assert_eq!(filtered.len(), 2);
+    }
+
+    #[test]
+    #[serial]
+    fn expected_failures_fail_on_errors() {
+        let mf: Modusfile = format!("{}\ntest_fail_typo :- suported(\"3.15\").", MODUSFILE)
+            .parse()
+            .unwrap();
+        let case = find_tests(&mf)
+            .into_iter()
+            .find(|t| t.name == "test_fail_typo")
+            .unwrap();
+        let result = run_test(&mf, &case);
+        match result.outcome {
+            TestOutcome::Failed(diags) => assert_eq!(
+                diags[0].message,
+                "test_fail_typo is expected to fail, but it could not be run"
+            ),
+            TestOutcome::Passed => panic!("expected test_fail_typo to fail"),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn junit_report() {
+        let mf: Modusfile = MODUSFILE.parse().unwrap();
+        let results = run_tests(&mf, Some("supported"));
+        let xml = to_junit_xml("Modusfile", &results);
+        assert!(xml.contains(r#"<testsuite name="Modusfile" tests="2" failures="1""#));
+        assert!(xml.contains(r#"<testcase name="test_supported""#));
+        assert!(xml.contains("<failure message=\"no proof found for test_unsupported\">"));
+    }
+}
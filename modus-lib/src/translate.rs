@@ -157,6 +157,8 @@ pub(crate) fn reset_negation_literal_id() {
 fn translate_term(t: &ModusTerm) -> (IRTerm, Vec<logic::Literal>) {
     match t {
         ModusTerm::Constant(c) => (IRTerm::Constant(process_raw_string(c)), Vec::new()),
+        ModusTerm::Integer(i) => (IRTerm::Integer(*i), Vec::new()),
+        ModusTerm::Boolean(b) => (IRTerm::Boolean(*b), Vec::new()),
         ModusTerm::FormatString {
             position,
             fragments,
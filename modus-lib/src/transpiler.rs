@@ -43,7 +43,8 @@ pub fn transpile(
     Ok(plan_to_docker(&build_plan))
 }
 
-fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
+/// Translates a build plan to a multi-stage Dockerfile.
+pub fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
     let topological_order = plan.topological_order();
 
     let mut instructions = topological_order
@@ -209,15 +210,20 @@ fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
         .flatten()
         .collect::<Vec<_>>();
 
-    if plan.outputs.len() > 1 {
+    if plan.outputs.len() > 1 || !plan.checks.is_empty() {
         use crate::dockerfile::{From, Run};
         instructions.push(Instruction::From(From {
             parent: ResolvedParent::Stage("busybox".to_owned()),
             alias: Some("force_multioutput".to_owned()),
         }));
 
-        for o in plan.outputs.iter() {
-            let k = format!("n_{}", o.node);
+        for node in plan
+            .outputs
+            .iter()
+            .map(|o| o.node)
+            .chain(plan.checks.iter().copied())
+        {
+            let k = format!("n_{}", node);
             instructions.push(Instruction::Run(Run(format!(
                 "--mount=type=bind,from={},source=/,target=/mnt true",
                 k,
@@ -197,21 +197,23 @@ impl Literal<IRTerm> {
                 let other_term_subs = other_term.substitute(&s);
                 if self_term_subs != other_term_subs {
                     match (self_term_subs.clone(), other_term_subs.clone()) {
-                        // cannot unify if they are both different constants
-                        (IRTerm::Constant(_), IRTerm::Constant(_)) => return None,
+                        // cannot unify if they are both different constants, which includes
+                        // constants of different types, e.g. "1" and 1
+                        (c1, c2) if c1.is_constant() && c2.is_constant() => return None,
 
                         (IRTerm::List(_), IRTerm::List(_)) => {
                             unimplemented!("TODO: borrow unification from Prolog.")
                         }
-                        (IRTerm::List(_), IRTerm::Constant(_))
-                        | (IRTerm::Constant(_), IRTerm::List(_)) => return None,
+                        (IRTerm::List(_), c) | (c, IRTerm::List(_)) if c.is_constant() => {
+                            return None
+                        }
                         (IRTerm::List(ts), v) | (v, IRTerm::List(ts)) => {
                             let mut upd = Substitution::<IRTerm>::new();
                             upd.insert(v.clone(), IRTerm::List(ts));
                             s = compose_extend(&s, &upd);
                         }
 
-                        (IRTerm::Constant(_), v) => {
+                        (c, v) if c.is_constant() => {
                             let mut upd = Substitution::<IRTerm>::new();
                             upd.insert(v.clone(), self_term_subs.clone());
                             s = compose_extend(&s, &upd);
@@ -305,6 +307,25 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[test]
+    fn unification_respects_types() {
+        let l: logic::Literal = "a(1, true)".parse().unwrap();
+        assert!(l.unify(&"a(1, true)".parse().unwrap()).is_some());
+        assert!(l.unify(&"a(\"1\", true)".parse().unwrap()).is_none());
+        assert!(l.unify(&"a(1, \"true\")".parse().unwrap()).is_none());
+        assert!(l.unify(&"a(01, true)".parse().unwrap()).is_some());
+
+        let mgu = l.unify(&"a(X, Y)".parse().unwrap()).unwrap();
+        assert_eq!(
+            mgu.get(&logic::IRTerm::UserVariable("X".into())),
+            Some(&logic::IRTerm::Integer(1))
+        );
+        assert_eq!(
+            mgu.get(&logic::IRTerm::UserVariable("Y".into())),
+            Some(&logic::IRTerm::Boolean(true))
+        );
+    }
+
     #[test]
     fn complex_non_unifiable() {
         let l: logic::Literal = "q(X, \"a\", X, \"b\")".parse().unwrap();
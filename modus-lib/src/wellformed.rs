@@ -16,7 +16,7 @@
 
 use std::collections::{HashMap, HashSet};
 
-use crate::logic::{Clause, IRTerm, Signature};
+use crate::logic::{Clause, IRTerm, Literal, Signature};
 
 /// infer image predicates, i.e. those that transitively depend on image/1
 /// check that image predicates depend on image/1 in each disjunct
@@ -42,8 +42,10 @@ fn combine_groundness(g1: &[bool], g2: &[bool]) -> Vec<bool> {
 }
 
 // infer grounded variables, check if grounded variables are grounded in each rule
+// the declared groundness of a predicate, from its mode declaration, replaces the inferred one
 pub fn check_grounded_variables(
     clauses: &[Clause<IRTerm>],
+    modes: &HashMap<Signature, Vec<bool>>,
 ) -> Result<HashMap<Signature, Vec<bool>>, HashSet<Signature>> {
     let mut errors: HashSet<Signature> = HashSet::new();
     let mut result: HashMap<Signature, Vec<bool>> = HashMap::new();
@@ -62,7 +64,7 @@ pub fn check_grounded_variables(
             .args
             .iter()
             .map(|t| match t {
-                IRTerm::Constant(_) => true,
+                t if t.is_constant() => true,
                 IRTerm::List(ts) => {
                     // either the terms of this list are constant or contained in the body
                     ts.iter()
@@ -88,6 +90,12 @@ pub fn check_grounded_variables(
         result.insert(sig, new_groundness);
     }
 
+    for (sig, declared) in modes {
+        if let Some(groundness) = result.get_mut(sig) {
+            *groundness = declared.clone();
+        }
+    }
+
     if errors.is_empty() {
         Ok(result)
     } else {
@@ -95,10 +103,98 @@ pub fn check_grounded_variables(
     }
 }
 
+/// A violation of a mode declaration.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ModeError {
+    /// Contains a literal and the index of an input argument which might not
+    /// be ground when the literal is selected.
+    UngroundInput(Literal, usize),
+    /// Contains the head of a clause and the index of an output argument which
+    /// the body of the clause does not bind.
+    UnboundOutput(Literal, usize),
+}
+
+/// The index of the first input argument of a literal that is not ground, given the
+/// bound variables.
+fn unground_input(
+    lit: &Literal,
+    modes: &HashMap<Signature, Vec<bool>>,
+    bound: &HashSet<IRTerm>,
+) -> Option<usize> {
+    let groundness = modes.get(&lit.signature())?;
+    lit.args
+        .iter()
+        .zip(groundness)
+        .position(|(arg, allows_ungrounded)| {
+            !allows_ungrounded && !arg.variables(true).is_subset(bound)
+        })
+}
+
+/// Checks that the clauses respect the declared modes, given as the groundness used in
+/// resolution.
+///
+/// Like in resolution, a literal is selected once its inputs are ground, regardless
+/// of its position in the body, and a positive literal binds all of its variables.
+/// The head variables of a predicate without a mode declaration are assumed to be ground.
+pub fn check_modes(
+    clauses: &[Clause<IRTerm>],
+    modes: &HashMap<Signature, Vec<bool>>,
+) -> Vec<ModeError> {
+    let mut errors = Vec::new();
+    for c in clauses {
+        let head_groundness = modes.get(&c.head.signature());
+        let mut bound: HashSet<IRTerm> = match head_groundness {
+            Some(groundness) => c
+                .head
+                .args
+                .iter()
+                .zip(groundness)
+                .filter(|(_, allows_ungrounded)| !**allows_ungrounded)
+                .flat_map(|(arg, _)| arg.variables(true))
+                .collect(),
+            None => c.head.variables(true),
+        };
+
+        let mut pending: Vec<&Literal> = c.body.iter().collect();
+        loop {
+            let pending_count = pending.len();
+            pending.retain(|lit| {
+                if unground_input(lit, modes, &bound).is_some() {
+                    return true;
+                }
+                if lit.positive {
+                    bound.extend(lit.variables(true));
+                }
+                false
+            });
+            if pending.len() == pending_count {
+                break;
+            }
+        }
+        // Outputs are only checked if the body can be resolved, otherwise the error is
+        // about the literal that can't be selected.
+        if !pending.is_empty() {
+            for lit in pending {
+                if let Some(index) = unground_input(lit, modes, &bound) {
+                    errors.push(ModeError::UngroundInput(lit.clone(), index));
+                }
+            }
+        } else if let Some(groundness) = head_groundness {
+            for (index, (arg, allows_ungrounded)) in c.head.args.iter().zip(groundness).enumerate()
+            {
+                if *allows_ungrounded && !arg.variables(true).is_subset(&bound) {
+                    errors.push(ModeError::UnboundOutput(c.head.clone(), index));
+                }
+            }
+        }
+    }
+    errors
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{logic::Predicate, modusfile};
+    use crate::{logic::Predicate, modusfile, translate};
     #[test]
     fn consistently_grounded() {
         let clauses: Vec<Clause> = vec![
@@ -106,7 +202,7 @@ mod tests {
             "a(X, Y) :- d(X).".parse().unwrap(),
             "b(X) :- d(X).".parse().unwrap(),
         ];
-        let result = check_grounded_variables(&clauses);
+        let result = check_grounded_variables(&clauses, &HashMap::new());
         assert!(result.is_ok());
         let a_sig = Signature(Predicate("a".into()), 2);
         let a_grounded = result.unwrap().get(&a_sig).unwrap().clone();
@@ -120,7 +216,7 @@ mod tests {
             "a(X, Y) :- b(X), c(X, Z).".parse().unwrap(),
             "a(X, Y) :- d(Y).".parse().unwrap(),
         ];
-        let result = check_grounded_variables(&clauses);
+        let result = check_grounded_variables(&clauses, &HashMap::new());
         assert!(result.is_ok());
         let a_sig = Signature(Predicate("a".into()), 2);
         let a_grounded = result.unwrap().get(&a_sig).unwrap().clone();
@@ -128,11 +224,51 @@ mod tests {
         assert!(!a_grounded[1]);
     }
 
+    #[test]
+    fn declared_groundness_replaces_inferred() {
+        let clauses: Vec<Clause> = vec!["a(X, Y) :- b(X), c(Y).".parse().unwrap()];
+        let a_sig = Signature(Predicate("a".into()), 2);
+        let modes = HashMap::from([(a_sig.clone(), vec![false, true])]);
+        let result = check_grounded_variables(&clauses, &modes).unwrap();
+        assert_eq!(result[&a_sig], [false, true]);
+    }
+
+    #[test]
+    fn checks_modes() {
+        let mf: modusfile::Modusfile = ":- mode version_of(+, -).
+            :- mode base(-).
+            version_of(\"alpine\", \"3.15\").
+            version_of(Name, V) :- string_concat(Name, \"-1\", V).
+            version_of(Name, V) :- string_eq(Name, \"debian\").
+            base(B) :- version_of(\"alpine\", B).
+            base(B) :- version_of(\"alpine\", V).
+            base(B) :- version_of(Name, B).
+            ok(B) :- version_of(N, B), string_eq(N, \"alpine\")."
+            .parse()
+            .unwrap();
+        let clauses = translate::translate_modusfile(&mf);
+        let errors: Vec<(String, usize)> = check_modes(&clauses, &mf.modes())
+            .into_iter()
+            .map(|e| match e {
+                ModeError::UngroundInput(lit, i) => (format!("input of {}", lit), i),
+                ModeError::UnboundOutput(lit, i) => (format!("output of {}", lit), i),
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                ("output of version_of(Name, V)".to_owned(), 1),
+                ("output of base(B)".to_owned(), 0),
+                ("input of version_of(Name, B)".to_owned(), 0),
+            ]
+        );
+    }
+
     #[test]
     fn groundness_after_translation() {
         let modus_clause: modusfile::ModusClause = "foo(X) :- bar(X) ; baz.".parse().unwrap();
         let clauses: Vec<Clause> = (&modus_clause).into();
-        let result = check_grounded_variables(&clauses);
+        let result = check_grounded_variables(&clauses, &HashMap::new());
         assert!(result.is_ok());
         let foo_sig = Signature(Predicate("foo".into()), 1);
         let foo_grounded = result.unwrap().get(&foo_sig).unwrap().clone();
@@ -0,0 +1,794 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Build backends, i.e. the tools used to run our buildkit frontend and to
+//! manage the resulting images.
+//!
+//! Modus only needs a handful of operations from such a tool: building a
+//! Dockerfile which points to our frontend, and tagging, removing, inspecting
+//! and pulling images. Each backend maps these to commands of a particular
+//! tool. Commands are returned rather than run, so that the caller can run
+//! builds in parallel.
+//!
+//! - `docker` uses the buildkit integration of `docker build`.
+//! - `podman` runs the build with `buildctl`, against a buildkitd instance
+//!   (e.g. one started with `podman run --privileged moby/buildkit`), and loads
+//!   the resulting images into Podman. Podman can't run buildkit frontends by
+//!   itself.
+//! - `buildctl` talks to a buildkitd instance directly, and keeps images in the
+//!   image store of buildkitd. There is no local image store in this case, so
+//!   base images are resolved by buildkit during the build.
+//! - `buildkitd` is like `buildctl`, but builds images by talking to buildkitd
+//!   over its gRPC API (see `buildkitd.rs`), solving the checks and all the
+//!   outputs in one session. `buildctl` is still used to export files.
+
+use std::{
+    fmt::Debug,
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::Arc,
+};
+
+pub const BACKEND_NAMES: &[&str] = &["docker", "podman", "buildctl", "buildkitd"];
+
+/// The address of buildkitd used by `buildctl` if `BUILDKIT_HOST` is not set.
+pub const DEFAULT_BUILDKIT_HOST: &str = "unix:///run/buildkit/buildkitd.sock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Docker,
+    Podman,
+    Buildctl,
+    Buildkitd,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(BackendKind::Docker),
+            "podman" => Ok(BackendKind::Podman),
+            "buildctl" => Ok(BackendKind::Buildctl),
+            "buildkitd" => Ok(BackendKind::Buildkitd),
+            _ => Err(format!(
+                "unknown backend {:?}, expected one of {}",
+                s,
+                BACKEND_NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl BackendKind {
+    /// Creates the backend, optionally overriding the path of the main executable.
+    pub fn backend(self, program: Option<&str>) -> Arc<dyn Backend> {
+        match self {
+            BackendKind::Docker => Arc::new(Docker {
+                program: program.unwrap_or("docker").to_owned(),
+            }),
+            BackendKind::Podman => Arc::new(Podman {
+                program: program.unwrap_or("podman").to_owned(),
+                buildctl: "buildctl".to_owned(),
+            }),
+            BackendKind::Buildctl => Arc::new(Buildctl {
+                program: program.unwrap_or("buildctl").to_owned(),
+            }),
+            BackendKind::Buildkitd => Arc::new(Buildkitd {
+                buildctl: Buildctl {
+                    program: program.unwrap_or("buildctl").to_owned(),
+                },
+                address: std::env::var("BUILDKIT_HOST")
+                    .ok()
+                    .filter(|a| !a.is_empty())
+                    .unwrap_or_else(|| DEFAULT_BUILDKIT_HOST.to_owned()),
+            }),
+        }
+    }
+}
+
+/// A build of a Dockerfile, which is expected to use our frontend.
+#[derive(Debug, Clone, Default)]
+pub struct BuildRequest<'a> {
+    /// Path to the Dockerfile, relative to the working directory of the build.
+    pub dockerfile: &'a str,
+    pub target: Option<String>,
+    /// Build arguments, which are passed on to the frontend.
+    pub build_args: Vec<(&'a str, String)>,
+    pub no_cache: bool,
+    /// File to write the ID of the built image to, read back with [`Backend::read_image_id`].
+    pub id_file: Option<&'a str>,
+    /// Directory to write the result to with the local exporter, instead of creating an image.
+    pub local_output: Option<&'a Path>,
+    pub quiet: bool,
+    pub verbose: bool,
+    pub additional_args: &'a [String],
+    /// The build context, which defaults to the current directory.
+    pub cwd: Option<&'a Path>,
+}
+
+pub trait Backend: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn build_command(&self, req: &BuildRequest) -> Command;
+
+    /// Reads the ID of an image built with [`BuildRequest::id_file`] set.
+    fn read_image_id(&self, id_file: &Path) -> std::io::Result<String> {
+        std::fs::read_to_string(id_file)
+    }
+
+    /// Whether builds can refer to images in the local image store. If so,
+    /// base images are resolved and tagged locally before the actual build.
+    fn builds_from_local_store(&self) -> bool;
+
+    // The following return None if the backend has no local image store.
+
+    fn tag_command(&self, image: &str, tag: &str) -> Option<Command>;
+
+    fn rm_command(&self, image: &str) -> Option<Command>;
+
+    /// A command which prints `format`, a Go template, for a local image, and
+    /// fails if there is no such image.
+    fn inspect_command(&self, image_ref: &str, format: &str) -> Option<Command>;
+
+    fn pull_command(&self, image_ref: &str) -> Option<Command>;
+
+    /// The address of buildkitd, if images are built by talking to it directly
+    /// rather than with [`Backend::build_command`].
+    fn buildkitd_address(&self) -> Option<&str> {
+        None
+    }
+}
+
+fn set_build_stdio(cmd: &mut Command, req: &BuildRequest) {
+    if let Some(cwd) = req.cwd {
+        cmd.current_dir(cwd);
+    }
+    cmd.stdin(Stdio::null())
+        .stdout(if req.quiet {
+            Stdio::null()
+        } else {
+            Stdio::inherit()
+        })
+        .stderr(Stdio::inherit());
+}
+
+fn simple_command(program: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    cmd
+}
+
+#[derive(Debug, Clone)]
+pub struct Docker {
+    program: String,
+}
+
+impl Backend for Docker {
+    fn name(&self) -> &'static str {
+        "docker"
+    }
+
+    fn build_command(&self, req: &BuildRequest) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(["build", ".", "-f", req.dockerfile]);
+        if req.no_cache {
+            cmd.arg("--no-cache");
+        }
+        for (k, v) in &req.build_args {
+            cmd.arg("--build-arg").arg(format!("{}={}", k, v));
+        }
+        if let Some(target) = &req.target {
+            cmd.args(["--target", target]);
+        }
+        if req.quiet {
+            cmd.arg("--quiet");
+        }
+        if let Some(id_file) = req.id_file {
+            cmd.args(["--iidfile", id_file]);
+        }
+        if let Some(dest) = req.local_output {
+            cmd.arg("--output")
+                .arg(format!("type=local,dest={}", dest.display()));
+        }
+        if req.verbose {
+            cmd.arg("--progress=plain");
+        }
+        cmd.args(req.additional_args);
+        set_build_stdio(&mut cmd, req);
+        cmd.env("DOCKER_BUILDKIT", "1");
+        cmd
+    }
+
+    fn builds_from_local_store(&self) -> bool {
+        true
+    }
+
+    fn tag_command(&self, image: &str, tag: &str) -> Option<Command> {
+        Some(simple_command(&self.program, &["tag", image, tag]))
+    }
+
+    fn rm_command(&self, image: &str) -> Option<Command> {
+        Some(simple_command(&self.program, &["image", "rm", image]))
+    }
+
+    fn inspect_command(&self, image_ref: &str, format: &str) -> Option<Command> {
+        Some(simple_command(
+            &self.program,
+            &["image", "inspect", "--format", format, image_ref],
+        ))
+    }
+
+    fn pull_command(&self, image_ref: &str) -> Option<Command> {
+        Some(simple_command(
+            &self.program,
+            &["pull", "--quiet", image_ref],
+        ))
+    }
+}
+
+/// Arguments of `buildctl build` for a request, except for the image output.
+fn buildctl_build_args(req: &BuildRequest) -> Vec<String> {
+    let dockerfile = Path::new(req.dockerfile);
+    let dockerfile_dir = match dockerfile.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let filename = dockerfile
+        .file_name()
+        .map(|f| f.to_string_lossy())
+        .unwrap_or_default();
+    let mut args = vec![
+        "build".to_owned(),
+        "--frontend".to_owned(),
+        "dockerfile.v0".to_owned(),
+        "--local".to_owned(),
+        "context=.".to_owned(),
+        "--local".to_owned(),
+        format!("dockerfile={}", dockerfile_dir.display()),
+        "--opt".to_owned(),
+        format!("filename={}", filename),
+    ];
+    if req.no_cache {
+        args.push("--no-cache".to_owned());
+    }
+    for (k, v) in &req.build_args {
+        args.push("--opt".to_owned());
+        args.push(format!("build-arg:{}={}", k, v));
+    }
+    if let Some(target) = &req.target {
+        args.push("--opt".to_owned());
+        args.push(format!("target={}", target));
+    }
+    if req.verbose {
+        args.push("--progress=plain".to_owned());
+    }
+    if let Some(id_file) = req.id_file {
+        args.push("--metadata-file".to_owned());
+        args.push(id_file.to_owned());
+    }
+    if let Some(dest) = req.local_output {
+        args.push("--output".to_owned());
+        args.push(format!("type=local,dest={}", dest.display()));
+    }
+    args
+}
+
+/// Reads the image config digest, which is what docker and podman use as image
+/// ID, from the metadata file written by `buildctl build`.
+fn read_buildctl_metadata(id_file: &Path) -> std::io::Result<String> {
+    let content = std::fs::read_to_string(id_file)?;
+    let metadata: serde_json::Value = serde_json::from_str(&content)?;
+    metadata
+        .get("containerimage.config.digest")
+        .and_then(|d| d.as_str())
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "no image digest in buildctl metadata",
+            )
+        })
+}
+
+#[derive(Debug, Clone)]
+pub struct Podman {
+    program: String,
+    buildctl: String,
+}
+
+impl Backend for Podman {
+    fn name(&self) -> &'static str {
+        "podman"
+    }
+
+    fn build_command(&self, req: &BuildRequest) -> Command {
+        let mut args = buildctl_build_args(req);
+        let mut cmd;
+        if req.local_output.is_some() {
+            cmd = Command::new(&self.buildctl);
+        } else {
+            // The docker exporter writes a tarball to stdout, which is loaded into Podman.
+            // The exit status of buildctl is passed through fd 3, so that a failed build
+            // is not masked by podman load (`set -o pipefail` is not supported by every sh).
+            args.push("--output".to_owned());
+            args.push("type=docker".to_owned());
+            cmd = Command::new("sh");
+            cmd.arg("-c")
+                .arg(concat!(
+                    r#"status=$( { { "$0" "$@"; echo $? >&3; } | "$MODUS_PODMAN" load --quiet >/dev/null; } 3>&1 ); "#,
+                    r#"loaded=$?; [ "$status" = 0 ] || exit "$status"; exit "$loaded""#
+                ))
+                .arg(&self.buildctl)
+                .env("MODUS_PODMAN", &self.program);
+        }
+        cmd.args(args).args(req.additional_args);
+        set_build_stdio(&mut cmd, req);
+        cmd
+    }
+
+    fn read_image_id(&self, id_file: &Path) -> std::io::Result<String> {
+        read_buildctl_metadata(id_file)
+    }
+
+    fn builds_from_local_store(&self) -> bool {
+        false
+    }
+
+    fn tag_command(&self, image: &str, tag: &str) -> Option<Command> {
+        Some(simple_command(&self.program, &["tag", image, tag]))
+    }
+
+    fn rm_command(&self, image: &str) -> Option<Command> {
+        Some(simple_command(&self.program, &["image", "rm", image]))
+    }
+
+    fn inspect_command(&self, image_ref: &str, format: &str) -> Option<Command> {
+        Some(simple_command(
+            &self.program,
+            &["image", "inspect", "--format", format, image_ref],
+        ))
+    }
+
+    fn pull_command(&self, image_ref: &str) -> Option<Command> {
+        Some(simple_command(
+            &self.program,
+            &["pull", "--quiet", image_ref],
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Buildctl {
+    program: String,
+}
+
+impl Backend for Buildctl {
+    fn name(&self) -> &'static str {
+        "buildctl"
+    }
+
+    fn build_command(&self, req: &BuildRequest) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(buildctl_build_args(req));
+        if req.local_output.is_none() && req.id_file.is_some() {
+            // Keep the image in the image store of buildkitd, so that it has a digest.
+            cmd.args(["--output", "type=image"]);
+        }
+        cmd.args(req.additional_args);
+        set_build_stdio(&mut cmd, req);
+        cmd
+    }
+
+    fn read_image_id(&self, id_file: &Path) -> std::io::Result<String> {
+        read_buildctl_metadata(id_file)
+    }
+
+    fn builds_from_local_store(&self) -> bool {
+        false
+    }
+
+    fn tag_command(&self, _image: &str, _tag: &str) -> Option<Command> {
+        None
+    }
+
+    fn rm_command(&self, _image: &str) -> Option<Command> {
+        None
+    }
+
+    fn inspect_command(&self, _image_ref: &str, _format: &str) -> Option<Command> {
+        None
+    }
+
+    fn pull_command(&self, _image_ref: &str) -> Option<Command> {
+        None
+    }
+}
+
+/// `buildctl`, except that images are built by talking to buildkitd directly.
+/// Both use `BUILDKIT_HOST` as the address of buildkitd.
+#[derive(Debug, Clone)]
+pub struct Buildkitd {
+    buildctl: Buildctl,
+    address: String,
+}
+
+impl Backend for Buildkitd {
+    fn name(&self) -> &'static str {
+        "buildkitd"
+    }
+
+    fn build_command(&self, req: &BuildRequest) -> Command {
+        self.buildctl.build_command(req)
+    }
+
+    fn read_image_id(&self, id_file: &Path) -> std::io::Result<String> {
+        self.buildctl.read_image_id(id_file)
+    }
+
+    fn builds_from_local_store(&self) -> bool {
+        false
+    }
+
+    fn tag_command(&self, _image: &str, _tag: &str) -> Option<Command> {
+        None
+    }
+
+    fn rm_command(&self, _image: &str) -> Option<Command> {
+        None
+    }
+
+    fn inspect_command(&self, _image_ref: &str, _format: &str) -> Option<Command> {
+        None
+    }
+
+    fn pull_command(&self, _image_ref: &str) -> Option<Command> {
+        None
+    }
+
+    fn buildkitd_address(&self) -> Option<&str> {
+        Some(&self.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buildkit::{self, gen_tmp_filename, BuildOptions, BuiltImage, DockerBuildOptions};
+    use crate::reporting::Profiling;
+    use modus_lib::imagegen::{BuildNode, BuildPlan, Output};
+    use std::path::PathBuf;
+
+    #[test]
+    fn buildctl_args() {
+        let args = buildctl_build_args(&BuildRequest {
+            dockerfile: "/tmp/ctx/a.Dockerfile",
+            target: Some("1".to_owned()),
+            build_args: vec![("no_cache", "false".to_owned())],
+            id_file: Some("a.iid"),
+            ..Default::default()
+        });
+        assert_eq!(
+            args.join(" "),
+            "build --frontend dockerfile.v0 --local context=. --local dockerfile=/tmp/ctx \
+             --opt filename=a.Dockerfile --opt build-arg:no_cache=false --opt target=1 \
+             --metadata-file a.iid"
+        );
+    }
+
+    /// A failed buildctl must fail the build even though podman load, at the end
+    /// of the pipeline, succeeds.
+    #[cfg(unix)]
+    #[test]
+    fn podman_build_fails_with_buildctl() {
+        let status = |buildctl: &str, podman: &str| {
+            let backend = Podman {
+                program: podman.to_owned(),
+                buildctl: buildctl.to_owned(),
+            };
+            backend
+                .build_command(&BuildRequest {
+                    dockerfile: "/tmp/ctx/a.Dockerfile",
+                    quiet: true,
+                    ..Default::default()
+                })
+                .status()
+                .unwrap()
+        };
+        assert!(status("true", "true").success());
+        assert!(!status("false", "true").success());
+        assert!(!status("true", "false").success());
+    }
+
+    /// Writes a fake docker to `dir`, which records its arguments in `dir/log`,
+    /// runs `prelude`, and pretends that every build produces the same image.
+    #[cfg(unix)]
+    fn fake_docker(dir: &Path, prelude: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let program = dir.join("fake-docker");
+        std::fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\n\
+                 echo \"$@\" >> {}\n\
+                 {}\n\
+                 while [ $# -gt 0 ]; do\n\
+                 if [ \"$1\" = --iidfile ]; then printf sha256:fake > \"$2\"; fi\n\
+                 shift\n\
+                 done\n",
+                dir.join("log").display(),
+                prelude
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        program
+    }
+
+    #[cfg(unix)]
+    fn options(dir: &Path, program: &Path, keep_going: bool) -> BuildOptions {
+        BuildOptions {
+            frontend_image: "frontend".to_owned(),
+            resolve_concurrency: 1,
+            export_concurrency: 1,
+            offline: false,
+            export_dir: dir.to_owned(),
+            backend: BackendKind::Docker.backend(program.to_str()),
+            docker_build_options: DockerBuildOptions::default(),
+            keep_going,
+            state_file: None,
+        }
+    }
+
+    #[cfg(unix)]
+    fn alpine_plan(outputs: &[&str]) -> BuildPlan {
+        let mut plan = BuildPlan::new();
+        let node = plan.new_node(
+            BuildNode::From {
+                image_ref: "alpine".to_owned(),
+                display_name: "alpine".to_owned(),
+            },
+            vec![],
+        );
+        for output in outputs {
+            plan.outputs.push(Output {
+                node,
+                source_literal: Some(output.parse().unwrap()),
+            });
+        }
+        plan
+    }
+
+    /// Runs a build against a fake docker.
+    #[cfg(unix)]
+    #[test]
+    fn build_with_fake_backend() {
+        let dir = std::env::temp_dir().join(gen_tmp_filename());
+        std::fs::create_dir(&dir).unwrap();
+        let program = fake_docker(&dir, "");
+
+        let options = options(&dir, &program, false);
+        let ids = buildkit::build(
+            alpine_plan(&["a"]),
+            &dir,
+            &options,
+            &mut Profiling::default(),
+        )
+        .unwrap()
+        .outputs;
+        assert_eq!(
+            ids.into_iter().collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![BuiltImage {
+                id: "sha256:fake".to_owned(),
+                up_to_date: false,
+            }]
+        );
+
+        let log = std::fs::read_to_string(dir.join("log")).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("image inspect --format "));
+        assert!(lines[1].starts_with("build . -f "));
+        assert_eq!(lines[2], "tag sha256:fake modus_tmp_tag_sha256:fake");
+        assert!(lines[3].contains("--build-arg has_dockerignore=false --iidfile"));
+        assert_eq!(lines[4], "image rm modus_tmp_tag_sha256:fake");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// The build of every output except the first fails, and so does the main
+    /// build of all of them together.
+    #[cfg(unix)]
+    #[test]
+    fn keep_going_after_failure() {
+        let dir = std::env::temp_dir().join(gen_tmp_filename());
+        std::fs::create_dir(&dir).unwrap();
+        let program = fake_docker(
+            &dir,
+            // Resolving images is quiet and has no target, unlike the main build.
+            "if [ \"$1\" = build ]; then case \" $* \" in \
+             *\" --target 0 \"*) ;; *\" --target \"*) exit 1;; *\" --quiet \"*) ;; *) exit 1;; \
+             esac; fi",
+        );
+        let plan = alpine_plan(&["a", "b", "c"]);
+
+        let res = buildkit::build(
+            plan.clone(),
+            &dir,
+            &options(&dir, &program, false),
+            &mut Profiling::default(),
+        );
+        assert!(matches!(
+            res,
+            Err(buildkit::BuildError::DockerBuildFailed(_))
+        ));
+
+        let res = buildkit::build(
+            plan,
+            &dir,
+            &options(&dir, &program, true),
+            &mut Profiling::default(),
+        )
+        .unwrap()
+        .outputs;
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].as_ref().unwrap().id, "sha256:fake");
+        assert!(matches!(
+            res[1],
+            Err(buildkit::BuildError::DockerBuildFailed(_))
+        ));
+        assert!(matches!(
+            res[2],
+            Err(buildkit::BuildError::DockerBuildFailed(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Only the check fails, so the outputs are built and the check is reported
+    /// in the JSON result.
+    #[cfg(unix)]
+    #[test]
+    fn keep_going_after_failed_check() {
+        let dir = std::env::temp_dir().join(gen_tmp_filename());
+        std::fs::create_dir(&dir).unwrap();
+        let program = fake_docker(
+            &dir,
+            "if [ \"$1\" = build ]; then case \" $* \" in \
+             *\" --target check-\"*) exit 1;; *\" --target \"*) ;; *\" --quiet \"*) ;; \
+             *) exit 1;; esac; fi",
+        );
+        let mut plan = alpine_plan(&["a", "b"]);
+        let check = plan.new_node(
+            BuildNode::Run {
+                parent: plan.outputs[0].node,
+                command: "make test".to_owned(),
+                cwd: "/".to_owned(),
+                additional_envs: Default::default(),
+            },
+            vec![plan.outputs[0].node],
+        );
+        plan.checks.push(check);
+
+        let res = buildkit::build(
+            plan.clone(),
+            &dir,
+            &options(&dir, &program, true),
+            &mut Profiling::default(),
+        )
+        .unwrap();
+        assert!(res.outputs.iter().all(Result::is_ok));
+        assert_eq!(res.failed_checks.len(), 1);
+        assert_eq!(res.failed_checks[0].0, check);
+
+        let mut json = Vec::new();
+        crate::reporting::write_build_result(&mut json, "json", &plan, &res).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 3);
+        assert_eq!(json[2]["status"], "failed");
+        assert!(json[2]["check"].as_str().unwrap().contains("make test"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Outputs recorded in the state file are skipped until their inputs change.
+    #[cfg(unix)]
+    #[test]
+    fn skip_unchanged_outputs() {
+        let dir = std::env::temp_dir().join(gen_tmp_filename());
+        std::fs::create_dir(&dir).unwrap();
+        let program = fake_docker(&dir, "");
+        let options = BuildOptions {
+            state_file: Some(dir.join("state.json")),
+            ..options(&dir, &program, false)
+        };
+        let build = |plan: &BuildPlan| -> Vec<bool> {
+            buildkit::build(plan.clone(), &dir, &options, &mut Profiling::default())
+                .unwrap()
+                .outputs
+                .into_iter()
+                .map(|r| r.unwrap().up_to_date)
+                .collect()
+        };
+        let builds = || {
+            std::fs::read_to_string(dir.join("log"))
+                .unwrap()
+                .lines()
+                .filter(|l| l.starts_with("build "))
+                .count()
+        };
+
+        let mut plan = alpine_plan(&["a", "b"]);
+        assert_eq!(build(&plan), vec![false, false]);
+        let nb_builds = builds();
+        assert_eq!(build(&plan), vec![true, true]);
+        // Resolving the base image is the only build.
+        assert_eq!(builds(), nb_builds + 1);
+
+        let node = plan.new_node(
+            BuildNode::Run {
+                parent: plan.outputs[1].node,
+                command: "true".to_owned(),
+                cwd: "/".to_owned(),
+                additional_envs: Default::default(),
+            },
+            vec![plan.outputs[1].node],
+        );
+        plan.outputs[1].node = node;
+        assert_eq!(build(&plan), vec![true, false]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Checks on up to date outputs are skipped with them, so nothing is built.
+    #[cfg(unix)]
+    #[test]
+    fn skip_checks_of_unchanged_outputs() {
+        let dir = std::env::temp_dir().join(gen_tmp_filename());
+        std::fs::create_dir(&dir).unwrap();
+        let program = fake_docker(&dir, "");
+        let options = BuildOptions {
+            state_file: Some(dir.join("state.json")),
+            ..options(&dir, &program, false)
+        };
+        let builds = || {
+            std::fs::read_to_string(dir.join("log"))
+                .unwrap()
+                .lines()
+                .filter(|l| l.starts_with("build "))
+                .count()
+        };
+
+        let mut plan = alpine_plan(&["a"]);
+        let check = plan.new_node(
+            BuildNode::Run {
+                parent: plan.outputs[0].node,
+                command: "make test".to_owned(),
+                cwd: "/".to_owned(),
+                additional_envs: Default::default(),
+            },
+            vec![plan.outputs[0].node],
+        );
+        plan.checks.push(check);
+        buildkit::build(plan.clone(), &dir, &options, &mut Profiling::default()).unwrap();
+        let nb_builds = builds();
+        let res = buildkit::build(plan, &dir, &options, &mut Profiling::default())
+            .unwrap()
+            .outputs;
+        assert!(res[0].as_ref().unwrap().up_to_date);
+        // Resolving the base image is the only build.
+        assert_eq!(builds(), nb_builds + 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -60,12 +60,16 @@ use std::{
     fs::OpenOptions,
     path::{Path, PathBuf},
     process::{Command, ExitStatus, Stdio},
+    sync::Arc,
     time::Instant,
 };
 
 use spawn_wait::{ProcessSet, SignalHandler};
 
-use modus_lib::imagegen::{BuildNode, BuildPlan, Output};
+use modus_lib::imagegen::{
+    BuildNode, BuildPlan, NodeId, Output, BUILD_PLAN_VERSION, PLAN_VERSION_LABEL,
+};
+use modus_lib::planview::output_names;
 
 use colored::Colorize;
 use rand::{
@@ -103,6 +107,40 @@ pub enum BuildError {
     UnableToWriteTmpFile(String, #[source] std::io::Error),
     #[error("Unable to read {0}: {1}")]
     UnableToReadTmpFile(String, #[source] std::io::Error),
+    #[error("Exporting files with docker build exited with code {0}.")]
+    ExportFailed(ExitStatus),
+    #[error("Unable to read lockfile {0}: {1}")]
+    UnableToReadLockfile(String, #[source] std::io::Error),
+    #[error("Unable to write lockfile {0}: {1}")]
+    UnableToWriteLockfile(String, #[source] std::io::Error),
+    #[error("Invalid lockfile {0}: {1}")]
+    InvalidLockfile(String, String),
+    #[error("Unable to read state file {0}: {1}")]
+    UnableToReadStateFile(String, #[source] std::io::Error),
+    #[error("Unable to write state file {0}: {1}")]
+    UnableToWriteStateFile(String, #[source] std::io::Error),
+    #[error("Invalid state file {0}: {1}")]
+    InvalidStateFile(String, String),
+    #[error("Unable to read {0} in the context: {1}")]
+    UnableToReadContextFile(String, #[source] std::io::Error),
+    #[error("The following images are not in the lockfile, run modus lock to add them: {0}")]
+    ImagesNotLocked(String),
+    #[error("docker pull {0} exited with code {1}.")]
+    DockerPullFailed(String, ExitStatus),
+    #[error("docker image inspect {0} exited with code {1}.")]
+    DockerInspectFailed(String, ExitStatus),
+    #[error("Invalid config for image {0}: {1}")]
+    InvalidImageConfig(String, String),
+    #[error("The frontend {0} accepts build plans of version {1}, but this version of modus creates plans of version {}. Use a frontend matching this version of modus.", BUILD_PLAN_VERSION)]
+    IncompatibleFrontend(String, String),
+    #[error("Could not find a registry digest for {0}.")]
+    NoDigestForImage(String),
+    #[error(
+        "The following images are not available locally, which is required in offline mode: {0}"
+    )]
+    ImagesNotAvailableOffline(String),
+    #[error("The {0} backend does not support {1}.")]
+    UnsupportedByBackend(&'static str, &'static str),
     #[error("Could not resolve {0}: docker build returned {1}")]
     CouldNotResolveImage(String, ExitStatus),
     #[error("{0}")]
@@ -111,13 +149,20 @@ pub enum BuildError {
         #[source]
         std::io::Error,
     ),
+    #[error("Unable to connect to buildkitd at {0}: {1}")]
+    BuildkitdUnavailable(String, #[source] std::io::Error),
+    #[error("buildkitd: {0}")]
+    BuildkitdFailed(String),
     #[error("Interrupted by user.")]
     Interrupted,
 }
 
 use BuildError::*;
 
+use crate::backend::{Backend, BuildRequest};
+use crate::buildkitd;
 use crate::reporting::Profiling;
+use crate::state::{image_exists, output_hashes, BuildState};
 
 #[derive(Debug, Clone, Default)]
 pub struct DockerBuildOptions {
@@ -132,72 +177,45 @@ pub struct BuildOptions {
     pub frontend_image: String,
     pub resolve_concurrency: u32,
     pub export_concurrency: u32,
+    /// Only use images which are available locally.
+    pub offline: bool,
+    /// Absolute path of the directory to write exported files to.
+    pub export_dir: PathBuf,
+    pub backend: Arc<dyn Backend>,
     pub docker_build_options: DockerBuildOptions,
+    /// Keep building the other outputs if one of them fails.
+    pub keep_going: bool,
+    /// Absolute path of the state file recording the inputs of the images
+    /// built, to skip outputs which did not change.
+    pub state_file: Option<PathBuf>,
 }
 
 fn make_buildkit_command(
+    backend: &dyn Backend,
     dockerfile: &str,
-    tag: Option<String>,
     target: Option<String>,
     has_dockerignore: bool,
     iidfile: Option<&str>,
     options: &DockerBuildOptions,
     cwd: Option<&Path>,
 ) -> Command {
-    let mut args = Vec::new();
-    args.push("build".to_string());
-    args.push(".".to_string());
-    args.push("-f".to_string());
-    args.push(dockerfile.to_owned());
-    if let Some(tag) = tag {
-        args.push("-t".to_string());
-        args.push(tag);
-    }
-    if options.no_cache {
-        args.push("--no-cache".to_string());
+    backend.build_command(&BuildRequest {
+        dockerfile,
+        target,
         // Sometimes it isn't enough to just use --no-cache, so we also tell our frontend
         // to issue ignore_cache.
-        args.push("--build-arg".to_string());
-        args.push("no_cache=true".to_string());
-    } else {
-        args.push("--build-arg".to_string());
-        args.push("no_cache=false".to_string());
-    }
-    if let Some(target) = target {
-        args.push("--target".to_string());
-        args.push(target);
-    }
-    if options.quiet {
-        args.push("--quiet".to_string());
-    }
-    args.push("--build-arg".to_string());
-    if has_dockerignore {
-        args.push("has_dockerignore=true".to_string());
-    } else {
-        args.push("has_dockerignore=false".to_string());
-    }
-    if let Some(iidfile) = iidfile {
-        args.push("--iidfile".to_string());
-        args.push(iidfile.to_owned());
-    }
-    if options.verbose {
-        args.push("--progress=plain".to_string());
-    }
-    args.extend_from_slice(&options.additional_args);
-    let mut cmd = Command::new("docker");
-    cmd.args(args);
-    if let Some(cwd) = cwd {
-        cmd.current_dir(cwd);
-    }
-    cmd.stdin(Stdio::null())
-        .stdout(if options.quiet {
-            Stdio::null()
-        } else {
-            Stdio::inherit()
-        })
-        .stderr(Stdio::inherit())
-        .env("DOCKER_BUILDKIT", "1");
-    cmd
+        build_args: vec![
+            ("no_cache", options.no_cache.to_string()),
+            ("has_dockerignore", has_dockerignore.to_string()),
+        ],
+        no_cache: options.no_cache,
+        id_file: iidfile,
+        local_output: None,
+        quiet: options.quiet,
+        verbose: options.verbose,
+        additional_args: &options.additional_args,
+        cwd,
+    })
 }
 
 /// A holder for a file name that deletes the file when dropped.
@@ -205,6 +223,8 @@ struct AutoDeleteTmpFilename(String);
 /// A holder for a directory in std::env::temp_dir() that deletes the directory when dropped.
 struct AutoRmTmpDir(PathBuf);
 pub const TMP_PREFIX: &str = "modus_temp_";
+/// Prefix of the temporary tags given to resolved images, followed by the image ID.
+const TMP_TAG_PREFIX: &str = "modus_tmp_tag_";
 pub const TMP_PREFIX_IGNORE_PATTERN: &str = "modus_temp_*";
 
 pub fn gen_tmp_filename() -> String {
@@ -356,7 +376,7 @@ fn resolve_froms(
     build_plan: &mut BuildPlan,
     build_options: &BuildOptions,
     sh: &mut SignalHandler,
-    image_cleanup: &mut DockerImageRmOnDrop,
+    image_cleanup: &mut ImageRmOnDrop,
 ) -> Result<(), BuildError> {
     #[derive(Debug, Clone, PartialEq, Eq, Hash)]
     enum ImageToResolve {
@@ -368,7 +388,9 @@ fn resolve_froms(
         .nodes
         .iter()
         .filter_map(|x| match x {
-            BuildNode::From { image_ref, .. } if !image_ref_is_hash(image_ref) => {
+            BuildNode::From { image_ref, .. }
+                if !image_ref_is_hash(image_ref) && !image_ref.starts_with(TMP_TAG_PREFIX) =>
+            {
                 Some(ImageToResolve::Ref(image_ref.to_owned()))
             }
             BuildNode::FromScratch { scratch_ref } => {
@@ -434,10 +456,7 @@ fn resolve_froms(
                 content.push_str("#syntax=");
                 content.push_str(&build_options.frontend_image);
                 content.push('\n');
-                content.push_str(
-                    &serde_json::to_string(&tmp_plan)
-                        .expect("Unable to serialize temporary build plan"),
-                );
+                content.push_str(&tmp_plan.to_json());
                 content
             }
             ImageToResolve::Scratch => "FROM scratch".to_owned(),
@@ -448,9 +467,9 @@ fn resolve_froms(
         std::fs::write(&dockerfile, content.as_bytes())
             .map_err(|e| BuildError::UnableToWriteTmpFile(dockerfile.display().to_string(), e))?;
         let cmd = make_buildkit_command(
+            &*build_options.backend,
             dockerfile.to_str().expect("path to be utf-8"),
             None,
-            None,
             false,
             Some(iidfile.to_str().expect("path to be utf-8")),
             &DockerBuildOptions {
@@ -491,17 +510,14 @@ fn resolve_froms(
                     return Err(CouldNotResolveImage(orig_str_repr.to_owned(), exit_status));
                 }
                 nb_done += 1;
-                let resolved = std::fs::read_to_string(&t.iidfile)
+                let resolved = build_options
+                    .backend
+                    .read_image_id(&t.iidfile)
                     .map_err(|e| UnableToReadTmpFile(t.iidfile.display().to_string(), e))?;
-                let tmp_tag = format!("modus_tmp_tag_{}", &resolved);
+                let tmp_tag = format!("{}{}", TMP_TAG_PREFIX, &resolved);
                 // tmp_tag is going to be something like modus_tmp_tag_sha256:1234....
                 // This is very much intentional.
-                let st = Command::new("docker")
-                    .args(&["tag", &resolved, &tmp_tag])
-                    .status()?;
-                if !st.success() {
-                    return Err(BuildError::DockerTagFailed(resolved, tmp_tag, st));
-                }
+                tag_image(&*build_options.backend, &resolved, &tmp_tag)?;
                 image_cleanup.add(tmp_tag.clone());
 
                 debug_assert!(!orig_to_resolved_tag.contains_key(&t.to_resolve));
@@ -556,105 +572,445 @@ fn resolve_froms(
     Ok(())
 }
 
-#[derive(Debug, Default)]
-struct DockerImageRmOnDrop(HashSet<String>);
+fn tag_image(backend: &dyn Backend, image: &str, tag: &str) -> Result<(), BuildError> {
+    let mut cmd = backend
+        .tag_command(image, tag)
+        .ok_or(UnsupportedByBackend(backend.name(), "tagging images"))?;
+    let st = cmd.status()?;
+    if !st.success() {
+        return Err(BuildError::DockerTagFailed(
+            image.to_owned(),
+            tag.to_owned(),
+            st,
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the ID of an image if it is in the local image store.
+fn local_image_id(backend: &dyn Backend, image_ref: &str) -> Result<Option<String>, BuildError> {
+    let out = backend
+        .inspect_command(image_ref, "{{.Id}}")
+        .ok_or(UnsupportedByBackend(backend.name(), "offline builds"))?
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if out.status.success() {
+        Ok(Some(String::from_utf8_lossy(&out.stdout).trim().to_owned()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The offline counterpart of `resolve_froms`, which resolves image references
+/// against the local image store only, instead of asking buildkit to pull them.
+///
+/// The frontend image also has to be available locally. All missing images are
+/// reported at once.
+fn resolve_froms_offline(
+    build_plan: &mut BuildPlan,
+    build_options: &BuildOptions,
+    image_cleanup: &mut ImageRmOnDrop,
+) -> Result<(), BuildError> {
+    let mut missing = Vec::new();
+    let backend = &*build_options.backend;
+    if !backend.builds_from_local_store() {
+        return Err(UnsupportedByBackend(backend.name(), "offline builds"));
+    }
+    if local_image_id(backend, &build_options.frontend_image)?.is_none() {
+        missing.push(build_options.frontend_image.clone());
+    }
+    let refs = build_plan
+        .nodes
+        .iter()
+        .filter_map(|x| match x {
+            BuildNode::From { image_ref, .. } => Some(image_ref.to_owned()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let mut orig_to_resolved_tag = HashMap::with_capacity(refs.len());
+    for image_ref in refs {
+        match local_image_id(backend, &image_ref)? {
+            Some(id) => {
+                let tmp_tag = format!("{}{}", TMP_TAG_PREFIX, id);
+                tag_image(&*build_options.backend, &id, &tmp_tag)?;
+                image_cleanup.add(tmp_tag.clone());
+                orig_to_resolved_tag.insert(image_ref, tmp_tag);
+            }
+            None => missing.push(image_ref),
+        }
+    }
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(ImagesNotAvailableOffline(missing.join(", ")));
+    }
+    for node in build_plan.nodes.iter_mut() {
+        if let BuildNode::From { image_ref, .. } = node {
+            *image_ref = orig_to_resolved_tag[image_ref].clone();
+        }
+    }
+    Ok(())
+}
+
+/// Checks the build plan version accepted by the frontend image, if it is
+/// available locally and has a version label. Otherwise, the frontend itself
+/// checks the version of the plan it gets.
+fn check_frontend_version(build_options: &BuildOptions) -> Result<(), BuildError> {
+    let format = format!("{{{{index .Config.Labels {:?}}}}}", PLAN_VERSION_LABEL);
+    let out = match build_options
+        .backend
+        .inspect_command(&build_options.frontend_image, &format)
+    {
+        Some(mut cmd) => cmd.stdin(Stdio::null()).stderr(Stdio::null()).output()?,
+        None => return Ok(()),
+    };
+    let version = String::from_utf8_lossy(&out.stdout).trim().to_owned();
+    // Missing labels are printed as "<no value>".
+    if !out.status.success() || version.is_empty() || version.starts_with('<') {
+        return Ok(());
+    }
+    if version != BUILD_PLAN_VERSION.to_string() {
+        return Err(IncompatibleFrontend(
+            build_options.frontend_image.clone(),
+            version,
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct ImageRmOnDrop(Arc<dyn Backend>, HashSet<String>);
+
+impl ImageRmOnDrop {
+    fn new(backend: Arc<dyn Backend>) -> Self {
+        Self(backend, HashSet::new())
+    }
 
-impl DockerImageRmOnDrop {
     pub fn add(&mut self, image_ref_or_tag: String) {
-        self.0.insert(image_ref_or_tag);
+        self.1.insert(image_ref_or_tag);
     }
 }
 
-impl Drop for DockerImageRmOnDrop {
+impl Drop for ImageRmOnDrop {
     fn drop(&mut self) {
-        eprintln!("Cleaning up {} temporary images and tags...", self.0.len());
-        for img in self.0.iter() {
-            let _ = Command::new("docker")
-                .args(&["image", "rm", img])
-                .stdout(Stdio::null())
-                .status();
+        eprintln!("Cleaning up {} temporary images and tags...", self.1.len());
+        for img in self.1.iter() {
+            if let Some(mut cmd) = self.0.rm_command(img) {
+                let _ = cmd.stdout(Stdio::null()).status();
+            }
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuiltImage {
+    pub id: String,
+    /// Whether the image was not built again, since it is recorded in the
+    /// state file with the same inputs.
+    pub up_to_date: bool,
+}
+
+/// The checks which failed, by their node in the build plan.
+pub type FailedChecks = Vec<(NodeId, BuildError)>;
+
+/// Outputs and checks only fail individually with `BuildOptions::keep_going`,
+/// otherwise the first failure fails the whole build.
+pub struct BuildResults {
+    /// The image of every output, following the order in build_plan.outputs.
+    pub outputs: Vec<Result<BuiltImage, BuildError>>,
+    pub failed_checks: FailedChecks,
+}
+
+/// The target given to the frontend to solve only one check, followed by its
+/// index in build_plan.checks. It can't be mistaken for the index of an output.
+pub const CHECK_TARGET_PREFIX: &str = "check-";
+
 /// Returns the image IDs on success, following the order in build_plan.outputs.
 pub fn build<P: AsRef<Path>>(
     mut build_plan: BuildPlan,
     context: P,
     build_options: &BuildOptions,
     profiling: &mut Profiling,
-) -> Result<Vec<String>, BuildError> {
+) -> Result<BuildResults, BuildError> {
     let mut sh = SignalHandler::default();
     let context = context.as_ref().canonicalize().map_err(CwdError)?;
     let previous_cwd = PathBuf::from(".").canonicalize().map_err(CwdError)?;
     let _restore_cwd = RestoreCwd(previous_cwd);
-    let mut image_cleanup = DockerImageRmOnDrop::default();
+    check_frontend_version(build_options)?;
+    // The state is keyed by the resolved base images, which only backends
+    // building from the local image store resolve before building.
+    if build_options.state_file.is_some() && !build_options.backend.builds_from_local_store() {
+        return Err(UnsupportedByBackend(
+            build_options.backend.name(),
+            "--state-file",
+        ));
+    }
+    let mut image_cleanup = ImageRmOnDrop::new(build_options.backend.clone());
     let resolving_start = Instant::now();
-    resolve_froms(&mut build_plan, build_options, &mut sh, &mut image_cleanup)?;
+    if build_options.offline {
+        resolve_froms_offline(&mut build_plan, build_options, &mut image_cleanup)?;
+    }
+    // In offline mode, this only needs to resolve scratch images, which does not require pulling anything.
+    // Backends which can't build from local images leave resolving to buildkit instead.
+    if build_options.backend.builds_from_local_store() {
+        resolve_froms(&mut build_plan, build_options, &mut sh, &mut image_cleanup)?;
+    }
     profiling.resolving_total = resolving_start.elapsed().as_secs_f32();
     std::env::set_current_dir(&context).map_err(EnterContextDir)?;
     let has_dockerignore = check_dockerignore()?;
+
+    let state = match &build_options.state_file {
+        Some(path) => Some((
+            BuildState::read(path)?,
+            output_hashes(&build_plan, &context, has_dockerignore)?,
+        )),
+        None => None,
+    };
+    let names = output_names(&build_plan);
+    let up_to_date: Vec<Option<String>> = match &state {
+        // With --no-cache, everything is built again, but the state is still updated.
+        Some((state, hashes)) if !build_options.docker_build_options.no_cache => names
+            .iter()
+            .zip(hashes)
+            .map(|(name, hash)| {
+                state
+                    .image(name, hash)
+                    .filter(|image| image_exists(&*build_options.backend, image))
+                    .map(ToOwned::to_owned)
+            })
+            .collect(),
+        _ => vec![None; build_plan.outputs.len()],
+    };
+    let nb_up_to_date = up_to_date.iter().filter(|x| x.is_some()).count();
+    if nb_up_to_date > 0 {
+        eprintln!(
+            "{}",
+            format!(
+                "{}/{} outputs are up to date, skipping them",
+                nb_up_to_date,
+                build_plan.outputs.len()
+            )
+            .blue()
+        );
+    }
+
+    let stale_plan = stale_plan(&build_plan, &up_to_date);
+    let nothing_to_build = stale_plan.outputs.is_empty()
+        && stale_plan.checks.is_empty()
+        && stale_plan.exports.is_empty();
+    let (built, failed_checks) = if nothing_to_build {
+        (Vec::new(), Vec::new())
+    } else {
+        build_outputs(
+            &stale_plan,
+            has_dockerignore,
+            build_options,
+            &mut sh,
+            &mut image_cleanup,
+            profiling,
+        )?
+    };
+    let mut built = built.into_iter();
+    let res: Vec<Result<BuiltImage, BuildError>> = up_to_date
+        .into_iter()
+        .map(|image| match image {
+            Some(id) => Ok(BuiltImage {
+                id,
+                up_to_date: true,
+            }),
+            None => built
+                .next()
+                .expect("Expected a result for every output")
+                .map(|id| BuiltImage {
+                    id,
+                    up_to_date: false,
+                }),
+        })
+        .collect();
+
+    if let (Some(path), Some((mut state, hashes))) = (&build_options.state_file, state) {
+        for ((name, hash), image) in names.into_iter().zip(hashes).zip(&res) {
+            if let Ok(image) = image {
+                state.record(name, hash, image.id.clone());
+            }
+        }
+        state.write(path)?;
+    }
+    Ok(BuildResults {
+        outputs: res,
+        failed_checks,
+    })
+}
+
+/// The plan building the outputs which are not up to date, with only the checks
+/// and exports on images they need. A check is on the images of the outputs it
+/// is built on top of.
+fn stale_plan(build_plan: &BuildPlan, up_to_date: &[Option<String>]) -> BuildPlan {
+    let mut stale_plan = build_plan.clone();
+    stale_plan.outputs = build_plan
+        .outputs
+        .iter()
+        .zip(up_to_date)
+        .filter(|(_, image)| image.is_none())
+        .map(|(output, _)| output.clone())
+        .collect();
+    let in_outputs: HashSet<NodeId> = build_plan
+        .nodes_needed_by(build_plan.outputs.iter().map(|o| o.node))
+        .into_iter()
+        .collect();
+    let needed: HashSet<NodeId> = build_plan
+        .nodes_needed_by(stale_plan.outputs.iter().map(|o| o.node))
+        .into_iter()
+        .collect();
+    stale_plan.checks.retain(|&check| {
+        let mut stack = vec![check];
+        let mut seen = HashSet::new();
+        while let Some(node) = stack.pop() {
+            if !seen.insert(node) {
+                continue;
+            }
+            if in_outputs.contains(&node) {
+                if needed.contains(&node) {
+                    return true;
+                }
+            } else {
+                stack.extend(build_plan.dependencies[node].iter().copied());
+            }
+        }
+        false
+    });
+    stale_plan
+        .exports
+        .retain(|export| needed.contains(&export.node));
+    stale_plan
+}
+
+/// Builds the outputs, checks and exports of the plan from the current
+/// directory, returning the image id of every output and the failed checks.
+fn build_outputs(
+    build_plan: &BuildPlan,
+    has_dockerignore: bool,
+    build_options: &BuildOptions,
+    sh: &mut SignalHandler,
+    image_cleanup: &mut ImageRmOnDrop,
+    profiling: &mut Profiling,
+) -> Result<(Vec<Result<String, BuildError>>, FailedChecks), BuildError> {
     let mut content = String::new();
     content.push_str("#syntax=");
     content.push_str(&build_options.frontend_image);
     content.push('\n');
-    content.push_str(&serde_json::to_string(&build_plan).expect("Unable to serialize build plan"));
+    content.push_str(&build_plan.to_json());
     if sh.termination_pending() {
         return Err(Interrupted);
     }
     let dockerfile = write_tmp_dockerfile(&content).map_err(UnableToCreateTempFile)?;
     use spawn_wait::WaitAnyResult::*;
-    eprintln!("{}", "Running docker build...".blue());
+    eprintln!(
+        "{}",
+        format!("Running {} build...", build_options.backend.name()).blue()
+    );
+    if let Some(address) = build_options.backend.buildkitd_address() {
+        let build_start = Instant::now();
+        let (outputs, failed_checks) = buildkitd::build_outputs(
+            address,
+            dockerfile.name(),
+            has_dockerignore,
+            build_plan,
+            build_options,
+            sh,
+        )?;
+        profiling.building = build_start.elapsed().as_secs_f32();
+        if !build_plan.exports.is_empty() {
+            if outputs.iter().all(Result::is_ok) && failed_checks.is_empty() {
+                export_files(dockerfile.name(), has_dockerignore, build_options, sh)?;
+            } else {
+                eprintln!("{}", "Not exporting files since the build failed".red());
+            }
+        }
+        return Ok((outputs, failed_checks));
+    }
     let main_img_iidfile = AutoDeleteTmpFilename::gen(".iid");
     let mut procs = ProcessSet::new();
     let build_start = Instant::now();
     procs.add_command(
         (),
         make_buildkit_command(
+            &*build_options.backend,
             dockerfile.name(),
             None,
-            None,
             has_dockerignore,
             Some(main_img_iidfile.name()),
             &build_options.docker_build_options,
             None,
         ),
     );
-    match procs.wait_any(&mut sh) {
+    let main_build_failure = match procs.wait_any(sh) {
         Subprocess(_, res) => {
             let (_, exit_status) = res.map_err(|e| UnableToRunDockerBuild(e))?;
             profiling.building = build_start.elapsed().as_secs_f32();
-            if !exit_status.success() {
+            if exit_status.success() {
+                None
+            } else if build_options.keep_going {
+                Some(exit_status)
+            } else {
                 return Err(DockerBuildFailed(exit_status));
             }
         }
         ReceivedTerminationSignal(_) => {
-            let _ = procs.sigint_all_and_wait(&mut sh);
+            let _ = procs.sigint_all_and_wait(sh);
             return Err(Interrupted);
         }
         NoProcessesRunning => unreachable!(),
+    };
+    let main_img_iid = match main_build_failure {
+        None => Some(
+            build_options
+                .backend
+                .read_image_id(Path::new(main_img_iidfile.name()))
+                .map_err(|e| UnableToReadTmpFile(main_img_iidfile.name().to_owned(), e))?,
+        ),
+        Some(exit_status) => {
+            // Some outputs may still build, so try each of them on its own.
+            eprintln!(
+                "{}",
+                format!(
+                    "Build failed with exit code {}, building each output separately",
+                    exit_status.code().unwrap_or(-1)
+                )
+                .red()
+            );
+            if !build_plan.exports.is_empty() {
+                eprintln!("{}", "Not exporting files since the build failed".red());
+            }
+            None
+        }
+    };
+    if main_img_iid.is_some() && !build_plan.exports.is_empty() {
+        export_files(dockerfile.name(), has_dockerignore, build_options, sh)?;
     }
-    let main_img_iid = std::fs::read_to_string(main_img_iidfile.name())
-        .map_err(|e| UnableToReadTmpFile(main_img_iidfile.name().to_owned(), e))?;
-    match build_plan.outputs.len() {
-        0 => unreachable!(), // not possible because if there is no solution to the initial query, there will be an SLD failure.
-        1 => Ok(vec![main_img_iid]),
-        nb_outputs => {
-            image_cleanup.add(main_img_iid.clone());
+    match (build_plan.outputs.len(), main_img_iid) {
+        // Only checks or exports are left to build.
+        (0, _) if main_build_failure.is_none() => Ok((Vec::new(), Vec::new())),
+        (1, Some(main_img_iid)) => Ok((vec![Ok(main_img_iid)], Vec::new())),
+        (nb_outputs, main_img_iid) => {
             let mut procs = ProcessSet::with_concurrency_limit(
                 build_options.export_concurrency.try_into().unwrap(),
             );
-            let mut res = vec![None; nb_outputs];
-            // Overwrite the last line printed by buildkit.
-            eprintln!("\x1b[1A\x1b[2K\r=== Build success, exporting individual images ===");
+            let mut res: Vec<Option<Result<String, BuildError>>> =
+                (0..nb_outputs).map(|_| None).collect();
+            if let Some(main_img_iid) = main_img_iid {
+                image_cleanup.add(main_img_iid);
+                // Overwrite the last line printed by buildkit.
+                eprintln!("\x1b[1A\x1b[2K\r=== Build success, exporting individual images ===");
+            }
             let mut iidfiles = Vec::with_capacity(nb_outputs);
             let exporting_start = Instant::now();
             for i in 0..nb_outputs {
                 let target_str = format!("{}", i);
                 let iidfile = AutoDeleteTmpFilename::gen(".iid");
                 let cmd = make_buildkit_command(
+                    &*build_options.backend,
                     dockerfile.name(),
-                    None,
                     Some(target_str),
                     has_dockerignore,
                     Some(iidfile.name()),
@@ -671,49 +1027,59 @@ pub fn build<P: AsRef<Path>>(
             }
             let mut nb_done = 0usize;
             loop {
-                match procs.wait_any(&mut sh) {
+                match procs.wait_any(sh) {
                     Subprocess(i, r) => {
                         let literal_str = build_plan.outputs[i]
                             .source_literal
                             .as_ref()
                             .expect("Expected source_literal to present in build plan")
                             .to_string();
-                        if let Err(err) = r {
-                            let _ = procs.sigint_all_and_wait(&mut sh);
-                            return Err(UnableToRunDockerBuild(err));
-                        }
-                        let exit_status = r.unwrap().1;
-                        if !exit_status.success() {
-                            eprintln!(
-                                "{}",
-                                format!(
-                                    "Exporting {} failed with exit code {}",
-                                    literal_str,
-                                    exit_status.code().unwrap_or(-1)
-                                )
-                                .red()
-                            );
-                            let _ = procs.sigint_all_and_wait(&mut sh);
-                            return Err(DockerBuildFailed(exit_status));
-                        }
-                        let iid = std::fs::read_to_string(iidfiles[i].name())
-                            .map_err(|e| UnableToReadTmpFile(iidfiles[i].name().to_owned(), e))?;
-                        res[i] = Some(iid);
+                        let exported =
+                            r.map_err(UnableToRunDockerBuild)
+                                .and_then(|(_, exit_status)| {
+                                    if !exit_status.success() {
+                                        eprintln!(
+                                            "{}",
+                                            format!(
+                                                "Exporting {} failed with exit code {}",
+                                                literal_str,
+                                                exit_status.code().unwrap_or(-1)
+                                            )
+                                            .red()
+                                        );
+                                        return Err(DockerBuildFailed(exit_status));
+                                    }
+                                    build_options
+                                        .backend
+                                        .read_image_id(Path::new(iidfiles[i].name()))
+                                        .map_err(|e| {
+                                            UnableToReadTmpFile(iidfiles[i].name().to_owned(), e)
+                                        })
+                                });
                         nb_done += 1;
-                        eprintln!(
-                            "{}",
-                            format!(
-                                "Exported {}/{}: {} -> {}",
-                                nb_done,
-                                nb_outputs,
-                                literal_str,
-                                res[i].as_ref().unwrap()
-                            )
-                            .blue()
-                        );
+                        match exported {
+                            Ok(iid) => {
+                                eprintln!(
+                                    "{}",
+                                    format!(
+                                        "Exported {}/{}: {} -> {}",
+                                        nb_done, nb_outputs, literal_str, iid
+                                    )
+                                    .blue()
+                                );
+                                res[i] = Some(Ok(iid));
+                            }
+                            Err(e) if build_options.keep_going => {
+                                res[i] = Some(Err(e));
+                            }
+                            Err(e) => {
+                                let _ = procs.sigint_all_and_wait(sh);
+                                return Err(e);
+                            }
+                        }
                     }
                     ReceivedTerminationSignal(_) => {
-                        let _ = procs.sigint_all_and_wait(&mut sh);
+                        let _ = procs.sigint_all_and_wait(sh);
                         return Err(Interrupted);
                     }
                     NoProcessesRunning => {
@@ -723,9 +1089,134 @@ pub fn build<P: AsRef<Path>>(
             }
             profiling.exporting_total = exporting_start.elapsed().as_secs_f32();
             debug_assert_eq!(nb_done, nb_outputs);
-            Ok(res.into_iter().map(|x| x.unwrap()).collect())
+            let res: Vec<Result<String, BuildError>> =
+                res.into_iter().map(|x| x.unwrap()).collect();
+            let failed_checks = match main_build_failure {
+                Some(_) => build_checks_separately(
+                    build_plan,
+                    dockerfile.name(),
+                    has_dockerignore,
+                    build_options,
+                    sh,
+                    image_cleanup,
+                )?,
+                None => Vec::new(),
+            };
+            match main_build_failure {
+                // Exports are not built when the build fails, so nothing else
+                // can have failed.
+                Some(exit_status) if res.iter().all(Result::is_ok) && failed_checks.is_empty() => {
+                    Err(DockerBuildFailed(exit_status))
+                }
+                _ => Ok((res, failed_checks)),
+            }
+        }
+    }
+}
+
+/// Builds every check on its own after a failed build with
+/// `BuildOptions::keep_going`, to find out which of them failed.
+fn build_checks_separately(
+    build_plan: &BuildPlan,
+    dockerfile: &str,
+    has_dockerignore: bool,
+    build_options: &BuildOptions,
+    sh: &mut SignalHandler,
+    image_cleanup: &mut ImageRmOnDrop,
+) -> Result<FailedChecks, BuildError> {
+    use spawn_wait::WaitAnyResult::*;
+    let mut procs =
+        ProcessSet::with_concurrency_limit(build_options.export_concurrency.try_into().unwrap());
+    let mut iidfiles = Vec::with_capacity(build_plan.checks.len());
+    for i in 0..build_plan.checks.len() {
+        let iidfile = AutoDeleteTmpFilename::gen(".iid");
+        let cmd = make_buildkit_command(
+            &*build_options.backend,
+            dockerfile,
+            Some(format!("{}{}", CHECK_TARGET_PREFIX, i)),
+            has_dockerignore,
+            Some(iidfile.name()),
+            &DockerBuildOptions {
+                no_cache: false,
+                verbose: false,
+                quiet: true,
+                ..build_options.docker_build_options.clone()
+            },
+            None,
+        );
+        iidfiles.push(iidfile);
+        procs.add_command(i, cmd);
+    }
+    let mut failed = Vec::new();
+    loop {
+        match procs.wait_any(sh) {
+            Subprocess(i, r) => match r.map_err(UnableToRunDockerBuild)? {
+                (_, exit_status) if exit_status.success() => {
+                    if let Ok(iid) = build_options
+                        .backend
+                        .read_image_id(Path::new(iidfiles[i].name()))
+                    {
+                        image_cleanup.add(iid);
+                    }
+                }
+                (_, exit_status) => failed.push((i, DockerBuildFailed(exit_status))),
+            },
+            ReceivedTerminationSignal(_) => {
+                let _ = procs.sigint_all_and_wait(sh);
+                return Err(Interrupted);
+            }
+            NoProcessesRunning => break,
+        }
+    }
+    failed.sort_by_key(|(i, _)| *i);
+    Ok(failed
+        .into_iter()
+        .map(|(i, e)| (build_plan.checks[i], e))
+        .collect())
+}
+
+/// Invokes docker build again with the local exporter of buildkit, which writes
+/// the files to export into the export directory. Everything should be cached
+/// at this point.
+fn export_files(
+    dockerfile: &str,
+    has_dockerignore: bool,
+    build_options: &BuildOptions,
+    sh: &mut SignalHandler,
+) -> Result<(), BuildError> {
+    use spawn_wait::WaitAnyResult::*;
+    let cmd = build_options.backend.build_command(&BuildRequest {
+        dockerfile,
+        build_args: vec![
+            ("no_cache", "false".to_owned()),
+            ("has_dockerignore", has_dockerignore.to_string()),
+            ("export", "true".to_owned()),
+        ],
+        local_output: Some(&build_options.export_dir),
+        quiet: true,
+        additional_args: &build_options.docker_build_options.additional_args,
+        ..Default::default()
+    });
+    let mut procs = ProcessSet::new();
+    procs.add_command((), cmd);
+    match procs.wait_any(sh) {
+        Subprocess(_, res) => {
+            let (_, exit_status) = res.map_err(UnableToRunDockerBuild)?;
+            if !exit_status.success() {
+                return Err(ExportFailed(exit_status));
+            }
+        }
+        ReceivedTerminationSignal(_) => {
+            let _ = procs.sigint_all_and_wait(sh);
+            return Err(Interrupted);
         }
+        NoProcessesRunning => unreachable!(),
     }
+    eprintln!(
+        "{}",
+        format!("Exported files to {}", build_options.export_dir.display()).blue()
+    );
+    Ok(())
 }
 
 pub fn check_dockerignore() -> Result<bool, BuildError> {
@@ -0,0 +1,1223 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A client of buildkitd, used by the `buildkitd` backend to build images
+//! without going through `docker build` or `buildctl`.
+//!
+//! All the solves of a build share one session, through which buildkitd pulls
+//! the Dockerfile and the context, so the context is only sent once. The main
+//! solve, which builds everything, runs alongside one solve per output that
+//! exports its image, and buildkitd shares the work between them. Progress is
+//! read from the status stream of the main solve.
+//!
+//! The session is a gRPC stream which tunnels a HTTP/2 connection the other
+//! way round: buildkitd is the client and calls the file sync and health
+//! check services we serve over it.
+//!
+//! Only the few messages and fields we use are defined here, with the tags of
+//! the protobuf definitions of buildkit.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    convert::TryInto,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use colored::Colorize;
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    ready, stream, SinkExt, StreamExt, TryStreamExt,
+};
+use h2::{client::ResponseFuture, server::SendResponse, RecvStream, SendStream};
+use http::{HeaderMap, HeaderValue, Request, Response};
+use modus_lib::imagegen::BuildPlan;
+use prost::Message;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use spawn_wait::SignalHandler;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::buildkit::{BuildError, BuildOptions, FailedChecks, CHECK_TARGET_PREFIX};
+use crate::filesync::{self, Filter, Packet};
+
+const SOLVE: &str = "/moby.buildkit.v1.Control/Solve";
+const STATUS: &str = "/moby.buildkit.v1.Control/Status";
+const SESSION: &str = "/moby.buildkit.v1.Control/Session";
+const HEALTH_CHECK: &str = "/grpc.health.v1.Health/Check";
+const DIFF_COPY: &str = "/moby.filesync.v1.FileSync/DiffCopy";
+
+/// How long to wait for the last progress events after a solve is done.
+const STATUS_GRACE_PERIOD: Duration = Duration::from_millis(200);
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SolveRequest {
+    #[prost(string, tag = "1")]
+    r#ref: String,
+    #[prost(string, tag = "3")]
+    exporter: String,
+    #[prost(string, tag = "5")]
+    session: String,
+    #[prost(string, tag = "6")]
+    frontend: String,
+    #[prost(map = "string, string", tag = "7")]
+    frontend_attrs: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SolveResponse {
+    #[prost(map = "string, string", tag = "1")]
+    exporter_response: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct StatusRequest {
+    #[prost(string, tag = "1")]
+    r#ref: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct StatusResponse {
+    #[prost(message, repeated, tag = "1")]
+    vertexes: Vec<Vertex>,
+    #[prost(message, repeated, tag = "3")]
+    logs: Vec<VertexLog>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Vertex {
+    #[prost(string, tag = "1")]
+    digest: String,
+    #[prost(string, tag = "3")]
+    name: String,
+    #[prost(bool, tag = "4")]
+    cached: bool,
+    #[prost(message, optional, tag = "5")]
+    started: Option<Timestamp>,
+    #[prost(message, optional, tag = "6")]
+    completed: Option<Timestamp>,
+    #[prost(string, tag = "7")]
+    error: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct VertexLog {
+    #[prost(string, tag = "1")]
+    vertex: String,
+    #[prost(bytes, tag = "4")]
+    msg: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Timestamp {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+/// The messages of the session stream.
+#[derive(Clone, PartialEq, prost::Message)]
+struct BytesMessage {
+    #[prost(bytes, tag = "1")]
+    data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HealthCheckResponse {
+    /// 1 is SERVING.
+    #[prost(int32, tag = "1")]
+    status: i32,
+}
+
+fn h2_error(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        e.into_io().unwrap()
+    } else {
+        io::Error::other(e)
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A gRPC message, prefixed by whether it is compressed and its length.
+fn encode_message<M: Message>(message: &M) -> Bytes {
+    let len = message.encoded_len();
+    let mut buf = BytesMut::with_capacity(5 + len);
+    buf.put_u8(0);
+    buf.put_u32(len as u32);
+    message
+        .encode(&mut buf)
+        .expect("Expected the buffer to have enough capacity");
+    buf.freeze()
+}
+
+/// Sends `data`, waiting for the peer to let us send more as needed.
+async fn send_data(stream: &mut SendStream<Bytes>, mut data: Bytes) -> io::Result<()> {
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let capacity = future::poll_fn(|cx| poll_send_capacity(stream, cx)).await?;
+        stream
+            .send_data(data.split_to(capacity.min(data.len())), false)
+            .map_err(h2_error)?;
+    }
+    Ok(())
+}
+
+/// Waits until some capacity is assigned to a stream with reserved capacity.
+fn poll_send_capacity(stream: &mut SendStream<Bytes>, cx: &mut Context) -> Poll<io::Result<usize>> {
+    loop {
+        let capacity = stream.capacity();
+        if capacity > 0 {
+            return Poll::Ready(Ok(capacity));
+        }
+        // This only returns when the capacity increased.
+        match ready!(stream.poll_capacity(cx)) {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Poll::Ready(Err(h2_error(e))),
+            None => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "stream closed",
+                )))
+            }
+        }
+    }
+}
+
+/// Decodes a `%`-encoded `grpc-message`.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Fails if `headers` hold a gRPC status other than OK.
+fn check_status(headers: &HeaderMap) -> io::Result<()> {
+    let status = headers.get("grpc-status").and_then(|s| s.to_str().ok());
+    match status {
+        None | Some("0") => Ok(()),
+        Some(code) => {
+            let message = headers
+                .get("grpc-message")
+                .and_then(|m| m.to_str().ok())
+                .map(percent_decode)
+                .unwrap_or_else(|| format!("gRPC call failed with status {}", code));
+            Err(io::Error::other(message))
+        }
+    }
+}
+
+fn grpc_response() -> Response<()> {
+    Response::builder()
+        .status(200)
+        .header("content-type", "application/grpc")
+        .body(())
+        .unwrap()
+}
+
+/// The trailers ending a call we serve. Errors are reported with the UNKNOWN
+/// status, since buildkitd only shows the message.
+fn grpc_trailers(result: &io::Result<()>) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    match result {
+        Ok(()) => {
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        }
+        Err(e) => {
+            trailers.insert("grpc-status", HeaderValue::from_static("2"));
+            let message: String = e
+                .to_string()
+                .chars()
+                .map(|c| if c.is_ascii_graphic() { c } else { ' ' })
+                .collect();
+            if let Ok(message) = HeaderValue::from_str(&message) {
+                trailers.insert("grpc-message", message);
+            }
+        }
+    }
+    trailers
+}
+
+/// Reads the messages of a gRPC call, whichever side of it we are on.
+struct MessageReader {
+    headers: HeaderMap,
+    body: RecvStream,
+    buf: BytesMut,
+}
+
+impl MessageReader {
+    fn new(headers: HeaderMap, body: RecvStream) -> Self {
+        MessageReader {
+            headers,
+            body,
+            buf: BytesMut::new(),
+        }
+    }
+
+    fn from_response(response: Response<RecvStream>) -> io::Result<Self> {
+        if response.status() != 200 {
+            return Err(invalid_data(format!(
+                "unexpected HTTP status {}",
+                response.status()
+            )));
+        }
+        let (parts, body) = response.into_parts();
+        Ok(MessageReader::new(parts.headers, body))
+    }
+
+    /// Polls the next message, undecoded.
+    fn poll_frame(&mut self, cx: &mut Context) -> Poll<io::Result<Option<Bytes>>> {
+        loop {
+            if self.buf.len() >= 5 {
+                let len = u32::from_be_bytes(self.buf[1..5].try_into().unwrap()) as usize;
+                if self.buf.len() >= 5 + len {
+                    if self.buf[0] != 0 {
+                        return Poll::Ready(Err(invalid_data(
+                            "compressed gRPC messages are not supported".to_owned(),
+                        )));
+                    }
+                    self.buf.advance(5);
+                    return Poll::Ready(Ok(Some(self.buf.split_to(len).freeze())));
+                }
+            }
+            match ready!(self.body.poll_data(cx)) {
+                Some(Ok(chunk)) => {
+                    let _ = self.body.flow_control().release_capacity(chunk.len());
+                    self.buf.extend_from_slice(&chunk);
+                }
+                Some(Err(e)) => return Poll::Ready(Err(h2_error(e))),
+                None if self.buf.is_empty() => return Poll::Ready(Ok(None)),
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "gRPC message cut short",
+                    )))
+                }
+            }
+        }
+    }
+
+    async fn next<M: Message + Default>(&mut self) -> io::Result<Option<M>> {
+        match future::poll_fn(|cx| self.poll_frame(cx)).await? {
+            Some(frame) => M::decode(frame)
+                .map(Some)
+                .map_err(|e| invalid_data(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the status of the call, once every message has been read.
+    async fn finish(mut self) -> io::Result<()> {
+        check_status(&self.headers)?;
+        match self.body.trailers().await.map_err(h2_error)? {
+            Some(trailers) => check_status(&trailers),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A byte stream over the messages of a session call.
+struct SessionConn {
+    reader: MessageReader,
+    send: SendStream<Bytes>,
+    /// What is left of the last message read.
+    pending: Bytes,
+}
+
+impl AsyncRead for SessionConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.pending.is_empty() {
+            match ready!(self.reader.poll_frame(cx))? {
+                Some(frame) => {
+                    let message =
+                        BytesMessage::decode(frame).map_err(|e| invalid_data(e.to_string()))?;
+                    self.pending = message.data.into();
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending.split_to(n));
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for SessionConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Leave room for the message header and the tag and length of the field.
+        self.send.reserve_capacity(buf.len() + 16);
+        let capacity = ready!(poll_send_capacity(&mut self.send, cx))?;
+        let n = buf.len().min(capacity.saturating_sub(16).max(1));
+        let frame = encode_message(&BytesMessage {
+            data: buf[..n].to_vec(),
+        });
+        self.send.send_data(frame, false).map_err(h2_error)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.send.send_data(Bytes::new(), true).map_err(h2_error))
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use mio::{unix::EventedFd, Evented, Poll, PollOpt, Ready, Token};
+    use std::{
+        io::{self, Read, Write},
+        os::unix::{io::AsRawFd, net::UnixStream},
+        path::Path,
+    };
+    use tokio::io::PollEvented;
+
+    /// A Unix socket which can be used with tokio, which needs the `uds`
+    /// feature for that otherwise.
+    #[derive(Debug)]
+    pub struct UnixConn(UnixStream);
+
+    impl UnixConn {
+        pub fn new(stream: UnixStream) -> io::Result<PollEvented<UnixConn>> {
+            stream.set_nonblocking(true)?;
+            PollEvented::new(UnixConn(stream))
+        }
+
+        pub fn connect(path: &Path) -> io::Result<PollEvented<UnixConn>> {
+            UnixConn::new(UnixStream::connect(path)?)
+        }
+    }
+
+    impl Evented for UnixConn {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &Poll) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).deregister(poll)
+        }
+    }
+
+    impl Read for UnixConn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for UnixConn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+}
+
+/// A connection to buildkitd, which can make several calls at once.
+#[derive(Clone)]
+struct Client {
+    send: h2::client::SendRequest<Bytes>,
+}
+
+impl Client {
+    /// Connects to `address`, either `unix://<path>` or `tcp://<host>:<port>`.
+    async fn connect(address: &str) -> io::Result<Client> {
+        if let Some(host) = address.strip_prefix("tcp://") {
+            use std::net::{SocketAddr, ToSocketAddrs};
+            let addrs: Vec<SocketAddr> = host.to_socket_addrs()?.collect();
+            let stream = tokio::net::TcpStream::connect(&addrs[..]).await?;
+            return Client::handshake(stream).await;
+        }
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix://") {
+            return Client::handshake(unix::UnixConn::connect(Path::new(path))?).await;
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported address {:?}", address),
+        ))
+    }
+
+    async fn handshake<T>(io: T) -> io::Result<Client>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (send, connection) = h2::client::handshake(io).await.map_err(h2_error)?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        Ok(Client { send })
+    }
+
+    /// Starts a call, whose messages are then sent on the returned stream.
+    async fn start_call(
+        &self,
+        method: &str,
+        metadata: &[(&str, &str)],
+    ) -> io::Result<(ResponseFuture, SendStream<Bytes>)> {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri(format!("http://buildkitd{}", method))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers");
+        for (key, value) in metadata {
+            request = request.header(*key, *value);
+        }
+        let request = request
+            .body(())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut send = self.send.clone().ready().await.map_err(h2_error)?;
+        send.send_request(request, false).map_err(h2_error)
+    }
+
+    /// Makes a call with a single request, and returns the response stream.
+    async fn call<M: Message>(&self, method: &str, request: &M) -> io::Result<MessageReader> {
+        let (response, mut send) = self.start_call(method, &[]).await?;
+        send_data(&mut send, encode_message(request)).await?;
+        send.send_data(Bytes::new(), true).map_err(h2_error)?;
+        MessageReader::from_response(response.await.map_err(h2_error)?)
+    }
+
+    async fn unary<Req: Message, Resp: Message + Default>(
+        &self,
+        method: &str,
+        request: &Req,
+    ) -> io::Result<Resp> {
+        let mut reader = self.call(method, request).await?;
+        let response = reader.next().await?;
+        reader.finish().await?;
+        response.ok_or_else(|| invalid_data(format!("no response to {}", method)))
+    }
+}
+
+/// Opens a session serving the directories in `dirs` by name, and returns its ID.
+async fn start_session(client: &Client, dirs: HashMap<String, PathBuf>) -> io::Result<String> {
+    let id = random_id();
+    // Lets buildkitd reuse what it got from earlier sessions for the same context.
+    let shared_key = dirs
+        .get("context")
+        .map(|dir| format!("{:x}", Sha256::digest(dir.to_string_lossy().as_bytes())))
+        .unwrap_or_default();
+    let metadata = [
+        ("x-docker-expose-session-uuid", id.as_str()),
+        ("x-docker-expose-session-name", "modus"),
+        ("x-docker-expose-session-sharedkey", shared_key.as_str()),
+        ("x-docker-expose-session-grpc-method", HEALTH_CHECK),
+        ("x-docker-expose-session-grpc-method", DIFF_COPY),
+    ];
+    let (response, send) = client.start_call(SESSION, &metadata).await?;
+    let dirs = Arc::new(dirs);
+    tokio::spawn(async move {
+        // If the session breaks, the solves fail with a message from buildkitd.
+        if let Ok(Ok(reader)) = response
+            .await
+            .map_err(h2_error)
+            .map(MessageReader::from_response)
+        {
+            let conn = SessionConn {
+                reader,
+                send,
+                pending: Bytes::new(),
+            };
+            let _ = serve_session(conn, dirs).await;
+        }
+    });
+    Ok(id)
+}
+
+async fn serve_session(conn: SessionConn, dirs: Arc<HashMap<String, PathBuf>>) -> io::Result<()> {
+    let mut connection = h2::server::handshake(conn).await.map_err(h2_error)?;
+    while let Some(call) = connection.accept().await {
+        let (request, respond) = call.map_err(h2_error)?;
+        tokio::spawn(serve_call(request, respond, dirs.clone()));
+    }
+    Ok(())
+}
+
+async fn serve_call(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    dirs: Arc<HashMap<String, PathBuf>>,
+) {
+    let mut send = match respond.send_response(grpc_response(), false) {
+        Ok(send) => send,
+        Err(_) => return,
+    };
+    let result = match request.uri().path() {
+        HEALTH_CHECK => health_check(request, &mut send).await,
+        DIFF_COPY => diff_copy(request, &mut send, &dirs).await,
+        method => Err(io::Error::other(format!("unknown method {}", method))),
+    };
+    let _ = send.send_trailers(grpc_trailers(&result));
+}
+
+async fn health_check(
+    request: Request<RecvStream>,
+    send: &mut SendStream<Bytes>,
+) -> io::Result<()> {
+    let (parts, body) = request.into_parts();
+    let mut reader = MessageReader::new(parts.headers, body);
+    while reader.next::<BytesMessage>().await?.is_some() {}
+    send_data(send, encode_message(&HealthCheckResponse { status: 1 })).await
+}
+
+/// Sends a directory, which buildkitd asks for with the `dir-name` header and
+/// filters with the other headers.
+async fn diff_copy(
+    request: Request<RecvStream>,
+    send: &mut SendStream<Bytes>,
+    dirs: &HashMap<String, PathBuf>,
+) -> io::Result<()> {
+    let (parts, body) = request.into_parts();
+    let values = |key: &str| -> Vec<String> {
+        parts
+            .headers
+            .get_all(key)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(ToOwned::to_owned)
+            .collect()
+    };
+    let dir_name = values("dir-name").into_iter().next().unwrap_or_default();
+    let dir = dirs.get(&dir_name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no directory named {:?} in the session", dir_name),
+        )
+    })?;
+    let filter = Filter {
+        include_patterns: values("include-patterns"),
+        exclude_patterns: values("exclude-patterns"),
+        follow_paths: values("followpaths"),
+    };
+    let entries = filesync::walk(dir, &filter)?;
+    let reader = MessageReader::new(parts.headers, body);
+    let incoming = stream::unfold(reader, |mut reader| async move {
+        match reader.next::<Packet>().await {
+            Ok(Some(packet)) => Some((Ok(packet), reader)),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), reader)),
+        }
+    });
+    let (outgoing, mut to_send) = mpsc::channel::<Packet>(16);
+    let write = async {
+        while let Some(packet) = to_send.next().await {
+            send_data(send, encode_message(&packet)).await?;
+        }
+        Ok(())
+    };
+    let sync = filesync::send(
+        &entries,
+        outgoing.sink_map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e)),
+        Box::pin(incoming),
+    );
+    future::try_join(sync, write).await.map(|_| ())
+}
+
+fn random_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(25)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProgressEvent {
+    Started(String),
+    Completed {
+        name: String,
+        cached: bool,
+        error: Option<String>,
+    },
+    Log(String),
+}
+
+/// Turns the status updates of a solve, which repeat the whole state of the
+/// vertices that changed, into events.
+#[derive(Debug, Default)]
+struct ProgressTracker {
+    /// Whether the start and the completion of each vertex were reported.
+    reported: HashMap<String, (bool, bool)>,
+}
+
+impl ProgressTracker {
+    fn update(&mut self, status: StatusResponse) -> Vec<ProgressEvent> {
+        let mut events = Vec::new();
+        for vertex in status.vertexes {
+            let (started, completed) = self.reported.entry(vertex.digest).or_default();
+            if vertex.started.is_some() && !vertex.cached && !*started {
+                *started = true;
+                events.push(ProgressEvent::Started(vertex.name.clone()));
+            }
+            if vertex.completed.is_some() && !*completed {
+                *completed = true;
+                events.push(ProgressEvent::Completed {
+                    name: vertex.name,
+                    cached: vertex.cached,
+                    error: Some(vertex.error).filter(|e| !e.is_empty()),
+                });
+            }
+        }
+        for log in status.logs {
+            events.push(ProgressEvent::Log(
+                String::from_utf8_lossy(&log.msg).into_owned(),
+            ));
+        }
+        events
+    }
+}
+
+fn print_progress(event: ProgressEvent, verbose: bool) {
+    match event {
+        ProgressEvent::Started(name) => eprintln!("=> {}", name),
+        ProgressEvent::Completed {
+            name, cached: true, ..
+        } => eprintln!("=> CACHED {}", name),
+        ProgressEvent::Completed {
+            name,
+            error: Some(error),
+            ..
+        } => eprintln!("{}", format!("=> ERROR {}: {}", name, error).red()),
+        ProgressEvent::Completed { .. } => {}
+        ProgressEvent::Log(text) if verbose => eprint!("{}", text),
+        ProgressEvent::Log(_) => {}
+    }
+}
+
+/// Makes the solves of a build, in one session.
+struct Solver {
+    client: Client,
+    session: String,
+    frontend_attrs: HashMap<String, String>,
+    verbose: bool,
+}
+
+impl Solver {
+    /// Solves `target` of our frontend, optionally exporting the result as an
+    /// image, and returns the response of the exporter.
+    async fn solve(
+        &self,
+        target: Option<String>,
+        export: bool,
+        show_progress: bool,
+    ) -> io::Result<HashMap<String, String>> {
+        let mut frontend_attrs = self.frontend_attrs.clone();
+        if let Some(target) = target {
+            frontend_attrs.insert("target".to_owned(), target);
+        }
+        let request = SolveRequest {
+            r#ref: random_id(),
+            exporter: if export { "image" } else { "" }.to_owned(),
+            session: self.session.clone(),
+            frontend: "dockerfile.v0".to_owned(),
+            frontend_attrs,
+        };
+        let solve = self.client.unary::<_, SolveResponse>(SOLVE, &request);
+        if !show_progress {
+            return Ok(solve.await?.exporter_response);
+        }
+        let progress = self.show_progress(&request.r#ref);
+        futures::pin_mut!(solve, progress);
+        let response = match future::select(solve, progress).await {
+            Either::Left((response, progress)) => {
+                let _ = tokio::time::timeout(STATUS_GRACE_PERIOD, progress).await;
+                response
+            }
+            Either::Right((_, solve)) => solve.await,
+        };
+        Ok(response?.exporter_response)
+    }
+
+    /// Prints the progress of a solve until it is done. Progress is only
+    /// informative, so errors are ignored.
+    async fn show_progress(&self, solve_ref: &str) {
+        let request = StatusRequest {
+            r#ref: solve_ref.to_owned(),
+        };
+        if let Ok(mut reader) = self.client.call(STATUS, &request).await {
+            let mut tracker = ProgressTracker::default();
+            while let Ok(Some(status)) = reader.next::<StatusResponse>().await {
+                for event in tracker.update(status) {
+                    print_progress(event, self.verbose);
+                }
+            }
+        }
+    }
+}
+
+fn image_id(exporter_response: &HashMap<String, String>) -> Result<String, BuildError> {
+    // The config digest is what docker and podman use as image ID.
+    exporter_response
+        .get("containerimage.config.digest")
+        .cloned()
+        .ok_or_else(|| {
+            BuildError::BuildkitdFailed("no image digest in the solve response".to_owned())
+        })
+}
+
+fn solve_failed(e: io::Error) -> BuildError {
+    BuildError::BuildkitdFailed(e.to_string())
+}
+
+/// Builds the outputs of `build_plan` by talking to buildkitd at `address`,
+/// with `dockerfile` pointing to our frontend. The current directory is the
+/// context.
+///
+/// Like the other backends, returns the image IDs, and with
+/// `BuildOptions::keep_going` the outputs and checks which failed.
+pub fn build_outputs(
+    address: &str,
+    dockerfile: &str,
+    has_dockerignore: bool,
+    build_plan: &BuildPlan,
+    build_options: &BuildOptions,
+    sh: &mut SignalHandler,
+) -> Result<(Vec<Result<String, BuildError>>, FailedChecks), BuildError> {
+    let context = std::env::current_dir().map_err(BuildError::CwdError)?;
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let build = build(
+            address,
+            dockerfile,
+            has_dockerignore,
+            &context,
+            build_plan,
+            build_options,
+        );
+        let interrupted = async {
+            while !sh.termination_pending() {
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+            }
+        };
+        futures::pin_mut!(build, interrupted);
+        // Dropping the build closes the connection, which cancels the solves.
+        match future::select(build, interrupted).await {
+            Either::Left((res, _)) => res,
+            Either::Right(_) => Err(BuildError::Interrupted),
+        }
+    })
+}
+
+async fn build(
+    address: &str,
+    dockerfile: &str,
+    has_dockerignore: bool,
+    context: &Path,
+    build_plan: &BuildPlan,
+    build_options: &BuildOptions,
+) -> Result<(Vec<Result<String, BuildError>>, FailedChecks), BuildError> {
+    let client = Client::connect(address)
+        .await
+        .map_err(|e| BuildError::BuildkitdUnavailable(address.to_owned(), e))?;
+    // The Dockerfile is written in the context.
+    let dirs = [("context", context), ("dockerfile", context)]
+        .iter()
+        .map(|(name, dir)| (name.to_string(), dir.to_path_buf()))
+        .collect();
+    let session = start_session(&client, dirs).await.map_err(solve_failed)?;
+    let options = &build_options.docker_build_options;
+    let mut frontend_attrs: HashMap<String, String> = [
+        ("filename", dockerfile.to_owned()),
+        // Sometimes it isn't enough to just use no-cache, so we also tell our
+        // frontend to issue ignore_cache.
+        ("build-arg:no_cache", options.no_cache.to_string()),
+        ("build-arg:has_dockerignore", has_dockerignore.to_string()),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.clone()))
+    .collect();
+    if options.no_cache {
+        frontend_attrs.insert("no-cache".to_owned(), String::new());
+    }
+    let solver = Solver {
+        client,
+        session,
+        frontend_attrs,
+        verbose: options.verbose,
+    };
+    let concurrency = build_options.export_concurrency.max(1) as usize;
+    let nb_outputs = build_plan.outputs.len();
+    let nb_done = Cell::new(0);
+    let solve_output = |i: usize| {
+        let solver = &solver;
+        let nb_done = &nb_done;
+        async move {
+            let literal_str = build_plan.outputs[i]
+                .source_literal
+                .as_ref()
+                .expect("Expected source_literal to present in build plan")
+                .to_string();
+            let res = solver
+                .solve(Some(i.to_string()), true, false)
+                .await
+                .map_err(solve_failed)
+                .and_then(|response| image_id(&response));
+            nb_done.set(nb_done.get() + 1);
+            match &res {
+                Ok(iid) => eprintln!(
+                    "{}",
+                    format!(
+                        "Exported {}/{}: {} -> {}",
+                        nb_done.get(),
+                        nb_outputs,
+                        literal_str,
+                        iid
+                    )
+                    .blue()
+                ),
+                Err(e) => eprintln!(
+                    "{}",
+                    format!("Exporting {} failed: {}", literal_str, e).red()
+                ),
+            }
+            res
+        }
+    };
+
+    // With a single output, the main solve builds and exports it.
+    let main = async {
+        solver
+            .solve(None, nb_outputs == 1, !options.quiet)
+            .await
+            .map_err(solve_failed)
+    };
+    let separate_outputs = if nb_outputs > 1 { nb_outputs } else { 0 };
+    let outputs = stream::iter(0..separate_outputs)
+        .map(solve_output)
+        .buffered(concurrency);
+    let (main_res, outputs_res) = if build_options.keep_going {
+        future::join(main, outputs.collect::<Vec<_>>()).await
+    } else {
+        let (main, outputs) = future::try_join(main, outputs.try_collect::<Vec<_>>()).await?;
+        (Ok(main), outputs.into_iter().map(Ok).collect())
+    };
+    let main_error = match main_res {
+        Ok(response) if nb_outputs == 1 => return Ok((vec![image_id(&response)], Vec::new())),
+        Ok(_) => return Ok((outputs_res, Vec::new())),
+        Err(e) => e,
+    };
+
+    // Some outputs and checks may still build, so try each of them on its own.
+    eprintln!(
+        "{}",
+        format!(
+            "Build failed: {}, building each output separately",
+            main_error
+        )
+        .red()
+    );
+    let outputs_res = if nb_outputs == 1 {
+        vec![solve_output(0).await]
+    } else {
+        outputs_res
+    };
+    let checks_res: Vec<_> = stream::iter(0..build_plan.checks.len())
+        .map(|i| {
+            let target = format!("{}{}", CHECK_TARGET_PREFIX, i);
+            let solve = solver.solve(Some(target), false, false);
+            async move { (i, solve.await) }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await;
+    let failed_checks: FailedChecks = checks_res
+        .into_iter()
+        .filter_map(|(i, res)| Some((build_plan.checks[i], solve_failed(res.err()?))))
+        .collect();
+    if outputs_res.iter().all(Result::is_ok) && failed_checks.is_empty() {
+        Err(main_error)
+    } else {
+        Ok((outputs_res, failed_checks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buildkit::gen_tmp_filename;
+    use filesync::PacketType;
+    use futures::channel::oneshot;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn vertex(digest: &str, cached: bool, completed: bool, error: &str) -> Vertex {
+        Vertex {
+            digest: digest.to_owned(),
+            name: format!("step {}", digest),
+            cached,
+            started: Some(Timestamp::default()),
+            completed: completed.then(Timestamp::default),
+            error: error.to_owned(),
+        }
+    }
+
+    #[test]
+    fn tracks_progress() {
+        let mut tracker = ProgressTracker::default();
+        let events = tracker.update(StatusResponse {
+            vertexes: vec![vertex("a", false, false, ""), vertex("b", true, true, "")],
+            logs: vec![VertexLog {
+                vertex: "a".to_owned(),
+                msg: b"hello\n".to_vec(),
+            }],
+        });
+        assert_eq!(
+            events,
+            [
+                ProgressEvent::Started("step a".to_owned()),
+                ProgressEvent::Completed {
+                    name: "step b".to_owned(),
+                    cached: true,
+                    error: None
+                },
+                ProgressEvent::Log("hello\n".to_owned()),
+            ]
+        );
+        let events = tracker.update(StatusResponse {
+            vertexes: vec![vertex("a", false, true, "exit code 1")],
+            logs: vec![],
+        });
+        assert_eq!(
+            events,
+            [ProgressEvent::Completed {
+                name: "step a".to_owned(),
+                cached: false,
+                error: Some("exit code 1".to_owned())
+            }]
+        );
+    }
+
+    #[test]
+    fn decodes_grpc_messages() {
+        assert_eq!(percent_decode("no%20such%zz file%"), "no such%zz file%");
+    }
+
+    /// Reads the content of `path` in the directory `dir_name` of a session,
+    /// as buildkitd would.
+    async fn fetch_file(session: &Client, dir_name: &str, path: &str) -> io::Result<Vec<u8>> {
+        let (response, mut send) = session
+            .start_call(
+                DIFF_COPY,
+                &[("dir-name", dir_name), ("include-patterns", path)],
+            )
+            .await?;
+        let mut reader = MessageReader::from_response(response.await.map_err(h2_error)?)?;
+        // Files are requested by their index in the walk.
+        let (mut nb_stats, mut id) = (0, None);
+        let mut content = Vec::new();
+        while let Some(packet) = reader.next::<Packet>().await? {
+            match (PacketType::from_i32(packet.r#type), packet.stat) {
+                (Some(PacketType::Stat), Some(stat)) => {
+                    if stat.path == path {
+                        id = Some(nb_stats);
+                    }
+                    nb_stats += 1;
+                }
+                (Some(PacketType::Stat), None) => {
+                    let request = Packet {
+                        r#type: PacketType::Req as i32,
+                        id: id.unwrap(),
+                        ..Default::default()
+                    };
+                    send_data(&mut send, encode_message(&request)).await?;
+                }
+                (Some(PacketType::Data), _) if packet.data.is_empty() => {
+                    let fin = Packet {
+                        r#type: PacketType::Fin as i32,
+                        ..Default::default()
+                    };
+                    send_data(&mut send, encode_message(&fin)).await?;
+                    send.send_data(Bytes::new(), true).map_err(h2_error)?;
+                }
+                (Some(PacketType::Data), _) => content.extend(packet.data),
+                _ => {}
+            }
+        }
+        reader.finish().await?;
+        Ok(content)
+    }
+
+    /// Answers a solve with the content of `Dockerfile` in the session as
+    /// image ID.
+    async fn fake_solve(
+        mut reader: MessageReader,
+        mut send: SendStream<Bytes>,
+        session: oneshot::Receiver<Client>,
+    ) -> io::Result<()> {
+        let request: SolveRequest = reader.next().await?.unwrap();
+        assert_eq!(request.frontend_attrs["filename"], "Dockerfile");
+        let session = session.await.unwrap();
+        let content = fetch_file(&session, "dockerfile", "Dockerfile").await?;
+        let response = SolveResponse {
+            exporter_response: [(
+                "containerimage.config.digest".to_owned(),
+                String::from_utf8(content).unwrap(),
+            )]
+            .iter()
+            .cloned()
+            .collect(),
+        };
+        send_data(&mut send, encode_message(&response)).await?;
+        send.send_trailers(grpc_trailers(&Ok(()))).map_err(h2_error)
+    }
+
+    /// Serves one connection as buildkitd, for a session and a solve.
+    async fn fake_buildkitd<T>(io: T) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut connection = h2::server::handshake(io).await.map_err(h2_error)?;
+        let (session_tx, session_rx) = oneshot::channel();
+        let (mut session_tx, mut session_rx) = (Some(session_tx), Some(session_rx));
+        while let Some(call) = connection.accept().await {
+            let (request, mut respond) = call.map_err(h2_error)?;
+            let send = respond
+                .send_response(grpc_response(), false)
+                .map_err(h2_error)?;
+            let (parts, body) = request.into_parts();
+            let reader = MessageReader::new(parts.headers, body);
+            match parts.uri.path() {
+                SESSION => {
+                    let session_tx = session_tx.take().unwrap();
+                    tokio::spawn(async move {
+                        let conn = SessionConn {
+                            reader,
+                            send,
+                            pending: Bytes::new(),
+                        };
+                        let client = Client::handshake(conn).await.unwrap();
+                        let health: HealthCheckResponse = client
+                            .unary(HEALTH_CHECK, &BytesMessage::default())
+                            .await
+                            .unwrap();
+                        assert_eq!(health.status, 1);
+                        let _ = session_tx.send(client);
+                    });
+                }
+                SOLVE => {
+                    let session_rx = session_rx.take().unwrap();
+                    tokio::spawn(fake_solve(reader, send, session_rx));
+                }
+                _ => {
+                    let mut send = send;
+                    let result = Err(io::Error::other("unknown method"));
+                    send.send_trailers(grpc_trailers(&result))
+                        .map_err(h2_error)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn solves_with_session() {
+        let context = std::env::temp_dir().join(gen_tmp_filename());
+        std::fs::create_dir_all(context.join("src")).unwrap();
+        std::fs::write(context.join("Dockerfile"), "sha256:1234").unwrap();
+        std::fs::write(context.join("src/main.rs"), "").unwrap();
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let response = runtime().block_on(async {
+            let server = unix::UnixConn::new(theirs).unwrap();
+            tokio::spawn(async move { fake_buildkitd(server).await.unwrap() });
+            let client = Client::handshake(unix::UnixConn::new(ours).unwrap())
+                .await
+                .unwrap();
+            let dirs = [("dockerfile".to_owned(), context.clone())]
+                .iter()
+                .cloned()
+                .collect();
+            let session = start_session(&client, dirs).await.unwrap();
+            let solver = Solver {
+                client,
+                session,
+                frontend_attrs: [("filename".to_owned(), "Dockerfile".to_owned())]
+                    .iter()
+                    .cloned()
+                    .collect(),
+                verbose: false,
+            };
+            solver.solve(None, true, false).await
+        });
+        assert_eq!(image_id(&response.unwrap()).unwrap(), "sha256:1234");
+        std::fs::remove_dir_all(context).unwrap();
+    }
+}
@@ -0,0 +1,480 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The sending side of the file sync protocol of buildkit (`fsutil`), which
+//! buildkitd uses to fetch the build context from the client.
+//!
+//! We first send the stat of every file to sync, walking the directory depth
+//! first with the entries of each directory sorted by name, then an empty stat.
+//! buildkitd requests the content of the regular files it doesn't have by
+//! their index in the walk, and each file is sent in chunks followed by an
+//! empty chunk. The sync ends when both sides have sent a FIN packet.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use futures::{channel::mpsc, future, Sink, SinkExt, Stream, StreamExt};
+use modus_lib::dockerignore::{may_match_inside, path_components, path_matches, DockerIgnore};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum PacketType {
+    Stat = 0,
+    Req = 1,
+    Data = 2,
+    Fin = 3,
+    Err = 4,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Packet {
+    #[prost(enumeration = "PacketType", tag = "1")]
+    pub r#type: i32,
+    #[prost(message, optional, tag = "2")]
+    pub stat: Option<Stat>,
+    #[prost(uint32, tag = "3")]
+    pub id: u32,
+    #[prost(bytes, tag = "4")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Stat {
+    #[prost(string, tag = "1")]
+    pub path: String,
+    /// A Go `os.FileMode`, i.e. the permission bits and the `MODE_*` bits below.
+    #[prost(uint32, tag = "2")]
+    pub mode: u32,
+    #[prost(uint32, tag = "3")]
+    pub uid: u32,
+    #[prost(uint32, tag = "4")]
+    pub gid: u32,
+    #[prost(int64, tag = "5")]
+    pub size: i64,
+    /// In nanoseconds since the epoch.
+    #[prost(int64, tag = "6")]
+    pub mod_time: i64,
+    #[prost(string, tag = "7")]
+    pub linkname: String,
+    #[prost(int64, tag = "8")]
+    pub devmajor: i64,
+    #[prost(int64, tag = "9")]
+    pub devminor: i64,
+}
+
+pub const MODE_DIR: u32 = 1 << 31;
+pub const MODE_SYMLINK: u32 = 1 << 27;
+pub const MODE_DEVICE: u32 = 1 << 26;
+pub const MODE_NAMED_PIPE: u32 = 1 << 25;
+pub const MODE_SOCKET: u32 = 1 << 24;
+pub const MODE_SETUID: u32 = 1 << 23;
+pub const MODE_SETGID: u32 = 1 << 22;
+pub const MODE_CHAR_DEVICE: u32 = 1 << 21;
+pub const MODE_STICKY: u32 = 1 << 20;
+
+/// Size of the chunks file contents are sent in.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Which files to send, as requested by buildkitd.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only send the paths matching one of these, if there are any.
+    pub include_patterns: Vec<String>,
+    /// `.dockerignore` patterns of the paths not to send.
+    pub exclude_patterns: Vec<String>,
+    /// Symlinks at these paths are followed, i.e. the file they point to is sent instead.
+    pub follow_paths: Vec<String>,
+}
+
+/// A file or directory to send.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub stat: Stat,
+    path: PathBuf,
+}
+
+impl Entry {
+    fn is_regular_file(&self) -> bool {
+        self.stat.mode & (MODE_DIR | MODE_SYMLINK | MODE_DEVICE | MODE_NAMED_PIPE | MODE_SOCKET)
+            == 0
+    }
+}
+
+/// Lists the entries under `root` to send, in the order of the protocol. The
+/// parent directories of included paths are sent even if they are not included
+/// themselves.
+pub fn walk(root: &Path, filter: &Filter) -> io::Result<Vec<Entry>> {
+    let walker = Walker {
+        filter,
+        ignore: DockerIgnore::parse(&filter.exclude_patterns.join("\n")),
+    };
+    let mut entries = Vec::new();
+    walker.walk_dir(root, "", &mut Vec::new(), &mut entries)?;
+    Ok(entries)
+}
+
+struct Walker<'a> {
+    filter: &'a Filter,
+    ignore: DockerIgnore,
+}
+
+impl Walker<'_> {
+    /// Walks `dir`, whose path relative to the root is `rel_dir`. `parents` are
+    /// the directories above which have not been sent yet.
+    fn walk_dir(
+        &self,
+        dir: &Path,
+        rel_dir: &str,
+        parents: &mut Vec<Entry>,
+        entries: &mut Vec<Entry>,
+    ) -> io::Result<()> {
+        let mut children = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        children.sort_by_key(|c| c.file_name());
+        for child in children {
+            let name = child.file_name().to_string_lossy().into_owned();
+            let rel_path = if rel_dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", rel_dir, name)
+            };
+            let path = child.path();
+            let follow = self
+                .filter
+                .follow_paths
+                .iter()
+                .any(|p| path_components(p) == path_components(&rel_path));
+            let metadata = if follow {
+                fs::metadata(&path)?
+            } else {
+                fs::symlink_metadata(&path)?
+            };
+            let excluded = self.ignore.is_ignored(&rel_path);
+            let included = !excluded
+                && (self.filter.include_patterns.is_empty()
+                    || self
+                        .filter
+                        .include_patterns
+                        .iter()
+                        .any(|p| path_matches(p, &rel_path)));
+            let entry = Entry {
+                stat: stat(&rel_path, &path, &metadata)?,
+                path,
+            };
+            if included {
+                entries.append(parents);
+            }
+            if !metadata.is_dir() {
+                if included {
+                    entries.push(entry);
+                }
+                continue;
+            }
+            let look_inside = if excluded {
+                // Paths inside may be re-included.
+                self.ignore.has_exceptions()
+            } else {
+                included
+                    || self
+                        .filter
+                        .include_patterns
+                        .iter()
+                        .any(|p| may_match_inside(p, &rel_path))
+            };
+            if included {
+                let dir = entry.path.clone();
+                entries.push(entry);
+                self.walk_dir(&dir, &rel_path, parents, entries)?;
+            } else if look_inside {
+                let depth = parents.len();
+                let dir = entry.path.clone();
+                parents.push(entry);
+                self.walk_dir(&dir, &rel_path, parents, entries)?;
+                // Only keep the parents if they have not been sent.
+                parents.truncate(depth);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn stat(rel_path: &str, path: &Path, metadata: &fs::Metadata) -> io::Result<Stat> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let file_type = metadata.file_type();
+    let unix_mode = metadata.mode();
+    let mut mode = unix_mode & 0o777;
+    for (unix_bit, bit) in [
+        (0o4000, MODE_SETUID),
+        (0o2000, MODE_SETGID),
+        (0o1000, MODE_STICKY),
+    ] {
+        if unix_mode & unix_bit != 0 {
+            mode |= bit;
+        }
+    }
+    let mut linkname = String::new();
+    let (mut devmajor, mut devminor) = (0, 0);
+    if file_type.is_dir() {
+        mode |= MODE_DIR;
+    } else if file_type.is_symlink() {
+        mode |= MODE_SYMLINK;
+        linkname = fs::read_link(path)?.to_string_lossy().into_owned();
+    } else if file_type.is_fifo() {
+        mode |= MODE_NAMED_PIPE;
+    } else if file_type.is_socket() {
+        mode |= MODE_SOCKET;
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        mode |= MODE_DEVICE;
+        if file_type.is_char_device() {
+            mode |= MODE_CHAR_DEVICE;
+        }
+        // The encoding of device numbers used by glibc.
+        let rdev = metadata.rdev();
+        devmajor = (((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff)) as i64;
+        devminor = ((rdev & 0xff) | ((rdev >> 12) & !0xff)) as i64;
+    }
+    Ok(Stat {
+        path: rel_path.to_owned(),
+        mode,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        size: if file_type.is_dir() {
+            0
+        } else {
+            metadata.size() as i64
+        },
+        mod_time: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+        linkname,
+        devmajor,
+        devminor,
+    })
+}
+
+#[cfg(not(unix))]
+fn stat(rel_path: &str, _path: &Path, metadata: &fs::Metadata) -> io::Result<Stat> {
+    let mod_time = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or_default();
+    Ok(Stat {
+        path: rel_path.to_owned(),
+        mode: if metadata.is_dir() {
+            MODE_DIR | 0o755
+        } else {
+            0o644
+        },
+        size: if metadata.is_dir() {
+            0
+        } else {
+            metadata.len() as i64
+        },
+        mod_time,
+        ..Default::default()
+    })
+}
+
+fn packet(packet_type: PacketType) -> Packet {
+    Packet {
+        r#type: packet_type as i32,
+        ..Default::default()
+    }
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Sends `entries` to buildkitd, which answers through `incoming`.
+///
+/// Requests are read while files are being sent, since buildkitd may not read
+/// more data before we take its next requests.
+pub async fn send<S, R>(entries: &[Entry], mut outgoing: S, mut incoming: R) -> io::Result<()>
+where
+    S: Sink<Packet, Error = io::Error> + Unpin,
+    R: Stream<Item = io::Result<Packet>> + Unpin,
+{
+    let (requests_tx, mut requests) = mpsc::unbounded();
+    let read = async move {
+        while let Some(p) = incoming.next().await {
+            let p = p?;
+            match PacketType::from_i32(p.r#type) {
+                Some(PacketType::Req) => {
+                    let _ = requests_tx.unbounded_send(Some(p.id));
+                }
+                Some(PacketType::Fin) => {
+                    let _ = requests_tx.unbounded_send(None);
+                    return Ok(());
+                }
+                Some(PacketType::Err) => {
+                    return Err(protocol_error(format!(
+                        "buildkitd failed to sync files: {}",
+                        String::from_utf8_lossy(&p.data)
+                    )))
+                }
+                _ => {
+                    return Err(protocol_error(format!(
+                        "unexpected file sync packet of type {}",
+                        p.r#type
+                    )))
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file sync ended before FIN",
+        ))
+    };
+    let write = async move {
+        for entry in entries {
+            outgoing
+                .send(Packet {
+                    stat: Some(entry.stat.clone()),
+                    ..packet(PacketType::Stat)
+                })
+                .await?;
+        }
+        outgoing.send(packet(PacketType::Stat)).await?;
+        while let Some(Some(id)) = requests.next().await {
+            let entry = entries
+                .get(id as usize)
+                .filter(|e| e.is_regular_file())
+                .ok_or_else(|| protocol_error(format!("invalid file requested: {}", id)))?;
+            // A file which can't be read is sent as empty, like fsutil does.
+            if let Ok(mut file) = fs::File::open(&entry.path) {
+                let mut buf = vec![0; CHUNK_SIZE];
+                loop {
+                    let n = file.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    outgoing
+                        .send(Packet {
+                            id,
+                            data: buf[..n].to_vec(),
+                            ..packet(PacketType::Data)
+                        })
+                        .await?;
+                }
+            }
+            outgoing
+                .send(Packet {
+                    id,
+                    ..packet(PacketType::Data)
+                })
+                .await?;
+        }
+        outgoing.send(packet(PacketType::Fin)).await?;
+        outgoing.close().await
+    };
+    future::try_join(read, write).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buildkit::gen_tmp_filename;
+
+    fn tmp_context(files: &[&str]) -> PathBuf {
+        let root = std::env::temp_dir().join(gen_tmp_filename());
+        for f in files {
+            let path = root.join(f);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, f).unwrap();
+        }
+        root
+    }
+
+    fn paths(root: &Path, filter: &Filter) -> Vec<String> {
+        walk(root, filter)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.stat.path)
+            .collect()
+    }
+
+    #[test]
+    fn walks_sorted_depth_first() {
+        let root = tmp_context(&["b", "a/z", "a/b/c", "a.txt"]);
+        assert_eq!(
+            paths(&root, &Filter::default()),
+            ["a", "a/b", "a/b/c", "a/z", "a.txt", "b"]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn filters_paths() {
+        let root = tmp_context(&["Dockerfile", "src/main.rs", "src/lib.rs", "docs/a.md"]);
+        let filter = Filter {
+            include_patterns: vec!["src/*.rs".to_owned(), "Dockerfile".to_owned()],
+            exclude_patterns: vec!["src/lib.rs".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(paths(&root, &filter), ["Dockerfile", "src", "src/main.rs"]);
+        let filter = Filter {
+            exclude_patterns: vec!["src".to_owned(), "!src/lib.rs".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            paths(&root, &filter),
+            ["Dockerfile", "docs", "docs/a.md", "src", "src/lib.rs"]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn sends_requested_files() {
+        let root = tmp_context(&["a/b", "c"]);
+        let entries = walk(&root, &Filter::default()).unwrap();
+        let (outgoing, received) = mpsc::unbounded();
+        let incoming = futures::stream::iter(vec![
+            Ok(Packet {
+                id: 2,
+                ..packet(PacketType::Req)
+            }),
+            Ok(packet(PacketType::Fin)),
+        ]);
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let received: Vec<Packet> = runtime.block_on(async {
+            send(
+                &entries,
+                outgoing.sink_map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e)),
+                incoming,
+            )
+            .await
+            .unwrap();
+            received.collect().await
+        });
+        let types: Vec<_> = received.iter().map(|p| p.r#type).collect();
+        use PacketType::*;
+        assert_eq!(
+            types,
+            [Stat, Stat, Stat, Stat, Data, Data, Fin].map(|t| t as i32)
+        );
+        assert_eq!(received[4].id, 2);
+        assert_eq!(received[4].data, b"c");
+        assert!(received[5].data.is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
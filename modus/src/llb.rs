@@ -0,0 +1,706 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Translation of build plans to buildkit LLB.
+//!
+//! This is used by our frontend, and by `modus transpile --format llb`, which
+//! writes the LLB definition to stdout without needing the frontend image. The
+//! two differ in where they get the build context and image configs from,
+//! which is abstracted by `LlbContext`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use buildkit_frontend::oci::{ImageConfig, ImageSpecification};
+use buildkit_llb::prelude::*;
+use modus_lib::imagegen::{BuildNode, BuildPlan, MergeNode, MergeOperation};
+
+use crate::backend::Backend;
+use crate::buildkit::{self, BuildError};
+use crate::buildkit_llb_types::OwnedOutput;
+
+/// Where the translation gets the build context and base image configs from.
+#[async_trait]
+pub trait LlbContext: Sync {
+    async fn read_local_file(&self, filename: &str) -> Result<Vec<u8>, failure::Error>;
+
+    async fn resolve_image_config(
+        &self,
+        image_ref: &str,
+        source: &source::ImageSource,
+        log_name: &str,
+    ) -> Result<ImageSpecification, failure::Error>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TranslateOptions {
+    pub has_dockerignore: bool,
+    pub no_cache: bool,
+}
+
+pub struct TranslatedPlan {
+    pub outputs: Vec<(OwnedOutput, Arc<ImageSpecification>)>,
+    pub checks: Vec<OwnedOutput>,
+    /// A layer containing all the exported files at their destination path,
+    /// or None if there is nothing to export.
+    pub exports: Option<OwnedOutput>,
+}
+
+pub fn empty_image_config() -> ImageConfig {
+    ImageConfig {
+        user: None,
+        exposed_ports: None,
+        env: None,
+        entrypoint: None,
+        cmd: None,
+        volumes: None,
+        working_dir: None,
+        labels: None,
+        stop_signal: None,
+    }
+}
+
+pub fn scratch_spec() -> ImageSpecification {
+    ImageSpecification {
+        architecture: buildkit_frontend::oci::Architecture::Amd64, // TODO
+        author: None,
+        config: Some(empty_image_config()),
+        created: None,
+        history: None,
+        os: buildkit_frontend::oci::OperatingSystem::Linux,
+        rootfs: None,
+    }
+}
+
+pub async fn handle_build_plan<C: LlbContext>(
+    ctx: &C,
+    options: &TranslateOptions,
+    build_plan: &BuildPlan,
+) -> Result<TranslatedPlan, failure::Error> {
+    let mut translated_nodes: Vec<Option<(OwnedOutput, Arc<ImageSpecification>)>> =
+        Vec::with_capacity(build_plan.nodes.len());
+    for _ in 0..build_plan.nodes.len() {
+        // Need to push in a loop since type is not cloneable.
+        translated_nodes.push(None);
+    }
+
+    fn get_cwd_from_image_spec(image_spec: &ImageSpecification) -> PathBuf {
+        image_spec
+            .config
+            .as_ref()
+            .and_then(|x| x.working_dir.clone())
+            .map(|x| {
+                if !x.has_root() {
+                    PathBuf::from("/").join(x)
+                } else {
+                    x
+                }
+            })
+            .unwrap_or_else(|| PathBuf::from("/"))
+    }
+
+    async fn get_local_source_for_copy<C: LlbContext>(
+        ctx: &C,
+        should_read_ignore_file: bool,
+    ) -> Result<OperationOutput<'static>, failure::Error> {
+        let mut source = Source::local("context").custom_name("Sending local context for copy");
+        if should_read_ignore_file {
+            let dockerignore_bytes = ctx.read_local_file(".dockerignore").await?;
+            let dockerignore = std::str::from_utf8(&dockerignore_bytes)
+                .expect("Expected .dockerignore to contain valid utf-8 content.");
+            for line in dockerignore.lines() {
+                source = source.add_exclude_pattern(line);
+            }
+        }
+        source = source.add_exclude_pattern(buildkit::TMP_PREFIX_IGNORE_PATTERN);
+        Ok(source.ref_counted().output())
+    }
+
+    let local_context = get_local_source_for_copy(ctx, options.has_dockerignore).await?;
+
+    for node_id in build_plan.topological_order().into_iter() {
+        let node = &build_plan.nodes[node_id];
+        use BuildNode::*;
+
+        fn new_cmd(
+            imgspec: &ImageSpecification,
+            this_cwd: &str,
+            parent: &OwnedOutput,
+            options: &TranslateOptions,
+        ) -> Command<'static> {
+            let mut cmd = Command::run("sh"); // TDDO: use image shell config
+            let user = imgspec
+                .config
+                .as_ref()
+                .and_then(|x| x.user.as_ref().map(|x| &x[..]));
+            cmd = cmd.cwd(get_cwd_from_image_spec(imgspec).join(this_cwd));
+            if let Some(user) = user {
+                cmd = cmd.user(user);
+            } else {
+                // This seems to cause docker to not try to set uid (thereby
+                // trying to resolve usernames) at all, which is what we want.
+                cmd = cmd.user("");
+            }
+            let envs = imgspec.config.as_ref().and_then(|x| x.env.as_ref());
+            if let Some(env_map) = envs {
+                for (key, value) in env_map.iter() {
+                    cmd = cmd.env(key, value);
+                }
+            }
+            cmd = cmd.mount(Mount::Layer(OutputIdx(0), parent.output(), "/"));
+            if options.no_cache {
+                cmd = cmd.ignore_cache(true);
+            }
+            cmd
+        }
+
+        fn iter_hm_sorted<K: Ord, V>(hm: &HashMap<K, V>) -> Vec<(&K, &V)> {
+            let mut v = hm.iter().collect::<Vec<_>>();
+            v.sort_unstable_by_key(|(k, _)| *k);
+            v
+        }
+
+        fn add_envs<'a>(mut cmd: Command<'a>, envs: &HashMap<String, String>) -> Command<'a> {
+            for (k, v) in iter_hm_sorted(envs) {
+                cmd = cmd.env(k, v);
+            }
+            cmd
+        }
+
+        let new_node: (OwnedOutput, Arc<ImageSpecification>) = match node {
+            /*
+                resolve_image_config will fail if we try to resolve an empty
+                image (like that produced by FROM scratch). Therefore, we have a
+                special case here for scratch images that don't try to resolve
+                its spec.
+            */
+            FromScratch {
+                scratch_ref: Some(scratch_ref),
+            } => {
+                let img_s = Source::image(scratch_ref).custom_name("from(\"scratch\")");
+                (img_s.ref_counted().into(), Arc::new(scratch_spec()))
+            }
+            /*
+                Backends which build without a local image store can't resolve
+                scratch ahead of time, so we create an empty layer instead.
+            */
+            FromScratch { scratch_ref: None } => {
+                let empty = FileSystem::mkdir(OutputIdx(0), LayerPath::Scratch("/"))
+                    .make_parents(true)
+                    .into_operation()
+                    .custom_name("from(\"scratch\")");
+                (empty.ref_counted().into(), Arc::new(scratch_spec()))
+            }
+            From {
+                image_ref,
+                display_name,
+            } => {
+                let img_s =
+                    Source::image(image_ref).custom_name(format!("from({:?})", display_name));
+                let log_name = format!("from({:?}) :: resolve image config", display_name);
+                let resolved_config = ctx
+                    .resolve_image_config(image_ref, &img_s, &log_name)
+                    .await?;
+                (img_s.ref_counted().into(), Arc::new(resolved_config))
+            }
+            Run {
+                parent,
+                command,
+                cwd,
+                additional_envs,
+            } => {
+                let parent = translated_nodes[*parent]
+                    .as_ref()
+                    .expect("Expected dependencies to already be built");
+                let parent_config = parent.1.clone();
+                let mut cmd = new_cmd(&*parent_config, &cwd[..], &parent.0, &options)
+                    .args(&["-c", &command[..]])
+                    .custom_name(format!("run({:?})", command));
+                cmd = add_envs(cmd, additional_envs);
+                let o = OwnedOutput::from_command(cmd.ref_counted(), 0);
+                (o, parent_config)
+            }
+            CopyFromImage {
+                parent,
+                src_image,
+                src_path: raw_src_path,
+                dst_path: raw_dst_path,
+            } => {
+                let parent = translated_nodes[*parent].as_ref().unwrap();
+                let src_image = translated_nodes[*src_image].as_ref().unwrap();
+                let src_cwd = get_cwd_from_image_spec(&src_image.1);
+                let src_path = src_cwd.join(raw_src_path);
+                let dst_path = get_cwd_from_image_spec(&parent.1).join(raw_dst_path);
+                let o = FileSystem::copy()
+                    .from(LayerPath::Other(src_image.0.output(), src_path))
+                    .to(OutputIdx(0), LayerPath::Other(parent.0.output(), dst_path))
+                    .create_path(true)
+                    .recursive(true)
+                    .into_operation()
+                    .custom_name(format!(
+                        "...::copy({:?}, {:?})",
+                        &raw_src_path, &raw_dst_path
+                    ))
+                    .ref_counted();
+                (o.into(), parent.1.clone())
+            }
+            CopyFromLocal {
+                parent,
+                src_path,
+                dst_path: raw_dst_path,
+            } => {
+                let parent = translated_nodes[*parent].as_ref().unwrap();
+                let dst_path = get_cwd_from_image_spec(&parent.1).join(raw_dst_path);
+                let o = FileSystem::copy()
+                    .from(LayerPath::Other(local_context.clone(), src_path))
+                    .to(OutputIdx(0), LayerPath::Other(parent.0.output(), dst_path))
+                    .create_path(true)
+                    .recursive(true)
+                    .into_operation()
+                    .custom_name(format!("copy({:?}, {:?})", &src_path, &raw_dst_path))
+                    .ref_counted();
+                (o.into(), parent.1.clone())
+            }
+            SetWorkdir {
+                parent,
+                new_workdir,
+            } => {
+                let parent = translated_nodes[*parent]
+                    .as_ref()
+                    .expect("Expected dependencies to already be built");
+                let parent_config = &*parent.1;
+                let parent_dir = get_cwd_from_image_spec(parent_config);
+                let mut new_config = parent_config.clone();
+                new_config
+                    .config
+                    .get_or_insert_with(empty_image_config)
+                    .working_dir = Some(parent_dir.join(new_workdir));
+                let new_config = Arc::new(new_config);
+                (parent.0.clone(), new_config)
+            }
+            SetEntrypoint {
+                parent,
+                new_entrypoint,
+            } => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut p_conf = (*p_conf).clone();
+                let img_conf = p_conf.config.get_or_insert_with(empty_image_config);
+                img_conf.entrypoint = Some(new_entrypoint.to_owned());
+                img_conf.cmd = None;
+                (p_out, Arc::new(p_conf))
+            }
+            SetCmd { parent, new_cmd } => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut p_conf = (*p_conf).clone();
+                let img_conf = p_conf.config.get_or_insert_with(empty_image_config);
+                img_conf.cmd = Some(new_cmd.to_owned());
+                (p_out, Arc::new(p_conf))
+            }
+            SetLabel {
+                parent,
+                label,
+                value,
+            } => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut p_conf = (*p_conf).clone();
+                p_conf
+                    .config
+                    .get_or_insert_with(empty_image_config)
+                    .labels
+                    .get_or_insert_with(BTreeMap::new)
+                    .insert(label.to_owned(), value.to_owned());
+                (p_out, Arc::new(p_conf))
+            }
+            Merge(MergeNode { parent, operations }) => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut cmd = new_cmd(&*p_conf, "", &p_out, &options);
+                let mut name = Vec::new();
+                let mut script = Vec::new();
+                let image_cwd = get_cwd_from_image_spec(&*p_conf);
+                debug_assert!(image_cwd.is_absolute());
+                use shell_escape::escape;
+                let mut mount_id = 0usize;
+                fn mkdir_pf(path: &str, script: &mut Vec<String>) {
+                    script.push(format!("(mkdir -p {} || true)", escape(path.into())));
+                }
+                fn cd(path: &str, script: &mut Vec<String>) {
+                    mkdir_pf(path, script);
+                    script.push(format!("echo cd {cd} && cd {cd}", cd = escape(path.into())));
+                }
+                fn cp_content(src: PathBuf, dst: &str, script: &mut Vec<String>) {
+                    let src_str = src.to_str().unwrap();
+                    let _s = src.join(".");
+                    let src_plus_dot = _s.to_str().unwrap();
+                    script.push(format!(
+                        "echo COPY '->' {dst} && (if [ -d {src} ]; then cp -r {src_plus_dot} {dst}; else cp -r {src} {dst}; fi)",
+                        src=escape(src_str.into()),
+                        dst=escape(dst.into()),
+                        src_plus_dot=escape(src_plus_dot.into())
+                    ));
+                }
+                for op in operations {
+                    match op {
+                        MergeOperation::Run {
+                            command,
+                            cwd,
+                            additional_envs,
+                        } => {
+                            let resolved_cwd = image_cwd.join(cwd);
+                            let resolved_cwd = resolved_cwd.to_str().unwrap(); // TODO: report error if image cwd is not valid utf8.
+                            cd(resolved_cwd, &mut script);
+                            for (k, v) in iter_hm_sorted(additional_envs) {
+                                script.push(format!(
+                                    "export {}={}",
+                                    escape(k.into()),
+                                    escape(v.into())
+                                ));
+                            }
+                            script.push(format!(
+                                "echo {cmd} && sh -c {cmd}",
+                                cmd = escape(command.into())
+                            ));
+                            name.push(format!("run({:?})::in_workdir({:?})", command, cwd));
+                        }
+                        MergeOperation::CopyFromImage {
+                            src_image,
+                            src_path,
+                            dst_path,
+                        } => {
+                            let (src_opt, src_conf) = translated_nodes[*src_image].clone().unwrap();
+                            let src_cwd = get_cwd_from_image_spec(&src_conf);
+                            let src_path = src_cwd.join(src_path);
+                            let dst_path = image_cwd.join(dst_path);
+
+                            let mut mount_dir = OsString::from("/__buildkit_merge_mount_");
+                            mount_dir.push(OsStr::new(&mount_id.to_string()));
+                            mount_id += 1;
+                            debug_assert!(src_path.is_absolute());
+                            debug_assert!(dst_path.is_absolute());
+                            mount_dir.push(&src_path);
+                            let mount_dir = PathBuf::from(mount_dir);
+                            cmd = cmd.mount(Mount::ReadOnlySelector(
+                                src_opt.output(),
+                                mount_dir.clone(),
+                                src_path.clone(),
+                            ));
+
+                            if let Some(par) = dst_path.parent() {
+                                mkdir_pf(par.to_str().unwrap(), &mut script);
+                            }
+                            cp_content(mount_dir, dst_path.to_str().unwrap(), &mut script);
+                            name.push(format!("...::copy({:?}, {:?})", src_path, dst_path));
+                        }
+                        MergeOperation::CopyFromLocal { src_path, dst_path } => {
+                            let mut mount_dir = OsString::from("/__buildkit_merge_mount_");
+                            mount_dir.push(OsStr::new(&mount_id.to_string()));
+                            mount_id += 1;
+                            mount_dir.push("/");
+                            debug_assert!(!src_path.starts_with("/"));
+                            mount_dir.push(src_path);
+                            let dst_path = image_cwd.join(dst_path);
+                            debug_assert!(dst_path.is_absolute());
+                            let mount_dir = PathBuf::from(mount_dir);
+                            cmd = cmd.mount(Mount::ReadOnlySelector(
+                                local_context.clone(),
+                                mount_dir.clone(),
+                                PathBuf::from(src_path),
+                            ));
+
+                            if let Some(par) = dst_path.parent() {
+                                mkdir_pf(par.to_str().unwrap(), &mut script);
+                            }
+                            cp_content(mount_dir, dst_path.to_str().unwrap(), &mut script);
+                            name.push(format!("copy({:?}, {:?})", src_path, dst_path));
+                        }
+                    }
+                }
+                cmd = cmd.args(&["-c", &script.join(" && ")]);
+                cmd = cmd.custom_name(format!("merge: {}", name.join(" + ")));
+
+                (OwnedOutput::from_command(cmd.ref_counted(), 0), p_conf)
+            }
+            SetEnv { parent, key, value } => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut p_conf = (*p_conf).clone();
+                p_conf
+                    .config
+                    .get_or_insert_with(empty_image_config)
+                    .env
+                    .get_or_insert_with(BTreeMap::new)
+                    .insert(key.to_owned(), value.to_owned());
+                (p_out, Arc::new(p_conf))
+            }
+            AppendEnvValue { parent, key, value } => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut p_conf = (*p_conf).clone();
+                p_conf
+                    .config
+                    .get_or_insert_with(empty_image_config)
+                    .env
+                    .get_or_insert_with(BTreeMap::new)
+                    .entry(key.to_owned())
+                    .or_insert_with(String::new)
+                    .push_str(&value);
+                (p_out, Arc::new(p_conf))
+            }
+            SetUser { parent, user } => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut p_conf = (*p_conf).clone();
+                p_conf.config.get_or_insert_with(empty_image_config).user = Some(user.to_owned());
+                (p_out, Arc::new(p_conf))
+            }
+        };
+        translated_nodes[node_id] = Some(new_node);
+    }
+    let mut outputs: Vec<(OwnedOutput, Arc<ImageSpecification>)> = Vec::new();
+    for o in &build_plan.outputs {
+        outputs.push(
+            translated_nodes[o.node]
+                .clone()
+                .expect("Expected output to be built"),
+        );
+    }
+    let checks = build_plan
+        .checks
+        .iter()
+        .map(|&c| {
+            translated_nodes[c]
+                .clone()
+                .expect("Expected check to be built")
+                .0
+        })
+        .collect();
+    let mut exports: Option<OwnedOutput> = None;
+    for export in &build_plan.exports {
+        let (src_out, src_conf) = translated_nodes[export.node]
+            .clone()
+            .expect("Expected exported image to be built");
+        let src_path = get_cwd_from_image_spec(&src_conf).join(&export.src_path);
+        let dst_path = PathBuf::from("/").join(&export.dst_path);
+        let dst = match &exports {
+            Some(prev) => LayerPath::Other(prev.output(), dst_path),
+            None => LayerPath::Scratch(dst_path),
+        };
+        let o = FileSystem::copy()
+            .from(LayerPath::Other(src_out.output(), src_path))
+            .to(OutputIdx(0), dst)
+            .create_path(true)
+            .recursive(true)
+            .into_operation()
+            .custom_name(format!(
+                "...::export({:?}, {:?})",
+                export.src_path, export.dst_path
+            ))
+            .ref_counted();
+        exports = Some(o.into());
+    }
+    Ok(TranslatedPlan {
+        outputs,
+        checks,
+        exports,
+    })
+}
+
+/// A command which mounts all of `outputs` on top of `stub`, which forces
+/// buildkit to build all of them in parallel.
+pub fn join_outputs<'a>(
+    stub: OperationOutput<'static>,
+    outputs: impl IntoIterator<Item = &'a OwnedOutput>,
+) -> OwnedOutput {
+    let mut command = Command::run("true")
+        .cwd("/")
+        .mount(Mount::Layer(OutputIdx(0), stub, "/"));
+    for (i, o) in outputs.into_iter().enumerate() {
+        let idx = i + 1;
+        command = command.mount(Mount::Layer(
+            OutputIdx(idx as u32),
+            o.output(),
+            format!("/_{}", idx),
+        ));
+    }
+    command = command.custom_name("Finishing multiple output images");
+    OwnedOutput::from_command(command.ref_counted(), 0)
+}
+
+/// Format for `image inspect` giving the parts of an image config we need, in
+/// the shape of the OCI image specification.
+const IMAGE_SPEC_FORMAT: &str =
+    r#"{"architecture":{{json .Architecture}},"os":{{json .Os}},"config":{{json .Config}}}"#;
+
+/// Reads the build context from a local directory, and the configs of base
+/// images from the local image store of a backend, pulling them if needed.
+pub struct LocalContext<'a> {
+    pub context_dir: &'a Path,
+    pub backend: &'a dyn Backend,
+}
+
+impl LocalContext<'_> {
+    fn inspect(&self, image_ref: &str) -> Result<ImageSpecification, BuildError> {
+        let out = self
+            .backend
+            .inspect_command(image_ref, IMAGE_SPEC_FORMAT)
+            .ok_or(BuildError::UnsupportedByBackend(
+                self.backend.name(),
+                "resolving image configs",
+            ))?
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()?;
+        if !out.status.success() {
+            return Err(BuildError::DockerInspectFailed(
+                image_ref.to_owned(),
+                out.status,
+            ));
+        }
+        serde_json::from_slice(&out.stdout)
+            .map_err(|e| BuildError::InvalidImageConfig(image_ref.to_owned(), e.to_string()))
+    }
+}
+
+#[async_trait]
+impl LlbContext for LocalContext<'_> {
+    async fn read_local_file(&self, filename: &str) -> Result<Vec<u8>, failure::Error> {
+        Ok(std::fs::read(self.context_dir.join(filename))?)
+    }
+
+    async fn resolve_image_config(
+        &self,
+        image_ref: &str,
+        _source: &source::ImageSource,
+        _log_name: &str,
+    ) -> Result<ImageSpecification, failure::Error> {
+        match self.inspect(image_ref) {
+            Err(BuildError::DockerInspectFailed(..)) => {}
+            res => return Ok(res?),
+        }
+        let st = self
+            .backend
+            .pull_command(image_ref)
+            .ok_or(BuildError::UnsupportedByBackend(
+                self.backend.name(),
+                "pulling images",
+            ))?
+            .stdout(Stdio::null())
+            .status()?;
+        if !st.success() {
+            return Err(BuildError::DockerPullFailed(image_ref.to_owned(), st).into());
+        }
+        Ok(self.inspect(image_ref)?)
+    }
+}
+
+/// Translates a build plan into a single LLB graph. If there is more than one
+/// thing to build (outputs, checks and exports), they are joined like the
+/// frontend does for multiple outputs.
+pub fn plan_terminal<C: LlbContext>(
+    ctx: &C,
+    options: &TranslateOptions,
+    build_plan: &BuildPlan,
+) -> Result<Terminal<'static>, failure::Error> {
+    let mut runtime = tokio::runtime::Builder::new().basic_scheduler().build()?;
+    let TranslatedPlan {
+        outputs,
+        checks,
+        exports,
+    } = runtime.block_on(handle_build_plan(ctx, options, build_plan))?;
+    let mut all: Vec<OwnedOutput> = outputs
+        .into_iter()
+        .map(|(o, _)| o)
+        .chain(checks)
+        .chain(exports)
+        .collect();
+    let output = if all.len() == 1 {
+        all.pop().unwrap()
+    } else {
+        let stub = Source::image("alpine")
+            .custom_name("Getting an alpine image as a stub for the final image")
+            .ref_counted();
+        join_outputs(SingleOwnedOutput::output(&stub), &all)
+    };
+    Ok(Terminal::with(output.output()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modus_lib::imagegen::Output;
+
+    struct FakeContext;
+
+    #[async_trait]
+    impl LlbContext for FakeContext {
+        async fn read_local_file(&self, filename: &str) -> Result<Vec<u8>, failure::Error> {
+            Err(failure::format_err!("unexpected read of {}", filename))
+        }
+
+        async fn resolve_image_config(
+            &self,
+            image_ref: &str,
+            _source: &source::ImageSource,
+            _log_name: &str,
+        ) -> Result<ImageSpecification, failure::Error> {
+            assert_eq!(image_ref, "alpine");
+            let mut spec = scratch_spec();
+            spec.config.as_mut().unwrap().working_dir = Some(PathBuf::from("/app"));
+            Ok(spec)
+        }
+    }
+
+    #[test]
+    fn plan_to_definition() {
+        let mut plan = BuildPlan::new();
+        let from = plan.new_node(
+            BuildNode::From {
+                image_ref: "alpine".to_owned(),
+                display_name: "alpine".to_owned(),
+            },
+            vec![],
+        );
+        let run = plan.new_node(
+            BuildNode::Run {
+                parent: from,
+                command: "echo hello".to_owned(),
+                cwd: "".to_owned(),
+                additional_envs: HashMap::new(),
+            },
+            vec![from],
+        );
+        plan.outputs.push(Output {
+            node: run,
+            source_literal: None,
+        });
+
+        let definition = plan_terminal(&FakeContext, &TranslateOptions::default(), &plan)
+            .unwrap()
+            .into_definition();
+        // The image, the command and the terminal.
+        assert_eq!(definition.def.len(), 3);
+        let names: Vec<&str> = definition
+            .metadata
+            .values()
+            .filter_map(|m| m.description.get("llb.customname"))
+            .map(|s| s.as_str())
+            .collect();
+        assert!(names.contains(&"run(\"echo hello\")"));
+        assert!(names.contains(&"from(\"alpine\")"));
+    }
+}
@@ -0,0 +1,347 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Lockfiles, which pin the image references used in `from` to a digest.
+//!
+//! `modus lock` pulls each image reached by a query and records the digest of
+//! it in a `Modusfile.lock` next to the Modusfile. `modus build --locked` then
+//! rewrites the references in the build plan to `name@digest`, which are used
+//! as-is instead of being resolved again.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use colored::Colorize;
+use modus_lib::imagegen::{BuildNode, BuildPlan};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::Backend;
+use crate::buildkit::{image_ref_is_hash, BuildError};
+
+const LOCKFILE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub version: u32,
+    /// Maps image references, as written in `from`, to their digest.
+    pub images: BTreeMap<String, String>,
+}
+
+impl Default for Lockfile {
+    fn default() -> Self {
+        Lockfile {
+            version: LOCKFILE_VERSION,
+            images: BTreeMap::new(),
+        }
+    }
+}
+
+/// Returns the path of the lockfile corresponding to a Modusfile, e.g. `Modusfile.lock`.
+pub fn lockfile_path(modusfile: &Path) -> PathBuf {
+    let mut name = modusfile
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".lock");
+    modusfile.with_file_name(name)
+}
+
+/// The image references used by the build plan which should be locked.
+pub fn image_refs(plan: &BuildPlan) -> BTreeSet<String> {
+    plan.nodes
+        .iter()
+        .filter_map(|node| match node {
+            BuildNode::From { image_ref, .. } if !image_ref_is_hash(image_ref) => {
+                Some(image_ref.to_owned())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Removes the tag from an image reference, e.g. `alpine:3.15` becomes `alpine`,
+/// but `localhost:5000/alpine` is left unchanged.
+fn strip_tag(image_ref: &str) -> &str {
+    match image_ref.rfind(':') {
+        Some(i) if !image_ref[i..].contains('/') => &image_ref[..i],
+        _ => image_ref,
+    }
+}
+
+impl Lockfile {
+    pub fn read(path: &Path) -> Result<Lockfile, BuildError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| BuildError::UnableToReadLockfile(path.display().to_string(), e))?;
+        let lockfile: Lockfile = serde_json::from_str(&content)
+            .map_err(|e| BuildError::InvalidLockfile(path.display().to_string(), e.to_string()))?;
+        if lockfile.version != LOCKFILE_VERSION {
+            return Err(BuildError::InvalidLockfile(
+                path.display().to_string(),
+                format!(
+                    "unsupported version {}, expected {}",
+                    lockfile.version, LOCKFILE_VERSION
+                ),
+            ));
+        }
+        Ok(lockfile)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), BuildError> {
+        let mut content = serde_json::to_string_pretty(self).expect("Unable to serialize lockfile");
+        content.push('\n');
+        std::fs::write(path, content)
+            .map_err(|e| BuildError::UnableToWriteLockfile(path.display().to_string(), e))
+    }
+
+    /// The pinned reference to use instead of `image_ref`, if it is locked.
+    pub fn pinned_ref(&self, image_ref: &str) -> Option<String> {
+        self.images
+            .get(image_ref)
+            .map(|digest| format!("{}@{}", strip_tag(image_ref), digest))
+    }
+
+    /// Replaces every image reference in the plan with its pinned version.
+    /// Fails, listing the missing references, if any image is not in the lockfile.
+    pub fn apply(&self, plan: &mut BuildPlan) -> Result<(), BuildError> {
+        let missing: Vec<String> = image_refs(plan)
+            .into_iter()
+            .filter(|r| !self.images.contains_key(r))
+            .collect();
+        if !missing.is_empty() {
+            return Err(BuildError::ImagesNotLocked(missing.join(", ")));
+        }
+        for node in plan.nodes.iter_mut() {
+            if let BuildNode::From { image_ref, .. } = node {
+                if let Some(pinned) = self.pinned_ref(image_ref) {
+                    *image_ref = pinned;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pulls an image and returns its registry digest, e.g. `sha256:1234...`.
+fn resolve_digest(backend: &dyn Backend, image_ref: &str) -> Result<String, BuildError> {
+    let unsupported = || BuildError::UnsupportedByBackend(backend.name(), "locking images");
+    let st = backend
+        .pull_command(image_ref)
+        .ok_or_else(unsupported)?
+        .stdout(Stdio::null())
+        .status()?;
+    if !st.success() {
+        return Err(BuildError::DockerPullFailed(image_ref.to_owned(), st));
+    }
+    let out = backend
+        .inspect_command(image_ref, "{{range .RepoDigests}}{{println .}}{{end}}")
+        .ok_or_else(unsupported)?
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        return Err(BuildError::DockerInspectFailed(
+            image_ref.to_owned(),
+            out.status,
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    repo_digest(&stdout, image_ref)
+        .ok_or_else(|| BuildError::NoDigestForImage(image_ref.to_owned()))
+}
+
+/// Normalizes the repository of an image reference without its tag, as docker
+/// does, e.g. `alpine` becomes `docker.io/library/alpine`.
+fn normalize_repository(repository: &str) -> String {
+    let (domain, path) = match repository.split_once('/') {
+        Some((domain, path))
+            if domain.contains('.') || domain.contains(':') || domain == "localhost" =>
+        {
+            (domain, path)
+        }
+        _ => ("docker.io", repository),
+    };
+    let domain = if domain == "index.docker.io" {
+        "docker.io"
+    } else {
+        domain
+    };
+    if domain == "docker.io" && !path.contains('/') {
+        format!("{}/library/{}", domain, path)
+    } else {
+        format!("{}/{}", domain, path)
+    }
+}
+
+/// Picks the digest of `image_ref` among the `RepoDigests` of an image, one per
+/// line. An image pulled under several names has a digest for each repository.
+fn repo_digest(repo_digests: &str, image_ref: &str) -> Option<String> {
+    let repository = normalize_repository(strip_tag(image_ref));
+    repo_digests
+        .lines()
+        .filter_map(|l| l.trim().split_once('@'))
+        .find(|(repo, _)| normalize_repository(repo) == repository)
+        .map(|(_, digest)| digest.to_owned())
+}
+
+/// Locks the given image references. Existing entries are kept unless `update` is set,
+/// in which case the lockfile is rebuilt from scratch. Entries for images which
+/// are not in `refs` anymore are removed.
+pub fn lock_images(
+    refs: &BTreeSet<String>,
+    existing: Option<Lockfile>,
+    update: bool,
+    backend: &dyn Backend,
+) -> Result<Lockfile, BuildError> {
+    let mut lockfile = match existing {
+        Some(l) if !update => l,
+        _ => Lockfile::default(),
+    };
+    lockfile
+        .images
+        .retain(|image_ref, _| refs.contains(image_ref));
+    for image_ref in refs {
+        if lockfile.images.contains_key(image_ref) {
+            continue;
+        }
+        let digest = resolve_digest(backend, image_ref)?;
+        eprintln!(
+            "{}",
+            format!("Locked from({:?}) to {}", image_ref, digest).blue()
+        );
+        lockfile.images.insert(image_ref.to_owned(), digest);
+    }
+    Ok(lockfile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_tag() {
+        assert_eq!(strip_tag("alpine"), "alpine");
+        assert_eq!(strip_tag("alpine:3.15"), "alpine");
+        assert_eq!(strip_tag("localhost:5000/alpine"), "localhost:5000/alpine");
+        assert_eq!(
+            strip_tag("localhost:5000/alpine:3.15"),
+            "localhost:5000/alpine"
+        );
+    }
+
+    #[test]
+    fn test_repo_digest() {
+        let digests = "localhost:5000/alpine@sha256:local\n\
+                       docker.io/library/alpine@sha256:hub\n";
+        assert_eq!(
+            repo_digest(digests, "alpine:3.15"),
+            Some("sha256:hub".to_owned())
+        );
+        assert_eq!(
+            repo_digest(digests, "docker.io/library/alpine"),
+            Some("sha256:hub".to_owned())
+        );
+        assert_eq!(
+            repo_digest(digests, "localhost:5000/alpine:3.15"),
+            Some("sha256:local".to_owned())
+        );
+        assert_eq!(repo_digest(digests, "debian"), None);
+        assert_eq!(
+            repo_digest("alpine@sha256:hub\n", "index.docker.io/library/alpine"),
+            Some("sha256:hub".to_owned())
+        );
+    }
+
+    /// Writes a fake backend which pulls anything, and reports every image as
+    /// also pushed to a local registry, with another digest there.
+    #[cfg(unix)]
+    fn fake_backend(dir: &Path) -> std::sync::Arc<dyn Backend> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let program = dir.join("fake-docker");
+        std::fs::write(
+            &program,
+            "#!/bin/sh\n\
+             if [ \"$1\" = image ]; then\n\
+             echo \"localhost:5000/$5@sha256:local\"\n\
+             echo \"$5@sha256:$5\"\n\
+             fi\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        crate::backend::BackendKind::Docker.backend(program.to_str())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock_images_prunes_unused() {
+        let dir = std::env::temp_dir().join(crate::buildkit::gen_tmp_filename());
+        std::fs::create_dir(&dir).unwrap();
+        let backend = fake_backend(&dir);
+
+        let mut existing = Lockfile::default();
+        existing
+            .images
+            .insert("alpine".to_owned(), "sha256:old".to_owned());
+        existing
+            .images
+            .insert("busybox".to_owned(), "sha256:old".to_owned());
+        let refs: BTreeSet<String> = ["alpine", "debian"].iter().map(|s| s.to_string()).collect();
+        let lockfile = lock_images(&refs, Some(existing), false, &*backend).unwrap();
+        assert_eq!(
+            lockfile.images.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("alpine".to_owned(), "sha256:old".to_owned()),
+                ("debian".to_owned(), "sha256:debian".to_owned()),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut plan = BuildPlan::new();
+        plan.new_node(
+            BuildNode::From {
+                image_ref: "alpine:3.15".to_owned(),
+                display_name: "alpine:3.15".to_owned(),
+            },
+            vec![],
+        );
+        let mut lockfile = Lockfile::default();
+        assert!(matches!(
+            lockfile.apply(&mut plan.clone()),
+            Err(BuildError::ImagesNotLocked(_))
+        ));
+
+        lockfile
+            .images
+            .insert("alpine:3.15".to_owned(), "sha256:abcd".to_owned());
+        lockfile.apply(&mut plan).unwrap();
+        match &plan.nodes[0] {
+            BuildNode::From {
+                image_ref,
+                display_name,
+            } => {
+                assert_eq!(image_ref, "alpine@sha256:abcd");
+                assert_eq!(display_name, "alpine:3.15");
+            }
+            _ => unreachable!(),
+        }
+        assert!(image_refs(&plan).is_empty());
+    }
+}
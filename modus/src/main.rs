@@ -14,8 +14,15 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod backend;
 mod buildkit;
+mod buildkit_llb_types;
+mod buildkitd;
+mod filesync;
+mod llb;
+mod lockfile;
 mod reporting;
+mod state;
 
 use clap::{arg, crate_version, Arg, Command};
 use codespan_reporting::{
@@ -33,7 +40,7 @@ use modus_lib::*;
 use modus_lib::{analysis::ModusSemantics, sld::tree_from_modusfile};
 use ptree::write_tree;
 use std::{ffi::OsStr, fs, path::Path, time::Instant};
-use std::{io::Write, path::PathBuf};
+use std::{io::Write, path::PathBuf, sync::Arc};
 
 use modus_lib::modusfile::Modusfile;
 
@@ -57,6 +64,108 @@ fn get_file_or_exit(path: &Path) -> SimpleFile<&str, String> {
     SimpleFile::new(file_name, file_content)
 }
 
+fn print_build_error_and_exit(e_str: &str, w: &StandardStream) -> ! {
+    let mut w = w.lock();
+    (move || -> std::io::Result<()> {
+        w.set_color(ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true))?;
+        write!(w, "build error")?;
+        w.set_color(&ColorSpec::new())?;
+        write!(w, ": ")?;
+        w.set_color(ColorSpec::new().set_bold(true))?;
+        write!(w, "{}", e_str)?;
+        w.set_color(&ColorSpec::new())?;
+        writeln!(w)?;
+        w.flush()?;
+        Ok(())
+    })()
+    .expect("Unable to write to stderr.");
+    std::process::exit(1)
+}
+
+fn backend_args() -> [Arg<'static>; 2] {
+    [
+        Arg::new("BACKEND")
+            .long("backend")
+            .takes_value(true)
+            .value_name("NAME")
+            .possible_values(backend::BACKEND_NAMES)
+            .default_value("docker")
+            .help("The tool used to build and store images")
+            .long_help("The tool used to build and store images.\n\
+                        docker: use the buildkit integration of docker build.\n\
+                        podman: build with buildctl against a buildkitd instance, and load the images into podman.\n\
+                        buildctl: build with buildctl against a buildkitd instance, keeping images in buildkitd.\n\
+                        buildkitd: like buildctl, but talk to buildkitd directly, building all the images in one session.\n\
+                        The podman, buildctl and buildkitd backends respect BUILDKIT_HOST, and do not support offline builds or --state-file."),
+        Arg::new("BACKEND_PROGRAM")
+            .long("backend-program")
+            .takes_value(true)
+            .value_name("PATH")
+            .required(false)
+            .help("Path of the executable of the backend, e.g. a custom docker binary"),
+    ]
+}
+
+fn backend_from_args(sub: &clap::ArgMatches) -> Arc<dyn backend::Backend> {
+    let kind: backend::BackendKind = sub
+        .value_of("BACKEND")
+        .unwrap()
+        .parse()
+        .expect("Expected clap to validate the backend name");
+    kind.backend(sub.value_of("BACKEND_PROGRAM"))
+}
+
+/// Parses and checks the Modusfile and the query, and plans the build, or
+/// prints the diagnostics and exits.
+fn plan_or_exit(
+    file: &SimpleFile<&str, String>,
+    query: &str,
+    err_writer: &StandardStream,
+    config: &Config,
+) -> imagegen::BuildPlan {
+    let emit = |diags: &[Diagnostic<()>], f: &SimpleFile<&str, &str>| {
+        for diag in diags {
+            term::emit(&mut err_writer.lock(), config, f, diag)
+                .expect("Error when printing to stderr.")
+        }
+    };
+    let query: modusfile::Expression = match query.parse::<modusfile::Expression>() {
+        Ok(e) => e.without_position(),
+        Err(e) => {
+            eprintln!("❌ Did not parse goal successfully",);
+            emit(&e, &SimpleFile::new("goal", query));
+            std::process::exit(1);
+        }
+    };
+    let source_file = SimpleFile::new(*file.name(), file.source().as_str());
+    let mf: Modusfile = match file.source().parse() {
+        Ok(mf) => mf,
+        Err(e) => {
+            eprintln!("❌ Did not parse Modusfile successfully.",);
+            emit(&e, &source_file);
+            std::process::exit(1);
+        }
+    };
+    if !analysis::check_and_output_analysis(
+        &mf.kinds(),
+        &mf,
+        Some(&query),
+        false,
+        &mut err_writer.lock(),
+        config,
+        file,
+    ) {
+        std::process::exit(1)
+    }
+    match imagegen::plan_from_modusfile(mf, query) {
+        Ok(plan) => plan,
+        Err(e) => {
+            emit(&e, &source_file);
+            std::process::exit(1)
+        }
+    }
+}
+
 fn main() {
     let matches = Command::new("modus")
         .version(crate_version!())
@@ -78,6 +187,20 @@ fn main() {
                         .help("Specify the build target(s)")
                         .index(2),
                 )
+                .arg(
+                    Arg::new("FORMAT")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(["dockerfile", "llb"])
+                        .default_value("dockerfile")
+                        .help("The output format")
+                        .long_help("The output format.\n\
+                                    dockerfile: a multi-stage Dockerfile.\n\
+                                    llb: a buildkit LLB definition in protobuf, which can be piped into `buildctl build`. \
+                                    The configs of base images are read from the local image store of the backend, \
+                                    and images are pulled if needed. The directory of the Modusfile is used as the build context.")
+                )
+                .args(backend_args())
         )
         .subcommand(
             Command::new("build")
@@ -181,6 +304,349 @@ fn main() {
                         .long_help("Output profiling information to a JSON file.\n\
                                     The format of the output is not specified.")
                 )
+                .arg(
+                    Arg::new("OFFLINE")
+                        .long("offline")
+                        .help("Only use images which are available locally")
+                        .long_help("Only use images which are available locally, instead of pulling them.\n\
+                                    This includes the buildkit frontend image, which can be loaded with e.g. docker load.\n\
+                                    The build fails, listing the missing images, if any of them is not available.")
+                )
+                .arg(
+                    Arg::new("LOCKED")
+                        .long("locked")
+                        .help("Use the image digests in the lockfile")
+                        .long_help("Use the image digests in the lockfile next to the Modusfile, e.g. Modusfile.lock.\n\
+                                    The build fails if an image is not in the lockfile. Use `modus lock` to create it.")
+                )
+                .arg(
+                    Arg::new("EXPORT_DIR")
+                        .long("export-dir")
+                        .allow_invalid_utf8(true)
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .default_value(".")
+                        .help("Directory to write files exported with ::export to")
+                        .long_help("Directory to write files exported with ::export to.\n\
+                                    Export destinations are relative to this directory, which is created if needed.\n\
+                                    The default is the current directory.")
+                )
+                .arg(
+                    Arg::new("STATE_FILE")
+                        .long("state-file")
+                        .allow_invalid_utf8(true)
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Skip outputs which did not change since they were built, as recorded in FILE")
+                        .long_help("Skip outputs which did not change since they were built, as recorded in FILE.\n\
+                                    An output is up to date if its build steps, resolved base images and the local files\n\
+                                    it copies are the same, and its image still exists. Commands are not run again even if\n\
+                                    they would give a different result, e.g. when downloading files.\n\
+                                    FILE is created if needed, and updated with the images built. It should not be in the\n\
+                                    build context, or be listed in .dockerignore.\n\
+                                    Only supported by the docker backend, which resolves base images before building.")
+                )
+                .arg(
+                    Arg::new("KEEP_GOING")
+                        .long("keep-going")
+                        .help("Keep building the other outputs if one fails")
+                        .long_help("Keep building the other outputs if one fails.\n\
+                                    The JSON output has the status of every output, and the build exits with an error\n\
+                                    at the end if any output failed. Files are not exported if the build failed.")
+                )
+                .arg(
+                    Arg::new("SHARD")
+                        .long("shard")
+                        .takes_value(true)
+                        .value_name("K/N")
+                        .validator(|s| s.parse::<sharding::Shard>().map(|_| ()))
+                        .help("Only build the K-th of N shards of the outputs")
+                        .long_help("Only build the K-th of N shards of the outputs, e.g. --shard 2/5.\n\
+                                    The outputs are split so that outputs sharing images are built on the same shard,\n\
+                                    and every machine running with the same N gets the same split.\n\
+                                    Use `modus plan --matrix N` to list the shards.")
+                )
+                .args(backend_args())
+        )
+        .subcommand(
+            Command::new("lock")
+                .about("Pin the images used by a query to their digests in a lockfile.")
+                .long_about("Pin the images used by a query to their digests in a lockfile.\n\
+                             The lockfile is written next to the Modusfile, e.g. Modusfile.lock, and used by `modus build --locked`.\n\
+                             Images which are already in the lockfile are kept as they are, unless --update is given.")
+                .arg(
+                    Arg::new("FILE")
+                        .required(false)
+                        .long_help("Specify the input Modusfile\n\
+                                    The default is to look for a Modusfile in the context directory.")
+                        .help("Specify the input Modusfile")
+                        .value_name("FILE")
+                        .short('f')
+                        .long("modusfile")
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("CONTEXT")
+                        .help("Specify the build context directory")
+                        .index(1)
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("QUERY")
+                        .required(true)
+                        .help("Specify the target query whose images to lock")
+                        .index(2),
+                )
+                .arg(arg!(--update "Resolve all images again, instead of keeping existing entries"))
+                .args(backend_args()),
+        )
+        .subcommand(
+            Command::new("plan")
+                .about("Print the build graph of a query without building it.")
+                .long_about("Print the build graph of a query without building it.\n\
+                             Each node is annotated with the location in the Modusfile it comes from.")
+                .arg(
+                    Arg::new("FILE")
+                        .required(false)
+                        .long_help("Specify the input Modusfile\n\
+                                    The default is to look for a Modusfile in the context directory.")
+                        .help("Specify the input Modusfile")
+                        .value_name("FILE")
+                        .short('f')
+                        .long("modusfile")
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("CONTEXT")
+                        .long_help("Specify the directory that contains the Modusfile.\n\
+                                    This is for compatibility with the `build` subcommand.")
+                        .help("Specify the directory that contains the Modusfile.")
+                        .index(1)
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("QUERY")
+                        .required(true)
+                        .help("Specify the target query to plan")
+                        .index(2),
+                )
+                .arg(
+                    Arg::new("FORMAT")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(planview::PLAN_FORMATS)
+                        .default_value("tree")
+                        .help("The output format")
+                        .long_help("The output format.\n\
+                                    tree: the instructions of each output image, with copied images nested below.\n\
+                                    json: the nodes of the plan with their dependencies and source locations.\n\
+                                    dot: a Graphviz graph, which can be rendered with e.g. `dot -Tsvg`."),
+                )
+                .arg(
+                    Arg::new("SHARD")
+                        .long("shard")
+                        .takes_value(true)
+                        .value_name("K/N")
+                        .validator(|s| s.parse::<sharding::Shard>().map(|_| ()))
+                        .help("Only show the K-th of N shards of the outputs"),
+                )
+                .arg(
+                    Arg::new("MATRIX")
+                        .long("matrix")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(|s| match s.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("expected a positive number"),
+                        })
+                        .conflicts_with("SHARD")
+                        .help("Print a CI matrix splitting the outputs into at most N shards")
+                        .long_help("Print a CI matrix splitting the outputs into at most N shards, as JSON.\n\
+                                    The matrix has the form {\"include\": [{\"shard\": \"1/2\", ...}, ...]},\n\
+                                    which can be used with GitHub Actions' fromJSON. Pass the shard to `modus build --shard`."),
+                ),
+        )
+        .subcommand(
+            Command::new("plan-diff")
+                .about("Compare the build graphs of two Modusfiles or plans.")
+                .long_about("Compare the build graphs of two Modusfiles or plans.\n\
+                             Steps are matched by what they do and the image they are applied on, so a step is \
+                             changed if anything before it changed. Outputs are matched by the literal they are built from.\n\
+                             To compare against another revision, write its Modusfile to a file first, \
+                             e.g. `git show HEAD~1:Modusfile > old.Modusfile`.")
+                .arg(
+                    Arg::new("OLD")
+                        .required(true)
+                        .help("The old Modusfile, or a JSON plan written by `modus plan --format json`")
+                        .index(1)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("NEW")
+                        .required(true)
+                        .help("The new Modusfile, or a JSON plan written by `modus plan --format json`")
+                        .index(2)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("QUERY")
+                        .required(false)
+                        .help("Specify the target query to plan, required unless both inputs are plans")
+                        .index(3),
+                )
+                .arg(
+                    Arg::new("FORMAT")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(["text", "json"])
+                        .default_value("text")
+                        .help("The output format"),
+                ),
+        )
+        .subcommand(
+            Command::new("affected")
+                .about("List the outputs of a query which depend on changed files.")
+                .long_about("List the outputs of a query which depend on changed files.\n\
+                             An output depends on a file if it, or an image it copies from, copies the file from the \
+                             build context, and the file is not excluded by the .dockerignore of the context.\n\
+                             Changes to the Modusfile itself are not taken into account, see `modus plan-diff`.")
+                .arg(
+                    Arg::new("FILE")
+                        .required(false)
+                        .long_help("Specify the input Modusfile\n\
+                                    The default is to look for a Modusfile in the context directory.")
+                        .help("Specify the input Modusfile")
+                        .value_name("FILE")
+                        .short('f')
+                        .long("modusfile")
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("CONTEXT")
+                        .help("Specify the build context directory")
+                        .index(1)
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("QUERY")
+                        .required(true)
+                        .help("Specify the target query")
+                        .index(2),
+                )
+                .arg(
+                    Arg::new("CHANGED_FILES")
+                        .long("changed-files")
+                        .takes_value(true)
+                        .multiple_values(true)
+                        .required(true)
+                        .value_name("PATH")
+                        .help("The changed files, relative to the context directory")
+                        .long_help("The changed files, relative to the context directory.\n\
+                                    Use - to read newline separated paths from stdin, e.g. from `git diff --name-only`."),
+                )
+                .arg(
+                    Arg::new("FORMAT")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(["text", "json"])
+                        .default_value("text")
+                        .help("The output format")
+                        .long_help("The output format.\n\
+                                    text: one affected output literal per line.\n\
+                                    json: the affected outputs, with the changed files each of them depends on."),
+                ),
+        )
+        .subcommand(
+            Command::new("lint")
+                .about("Report common mistakes in the images built by a query.")
+                .long_about("Report common mistakes in the images built by a query.\n\
+                             Warnings can be allowed with a `# modus:allow(code, ...)` comment on the line of the literal \
+                             they are reported at, or on the line above it. The lints are:\n\
+                             untagged-image: an image is used without a tag or digest, or with the latest tag.\n\
+                             apt-get-yes: apt-get install is run without -y.\n\
+                             apt-get-cleanup: apt-get lists are not removed in the step that downloads them.\n\
+                             cd-in-run: a command changes directory instead of using ::in_workdir.\n\
+                             early-context-copy: the whole context is copied before running commands.")
+                .arg(
+                    Arg::new("FILE")
+                        .required(false)
+                        .long_help("Specify the input Modusfile\n\
+                                    The default is to look for a Modusfile in the context directory.")
+                        .help("Specify the input Modusfile")
+                        .value_name("FILE")
+                        .short('f')
+                        .long("modusfile")
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("CONTEXT")
+                        .help("Specify the build context directory")
+                        .index(1)
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("QUERY")
+                        .required(true)
+                        .help("Specify the target query")
+                        .index(2),
+                )
+                .arg(arg!(--"deny-warnings" "Exit with an error if there are any warnings")),
+        )
+        .subcommand(
+            Command::new("policy")
+                .about("Check build plans against policies.")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("check")
+                        .about("Report the violations of a policy by the build plan of a query.")
+                        .long_about("Report the violations of a policy by the build plan of a query.\n\
+                                     A policy is a Modusfile defining violation(Subject, Message), where the subject is a node \
+                                     id or an output literal. It is evaluated over these facts about the plan:\n\
+                                     node_from(Node, Ref), node_run(Node, Command), node_copy(Node, Src, Dst), \
+                                     node_copy_image(Node, Image, Src, Dst), node_workdir(Node, Dir), node_env(Node, Key, Value), \
+                                     node_user(Node, User), node_label(Node, Label, Value), node_parent(Node, Parent), \
+                                     output(Output, Node), output_node(Output, Node), output_from(Output, Ref) and \
+                                     output_user(Output, User).\n\
+                                     output_user is root unless the plan sets the user, since the user of base images is not known.")
+                        .arg(
+                            Arg::new("FILE")
+                                .required(false)
+                                .long_help("Specify the input Modusfile\n\
+                                            The default is to look for a Modusfile in the context directory.")
+                                .help("Specify the input Modusfile")
+                                .value_name("FILE")
+                                .short('f')
+                                .long("modusfile")
+                                .allow_invalid_utf8(true),
+                        )
+                        .arg(
+                            Arg::new("CONTEXT")
+                                .help("Specify the build context directory")
+                                .index(1)
+                                .required(true)
+                                .allow_invalid_utf8(true),
+                        )
+                        .arg(
+                            Arg::new("QUERY")
+                                .required(true)
+                                .help("Specify the target query")
+                                .index(2),
+                        )
+                        .arg(
+                            Arg::new("POLICY")
+                                .long("policy")
+                                .short('p')
+                                .takes_value(true)
+                                .required(true)
+                                .value_name("FILE")
+                                .allow_invalid_utf8(true)
+                                .help("The Modusfile defining the policy"),
+                        ),
+                ),
         )
         .subcommand(
             Command::new("proof")
@@ -239,6 +705,71 @@ fn main() {
                         .allow_invalid_utf8(true),
                 )
                 .arg(arg!(-v --verbose "display the evaluated kinds for all the clauses"))
+                .arg(
+                    Arg::new("FIX")
+                        .long("fix")
+                        .help("Apply the suggested replacements of unknown predicates and operators")
+                        .long_help("Apply the suggested replacements of unknown predicates and operators to the Modusfile.\n\
+                                    Only the names with exactly one similar predicate or operator of the same arity are replaced."),
+                )
+                .arg(
+                    Arg::new("ENTRY")
+                        .long("entry")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .value_name("PREDICATE")
+                        .help("Warn about the predicates not used by these entry points")
+                        .long_help("Warn about the predicates not used by these entry points, i.e. the predicates that are queried.\n\
+                                    Tests are always entry points."),
+                )
+                .arg(
+                    Arg::new("ALL_ENTRIES")
+                        .long("all-entries")
+                        .conflicts_with("ENTRY")
+                        .help("Warn about the predicates not used by any image predicate or test"),
+                )
+        )
+        .subcommand(
+            Command::new("test")
+                .about("Run the tests declared in a Modusfile.")
+                .long_about("Run the tests declared in a Modusfile.\n\
+                             A test is a nullary predicate whose name starts with `test_`, and passes if it can be proven.\n\
+                             Tests whose name starts with `test_fail_` are expected to fail.\n\
+                             Tests are only run through the solver, no images are built.")
+                .arg(
+                    Arg::new("FILE")
+                        .required(false)
+                        .long_help("Set the input Modusfile\n\
+                                    The default is to look for a Modusfile in the context directory.")
+                        .help("Set the input Modusfile")
+                        .value_name("FILE")
+                        .short('f')
+                        .long("modusfile")
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("CONTEXT")
+                        .long_help("Specify the directory that contains the Modusfile.\n\
+                                    This is for compatibility with the `build` subcommand.")
+                        .help("Specify the directory that contains the Modusfile.")
+                        .index(1)
+                        .required(true)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("FILTER")
+                        .required(false)
+                        .help("Only run the tests whose name contains this string")
+                        .index(2),
+                )
+                .arg(
+                    Arg::new("JUNIT_OUTPUT")
+                        .long("junit")
+                        .value_name("FILE")
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                        .help("Write the test results as a JUnit XML report"),
+                )
         )
         .get_matches();
 
@@ -261,54 +792,32 @@ fn main() {
         ("transpile", sub) => {
             let input_file = sub.value_of("FILE").unwrap();
             let file = get_file_or_exit(Path::new(input_file));
-            let query: modusfile::Expression = match sub
-                .value_of("QUERY")
-                .map(|s| s.parse::<modusfile::Expression>())
-                .unwrap()
-            {
-                Ok(e) => e.without_position(),
-                Err(e) => {
-                    eprintln!("❌ Did not parse goal successfully",);
-                    let temp_file =
-                        SimpleFile::new("goal", sub.value_of("QUERY").unwrap_or_default());
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
-                    std::process::exit(1);
-                }
-            };
+            let build_plan =
+                plan_or_exit(&file, sub.value_of("QUERY").unwrap(), &err_writer, &config);
 
-            let mf: Modusfile = match file.source().parse() {
-                Ok(mf) => mf,
-                Err(e) => {
-                    eprintln!("❌ Did not parse Modusfile successfully",);
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
-                    std::process::exit(1);
+            if sub.value_of("FORMAT") == Some("llb") {
+                let context_dir = Path::new(input_file)
+                    .parent()
+                    .filter(|p| !p.as_os_str().is_empty())
+                    .unwrap_or_else(|| Path::new("."));
+                let backend = backend_from_args(sub);
+                let ctx = llb::LocalContext {
+                    context_dir,
+                    backend: &*backend,
+                };
+                let options = llb::TranslateOptions {
+                    has_dockerignore: context_dir.join(".dockerignore").exists(),
+                    no_cache: false,
+                };
+                if let Err(e) = llb::plan_terminal(&ctx, &options, &build_plan)
+                    .and_then(|t| Ok(t.write_definition(std::io::stdout().lock())?))
+                {
+                    print_build_error_and_exit(&e.to_string(), &err_writer);
                 }
-            };
-            let kind_res = mf.kinds();
-            if !analysis::check_and_output_analysis(
-                &kind_res,
-                &mf,
-                Some(&query),
-                false,
-                &mut err_writer.lock(),
-                &config,
-                &file,
-            ) {
-                std::process::exit(1)
+                return;
             }
 
-            let df_res = transpiler::transpile(mf, query);
-
-            match df_res {
-                Ok(df) => println!("{}", df),
-                Err(e) => {
-                    for diag_error in e {
-                        term::emit(&mut err_writer.lock(), &config, &file, &diag_error)
-                            .expect("Error when printing to stderr.")
-                    }
-                    std::process::exit(1)
-                }
-            }
+            println!("{}", transpiler::plan_to_docker(&build_plan));
         }
         ("build", sub) => {
             let context_dir = sub.value_of_os("CONTEXT").unwrap();
@@ -317,72 +826,10 @@ fn main() {
                 .map(PathBuf::from)
                 .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
             let file = get_file_or_exit(input_file.as_path());
-            let query: modusfile::Expression = match sub
-                .value_of("QUERY")
-                .map(|s| s.parse::<modusfile::Expression>())
-                .unwrap()
-            {
-                Ok(e) => e.without_position(),
-                Err(e) => {
-                    eprintln!("❌ Did not parse goal successfully",);
-                    let temp_file =
-                        SimpleFile::new("goal", sub.value_of("QUERY").unwrap_or_default());
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
-                    std::process::exit(1);
-                }
-            };
 
             let parse_start = Instant::now();
-
-            let mf: Modusfile = match file.source().parse() {
-                Ok(mf) => mf,
-                Err(e) => {
-                    eprintln!("❌ Did not parse Modusfile successfully.",);
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
-                    std::process::exit(1);
-                }
-            };
-            let kind_res = mf.kinds();
-            if !analysis::check_and_output_analysis(
-                &kind_res,
-                &mf,
-                Some(&query),
-                false,
-                &mut err_writer.lock(),
-                &config,
-                &file,
-            ) {
-                std::process::exit(1)
-            }
-
-            let build_plan = match imagegen::plan_from_modusfile(mf, query) {
-                Ok(plan) => plan,
-                Err(e) => {
-                    for diag_error in e {
-                        term::emit(&mut err_writer.lock(), &config, &file, &diag_error)
-                            .expect("Error when printing to stderr.")
-                    }
-                    std::process::exit(1)
-                }
-            };
-
-            fn print_build_error_and_exit(e_str: &str, w: &StandardStream) -> ! {
-                let mut w = w.lock();
-                (move || -> std::io::Result<()> {
-                    w.set_color(ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true))?;
-                    write!(w, "build error")?;
-                    w.set_color(&ColorSpec::new())?;
-                    write!(w, ": ")?;
-                    w.set_color(ColorSpec::new().set_bold(true))?;
-                    write!(w, "{}", e_str)?;
-                    w.set_color(&ColorSpec::new())?;
-                    writeln!(w)?;
-                    w.flush()?;
-                    Ok(())
-                })()
-                .expect("Unable to write to stderr.");
-                std::process::exit(1)
-            }
+            let build_plan =
+                plan_or_exit(&file, sub.value_of("QUERY").unwrap(), &err_writer, &config);
 
             let options = BuildOptions {
                 frontend_image: sub.value_of("CUSTOM_FRONTEND").unwrap().to_owned(),
@@ -407,6 +854,23 @@ fn main() {
                         })
                     })
                     .unwrap_or_else(|| num_cpus::get() as u32), // Cast: we're not getting 2^32 CPU computers anytime soon
+                offline: sub.is_present("OFFLINE"),
+                export_dir: {
+                    let export_dir = Path::new(sub.value_of_os("EXPORT_DIR").unwrap());
+                    fs::create_dir_all(export_dir)
+                        .and_then(|_| export_dir.canonicalize())
+                        .unwrap_or_else(|e| {
+                            print_build_error_and_exit(
+                                &format!(
+                                    "Unable to create export directory {}: {}",
+                                    export_dir.display(),
+                                    e
+                                ),
+                                &err_writer,
+                            )
+                        })
+                },
+                backend: backend_from_args(sub),
                 docker_build_options: DockerBuildOptions {
                     verbose: sub.is_present("VERBOSE"),
                     no_cache: sub.is_present("NO_CACHE"),
@@ -416,16 +880,53 @@ fn main() {
                         .map(|x| x.map(ToOwned::to_owned).collect())
                         .unwrap_or_default(),
                 },
+                keep_going: sub.is_present("KEEP_GOING"),
+                // Relative to the current directory, which changes during the build.
+                state_file: sub.value_of_os("STATE_FILE").map(|f| {
+                    std::env::current_dir()
+                        .map(|cwd| cwd.join(f))
+                        .unwrap_or_else(|e| {
+                            print_build_error_and_exit(
+                                &format!("Could not get current working directory: {}", e),
+                                &err_writer,
+                            )
+                        })
+                }),
             };
 
+            let mut build_plan = build_plan;
+            if sub.is_present("LOCKED") {
+                if let Err(e) = lockfile::Lockfile::read(&lockfile::lockfile_path(&input_file))
+                    .and_then(|l| l.apply(&mut build_plan))
+                {
+                    print_build_error_and_exit(&e.to_string(), &err_writer);
+                }
+            }
+
+            if let Some(shard) = sub.value_of("SHARD") {
+                let shard: sharding::Shard =
+                    shard.parse().expect("Expected clap to validate the shard");
+                build_plan = sharding::shard_plan(&build_plan, shard);
+            }
+
             let mut profiling = Profiling::default();
             profiling.planning = parse_start.elapsed().as_secs_f32();
 
-            match buildkit::build(build_plan.clone(), context_dir, &options, &mut profiling) {
+            let result = if build_plan.outputs.is_empty() {
+                // Only possible with --shard, if there are fewer outputs than shards.
+                eprintln!("{}", "Nothing to build in this shard.".blue());
+                Ok(buildkit::BuildResults {
+                    outputs: Vec::new(),
+                    failed_checks: Vec::new(),
+                })
+            } else {
+                buildkit::build(build_plan.clone(), context_dir, &options, &mut profiling)
+            };
+            match result {
                 Err(e) => {
                     print_build_error_and_exit(&e.to_string(), &err_writer);
                 }
-                Ok(image_ids) => {
+                Ok(results) => {
                     let total_dur = parse_start.elapsed();
                     profiling.total = total_dur.as_secs_f32();
                     if sub.is_present("JSON_OUTPUT") {
@@ -460,7 +961,7 @@ fn main() {
                             json_out,
                             &json_out_name.to_string_lossy(),
                             &build_plan,
-                            &image_ids[..],
+                            &results,
                         ) {
                             print_build_error_and_exit(&e, &err_writer);
                         }
@@ -473,6 +974,253 @@ fn main() {
                             );
                         }
                     }
+                    let nb_failed = results.outputs.iter().filter(|r| r.is_err()).count();
+                    if nb_failed > 0 {
+                        print_build_error_and_exit(
+                            &format!(
+                                "{} of {} outputs failed to build.",
+                                nb_failed,
+                                results.outputs.len()
+                            ),
+                            &err_writer,
+                        );
+                    }
+                    if !results.failed_checks.is_empty() {
+                        print_build_error_and_exit(
+                            &format!(
+                                "{} of {} checks failed to build.",
+                                results.failed_checks.len(),
+                                build_plan.checks.len()
+                            ),
+                            &err_writer,
+                        );
+                    }
+                }
+            }
+        }
+        ("lock", sub) => {
+            let context_dir = sub.value_of_os("CONTEXT").unwrap();
+            let input_file = sub
+                .value_of_os("FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
+            let file = get_file_or_exit(input_file.as_path());
+            let build_plan =
+                plan_or_exit(&file, sub.value_of("QUERY").unwrap(), &err_writer, &config);
+
+            let lockfile_path = lockfile::lockfile_path(&input_file);
+            let existing = if lockfile_path.exists() {
+                Some(
+                    lockfile::Lockfile::read(&lockfile_path).unwrap_or_else(|e| {
+                        print_build_error_and_exit(&e.to_string(), &err_writer)
+                    }),
+                )
+            } else {
+                None
+            };
+            let refs = lockfile::image_refs(&build_plan);
+            match lockfile::lock_images(
+                &refs,
+                existing,
+                sub.is_present("update"),
+                &*backend_from_args(sub),
+            )
+            .and_then(|l| l.write(&lockfile_path).map(|_| l))
+            {
+                Ok(l) => eprintln!(
+                    "Wrote {} image(s) to {}",
+                    l.images.len(),
+                    lockfile_path.display()
+                ),
+                Err(e) => print_build_error_and_exit(&e.to_string(), &err_writer),
+            }
+        }
+        ("plan", sub) => {
+            let context_dir = sub.value_of_os("CONTEXT").unwrap();
+            let input_file = sub
+                .value_of_os("FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
+            let file = get_file_or_exit(input_file.as_path());
+            let mut build_plan =
+                plan_or_exit(&file, sub.value_of("QUERY").unwrap(), &err_writer, &config);
+            if let Some(n) = sub.value_of("MATRIX") {
+                let n: usize = n
+                    .parse()
+                    .expect("Expected clap to validate the matrix size");
+                println!("{}", sharding::matrix_json(&build_plan, n));
+                return;
+            }
+            if let Some(shard) = sub.value_of("SHARD") {
+                let shard: sharding::Shard =
+                    shard.parse().expect("Expected clap to validate the shard");
+                build_plan = sharding::shard_plan(&build_plan, shard);
+            }
+            let format: planview::PlanFormat = sub
+                .value_of("FORMAT")
+                .unwrap()
+                .parse()
+                .expect("Expected clap to validate the format");
+            planview::write_plan(format, &build_plan, &file, &mut std::io::stdout().lock())
+                .expect("Error when printing to stdout.");
+        }
+        ("plan-diff", sub) => {
+            let read_plan = |arg: &str| {
+                let file = get_file_or_exit(Path::new(sub.value_of_os(arg).unwrap()));
+                let plan = if file.source().trim_start().starts_with('{') {
+                    planview::read_plan_json(file.source().as_bytes())
+                        .unwrap_or_else(|e| print_build_error_and_exit(&e.to_string(), &err_writer))
+                } else {
+                    let query = sub.value_of("QUERY").unwrap_or_else(|| {
+                        print_build_error_and_exit(
+                            "a query is needed to plan a Modusfile",
+                            &err_writer,
+                        )
+                    });
+                    plan_or_exit(&file, query, &err_writer, &config)
+                };
+                (plan, file)
+            };
+            let (old_plan, old_file) = read_plan("OLD");
+            let (new_plan, new_file) = read_plan("NEW");
+            let diff = plandiff::PlanDiff::new(&old_plan, &new_plan);
+            let old = plandiff::PlanWithSource {
+                plan: &old_plan,
+                file: &old_file,
+            };
+            let new = plandiff::PlanWithSource {
+                plan: &new_plan,
+                file: &new_file,
+            };
+            let out = &mut std::io::stdout().lock();
+            match sub.value_of("FORMAT") {
+                Some("json") => plandiff::write_diff_json(&diff, &old, &new, out),
+                _ => plandiff::write_diff_text(&diff, &old, &new, out),
+            }
+            .expect("Error when printing to stdout.");
+        }
+        ("affected", sub) => {
+            let context_dir = Path::new(sub.value_of_os("CONTEXT").unwrap());
+            let input_file = sub
+                .value_of_os("FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| context_dir.join("Modusfile"));
+            let file = get_file_or_exit(input_file.as_path());
+            let build_plan =
+                plan_or_exit(&file, sub.value_of("QUERY").unwrap(), &err_writer, &config);
+
+            let mut changed_files = Vec::new();
+            for f in sub.values_of("CHANGED_FILES").unwrap() {
+                if f == "-" {
+                    let mut input = String::new();
+                    if let Err(e) = std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)
+                    {
+                        print_build_error_and_exit(
+                            &format!("Unable to read changed files from stdin: {}", e),
+                            &err_writer,
+                        );
+                    }
+                    changed_files.extend(
+                        input
+                            .lines()
+                            .filter(|l| !l.trim().is_empty())
+                            .map(ToOwned::to_owned),
+                    );
+                } else {
+                    changed_files.push(f.to_owned());
+                }
+            }
+            let ignore = match fs::read_to_string(context_dir.join(".dockerignore")) {
+                Ok(content) => dockerignore::DockerIgnore::parse(&content),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+                Err(e) => print_build_error_and_exit(
+                    &format!("Unable to read .dockerignore: {}", e),
+                    &err_writer,
+                ),
+            };
+
+            let affected = affected::affected_outputs(&build_plan, &changed_files, &ignore);
+            if sub.value_of("FORMAT") == Some("json") {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&affected).expect("Unable to serialize outputs")
+                );
+            } else {
+                for output in affected {
+                    println!("{}", output.name);
+                }
+            }
+        }
+        ("lint", sub) => {
+            let context_dir = Path::new(sub.value_of_os("CONTEXT").unwrap());
+            let input_file = sub
+                .value_of_os("FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| context_dir.join("Modusfile"));
+            let file = get_file_or_exit(input_file.as_path());
+            let build_plan =
+                plan_or_exit(&file, sub.value_of("QUERY").unwrap(), &err_writer, &config);
+
+            let diags = lint::lint(&build_plan, file.source());
+            print_diagnostics(&diags, &mut err_writer.lock(), &config, &file);
+            if diags.is_empty() {
+                println!("No lint warnings.");
+            } else {
+                eprintln!("{} lint warning(s).", diags.len());
+                if sub.is_present("deny-warnings") {
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("policy", sub) => {
+            if let Some(("check", sub)) = sub.subcommand() {
+                let context_dir = Path::new(sub.value_of_os("CONTEXT").unwrap());
+                let input_file = sub
+                    .value_of_os("FILE")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| context_dir.join("Modusfile"));
+                let file = get_file_or_exit(input_file.as_path());
+                let build_plan =
+                    plan_or_exit(&file, sub.value_of("QUERY").unwrap(), &err_writer, &config);
+
+                let policy_file = get_file_or_exit(Path::new(sub.value_of_os("POLICY").unwrap()));
+                let policy = match policy_file.source().parse::<Modusfile>() {
+                    Ok(mf) => mf,
+                    Err(e) => {
+                        eprintln!("❌ Did not parse policy successfully.",);
+                        print_diagnostics(&e, &mut err_writer.lock(), &config, &policy_file);
+                        std::process::exit(1);
+                    }
+                };
+                if !analysis::check_and_output_analysis(
+                    &policy.kinds(),
+                    &policy,
+                    None,
+                    false,
+                    &mut err_writer.lock(),
+                    &config,
+                    &policy_file,
+                ) {
+                    std::process::exit(1)
+                }
+
+                let violations = match policy::check_policy(&build_plan, &policy) {
+                    Ok(violations) => violations,
+                    Err(e) => {
+                        print_diagnostics(&e, &mut err_writer.lock(), &config, &policy_file);
+                        std::process::exit(1);
+                    }
+                };
+                let diags: Vec<_> = violations
+                    .iter()
+                    .map(|v| v.get_diagnostic(&build_plan))
+                    .collect();
+                print_diagnostics(&diags, &mut err_writer.lock(), &config, &file);
+                if violations.is_empty() {
+                    println!("No policy violations.");
+                } else {
+                    eprintln!("❌ {} policy violation(s).", violations.len());
+                    std::process::exit(1);
                 }
             }
         }
@@ -571,7 +1319,22 @@ fn main() {
                 .value_of_os("FILE")
                 .map(PathBuf::from)
                 .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
-            let file = get_file_or_exit(input_file.as_path());
+            let mut file = get_file_or_exit(input_file.as_path());
+
+            if sub.is_present("FIX") {
+                if let Ok(mf) = file.source().parse::<Modusfile>() {
+                    let fixes = suggest::modusfile_fixes(&mf);
+                    let (fixed, applied) = suggest::apply_fixes(file.source(), &fixes);
+                    if applied > 0 {
+                        if let Err(e) = fs::write(&input_file, fixed) {
+                            eprintln!("Error writing {}: {}", input_file.display(), e);
+                            std::process::exit(1);
+                        }
+                        println!("Applied {} fix(es).", applied);
+                        file = get_file_or_exit(input_file.as_path());
+                    }
+                }
+            }
 
             let is_verbose = sub.is_present("verbose");
 
@@ -589,12 +1352,93 @@ fn main() {
                     ) {
                         std::process::exit(1)
                     }
+                    let entries = if sub.is_present("ALL_ENTRIES") {
+                        Some(analysis::Entries::All)
+                    } else {
+                        sub.values_of("ENTRY").map(|values| {
+                            analysis::Entries::Predicates(values.map(ToOwned::to_owned).collect())
+                        })
+                    };
+                    let warnings = analysis::dead_code(&mf, &kind_res, entries.as_ref());
+                    print_diagnostics(&warnings, &mut err_writer.lock(), &config, &file);
+                }
+                Err(e) => {
+                    eprintln!("❌ Did not parse Modusfile successfully.",);
+                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                    std::process::exit(1);
                 }
+            }
+        }
+        ("test", sub) => {
+            let context_dir = sub.value_of_os("CONTEXT").unwrap();
+            let input_file = sub
+                .value_of_os("FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
+            let file = get_file_or_exit(input_file.as_path());
+
+            let mf = match file.source().parse::<Modusfile>() {
+                Ok(mf) => mf,
                 Err(e) => {
                     eprintln!("❌ Did not parse Modusfile successfully.",);
                     print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
                     std::process::exit(1);
                 }
+            };
+            let kind_res = mf.kinds();
+            if !analysis::check_and_output_analysis(
+                &kind_res,
+                &mf,
+                None,
+                false,
+                &mut err_writer.lock(),
+                &config,
+                &file,
+            ) {
+                std::process::exit(1)
+            }
+
+            let results = testing::run_tests(&mf, sub.value_of("FILTER"));
+            println!("running {} test(s)", results.len());
+            for r in &results {
+                let status = if r.passed() {
+                    "ok".green()
+                } else {
+                    "FAILED".red()
+                };
+                println!("test {} ... {}", r.case.name, status);
+            }
+            let failed: Vec<_> = results.iter().filter(|r| !r.passed()).collect();
+            if !failed.is_empty() {
+                println!("\nfailures:");
+                for r in &failed {
+                    println!("\n---- {} ----", r.case.name);
+                    if let testing::TestOutcome::Failed(diags) = &r.outcome {
+                        print_diagnostics(diags, &mut out_writer.lock(), &config, &file);
+                    }
+                }
+            }
+            println!(
+                "\ntest result: {}. {} passed; {} failed",
+                if failed.is_empty() {
+                    "ok".green()
+                } else {
+                    "FAILED".red()
+                },
+                results.len() - failed.len(),
+                failed.len()
+            );
+
+            if let Some(junit_path) = sub.value_of_os("JUNIT_OUTPUT") {
+                let xml = testing::to_junit_xml(file.name(), &results);
+                if let Err(e) = fs::write(junit_path, xml) {
+                    eprintln!("Unable to write JUnit report: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            if !failed.is_empty() {
+                std::process::exit(1);
             }
         }
         _ => (),
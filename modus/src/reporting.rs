@@ -25,14 +25,33 @@ use serde::{ser::SerializeSeq, Serialize};
 use modus_lib::{
     imagegen::BuildPlan,
     logic::{IRTerm, Literal},
+    planview::describe_node,
 };
 
-pub type BuildResult = Vec<Image>;
+use crate::buildkit::{BuildResults, BuiltImage};
+
+pub type BuildResult = Vec<ResultEntry>;
 
 #[derive(Debug, Clone)]
 pub enum ConstantTerm {
     Constant(String),
-    List(Vec<String>),
+    Integer(i64),
+    Boolean(bool),
+    List(Vec<ConstantTerm>),
+}
+
+impl ConstantTerm {
+    fn from_term(t: IRTerm) -> Self {
+        match t {
+            IRTerm::Constant(x) => ConstantTerm::Constant(x),
+            IRTerm::Integer(i) => ConstantTerm::Integer(i),
+            IRTerm::Boolean(b) => ConstantTerm::Boolean(b),
+            IRTerm::List(ts) => {
+                ConstantTerm::List(ts.into_iter().map(ConstantTerm::from_term).collect())
+            }
+            _ => panic!("Expected constant"),
+        }
+    }
 }
 
 impl Serialize for ConstantTerm {
@@ -42,6 +61,8 @@ impl Serialize for ConstantTerm {
     {
         match self {
             ConstantTerm::Constant(c) => serializer.serialize_str(c),
+            ConstantTerm::Integer(i) => serializer.serialize_i64(*i),
+            ConstantTerm::Boolean(b) => serializer.serialize_bool(*b),
             ConstantTerm::List(cs) => {
                 let mut seq = serializer.serialize_seq(Some(cs.len()))?;
                 for c in cs {
@@ -66,33 +87,58 @@ impl ConstantLiteral {
             args: lit
                 .args
                 .into_iter()
-                .map(|x| match x {
-                    IRTerm::Constant(x) => ConstantTerm::Constant(x),
-                    IRTerm::List(ts) => ConstantTerm::List(
-                        ts.iter()
-                            .map(|t| t.as_constant().unwrap().to_owned())
-                            .collect(),
-                    ),
-                    _ => panic!("Expected constant"),
-                })
+                .map(ConstantTerm::from_term)
                 .collect::<Vec<_>>(),
         }
     }
 }
 
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageStatus {
+    Success,
+    /// Not built again, see `modus build --state-file`.
+    #[serde(rename = "up-to-date")]
+    UpToDate,
+    Failed,
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct Image {
     #[serde(flatten)]
     pub source_literal: ConstantLiteral,
-    pub digest: String,
+    pub status: ImageStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Why the image failed to build, with `--keep-going`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A check which failed to build, with `--keep-going`. Checks are only reported
+/// when they fail, after all the images.
+#[derive(Serialize, Debug, Clone)]
+pub struct FailedCheck {
+    /// The literal the check was generated from.
+    pub check: String,
+    pub status: ImageStatus,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ResultEntry {
+    Image(Image),
+    Check(FailedCheck),
 }
 
 pub fn write_build_result<F: Write, P: Display>(
     mut json_out: F,
     json_out_name: P,
     build_plan: &BuildPlan,
-    image_ids: &[String],
+    results: &BuildResults,
 ) -> Result<(), String> {
+    let image_ids = &results.outputs;
     debug_assert_eq!(build_plan.outputs.len(), image_ids.len());
     debug_assert!(build_plan
         .outputs
@@ -103,13 +149,33 @@ pub fn write_build_result<F: Write, P: Display>(
         .outputs
         .iter()
         .zip(image_ids)
-        .map(|(o, i)| Image {
-            source_literal: ConstantLiteral::from_literal(
-                o.source_literal.as_ref().unwrap().clone(),
-            ),
-            digest: i.clone(),
+        .map(|(o, i)| {
+            ResultEntry::Image(Image {
+                source_literal: ConstantLiteral::from_literal(
+                    o.source_literal.as_ref().unwrap().clone(),
+                ),
+                status: match i {
+                    Ok(BuiltImage {
+                        up_to_date: true, ..
+                    }) => ImageStatus::UpToDate,
+                    Ok(_) => ImageStatus::Success,
+                    Err(_) => ImageStatus::Failed,
+                },
+                digest: i.as_ref().ok().map(|i| i.id.clone()),
+                error: i.as_ref().err().map(ToString::to_string),
+            })
         })
-        .collect::<Vec<_>>();
+        .chain(results.failed_checks.iter().map(|(node, e)| {
+            ResultEntry::Check(FailedCheck {
+                check: build_plan
+                    .node_source(*node)
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| describe_node(&build_plan.nodes[*node])),
+                status: ImageStatus::Failed,
+                error: e.to_string(),
+            })
+        }))
+        .collect::<BuildResult>();
 
     json_out
         .write_all(
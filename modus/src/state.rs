@@ -0,0 +1,285 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! State files, which record the image built for each output, so that
+//! `modus build --state-file` can skip outputs whose inputs did not change.
+//!
+//! The inputs of an output are summarized by a hash of the content hash of its
+//! node in the build plan, which covers the parameters of every step and the
+//! resolved base images, and of the local files it copies, leaving out those
+//! excluded by `.dockerignore`. Commands may still depend on anything else,
+//! e.g. files downloaded from the network, which is why this is opt-in.
+
+use std::{collections::BTreeMap, fs, io::ErrorKind, path::Path, process::Stdio};
+
+use modus_lib::{
+    affected::local_copy_sources,
+    dockerignore::{path_components, DockerIgnore},
+    imagegen::BuildPlan,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::backend::Backend;
+use crate::buildkit::BuildError;
+
+const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputState {
+    /// The hash of the inputs of the output, see `output_hashes`.
+    pub hash: String,
+    pub image: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildState {
+    pub version: u32,
+    /// Maps output literals to the last image built for them.
+    pub outputs: BTreeMap<String, OutputState>,
+}
+
+impl Default for BuildState {
+    fn default() -> Self {
+        BuildState {
+            version: STATE_VERSION,
+            outputs: BTreeMap::new(),
+        }
+    }
+}
+
+impl BuildState {
+    /// Reads a state file, or returns an empty state if it does not exist yet.
+    pub fn read(path: &Path) -> Result<BuildState, BuildError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BuildState::default()),
+            Err(e) => {
+                return Err(BuildError::UnableToReadStateFile(
+                    path.display().to_string(),
+                    e,
+                ))
+            }
+        };
+        let state: BuildState = serde_json::from_str(&content)
+            .map_err(|e| BuildError::InvalidStateFile(path.display().to_string(), e.to_string()))?;
+        if state.version != STATE_VERSION {
+            return Err(BuildError::InvalidStateFile(
+                path.display().to_string(),
+                format!(
+                    "unsupported version {}, expected {}",
+                    state.version, STATE_VERSION
+                ),
+            ));
+        }
+        Ok(state)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), BuildError> {
+        let mut content = serde_json::to_string_pretty(self).expect("Unable to serialize state");
+        content.push('\n');
+        fs::write(path, content)
+            .map_err(|e| BuildError::UnableToWriteStateFile(path.display().to_string(), e))
+    }
+
+    /// The image last built for `output`, if its inputs had the same hash.
+    pub fn image(&self, output: &str, hash: &str) -> Option<&str> {
+        self.outputs
+            .get(output)
+            .filter(|o| o.hash == hash)
+            .map(|o| o.image.as_str())
+    }
+
+    pub fn record(&mut self, output: String, hash: String, image: String) {
+        self.outputs.insert(output, OutputState { hash, image });
+    }
+}
+
+/// Whether the image is still in the local image store.
+pub fn image_exists(backend: &dyn Backend, image: &str) -> bool {
+    match backend.inspect_command(image, "{{.Id}}") {
+        Some(mut cmd) => cmd
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success()),
+        None => false,
+    }
+}
+
+/// Adds the files at `name`, a normalized path relative to the context, to the
+/// hash. Directories are walked in a fixed order, and symbolic links are not
+/// followed.
+fn hash_path(
+    hasher: &mut Sha256,
+    context: &Path,
+    name: &str,
+    ignore: &DockerIgnore,
+) -> Result<(), BuildError> {
+    let path = context.join(name);
+    let err = |e| BuildError::UnableToReadContextFile(name.to_owned(), e);
+    let metadata = match fs::symlink_metadata(&path) {
+        Ok(m) => m,
+        // The build fails later on, unless the path is created by then.
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            hasher.input(format!("missing {}\0", name));
+            return Ok(());
+        }
+        Err(e) => return Err(err(e)),
+    };
+    let ignored = !name.is_empty() && ignore.is_ignored(name);
+    if metadata.is_dir() {
+        if ignored && !ignore.has_exceptions() {
+            return Ok(());
+        }
+        hasher.input(format!("dir {}\0", name));
+        let mut entries: Vec<String> = fs::read_dir(&path)
+            .map_err(err)?
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<_, _>>()
+            .map_err(err)?;
+        entries.sort();
+        for entry in entries {
+            let entry = if name.is_empty() {
+                entry
+            } else {
+                format!("{}/{}", name, entry)
+            };
+            hash_path(hasher, context, &entry, ignore)?;
+        }
+    } else if !ignored {
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path).map_err(err)?;
+            hasher.input(format!("link {} {}\0", name, target.display()));
+        } else {
+            hasher.input(format!("file {} {}\0", name, is_executable(&metadata)));
+            hasher.input(fs::read(&path).map_err(err)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// The hash of the inputs of every output, following the order in
+/// `plan.outputs`. This must be called after base images are resolved, so that
+/// a new version of a base image changes the hash.
+pub fn output_hashes(
+    plan: &BuildPlan,
+    context: &Path,
+    has_dockerignore: bool,
+) -> Result<Vec<String>, BuildError> {
+    let ignore = if has_dockerignore {
+        let content = fs::read_to_string(context.join(".dockerignore"))
+            .map_err(|e| BuildError::UnableToReadContextFile(".dockerignore".to_owned(), e))?;
+        DockerIgnore::parse(&content)
+    } else {
+        DockerIgnore::default()
+    };
+    let node_hashes = plan.content_hashes();
+    plan.outputs
+        .iter()
+        .map(|output| {
+            let mut hasher = Sha256::new();
+            hasher.input(&node_hashes[output.node]);
+            for src in local_copy_sources(plan, output.node) {
+                let src = path_components(src).join("/");
+                hasher.input(format!("\0copy {}\0", src));
+                hash_path(&mut hasher, context, &src, &ignore)?;
+            }
+            Ok(format!("{:x}", hasher.result()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buildkit::gen_tmp_filename;
+    use modus_lib::imagegen::{BuildNode, Output};
+
+    fn copy_plan(src_path: &str) -> BuildPlan {
+        let mut plan = BuildPlan::new();
+        let from = plan.new_node(
+            BuildNode::From {
+                image_ref: "alpine".to_owned(),
+                display_name: "alpine".to_owned(),
+            },
+            vec![],
+        );
+        let copy = plan.new_node(
+            BuildNode::CopyFromLocal {
+                parent: from,
+                src_path: src_path.to_owned(),
+                dst_path: "/app".to_owned(),
+            },
+            vec![from],
+        );
+        plan.outputs.push(Output {
+            node: copy,
+            source_literal: None,
+        });
+        plan
+    }
+
+    #[test]
+    fn hashes_copied_files() {
+        let dir = std::env::temp_dir().join(gen_tmp_filename());
+        fs::create_dir_all(dir.join("app")).unwrap();
+        fs::write(dir.join("app/main.c"), "int main() {}").unwrap();
+        fs::write(dir.join("app/README.md"), "hello").unwrap();
+        fs::write(dir.join(".dockerignore"), "**/*.md").unwrap();
+
+        let plan = copy_plan("./app");
+        let hash = || output_hashes(&plan, &dir, true).unwrap()[0].clone();
+        let original = hash();
+        assert_eq!(hash(), original);
+        fs::write(dir.join("app/README.md"), "changed").unwrap();
+        assert_eq!(hash(), original);
+        fs::write(dir.join("app/main.c"), "int main() { return 1; }").unwrap();
+        let changed = hash();
+        assert_ne!(changed, original);
+        assert_ne!(
+            output_hashes(&copy_plan("app/main.c"), &dir, true).unwrap()[0],
+            changed
+        );
+        // Copying the whole context.
+        assert_ne!(
+            output_hashes(&copy_plan("."), &dir, true).unwrap()[0],
+            changed
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state() {
+        let mut state = BuildState::default();
+        state.record("a".to_owned(), "1".to_owned(), "sha256:a".to_owned());
+        assert_eq!(state.image("a", "1"), Some("sha256:a"));
+        assert_eq!(state.image("a", "2"), None);
+        assert_eq!(state.image("b", "1"), None);
+    }
+}
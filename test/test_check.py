@@ -0,0 +1,53 @@
+# Modus, a language for building container images
+# Copyright (C) 2022 University College London
+
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU Affero General Public License as
+# published by the Free Software Foundation, either version 3 of the
+# License, or (at your option) any later version.
+
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU Affero General Public License for more details.
+
+# You should have received a copy of the GNU Affero General Public License
+# along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+from modustest import ModusTestCase, Fact
+from textwrap import dedent
+
+
+class TestCheck(ModusTestCase):
+    def test_check_not_in_image(self):
+        mf = dedent("""\
+            a :-
+                from("alpine"),
+                run("echo aaa > /tmp/file"),
+                run("echo bbb > /tmp/check && test -f /tmp/file")::check,
+                run("echo ccc >> /tmp/file").""")
+        imgs = self.build(mf, "a")
+        img = imgs[Fact("a", ())]
+        self.assertEqual(img.read_file("/tmp/file"), "aaa\nccc\n")
+        self.assertFalse(img.contains_file("/tmp/check"))
+
+    def test_check_failure_fails_build(self):
+        mf = dedent("""\
+            a :-
+                from("alpine"),
+                run("false")::check,
+                run("echo aaa > /tmp/file").""")
+        self.build(mf, "a", should_succeed=False)
+
+    def test_check_in_workdir(self):
+        mf = dedent("""\
+            a :-
+                from("alpine"),
+                (
+                    run("echo aaa > file"),
+                    run("test -f file")::check
+                )::in_workdir("/tmp").""")
+        imgs = self.build(mf, "a")
+        img = imgs[Fact("a", ())]
+        self.assertEqual(img.read_file("/tmp/file"), "aaa\n")
@@ -0,0 +1,62 @@
+# Modus, a language for building container images
+# Copyright (C) 2022 University College London
+
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU Affero General Public License as
+# published by the Free Software Foundation, either version 3 of the
+# License, or (at your option) any later version.
+
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU Affero General Public License for more details.
+
+# You should have received a copy of the GNU Affero General Public License
+# along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+import os
+import unittest
+from subprocess import run, PIPE
+from textwrap import dedent
+from pathlib import Path
+
+from modustest import ModusTestCase, Image, MODUS_BUILDKIT_FRONTEND
+
+
+@unittest.skipIf(MODUS_BUILDKIT_FRONTEND is None, "needs MODUS_BUILDKIT_FRONTEND to write the #syntax line")
+class TestDockerBuild(ModusTestCase):
+    """Builds Modusfiles with docker build directly, letting the frontend run the solver."""
+
+    def docker_build(self, modusfile, query, should_succeed=True):
+        (Path(self.context.name) / "Modusfile").write_text(f"# syntax={MODUS_BUILDKIT_FRONTEND}\n{modusfile}")
+        iidfile = Path(self.context.name) / "iid"
+        cmd = ["docker", "build", "-f", "Modusfile", "--build-arg", f"query={query}",
+               "--iidfile", str(iidfile), self.context.name]
+        result = run(cmd, text=True, stdout=PIPE, stderr=PIPE, env={**os.environ, "DOCKER_BUILDKIT": "1"})
+        if not should_succeed:
+            self.assertNotEqual(result.returncode, 0)
+            return result.stderr
+        self.assertEqual(result.returncode, 0, result.stderr)
+        digest = iidfile.read_text()
+        self._images.add(digest)
+        return Image(digest)
+
+    def test_build(self):
+        self.context.add_file("data.txt", "hello")
+        mf = dedent("""\
+            a(version) :- from("alpine"), copy("data.txt", "/data.txt"), run(f"echo ${version} > /version").""")
+        img = self.docker_build(mf, 'a("1.0")')
+        self.assertEqual(img.read_file("/data.txt"), "hello")
+        self.assertEqual(img.read_file("/version"), "1.0\n")
+
+    def test_scratch(self):
+        self.context.add_file("data.txt", "hello")
+        mf = dedent("""\
+            a :- from("scratch"), copy("data.txt", "/data.txt").""")
+        self.docker_build(mf, "a")
+
+    def test_no_proof(self):
+        mf = dedent("""\
+            a(version) :- version = "1.0", from("alpine").""")
+        self.docker_build(mf, 'a("2.0")', should_succeed=False)
@@ -0,0 +1,47 @@
+# Modus, a language for building container images
+# Copyright (C) 2022 University College London
+
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU Affero General Public License as
+# published by the Free Software Foundation, either version 3 of the
+# License, or (at your option) any later version.
+
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU Affero General Public License for more details.
+
+# You should have received a copy of the GNU Affero General Public License
+# along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+from modustest import ModusTestCase, Fact
+from textwrap import dedent
+from pathlib import Path
+
+
+class TestExport(ModusTestCase):
+    def test_export_file(self):
+        mf = dedent("""\
+            app :- from("alpine"), run("mkdir -p /out && echo hello > /out/bin").
+            release :- app::export("/out/bin", "dist/").""")
+        self.build(mf, "release")
+        self.assertEqual((Path(self.context.name) / "dist/bin").read_text(), "hello\n")
+
+    def test_export_relative_to_workdir(self):
+        mf = dedent("""\
+            app :-
+                from("alpine")::set_workdir("/app"),
+                run("mkdir -p target && echo hello > target/bin").
+            release :- app::export("target", "dist").""")
+        self.build(mf, "release")
+        self.assertEqual((Path(self.context.name) / "dist/bin").read_text(), "hello\n")
+
+    def test_export_does_not_change_image(self):
+        mf = dedent("""\
+            app :- from("alpine"), run("echo hello > /bin_file").
+            release :- app::export("/bin_file", "bin_file").""")
+        imgs = self.build(mf, "release")
+        img = imgs[Fact("release", ())]
+        self.assertEqual(img.read_file("/bin_file"), "hello\n")
+        self.assertEqual((Path(self.context.name) / "bin_file").read_text(), "hello\n")